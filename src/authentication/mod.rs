@@ -1,9 +1,20 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use crate::protocol::socks::v5::ProtectionLevel;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AuthenticationMethod {
     NoAuthentication,
+    Gssapi,
     UsernamePassword,
+    /// Bearer token, sent as password of SOCKS5 username/password
+    /// authentication, the user name is ignored.
+    Token,
 }
 
 pub enum Authentication {
@@ -11,20 +22,188 @@ pub enum Authentication {
     Token { token: Vec<u8> },
 }
 
-#[derive(Debug, Default)]
+/// Security context of a GSS-API session, one per connection.
+#[derive(Default)]
+pub struct GssapiContext {
+    /// Name of the client, set by [`GssapiAuthenticator`] once the context is
+    /// established.
+    pub principal: Option<String>,
+
+    /// State of the underlying mechanism, e.g. a Kerberos security context.
+    pub state: Option<Box<dyn Any + Send + Sync>>,
+}
+
+impl std::fmt::Debug for GssapiContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GssapiContext").field("principal", &self.principal).finish_non_exhaustive()
+    }
+}
+
+/// Result of processing a context establishment token.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GssapiAccept {
+    /// The context is not established yet, `token` is sent to the client and
+    /// another token from the client is expected.
+    Continue { token: Vec<u8> },
+
+    /// The context is established, `token` is sent to the client if it is not
+    /// empty.
+    Established { token: Vec<u8> },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GssapiError {
+    message: String,
+}
+
+impl GssapiError {
+    #[inline]
+    #[must_use]
+    pub fn new(message: impl Into<String>) -> Self { Self { message: message.into() } }
+}
+
+impl std::fmt::Display for GssapiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for GssapiError {}
+
+/// Acceptor side of a GSS-API mechanism used by SOCKS5 method `GSSAPI`
+/// (RFC 1961).
+pub trait GssapiAuthenticator: Send + Sync {
+    /// Processes a context establishment token sent by the client.
+    fn accept_context(
+        &self,
+        context: &mut GssapiContext,
+        token: &[u8],
+    ) -> Result<GssapiAccept, GssapiError>;
+
+    /// Protects `message` with `gss_wrap`, `confidential` requests
+    /// confidentiality in addition to integrity.
+    fn wrap(
+        &self,
+        context: &GssapiContext,
+        confidential: bool,
+        message: &[u8],
+    ) -> Result<Vec<u8>, GssapiError>;
+
+    /// Verifies and recovers a message protected by the client with
+    /// `gss_wrap`.
+    fn unwrap(&self, context: &GssapiContext, token: &[u8]) -> Result<Vec<u8>, GssapiError>;
+
+    /// Returns whether per-message protection `level` is supported.
+    fn supports_protection_level(&self, level: ProtectionLevel) -> bool {
+        level == ProtectionLevel::Integrity
+    }
+}
+
+/// GSS-API authenticator which accepts any token and leaves messages
+/// unprotected, it provides NO security and is only meant for testing clients.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopGssapi;
+
+impl GssapiAuthenticator for NoopGssapi {
+    fn accept_context(
+        &self,
+        _context: &mut GssapiContext,
+        _token: &[u8],
+    ) -> Result<GssapiAccept, GssapiError> {
+        Ok(GssapiAccept::Established { token: Vec::new() })
+    }
+
+    fn wrap(
+        &self,
+        _context: &GssapiContext,
+        _confidential: bool,
+        message: &[u8],
+    ) -> Result<Vec<u8>, GssapiError> {
+        Ok(message.to_vec())
+    }
+
+    fn unwrap(&self, _context: &GssapiContext, token: &[u8]) -> Result<Vec<u8>, GssapiError> {
+        Ok(token.to_vec())
+    }
+}
+
+#[derive(Default)]
 pub struct AuthenticationManager {
     user_list: HashMap<Vec<u8>, Vec<u8>>,
+    token_list: HashSet<Vec<u8>>,
+    gssapi: Option<Arc<dyn GssapiAuthenticator>>,
+}
+
+impl std::fmt::Debug for AuthenticationManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthenticationManager")
+            .field("user_list", &self.user_list)
+            .field("token_count", &self.token_list.len())
+            .field("gssapi", &self.gssapi.is_some())
+            .finish()
+    }
 }
 
 impl AuthenticationManager {
     #[inline]
     #[must_use]
-    pub fn new() -> Self { Self { user_list: HashMap::default() } }
+    pub fn new() -> Self {
+        Self { user_list: HashMap::default(), token_list: HashSet::default(), gssapi: None }
+    }
+
+    /// Adds a user, password of an existing user is replaced.
+    #[inline]
+    pub fn add_user(&mut self, user_name: Vec<u8>, password: Vec<u8>) {
+        self.user_list.insert(user_name, password);
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn user_count(&self) -> usize { self.user_list.len() }
+
+    /// Adds a bearer token, empty tokens are ignored as they would accept
+    /// clients sending no credential.
+    #[inline]
+    pub fn add_token(&mut self, token: Vec<u8>) {
+        if !token.is_empty() {
+            self.token_list.insert(token);
+        }
+    }
+
+    /// Removes a bearer token, returns whether it is present.
+    #[inline]
+    pub fn remove_token(&mut self, token: &[u8]) -> bool { self.token_list.remove(token) }
+
+    #[inline]
+    #[must_use]
+    pub fn token_count(&self) -> usize { self.token_list.len() }
+
+    /// Enables method `GSSAPI`, which is preferred over other methods offered
+    /// by clients.
+    #[inline]
+    pub fn set_gssapi(&mut self, authenticator: Arc<dyn GssapiAuthenticator>) {
+        self.gssapi = Some(authenticator);
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn gssapi(&self) -> Option<Arc<dyn GssapiAuthenticator>> { self.gssapi.clone() }
 
+    /// Requires user name and password once any user is added, requires a
+    /// token once any token is added, requires `GSSAPI` if it is enabled
+    /// without any user or token.
     #[inline]
     #[must_use]
-    pub const fn supported_method(&self, _addr: &SocketAddr) -> AuthenticationMethod {
-        AuthenticationMethod::NoAuthentication
+    pub fn supported_method(&self, _addr: &SocketAddr) -> AuthenticationMethod {
+        if !self.user_list.is_empty() {
+            AuthenticationMethod::UsernamePassword
+        } else if !self.token_list.is_empty() {
+            AuthenticationMethod::Token
+        } else if self.gssapi.is_some() {
+            AuthenticationMethod::Gssapi
+        } else {
+            AuthenticationMethod::NoAuthentication
+        }
     }
 
     pub async fn authenticate(&self, auth: Authentication) -> bool {
@@ -36,10 +215,66 @@ impl AuthenticationManager {
                 }
             }
             Authentication::Token { token } => {
-                let _unused = token;
-                // TODO
-                false
+                // every token is compared, so that the time taken does not tell which
+                // token is close to the one presented
+                !token.is_empty()
+                    && self
+                        .token_list
+                        .iter()
+                        .fold(false, |matched, known| matched | constant_time_eq(known, &token))
             }
         }
     }
 }
+
+// compares without short-circuiting on the first mismatched byte, only the
+// length may be revealed by the time taken
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{Authentication, AuthenticationManager, AuthenticationMethod};
+
+    fn token(token: &[u8]) -> Authentication { Authentication::Token { token: token.to_vec() } }
+
+    #[tokio::test]
+    async fn authenticate_token() {
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        let mut manager = AuthenticationManager::new();
+        assert_eq!(manager.supported_method(&client_addr), AuthenticationMethod::NoAuthentication);
+
+        manager.add_token(b"s3cr3t-token".to_vec());
+        manager.add_token(b"another-token".to_vec());
+        assert_eq!(manager.supported_method(&client_addr), AuthenticationMethod::Token);
+        assert_eq!(manager.token_count(), 2);
+
+        assert!(manager.authenticate(token(b"s3cr3t-token")).await);
+        assert!(manager.authenticate(token(b"another-token")).await);
+        assert!(!manager.authenticate(token(b"s3cr3t-tokem")).await);
+        assert!(!manager.authenticate(token(b"s3cr3t")).await);
+
+        assert!(manager.remove_token(b"s3cr3t-token"));
+        assert!(!manager.remove_token(b"s3cr3t-token"));
+        assert!(!manager.authenticate(token(b"s3cr3t-token")).await);
+    }
+
+    #[tokio::test]
+    async fn reject_empty_token() {
+        let mut manager = AuthenticationManager::new();
+        assert!(!manager.authenticate(token(b"")).await);
+
+        manager.add_token(Vec::new());
+        assert_eq!(manager.token_count(), 0);
+        assert!(!manager.authenticate(token(b"")).await);
+
+        manager.add_token(b"token".to_vec());
+        assert!(!manager.authenticate(token(b"")).await);
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::error::Error;
+
+/// Loads configuration from all `.toml` files in `dir`.
+///
+/// Files are merged in lexical order of their file names, later files take
+/// precedence over earlier ones. Tables are merged recursively key by key,
+/// while arrays and scalar values are replaced as a whole, arrays are never
+/// concatenated.
+pub fn load<T: DeserializeOwned>(dir: &Path) -> Result<T, Error> {
+    let read_dir_error = |source| Error::ReadConfigFile { source, file_path: dir.to_owned() };
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(read_dir_error)? {
+        let path = entry.map_err(read_dir_error)?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut fragments = Vec::with_capacity(files.len());
+    for file_path in files {
+        let content = std::fs::read_to_string(&file_path)
+            .map_err(|source| Error::ReadConfigFile { source, file_path })?;
+        fragments.push(content);
+    }
+
+    from_fragments(fragments.iter().map(String::as_str))
+}
+
+pub fn from_fragments<'a, T, I>(fragments: I) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut merged = toml::Value::Table(toml::Table::new());
+    for fragment in fragments {
+        let value =
+            toml::from_str(fragment).map_err(|source| Error::ParseConfigFromToml { source })?;
+        merge(&mut merged, value);
+    }
+
+    merged.try_into().map_err(|source| Error::ParseConfigFromToml { source })
+}
+
+fn merge(base: &mut toml::Value, other: toml::Value) {
+    match (base, other) {
+        (toml::Value::Table(base), toml::Value::Table(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, Eq, PartialEq)]
+    struct Server {
+        ip: String,
+        port: u16,
+        ports: Vec<u16>,
+    }
+
+    #[derive(Debug, Deserialize, Eq, PartialEq)]
+    struct Config {
+        name: String,
+        server: Server,
+    }
+
+    #[test]
+    fn merge_fragments() {
+        let servers = r#"
+name = "servers"
+
+[server]
+ip = "127.0.0.1"
+port = 3128
+ports = [3129, 3130]
+"#;
+        let overrides = r#"
+name = "overrides"
+
+[server]
+port = 8080
+ports = [8081]
+"#;
+
+        let config: Config = super::from_fragments([servers, overrides]).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                name: "overrides".to_string(),
+                server: Server { ip: "127.0.0.1".to_string(), port: 8080, ports: vec![8081] }
+            }
+        );
+    }
+}
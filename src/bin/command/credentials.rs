@@ -0,0 +1,84 @@
+use tunelo::authentication::AuthenticationManager;
+
+use crate::error::Error;
+
+/// Environment variable of credentials in form of `user:pass`, multiple
+/// entries are separated by commas.
+pub const AUTH_ENV: &str = "TUNELO_AUTH";
+
+pub fn authentication_manager() -> Result<AuthenticationManager, Error> {
+    authentication_manager_from(std::env::var(AUTH_ENV).ok().as_deref())
+}
+
+fn authentication_manager_from(credentials: Option<&str>) -> Result<AuthenticationManager, Error> {
+    let mut manager = AuthenticationManager::new();
+    let Some(credentials) = credentials.filter(|c| !c.trim().is_empty()) else {
+        return Ok(manager);
+    };
+
+    for (index, entry) in credentials.split(',').enumerate() {
+        // password may contain colons, user name may not
+        match entry.trim().split_once(':') {
+            Some((user_name, password)) if !user_name.is_empty() => {
+                manager.add_user(user_name.as_bytes().to_vec(), password.as_bytes().to_vec());
+            }
+            _ => return Err(Error::InvalidCredentials { index }),
+        }
+    }
+
+    tracing::info!("Loaded {} credential(s) from {AUTH_ENV}", manager.user_count());
+    Ok(manager)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tunelo::authentication::{Authentication, AuthenticationMethod};
+
+    use super::authentication_manager_from;
+    use crate::error::Error;
+
+    fn auth(user_name: &str, password: &str) -> Authentication {
+        Authentication::UsernamePassword {
+            user_name: user_name.as_bytes().to_vec(),
+            password: password.as_bytes().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_credentials() {
+        let manager = authentication_manager_from(Some("alice:secret, bob:pa:ss")).unwrap();
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        assert_eq!(manager.supported_method(&client_addr), AuthenticationMethod::UsernamePassword);
+
+        assert!(manager.authenticate(auth("alice", "secret")).await);
+        assert!(manager.authenticate(auth("bob", "pa:ss")).await);
+        assert!(!manager.authenticate(auth("alice", "pa:ss")).await);
+        assert!(!manager.authenticate(auth("carol", "secret")).await);
+    }
+
+    #[test]
+    fn no_credentials() {
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        for credentials in [None, Some(""), Some("  ")] {
+            let manager = authentication_manager_from(credentials).unwrap();
+            assert_eq!(
+                manager.supported_method(&client_addr),
+                AuthenticationMethod::NoAuthentication
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_credentials() {
+        for (credentials, expected) in [("alice", 0), ("alice:secret,:secret", 1)] {
+            let Err(Error::InvalidCredentials { index }) =
+                authentication_manager_from(Some(credentials))
+            else {
+                panic!("{credentials} should be rejected");
+            };
+            assert_eq!(index, expected);
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
     net::{IpAddr, Ipv4Addr},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use clap::Args;
@@ -9,34 +10,51 @@ use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use tokio::sync::Mutex;
 use tunelo::{
-    authentication::AuthenticationManager,
-    filter::SimpleFilter,
+    common::utils::safe_duration,
     server::http::{self, Server, ServerOptions},
+    service::{ErrorVerbosity, LogPrivacy},
     transport::{Resolver, Transport},
 };
 
-use crate::{error, error::Error, shutdown, signal_handler};
+use crate::{
+    command::{self, credentials, FilterEventLevel},
+    error::{self, Error},
+    shutdown, signal_handler,
+};
 
 pub async fn run<P: AsRef<Path>>(
     resolver: Arc<dyn Resolver>,
     opts: Options,
     config_file: Option<P>,
+    config_dir: Option<P>,
 ) -> Result<(), Error> {
-    let config = match config_file {
-        Some(path) => Config::load(path)?.merge(opts),
-        None => Config::default().merge(opts),
+    let debug_options = opts.debug;
+    let config = match (config_file, config_dir) {
+        (Some(path), _) => Config::load(path)?.merge(opts),
+        (None, Some(dir)) => Config::load_dir(dir)?.merge(opts),
+        (None, None) => Config::default().merge(opts),
     };
 
-    let server_config: ServerOptions = config.into();
+    let allow_domains_file = config.allow_domains_file.clone();
+    let resolved_filter =
+        command::resolved_filter(config.geoip_database.as_deref(), &config.deny_countries)?;
+    let filter_events =
+        command::filter_events(config.filter_event_level, config.log_allowed_destinations);
+    let server_config: ServerOptions = config.try_into()?;
 
     let http_server = {
-        let filter = {
-            let mut f = SimpleFilter::deny_list();
-            f.add_socket(server_config.listen_socket());
-            Arc::new(f)
+        let filter =
+            command::server_filter([server_config.listen_socket()], allow_domains_file.as_deref())?;
+        let transport = Transport::direct(resolver, filter)
+            .with_log_privacy(server_config.log_privacy)
+            .with_filter_events(filter_events);
+        let transport = match resolved_filter {
+            Some(resolved_filter) => transport.with_resolved_filter(resolved_filter),
+            None => transport,
         };
-        let transport = Arc::new(Transport::direct(resolver, filter));
-        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let transport = debug_options.apply(transport);
+        let transport = Arc::new(transport);
+        let authentication_manager = Arc::new(Mutex::new(credentials::authentication_manager()?));
         Server::new(server_config, transport, authentication_manager)
     };
 
@@ -60,37 +78,348 @@ pub struct Options {
 
     #[arg(long = "port", help = "Port number to listen")]
     port: Option<u16>,
+
+    #[arg(long = "log-connection-open", help = "Log at info level when a connection is opened")]
+    log_connection_open: Option<bool>,
+
+    #[arg(
+        long = "max-connections",
+        help = "Maximum number of concurrent connections, further connections are refused"
+    )]
+    max_connections: Option<usize>,
+
+    #[arg(
+        long = "max-connections-per-ip",
+        help = "Maximum number of concurrent connections from each source IP"
+    )]
+    max_connections_per_ip: Option<usize>,
+
+    #[arg(
+        long = "connection-rate",
+        help = "Maximum number of new connections per second from each source IP"
+    )]
+    connection_rate: Option<u32>,
+
+    #[arg(
+        long = "connection-burst",
+        requires = "connection_rate",
+        help = "Maximum number of new connections at once from each source IP, defaults to the \
+                connection rate"
+    )]
+    connection_burst: Option<u32>,
+
+    #[arg(long = "access-log", help = "File to write access logs in Combined Log Format")]
+    access_log: Option<PathBuf>,
+
+    #[arg(
+        long = "transparent",
+        help = "Act as transparent proxy for connections redirected by iptables REDIRECT"
+    )]
+    transparent: Option<bool>,
+
+    #[arg(
+        long = "suppress-identification",
+        help = "Leave out strings identifying the server from responses, e.g. the product name in \
+                the authentication realm"
+    )]
+    suppress_identification: Option<bool>,
+
+    #[arg(long = "max-uri-length", help = "Maximum length of request URI")]
+    max_uri_length: Option<usize>,
+
+    #[arg(
+        long = "log-privacy",
+        help = "Policy of logging destinations, one of \"full\", \"domain-only\" and \"hashed\""
+    )]
+    log_privacy: Option<LogPrivacy>,
+
+    #[arg(
+        long = "filter-event-level",
+        value_enum,
+        help = "Level of events of destinations denied by filter, defaults to info"
+    )]
+    filter_event_level: Option<FilterEventLevel>,
+
+    #[arg(
+        long = "log-allowed-destinations",
+        help = "Emit events of destinations allowed by filter too, at the same level"
+    )]
+    log_allowed_destinations: Option<bool>,
+
+    #[arg(
+        long = "error-verbosity",
+        help = "Detail of errors of connecting destinations, one of \"terse\" and \"verbose\""
+    )]
+    error_verbosity: Option<ErrorVerbosity>,
+
+    #[arg(
+        long = "block-page",
+        help = "File replied with 403 Forbidden for denied destinations, HTML if named *.html, \
+                \"{host}\" in it is replaced with the denied host"
+    )]
+    block_page: Option<PathBuf>,
+
+    #[arg(
+        long = "idle-timeout",
+        help = "Close connections idle for this many seconds, 0 disables the timeout"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[arg(
+        long = "tls-certificate",
+        requires = "tls_private_key",
+        help = "Certificate chain in PEM format, accept clients over TLS with it"
+    )]
+    tls_certificate: Option<PathBuf>,
+
+    #[arg(
+        long = "tls-private-key",
+        requires = "tls_certificate",
+        help = "Private key of the TLS certificate in PEM format"
+    )]
+    tls_private_key: Option<PathBuf>,
+
+    #[arg(
+        long = "handshake-timeout",
+        help = "Disconnect clients not completing the request header in this many seconds, 0 \
+                disables, defaults to 10"
+    )]
+    handshake_timeout: Option<u64>,
+
+    #[arg(
+        long = "drain-timeout",
+        help = "Wait for this many seconds for connections to finish on shutdown"
+    )]
+    drain_timeout: Option<u64>,
+
+    #[arg(
+        long = "allow-domains-file",
+        help = "File of domains allowed as destinations with their subdomains, one per line, \
+                other destinations are denied"
+    )]
+    allow_domains_file: Option<PathBuf>,
+
+    #[arg(
+        long = "geoip-database",
+        help = "MaxMind DB of countries of IP addresses, e.g. GeoLite2 Country"
+    )]
+    geoip_database: Option<PathBuf>,
+
+    #[arg(
+        long = "deny-countries",
+        requires = "geoip_database",
+        value_delimiter = ',',
+        help = "Country codes of destinations to deny, e.g. \"KP,IR\", checked after resolving"
+    )]
+    deny_countries: Option<Vec<String>>,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    debug: command::DebugOptions,
 }
 
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Config {
     ip: IpAddr,
     port: u16,
+    #[serde(default)]
+    log_connection_open: bool,
+    #[serde(default)]
+    max_connections: Option<usize>,
+    #[serde(default)]
+    max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    connection_rate: Option<u32>,
+    #[serde(default)]
+    connection_burst: Option<u32>,
+    #[serde(default)]
+    access_log: Option<PathBuf>,
+    #[serde(default)]
+    transparent: bool,
+    #[serde(default)]
+    suppress_identification: bool,
+    #[serde(default)]
+    max_uri_length: Option<usize>,
+    #[serde(default)]
+    log_privacy: LogPrivacy,
+    #[serde(default)]
+    filter_event_level: FilterEventLevel,
+    #[serde(default)]
+    log_allowed_destinations: bool,
+    #[serde(default)]
+    error_verbosity: ErrorVerbosity,
+    #[serde(default)]
+    block_page: Option<PathBuf>,
+    #[serde(default)]
+    idle_timeout: Option<u64>,
+    #[serde(default)]
+    tls_certificate: Option<PathBuf>,
+    #[serde(default)]
+    tls_private_key: Option<PathBuf>,
+    #[serde(default)]
+    handshake_timeout: Option<u64>,
+    #[serde(default)]
+    drain_timeout: Option<u64>,
+    #[serde(default)]
+    allow_domains_file: Option<PathBuf>,
+    #[serde(default)]
+    geoip_database: Option<PathBuf>,
+    #[serde(default)]
+    deny_countries: Vec<String>,
 }
 
 impl Default for Config {
     #[inline]
-    fn default() -> Self { Self { ip: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 8118 } }
+    fn default() -> Self {
+        Self {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8118,
+            log_connection_open: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            connection_rate: None,
+            connection_burst: None,
+            access_log: None,
+            transparent: false,
+            suppress_identification: false,
+            max_uri_length: None,
+            log_privacy: LogPrivacy::default(),
+            filter_event_level: FilterEventLevel::default(),
+            log_allowed_destinations: false,
+            error_verbosity: ErrorVerbosity::default(),
+            block_page: None,
+            idle_timeout: None,
+            tls_certificate: None,
+            tls_private_key: None,
+            handshake_timeout: None,
+            drain_timeout: None,
+            allow_domains_file: None,
+            geoip_database: None,
+            deny_countries: Vec::new(),
+        }
+    }
 }
 
 impl Config {
     impl_config_load!(Config);
 
     pub fn merge(mut self, opts: Options) -> Self {
-        let Options { mut ip, mut port } = opts;
+        let Options {
+            mut ip,
+            mut port,
+            mut log_connection_open,
+            max_connections,
+            max_connections_per_ip,
+            connection_rate,
+            connection_burst,
+            access_log,
+            mut transparent,
+            mut suppress_identification,
+            max_uri_length,
+            mut log_privacy,
+            mut filter_event_level,
+            mut log_allowed_destinations,
+            mut error_verbosity,
+            block_page,
+            idle_timeout,
+            tls_certificate,
+            tls_private_key,
+            handshake_timeout,
+            drain_timeout,
+            allow_domains_file,
+            geoip_database,
+            mut deny_countries,
+            debug: _,
+        } = opts;
 
         merge_option_field!(self, ip);
         merge_option_field!(self, port);
+        merge_option_field!(self, log_connection_open);
+        if max_connections.is_some() {
+            self.max_connections = max_connections;
+        }
+        if max_connections_per_ip.is_some() {
+            self.max_connections_per_ip = max_connections_per_ip;
+        }
+        if connection_rate.is_some() {
+            self.connection_rate = connection_rate;
+        }
+        if connection_burst.is_some() {
+            self.connection_burst = connection_burst;
+        }
+        if access_log.is_some() {
+            self.access_log = access_log;
+        }
+        merge_option_field!(self, transparent);
+        merge_option_field!(self, suppress_identification);
+        if max_uri_length.is_some() {
+            self.max_uri_length = max_uri_length;
+        }
+        merge_option_field!(self, log_privacy);
+        merge_option_field!(self, filter_event_level);
+        merge_option_field!(self, log_allowed_destinations);
+        merge_option_field!(self, error_verbosity);
+        if block_page.is_some() {
+            self.block_page = block_page;
+        }
+        if idle_timeout.is_some() {
+            self.idle_timeout = idle_timeout;
+        }
+        if tls_certificate.is_some() {
+            self.tls_certificate = tls_certificate;
+        }
+        if tls_private_key.is_some() {
+            self.tls_private_key = tls_private_key;
+        }
+        if handshake_timeout.is_some() {
+            self.handshake_timeout = handshake_timeout;
+        }
+        if drain_timeout.is_some() {
+            self.drain_timeout = drain_timeout;
+        }
+        if allow_domains_file.is_some() {
+            self.allow_domains_file = allow_domains_file;
+        }
+        if geoip_database.is_some() {
+            self.geoip_database = geoip_database;
+        }
+        merge_option_field!(self, deny_countries);
 
         self
     }
 }
 
-impl From<Config> for http::ServerOptions {
-    fn from(val: Config) -> Self {
-        let listen_address = val.ip;
-        let listen_port = val.port;
+impl TryInto<http::ServerOptions> for Config {
+    type Error = Error;
+
+    fn try_into(self) -> Result<http::ServerOptions, Self::Error> {
+        let listen_address = self.ip;
+        let listen_port = self.port;
 
-        Self { listen_address, listen_port }
+        Ok(http::ServerOptions {
+            listen_address,
+            listen_port,
+            log_connection_open: self.log_connection_open,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            connection_rate_limit: command::connection_rate_limit(
+                self.connection_rate,
+                self.connection_burst,
+            )?,
+            access_log: self.access_log,
+            transparent: self.transparent,
+            suppress_identification: self.suppress_identification,
+            max_uri_length: self.max_uri_length,
+            log_privacy: self.log_privacy,
+            error_verbosity: self.error_verbosity,
+            block_page: self.block_page,
+            idle_timeout: self
+                .idle_timeout
+                .and_then(|secs| safe_duration(Duration::from_secs(secs))),
+            tls: command::tls_server_config(self.tls_certificate, self.tls_private_key)?,
+            handshake_timeout: command::handshake_timeout(self.handshake_timeout),
+            drain_timeout: self.drain_timeout.map(Duration::from_secs),
+            ..Default::default()
+        })
     }
 }
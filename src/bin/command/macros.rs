@@ -15,6 +15,10 @@ macro_rules! impl_config_load {
             Ok(config)
         }
 
+        pub fn load_dir<P: AsRef<Path>>(path: P) -> Result<$config, Error> {
+            crate::command::config_dir::load(path.as_ref())
+        }
+
         pub fn from_toml(content: &str) -> Result<$config, Error> {
             toml::from_str(&content).map_err(|source| Error::ParseConfigFromToml { source })
         }
@@ -1,19 +1,41 @@
 #[macro_use]
 pub mod macros;
+mod config_dir;
+mod credentials;
 pub mod http_server;
 pub mod multi_proxy;
+pub mod probe;
 pub mod proxy_chain;
 pub mod proxy_checker;
 pub mod socks_server;
 
-use std::{future::Future, io::Write, path::PathBuf, pin::Pin, sync::Arc};
+use std::{
+    future::Future,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
+use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use tokio::runtime;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use tunelo::transport::{Resolver, TrustDnsResolver};
+#[cfg(feature = "debug")]
+use tunelo::transport::DebugLatency;
+use tunelo::{
+    common::utils::safe_duration,
+    filter::{
+        CombinePolicy, ComposerFilter, FilterEvents, HostFilter, PatternFilter, SimpleFilter,
+    },
+    server::{RateLimit, TlsServerConfig, DEFAULT_HANDSHAKE_TIMEOUT},
+    transport::{self, ReloadingResolver, Resolver, StaticResolver, Transport, TrustDnsResolver},
+};
+use url::Url;
 
 use crate::{
     consts,
@@ -26,10 +48,71 @@ pub struct Cli {
     #[arg(long = "config", short = 'c')]
     config_file: Option<PathBuf>,
 
+    #[arg(
+        long = "config-dir",
+        conflicts_with = "config_file",
+        help = "Directory of TOML config fragments"
+    )]
+    config_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "resolver",
+        value_enum,
+        default_value_t = ResolverKind::System,
+        global = true,
+        help = "Domain name resolver"
+    )]
+    resolver: ResolverKind,
+
+    #[arg(
+        long = "doh-url",
+        global = true,
+        required_if_eq("resolver", "doh"),
+        help = "Endpoint of DNS over HTTPS resolver, e.g. https://cloudflare-dns.com/dns-query"
+    )]
+    doh_url: Option<Url>,
+
+    #[arg(
+        long = "hosts-file",
+        global = true,
+        help = "File of static host names in the format of /etc/hosts, other names are resolved \
+                by the resolver"
+    )]
+    hosts_file: Option<PathBuf>,
+
     #[command(subcommand)]
     commands: Option<Commands>,
 }
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ResolverKind {
+    /// Resolver configured by system, e.g. `/etc/resolv.conf`
+    #[default]
+    System,
+
+    /// DNS over HTTPS, for networks where plain DNS is blocked
+    Doh,
+}
+
+/// Level of filter decision events.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterEventLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Clone, Debug)]
+struct ResolverOptions {
+    kind: ResolverKind,
+    doh_url: Option<Url>,
+    hosts_file: Option<PathBuf>,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     #[command(about = "Show current version")]
@@ -42,6 +125,20 @@ pub enum Commands {
     MultiProxy {
         #[arg(long = "config", short = 'c')]
         config_file: Option<PathBuf>,
+
+        #[arg(
+            long = "config-dir",
+            conflicts_with = "config_file",
+            help = "Directory of TOML config fragments"
+        )]
+        config_dir: Option<PathBuf>,
+
+        #[arg(
+            long = "metrics-addr",
+            help = "Serve transport metrics in Prometheus format at this address, e.g. \
+                    127.0.0.1:9090"
+        )]
+        metrics_addr: Option<SocketAddr>,
     },
 
     #[command(about = "Run as proxy chain server")]
@@ -49,6 +146,13 @@ pub enum Commands {
         #[arg(long = "config", short = 'c')]
         config_file: Option<PathBuf>,
 
+        #[arg(
+            long = "config-dir",
+            conflicts_with = "config_file",
+            help = "Directory of TOML config fragments"
+        )]
+        config_dir: Option<PathBuf>,
+
         #[clap(flatten)]
         options: proxy_chain::Options,
     },
@@ -58,15 +162,35 @@ pub enum Commands {
         #[arg(long = "config", short = 'c')]
         config_file: Option<PathBuf>,
 
+        #[arg(
+            long = "config-dir",
+            conflicts_with = "config_file",
+            help = "Directory of TOML config fragments"
+        )]
+        config_dir: Option<PathBuf>,
+
         #[clap(flatten)]
         options: proxy_checker::Options,
     },
 
+    #[command(about = "Connect to a destination through a proxy server and show details")]
+    Probe {
+        #[clap(flatten)]
+        options: probe::Options,
+    },
+
     #[command(about = "Run as SOCKS proxy server")]
     SocksServer {
         #[arg(long = "config", short = 'c')]
         config_file: Option<PathBuf>,
 
+        #[arg(
+            long = "config-dir",
+            conflicts_with = "config_file",
+            help = "Directory of TOML config fragments"
+        )]
+        config_dir: Option<PathBuf>,
+
         #[clap(flatten)]
         options: socks_server::Options,
     },
@@ -76,6 +200,13 @@ pub enum Commands {
         #[arg(long = "config", short = 'c')]
         config_file: Option<PathBuf>,
 
+        #[arg(
+            long = "config-dir",
+            conflicts_with = "config_file",
+            help = "Directory of TOML config fragments"
+        )]
+        config_dir: Option<PathBuf>,
+
         #[clap(flatten)]
         options: http_server::Options,
     },
@@ -88,6 +219,11 @@ impl Default for Cli {
 
 impl Cli {
     pub fn run(self) -> Result<(), Error> {
+        let resolver_options = ResolverOptions {
+            kind: self.resolver,
+            doh_url: self.doh_url,
+            hosts_file: self.hosts_file,
+        };
         match self.commands {
             Some(Commands::Version) => {
                 let mut stdout = std::io::stdout();
@@ -102,28 +238,43 @@ impl Cli {
                 clap_complete::generate(shell, &mut app, bin_name, &mut std::io::stdout());
                 Ok(())
             }
-            Some(Commands::ProxyChain { options, config_file }) => {
-                execute(move |resolver| Box::pin(proxy_chain::run(resolver, options, config_file)))
+            Some(Commands::ProxyChain { options, config_file, config_dir }) => {
+                execute(resolver_options, move |resolver| {
+                    Box::pin(proxy_chain::run(resolver, options, config_file, config_dir))
+                })
             }
-            Some(Commands::SocksServer { options, config_file }) => {
-                execute(move |resolver| Box::pin(socks_server::run(resolver, options, config_file)))
+            Some(Commands::SocksServer { options, config_file, config_dir }) => {
+                execute(resolver_options, move |resolver| {
+                    Box::pin(socks_server::run(resolver, options, config_file, config_dir))
+                })
             }
-            Some(Commands::HttpServer { options, config_file }) => {
-                execute(move |resolver| Box::pin(http_server::run(resolver, options, config_file)))
+            Some(Commands::HttpServer { options, config_file, config_dir }) => {
+                execute(resolver_options, move |resolver| {
+                    Box::pin(http_server::run(resolver, options, config_file, config_dir))
+                })
             }
-            Some(Commands::ProxyChecker { options, config_file }) => {
-                execute(move |_resolver| Box::pin(proxy_checker::run(options, config_file)))
+            Some(Commands::ProxyChecker { options, config_file, config_dir }) => {
+                execute(resolver_options, move |_resolver| {
+                    Box::pin(proxy_checker::run(options, config_file, config_dir))
+                })
             }
-            Some(Commands::MultiProxy { config_file }) => {
-                execute(move |resolver| Box::pin(multi_proxy::run(resolver, config_file)))
+            Some(Commands::Probe { options }) => {
+                execute(resolver_options, move |_resolver| Box::pin(probe::run(options)))
             }
-            None => execute(move |resolver| Box::pin(multi_proxy::run(resolver, self.config_file))),
+            Some(Commands::MultiProxy { config_file, config_dir, metrics_addr }) => {
+                execute(resolver_options, move |resolver| {
+                    Box::pin(multi_proxy::run(resolver, config_file, config_dir, metrics_addr))
+                })
+            }
+            None => execute(resolver_options, move |resolver| {
+                Box::pin(multi_proxy::run(resolver, self.config_file, self.config_dir, None))
+            }),
         }
     }
 }
 
 #[inline]
-fn execute<F>(f: F) -> Result<(), Error>
+fn execute<F>(resolver_options: ResolverOptions, f: F) -> Result<(), Error>
 where
     F: FnOnce(Arc<dyn Resolver>) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>,
 {
@@ -138,26 +289,203 @@ where
         .build()
         .context(error::InitializeTokioRuntimeSnafu)?;
 
-    let resolver = {
-        runtime
-            .block_on(async move {
-                tracing::info!("Initializing domain name resolver");
-
-                match TrustDnsResolver::from_system_conf().await {
-                    Ok(resolver) => Ok(resolver),
-                    Err(err) => {
-                        tracing::warn!(
-                            "Failed to initialize domain name resolver from system configuration, \
-                             try to initialize with fallback option, error: {err}"
-                        );
-                        TrustDnsResolver::new_default().await
-                    }
-                }
-            })
-            .context(error::InitializeDomainNameResolverSnafu)?
+    let ResolverOptions { kind, doh_url, hosts_file } = resolver_options;
+    let resolver: Arc<dyn Resolver> = match (kind, doh_url) {
+        #[cfg(feature = "doh")]
+        (ResolverKind::Doh, Some(doh_url)) => {
+            tracing::info!("Initializing DNS over HTTPS resolver with endpoint {doh_url}");
+            Arc::new(
+                transport::DohResolver::new(doh_url)
+                    .context(error::InitializeDomainNameResolverSnafu)?,
+            )
+        }
+        #[cfg(not(feature = "doh"))]
+        (ResolverKind::Doh, Some(_)) => return Err(Error::DohUnsupported),
+        _ => {
+            let resolver = runtime
+                .block_on(async move {
+                    tracing::info!("Initializing domain name resolver");
+                    ReloadingResolver::new(Arc::new(|| Box::pin(init_system_resolver()))).await
+                })
+                .context(error::InitializeDomainNameResolverSnafu)?;
+            Arc::new(resolver.with_reload_interval(consts::SYSTEM_RESOLVER_RELOAD_INTERVAL))
+        }
+    };
+
+    let resolver = match hosts_file {
+        Some(hosts_file) => {
+            tracing::info!("Loading static host names from {}", hosts_file.display());
+            let resolver = StaticResolver::builder()
+                .hosts_file(hosts_file)
+                .context(error::InitializeDomainNameResolverSnafu)?
+                .fallback(resolver)
+                .build();
+            Arc::new(resolver)
+        }
+        None => resolver,
+    };
+
+    runtime.block_on(f(resolver))
+}
+
+// the system configuration is read again when the resolver is re-initialized
+async fn init_system_resolver() -> Result<Arc<dyn Resolver>, transport::Error> {
+    let resolver = match TrustDnsResolver::from_system_conf().await {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            tracing::warn!(
+                "Failed to initialize domain name resolver from system configuration, try to \
+                 initialize with fallback option, error: {err}"
+            );
+            TrustDnsResolver::new_default().await?
+        }
+    };
+    Ok(Arc::new(resolver))
+}
+
+// connections to the servers themselves are denied, only domains in
+// `allow_domains_file` and their subdomains are allowed if it is provided
+fn server_filter(
+    listen_sockets: impl IntoIterator<Item = SocketAddr>,
+    allow_domains_file: Option<&Path>,
+) -> Result<Arc<dyn HostFilter>, Error> {
+    let mut filter = SimpleFilter::deny_list();
+    listen_sockets.into_iter().for_each(|socket| filter.add_socket(socket));
+
+    let Some(allow_domains_file) = allow_domains_file else {
+        return Ok(Arc::new(filter));
     };
+    tracing::info!("Loading allowed domains from {}", allow_domains_file.display());
+    let allowed_domains = PatternFilter::load_allowed_domains(allow_domains_file)
+        .context(error::LoadAllowedDomainsSnafu)?;
+    let mut composer = ComposerFilter::with_policy(CombinePolicy::AllMustAllow);
+    composer.push(Arc::new(filter));
+    composer.push(Arc::new(allowed_domains));
+    Ok(Arc::new(composer))
+}
+
+// TLS is terminated if both of the certificate and its private key are provided
+fn tls_server_config(
+    certificate_file: Option<PathBuf>,
+    private_key_file: Option<PathBuf>,
+) -> Result<Option<TlsServerConfig>, Error> {
+    match (certificate_file, private_key_file) {
+        (Some(certificate_file), Some(private_key_file)) => {
+            Ok(Some(TlsServerConfig::new(certificate_file, private_key_file)))
+        }
+        (Some(_), None) => Err(Error::NoTlsPrivateKey),
+        (None, Some(_)) => Err(Error::NoTlsCertificate),
+        (None, None) => Ok(None),
+    }
+}
+
+// `None` keeps the default handshake timeout, 0 disables it
+fn handshake_timeout(secs: Option<u64>) -> Option<Duration> {
+    secs.map_or(Some(DEFAULT_HANDSHAKE_TIMEOUT), |secs| safe_duration(Duration::from_secs(secs)))
+}
+
+// decisions of filter are emitted at `level`, allowed destinations only with
+// `log_allowed`
+const fn filter_events(level: FilterEventLevel, log_allowed: bool) -> FilterEvents {
+    let level = match level {
+        FilterEventLevel::Error => tracing::Level::ERROR,
+        FilterEventLevel::Warn => tracing::Level::WARN,
+        FilterEventLevel::Info => tracing::Level::INFO,
+        FilterEventLevel::Debug => tracing::Level::DEBUG,
+        FilterEventLevel::Trace => tracing::Level::TRACE,
+    };
+    FilterEvents { level, log_allowed }
+}
+
+// default of switches in configuration files which are on unless turned off
+const fn enabled_by_default() -> bool { true }
+
+// the burst defaults to the rate, a zero rate or burst would refuse every
+// connection
+fn connection_rate_limit(
+    per_second: Option<u32>,
+    burst: Option<u32>,
+) -> Result<Option<RateLimit>, Error> {
+    let Some(per_second) = per_second else {
+        return Ok(None);
+    };
+    let burst = burst.unwrap_or(per_second);
+    if per_second == 0 || burst == 0 {
+        return Err(Error::InvalidConnectionRate);
+    }
+    Ok(Some(RateLimit { per_second, burst }))
+}
+
+// addresses resolved in `deny_countries` of `geoip_database` are denied,
+// nothing is denied without a database
+#[cfg(feature = "geoip")]
+fn resolved_filter(
+    geoip_database: Option<&Path>,
+    deny_countries: &[String],
+) -> Result<Option<Arc<dyn HostFilter>>, Error> {
+    let Some(geoip_database) = geoip_database else {
+        return Ok(None);
+    };
+    tracing::info!("Loading GeoIP database from {}", geoip_database.display());
+    let filter = tunelo::filter::GeoIpFilter::deny_countries(geoip_database, deny_countries)
+        .context(error::LoadGeoIpDatabaseSnafu)?;
+    Ok(Some(Arc::new(filter)))
+}
+
+#[cfg(not(feature = "geoip"))]
+fn resolved_filter(
+    geoip_database: Option<&Path>,
+    _deny_countries: &[String],
+) -> Result<Option<Arc<dyn HostFilter>>, Error> {
+    match geoip_database {
+        Some(_) => Err(Error::GeoIpUnsupported),
+        None => Ok(None),
+    }
+}
+
+/// Options of artificial latency for testing clients, only available with the
+/// `debug` feature.
+#[cfg(feature = "debug")]
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct DebugOptions {
+    #[arg(long = "debug-latency", help = "Delay each chunk relayed by milliseconds")]
+    latency: Option<u64>,
+
+    #[arg(
+        long = "debug-jitter",
+        requires = "latency",
+        help = "Delay each chunk relayed by up to milliseconds more at random"
+    )]
+    jitter: Option<u64>,
+}
+
+#[cfg(feature = "debug")]
+impl DebugOptions {
+    pub fn apply<Stream>(self, transport: Transport<Stream>) -> Transport<Stream>
+    where
+        Stream: Unpin + tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        let Some(latency) = self.latency else {
+            return transport;
+        };
+        let latency = DebugLatency {
+            latency: Duration::from_millis(latency),
+            jitter: Duration::from_millis(self.jitter.unwrap_or_default()),
+        };
+        tracing::warn!("Inject artificial latency {latency:?} into relays");
+        transport.with_debug_latency(latency)
+    }
+}
+
+/// Placeholder of options of artificial latency without the `debug` feature,
+/// so that commands take the same options in either build.
+#[cfg(not(feature = "debug"))]
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct DebugOptions {}
 
-    runtime.block_on(f(Arc::new(resolver)))
+#[cfg(not(feature = "debug"))]
+impl DebugOptions {
+    pub fn apply<Stream>(self, transport: Transport<Stream>) -> Transport<Stream> { transport }
 }
 
 fn init_tracing() {
@@ -1,13 +1,16 @@
 use std::{
     collections::HashSet,
+    fmt,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
+use tunelo::common::utils::safe_duration;
 
+use crate::command;
 pub use crate::error::Error;
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -15,14 +18,30 @@ pub struct Config {
     pub proxy_servers: HashSet<ProxyServer>,
 
     pub socks_server: Option<SocksServer>,
+    #[serde(default)]
+    pub socks_servers: Vec<SocksServer>,
     pub http_server: Option<HttpServer>,
+
+    #[serde(default)]
+    pub allow_domains_file: Option<PathBuf>,
+    #[serde(default)]
+    pub geoip_database: Option<PathBuf>,
+    #[serde(default)]
+    pub deny_countries: Vec<String>,
 }
 
 impl Config {
     impl_config_load!(Config);
 
     pub fn enable_socks(&self) -> bool {
-        self.proxy_servers.contains(&ProxyServer::Socks) && self.socks_server.is_some()
+        self.proxy_servers.contains(&ProxyServer::Socks)
+            && (self.socks_server.is_some() || !self.socks_servers.is_empty())
+    }
+
+    /// Returns all SOCKS server entries, each of them is served on its own
+    /// listen socket.
+    pub fn socks_server_configs(&self) -> Vec<SocksServer> {
+        self.socks_server.iter().chain(&self.socks_servers).cloned().collect()
     }
 
     pub fn enable_http(&self) -> bool {
@@ -37,7 +56,11 @@ impl Default for Config {
         Self {
             proxy_servers,
             socks_server: Some(SocksServer::default()),
+            socks_servers: Vec::new(),
             http_server: Some(HttpServer::default()),
+            allow_domains_file: None,
+            geoip_database: None,
+            deny_countries: Vec::new(),
         }
     }
 }
@@ -61,11 +84,11 @@ impl FromStr for ProxyServer {
     }
 }
 
-impl ToString for ProxyServer {
-    fn to_string(&self) -> String {
+impl fmt::Display for ProxyServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Socks => "socks".to_owned(),
-            Self::Http => "http".to_owned(),
+            Self::Socks => write!(f, "socks"),
+            Self::Http => write!(f, "http"),
         }
     }
 }
@@ -77,7 +100,13 @@ pub struct SocksServer {
 
     udp_ip: IpAddr,
     udp_ports: Vec<u16>,
+    #[serde(default)]
+    udp_addresses: Vec<SocketAddr>,
+    #[serde(default)]
+    udp_pin_client_source: bool,
 
+    #[serde(default = "command::enabled_by_default")]
+    enable_socks4: bool,
     enable_socks4a: bool,
     enable_socks5: bool,
 
@@ -86,6 +115,12 @@ pub struct SocksServer {
     enable_udp_associate: bool,
 
     connection_timeout: u64,
+    #[serde(default)]
+    idle_timeout: Option<u64>,
+    #[serde(default)]
+    handshake_timeout: Option<u64>,
+    #[serde(default)]
+    bind_timeout: Option<u64>,
     tcp_keepalive: u64,
     udp_cache_expiry_duration: u64,
 }
@@ -98,7 +133,10 @@ impl Default for SocksServer {
 
             udp_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             udp_ports: vec![3129],
+            udp_addresses: Vec::new(),
+            udp_pin_client_source: false,
 
+            enable_socks4: true,
             enable_socks4a: true,
             enable_socks5: true,
 
@@ -107,6 +145,9 @@ impl Default for SocksServer {
             enable_udp_associate: false,
 
             connection_timeout: 20,
+            idle_timeout: None,
+            handshake_timeout: None,
+            bind_timeout: None,
             tcp_keepalive: 5,
             udp_cache_expiry_duration: 30,
         }
@@ -119,11 +160,17 @@ impl From<SocksServer> for tunelo::server::socks::ServerOptions {
 
         let listen_address = val.tcp_ip;
         let listen_port = val.tcp_port;
-        let udp_ports: HashSet<_> = val.udp_ports.into_iter().collect();
+        // UDP sockets are bound to `udp_ip` rather than the TCP listen IP
+        let udp_addresses: Vec<_> = val
+            .udp_ports
+            .iter()
+            .map(|port| SocketAddr::new(val.udp_ip, *port))
+            .chain(val.udp_addresses)
+            .collect();
 
         let supported_versions = {
             let mut versions = HashSet::new();
-            if val.enable_socks4a {
+            if val.enable_socks4 || val.enable_socks4a {
                 versions.insert(SocksVersion::V4);
             }
             if val.enable_socks5 {
@@ -138,7 +185,7 @@ impl From<SocksServer> for tunelo::server::socks::ServerOptions {
                 commands.insert(SocksCommand::TcpConnect);
             }
 
-            match (val.enable_udp_associate, udp_ports.is_empty()) {
+            match (val.enable_udp_associate, udp_addresses.is_empty()) {
                 (false, _) => {}
                 (true, true) => {}
                 (true, false) => {
@@ -156,37 +203,58 @@ impl From<SocksServer> for tunelo::server::socks::ServerOptions {
         Self {
             listen_address,
             listen_port,
-            udp_ports,
+            udp_ports: HashSet::new(),
+            udp_addresses,
+            udp_pin_client_source: val.udp_pin_client_source,
 
             supported_versions,
+            enable_socks4: val.enable_socks4,
+            enable_socks4a: val.enable_socks4a,
             supported_commands,
 
             udp_cache_expiry_duration: Duration::from_secs(val.udp_cache_expiry_duration),
-            connection_timeout: Duration::from_secs(val.connection_timeout),
+            idle_timeout: val
+                .idle_timeout
+                .and_then(|secs| safe_duration(Duration::from_secs(secs))),
+            handshake_timeout: command::handshake_timeout(val.handshake_timeout),
+            bind_timeout: val
+                .bind_timeout
+                .and_then(|secs| safe_duration(Duration::from_secs(secs))),
             tcp_keepalive: Duration::from_secs(val.tcp_keepalive),
+            ..Default::default()
         }
     }
 }
 
 impl SocksServer {
     pub fn listen_socket(&self) -> SocketAddr { SocketAddr::new(self.tcp_ip, self.tcp_port) }
+
+    /// Time limit of connecting destinations, `None` if it is disabled.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        safe_duration(Duration::from_secs(self.connection_timeout))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct HttpServer {
     host: IpAddr,
     port: u16,
+    #[serde(default)]
+    handshake_timeout: Option<u64>,
 }
 
 impl Default for HttpServer {
-    fn default() -> Self { Self { host: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 8080 } }
+    fn default() -> Self {
+        Self { host: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 8080, handshake_timeout: None }
+    }
 }
 
 impl From<HttpServer> for tunelo::server::http::ServerOptions {
     fn from(val: HttpServer) -> Self {
         let listen_address = val.host;
         let listen_port = val.port;
-        Self { listen_address, listen_port }
+        let handshake_timeout = command::handshake_timeout(val.handshake_timeout);
+        Self { listen_address, listen_port, handshake_timeout, ..Default::default() }
     }
 }
 
@@ -194,15 +262,10 @@ impl HttpServer {
     pub fn listen_socket(&self) -> SocketAddr { SocketAddr::new(self.host, self.port) }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum AuthenticationMethod {}
-
-impl ToString for AuthenticationMethod {
-    fn to_string(&self) -> String { String::new() }
-}
-
 #[cfg(test)]
 mod tests {
+    use tunelo::protocol::socks::{SocksCommand, SocksVersion};
+
     use super::*;
 
     #[test]
@@ -241,7 +304,10 @@ port = 8118
 
                 udp_ip: "127.0.0.1".parse().unwrap(),
                 udp_ports: vec![10001, 10002, 10003],
+                udp_addresses: Vec::new(),
+                udp_pin_client_source: false,
 
+                enable_socks4: true,
                 enable_socks4a: true,
                 enable_socks5: true,
 
@@ -250,13 +316,112 @@ port = 8118
                 enable_udp_associate: true,
 
                 connection_timeout: 10,
+                idle_timeout: None,
+                handshake_timeout: None,
+                bind_timeout: None,
                 tcp_keepalive: 10,
                 udp_cache_expiry_duration: 10,
             }),
-            http_server: Some(HttpServer { host: "127.0.0.1".parse().unwrap(), port: 8118 }),
+            socks_servers: Vec::new(),
+            http_server: Some(HttpServer {
+                host: "127.0.0.1".parse().unwrap(),
+                port: 8118,
+                handshake_timeout: None,
+            }),
+            allow_domains_file: None,
+            geoip_database: None,
+            deny_countries: Vec::new(),
         };
 
         assert_eq!(Config::from_toml(toml)?, config);
         Ok(())
     }
+
+    #[test]
+    fn config_load_multiple_socks_servers() -> Result<(), Box<dyn std::error::Error>> {
+        let toml = r#"
+proxy_servers = ["socks"]
+
+[[socks_servers]]
+tcp_ip = "127.0.0.1"
+tcp_port = 1080
+udp_ip = "127.0.0.1"
+udp_ports = []
+enable_socks4 = false
+enable_socks4a = false
+enable_socks5 = true
+enable_tcp_connect = true
+enable_tcp_bind = true
+enable_udp_associate = false
+connection_timeout = 10
+bind_timeout = 60
+tcp_keepalive = 10
+udp_cache_expiry_duration = 10
+
+[[socks_servers]]
+tcp_ip = "127.0.0.1"
+tcp_port = 1081
+udp_ip = "127.0.0.1"
+udp_ports = []
+enable_socks4 = false
+enable_socks4a = true
+enable_socks5 = false
+enable_tcp_connect = true
+enable_tcp_bind = false
+enable_udp_associate = false
+connection_timeout = 10
+tcp_keepalive = 10
+udp_cache_expiry_duration = 10
+"#;
+
+        let config = Config::from_toml(toml)?;
+        assert!(config.enable_socks());
+        assert!(!config.enable_http());
+
+        let options: Vec<tunelo::server::socks::ServerOptions> =
+            config.socks_server_configs().into_iter().map(Into::into).collect();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options[0].listen_port, 1080);
+        assert_eq!(options[0].supported_versions, HashSet::from([SocksVersion::V5]));
+        assert_eq!(options[0].bind_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(options[1].bind_timeout, None);
+        assert_eq!(options[1].listen_port, 1081);
+        assert_eq!(options[1].supported_versions, HashSet::from([SocksVersion::V4]));
+        assert!(!options[1].enable_socks4);
+        assert!(options[1].enable_socks4a);
+        Ok(())
+    }
+
+    #[test]
+    fn bind_udp_associate_addresses() -> Result<(), Box<dyn std::error::Error>> {
+        let toml = r#"
+proxy_servers = ["socks"]
+
+[socks_server]
+tcp_ip = "0.0.0.0"
+tcp_port = 1080
+udp_ip = "192.0.2.1"
+udp_ports = [3129]
+udp_addresses = ["198.51.100.1:3129", "[2001:db8::1]:3129"]
+enable_socks5 = true
+enable_socks4a = false
+enable_tcp_connect = true
+enable_tcp_bind = false
+enable_udp_associate = true
+connection_timeout = 10
+tcp_keepalive = 10
+udp_cache_expiry_duration = 10
+"#;
+
+        let options: tunelo::server::socks::ServerOptions =
+            Config::from_toml(toml)?.socks_server_configs().remove(0).into();
+        assert!(options.udp_ports.is_empty());
+        assert_eq!(
+            options.udp_addresses,
+            ["192.0.2.1:3129", "198.51.100.1:3129", "[2001:db8::1]:3129"]
+                .map(|addr| addr.parse::<SocketAddr>().unwrap())
+        );
+        assert!(options.supported_commands.contains(&SocksCommand::UdpAssociate));
+        Ok(())
+    }
 }
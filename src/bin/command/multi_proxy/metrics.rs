@@ -0,0 +1,162 @@
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+use futures::{Future, FutureExt};
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+use tunelo::{
+    service::http::{self, HttpMetrics},
+    transport::{self, TransportMetrics},
+};
+
+use crate::error::{self, Error};
+
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serves `metrics`, and `http_metrics` of the HTTP proxy server if any, in the
+/// Prometheus text format at `/metrics` until `shutdown_signal` completes.
+pub async fn serve<F>(
+    listen_address: SocketAddr,
+    metrics: TransportMetrics,
+    http_metrics: Option<HttpMetrics>,
+    shutdown_signal: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()>,
+{
+    let listener = TcpListener::bind(listen_address)
+        .await
+        .context(error::BindMetricsEndpointSnafu { listen_address })?;
+    tracing::info!("Serving metrics at http://{listen_address}/metrics");
+
+    let mut shutdown_signal = Box::pin(shutdown_signal.fuse());
+    loop {
+        let stream = futures::select! {
+            stream = listener.accept().fuse() => stream,
+            () = shutdown_signal => break,
+        };
+
+        match stream {
+            Ok((stream, peer_addr)) => {
+                let metrics = metrics.clone();
+                let http_metrics = http_metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(err) =
+                        handle_connection(stream, &metrics, http_metrics.as_ref()).await
+                    {
+                        tracing::debug!("Failed to serve metrics to {peer_addr}, error: {err}");
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("Failed to accept metrics connection, error: {err}"),
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_connection<S>(
+    mut stream: S,
+    metrics: &TransportMetrics,
+    http_metrics: Option<&HttpMetrics>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+    let path = loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                break request.path.unwrap_or_default().to_owned();
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_HEADER_SIZE => continue,
+            _ => return write_response(&mut stream, "400 Bad Request", "").await,
+        }
+    };
+
+    match path.as_str() {
+        "/metrics" => {
+            let mut body = transport::render_prometheus(metrics);
+            if let Some(http_metrics) = http_metrics {
+                body.push_str(&http::render_prometheus(http_metrics));
+            }
+            write_response(&mut stream, "200 OK", &body).await
+        }
+        _ => write_response(&mut stream, "404 Not Found", "").await,
+    }
+}
+
+async fn write_response<S>(stream: &mut S, status: &str, body: &str) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tunelo::{service::http::HttpMetrics, transport::TransportMetrics};
+
+    use super::handle_connection;
+
+    async fn get(
+        metrics: &TransportMetrics,
+        http_metrics: Option<&HttpMetrics>,
+        path: &str,
+    ) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        handle_connection(server, metrics, http_metrics).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() {
+        let metrics = TransportMetrics::new();
+        let (_client, _prev) = metrics.count_client();
+
+        let response = get(&metrics, None, "/metrics").await;
+        let (header, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(header.starts_with("HTTP/1.1 200 OK"));
+        assert!(body.contains("# TYPE tunelo_active_clients gauge\ntunelo_active_clients 1\n"));
+        assert!(!body.contains("tunelo_http_"));
+
+        assert!(get(&metrics, None, "/").await.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn serve_http_metrics() {
+        let metrics = TransportMetrics::new();
+        let http_metrics = HttpMetrics::new();
+
+        let response = get(&metrics, Some(&http_metrics), "/metrics").await;
+        assert!(response.contains("tunelo_active_clients 0\n"));
+        assert!(response.contains("tunelo_http_connect_requests_total 0\n"));
+        assert!(response.contains("tunelo_http_forward_requests_total 0\n"));
+    }
+}
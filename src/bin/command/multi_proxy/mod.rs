@@ -1,96 +1,134 @@
-use std::{future::Future, path::Path, pin::Pin, sync::Arc};
+use std::{collections::HashMap, future::Future, net::SocketAddr, path::Path, pin::Pin, sync::Arc};
 
-use futures::future::join_all;
+use futures::{future::join_all, FutureExt};
 use snafu::ResultExt;
-use tokio::sync::Mutex;
+use tokio::{net::TcpListener, sync::Mutex};
 use tunelo::{
     authentication::AuthenticationManager,
-    filter::SimpleFilter,
     server::{http, socks},
-    transport::{Resolver, Transport},
+    transport::{Resolver, Timeouts, Transport},
 };
 
-use crate::{error, error::Error, shutdown, signal_handler};
+use crate::{
+    command::{self, credentials},
+    error,
+    error::Error,
+    shutdown, signal_handler,
+};
 
 mod config;
+mod metrics;
 
 pub use self::config::Config;
 
 pub async fn run<P: AsRef<Path>>(
     resolver: Arc<dyn Resolver>,
     config_file: Option<P>,
+    config_dir: Option<P>,
+    metrics_addr: Option<SocketAddr>,
 ) -> Result<(), Error> {
-    let config = match config_file {
-        Some(path) => Config::load(&path)?,
-        None => Config::default(),
+    let config = match (config_file, config_dir) {
+        (Some(path), _) => Config::load(&path)?,
+        (None, Some(dir)) => Config::load_dir(dir)?,
+        (None, None) => Config::default(),
     };
 
-    let socks_server_config =
-        if config.enable_socks() { config.socks_server.clone() } else { None };
+    let (shutdown_sender, mut shutdown_receiver) = shutdown::new();
+    signal_handler::start(Box::new(move || {
+        shutdown_sender.shutdown();
+    }));
+
+    let authentication_manager = credentials::authentication_manager()?;
+    serve(resolver, config, authentication_manager, metrics_addr, HashMap::new(), async move {
+        shutdown_receiver.wait().await;
+    })
+    .await
+}
+
+/// Serves the proxy servers of `config`, servers whose listen address is a
+/// key of `listeners` accept connections by the listener instead of binding
+/// the address.
+async fn serve<F>(
+    resolver: Arc<dyn Resolver>,
+    config: Config,
+    authentication_manager: AuthenticationManager,
+    metrics_addr: Option<SocketAddr>,
+    mut listeners: HashMap<SocketAddr, TcpListener>,
+    shutdown_signal: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()>,
+{
+    let socks_server_configs =
+        if config.enable_socks() { config.socks_server_configs() } else { Vec::new() };
     let http_server_config = if config.enable_http() { config.http_server.clone() } else { None };
 
-    let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
-    let filter = {
-        let mut f = SimpleFilter::deny_list();
-        if let Some(config) = socks_server_config.as_ref() {
-            f.add_socket(config.listen_socket())
-        }
-        if let Some(config) = http_server_config.as_ref() {
-            f.add_socket(config.listen_socket())
-        }
-        Arc::new(f)
-    };
+    let authentication_manager = Arc::new(Mutex::new(authentication_manager));
+    let filter = command::server_filter(
+        socks_server_configs
+            .iter()
+            .map(config::SocksServer::listen_socket)
+            .chain(http_server_config.as_ref().map(config::HttpServer::listen_socket)),
+        config.allow_domains_file.as_deref(),
+    )?;
 
-    let transport = Arc::new(Transport::direct(resolver, filter));
+    // servers share the transport, which connects destinations within the
+    // shortest connection timeout of them
+    let timeouts = Timeouts {
+        connect: socks_server_configs.iter().filter_map(config::SocksServer::connect_timeout).min(),
+        ..Timeouts::default()
+    };
+    let transport = Transport::direct(resolver, filter).with_timeouts(timeouts);
+    let transport =
+        match command::resolved_filter(config.geoip_database.as_deref(), &config.deny_countries)? {
+            Some(resolved_filter) => Arc::new(transport.with_resolved_filter(resolved_filter)),
+            None => Arc::new(transport),
+        };
 
-    let (shutdown_sender, mut shutdown_receiver) = shutdown::new();
+    let shutdown_signal = shutdown_signal.shared();
 
-    type ServeFuture = Pin<Box<dyn Future<Output = Result<(), Error>>>>;
+    type ServeFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
     let mut futs: Vec<ServeFuture> = Vec::new();
 
-    if let Some(config) = socks_server_config {
-        let socks_serve = {
-            let mut shutdown_receiver = shutdown_sender.subscribe();
-            let server = socks::Server::new(
-                config.into(),
-                transport.clone(),
-                authentication_manager.clone(),
-            );
-
-            let signal = async move {
-                shutdown_receiver.wait().await;
-            };
-            Box::pin(async {
-                server.serve_with_shutdown(signal).await.context(error::RunSocksServerSnafu)
-            })
-        };
-
-        futs.push(socks_serve);
+    for config in socks_server_configs {
+        let listener = listeners.remove(&config.listen_socket());
+        let server =
+            socks::Server::new(config.into(), transport.clone(), authentication_manager.clone());
+        let signal = shutdown_signal.clone();
+        futs.push(Box::pin(async move {
+            match listener {
+                Some(listener) => server.serve_with_listener(listener, signal).await,
+                None => server.serve_with_shutdown(signal).await,
+            }
+            .context(error::RunSocksServerSnafu)
+        }));
     }
 
+    let mut http_metrics = None;
     if let Some(config) = http_server_config {
-        let http_serve = {
-            let server = http::Server::new(config.into(), transport, authentication_manager);
-
-            let signal = async move {
-                shutdown_receiver.wait().await;
-            };
-            Box::pin(async {
-                server.serve_with_shutdown(signal).await.context(error::RunHttpServerSnafu)
-            })
-        };
+        let listener = listeners.remove(&config.listen_socket());
+        let server = http::Server::new(config.into(), transport.clone(), authentication_manager);
+        http_metrics = Some(server.metrics());
+        let signal = shutdown_signal.clone();
+        futs.push(Box::pin(async move {
+            match listener {
+                Some(listener) => server.serve_with_listener(listener, signal).await,
+                None => server.serve_with_shutdown(signal).await,
+            }
+            .context(error::RunHttpServerSnafu)
+        }));
+    }
 
-        futs.push(http_serve);
+    if let Some(listen_address) = metrics_addr {
+        let metrics = transport.metrics().clone();
+        let signal = shutdown_signal.clone();
+        futs.push(Box::pin(metrics::serve(listen_address, metrics, http_metrics, signal)));
     }
 
     if futs.is_empty() {
         return Err(Error::NoProxyServer);
     }
 
-    signal_handler::start(Box::new(move || {
-        shutdown_sender.shutdown();
-    }));
-
     let handle = join_all(futs).await;
     let errors: Vec<_> = handle.into_iter().filter_map(Result::err).collect();
     if !errors.is_empty() {
@@ -99,3 +137,189 @@ pub async fn run<P: AsRef<Path>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+    use tunelo::{authentication::AuthenticationManager, transport::TokioResolver};
+
+    use super::{serve, Config};
+
+    /// Binds a listener for a proxy server and records it by its address, so
+    /// that connecting to the server succeeds right away.
+    async fn bind_listener(listeners: &mut HashMap<SocketAddr, TcpListener>) -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let _unused = listeners.insert(listen_addr, listener);
+        listen_addr.port()
+    }
+
+    // SOCKS4 and SOCKS4a are enabled or disabled together
+    fn socks_server_entry(port: u16, enable_socks4: bool, enable_socks5: bool) -> String {
+        format!(
+            r#"
+[[socks_servers]]
+tcp_ip = "127.0.0.1"
+tcp_port = {port}
+udp_ip = "127.0.0.1"
+udp_ports = []
+enable_socks4 = {enable_socks4}
+enable_socks4a = {enable_socks4}
+enable_socks5 = {enable_socks5}
+enable_tcp_connect = true
+enable_tcp_bind = false
+enable_udp_associate = false
+connection_timeout = 10
+tcp_keepalive = 10
+udp_cache_expiry_duration = 10
+"#
+        )
+    }
+
+    // sends `request` to the server listening on `port` and returns everything
+    // received before the server closes or stops replying
+    async fn exchange(port: u16, request: &[u8], reply_len: usize) -> Vec<u8> {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await.unwrap();
+        stream.write_all(request).await.unwrap();
+
+        let mut reply = Vec::new();
+        let _ = (&mut stream).take(reply_len as u64).read_to_end(&mut reply).await;
+        reply
+    }
+
+    #[tokio::test]
+    async fn socks_listeners_with_different_versions() {
+        let mut listeners = HashMap::new();
+        let socks5_port = bind_listener(&mut listeners).await;
+        let socks4_port = bind_listener(&mut listeners).await;
+        let config = Config::from_toml(&format!(
+            "proxy_servers = [\"socks\"]\n{}{}",
+            socks_server_entry(socks5_port, false, true),
+            socks_server_entry(socks4_port, true, false)
+        ))
+        .unwrap();
+
+        // SOCKS5 handshake with "no authentication" method
+        let socks5_handshake = [0x05, 0x01, 0x00];
+        // SOCKS4 "TCP connect" to a port nobody listens on
+        let socks4_request = [0x04, 0x01, 0x00, 0x01, 0x7f, 0x00, 0x00, 0x01, 0x00];
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serve = serve(
+            Arc::new(TokioResolver::new()),
+            config,
+            AuthenticationManager::new(),
+            None,
+            listeners,
+            async {
+                let _ = shutdown_rx.await;
+            },
+        );
+        let client = async {
+            assert_eq!(exchange(socks5_port, &socks5_handshake, 2).await, [0x05, 0x00]);
+            assert!(exchange(socks5_port, &socks4_request, 8).await.is_empty());
+
+            assert!(exchange(socks4_port, &socks5_handshake, 2).await.is_empty());
+            let reply = exchange(socks4_port, &socks4_request, 8).await;
+            assert_eq!(reply.len(), 8);
+            assert_eq!(reply[0], 0x00);
+
+            shutdown_tx.send(()).unwrap();
+        };
+
+        let (result, ()) = tokio::join!(serve, client);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_unauthenticated_clients_with_credentials() {
+        let mut listeners = HashMap::new();
+        let port = bind_listener(&mut listeners).await;
+        let config = Config::from_toml(&format!(
+            "proxy_servers = [\"socks\"]\n{}",
+            socks_server_entry(port, false, true)
+        ))
+        .unwrap();
+        let mut authentication_manager = AuthenticationManager::new();
+        authentication_manager.add_user(b"alice".to_vec(), b"secret".to_vec());
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serve = serve(
+            Arc::new(TokioResolver::new()),
+            config,
+            authentication_manager,
+            None,
+            listeners,
+            async {
+                let _ = shutdown_rx.await;
+            },
+        );
+        let client = async {
+            // SOCKS5 handshake with "no authentication" method only
+            assert_eq!(exchange(port, &[0x05, 0x01, 0x00], 2).await, [0x05, 0xff]);
+            // SOCKS5 handshake with "username/password" method
+            assert_eq!(exchange(port, &[0x05, 0x01, 0x02], 2).await, [0x05, 0x02]);
+
+            shutdown_tx.send(()).unwrap();
+        };
+
+        let (result, ()) = tokio::join!(serve, client);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn allow_domains_file() {
+        let file_path = std::env::temp_dir()
+            .join(format!("tunelo-multi-proxy-allowed-domains-{}.txt", std::process::id()));
+        std::fs::write(&file_path, "example.com\n").unwrap();
+        let destination = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let destination_port = destination.local_addr().unwrap().port();
+
+        let mut listeners = HashMap::new();
+        let port = bind_listener(&mut listeners).await;
+        let config = Config::from_toml(&format!(
+            "proxy_servers = [\"socks\"]\nallow_domains_file = {:?}\n{}",
+            file_path.display().to_string(),
+            socks_server_entry(port, true, false)
+        ))
+        .unwrap();
+
+        // SOCKS4 "TCP connect" to a listening address not in the allowed domains
+        let [port_high, port_low] = destination_port.to_be_bytes();
+        let socks4_request = [0x04, 0x01, port_high, port_low, 0x7f, 0x00, 0x00, 0x01, 0x00];
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let serve = serve(
+            Arc::new(TokioResolver::new()),
+            config,
+            AuthenticationManager::new(),
+            None,
+            listeners,
+            async {
+                let _ = shutdown_rx.await;
+            },
+        );
+        let client = async {
+            let reply = exchange(port, &socks4_request, 8).await;
+            assert_eq!(reply.len(), 8);
+            // request rejected
+            assert_eq!(reply[1], 0x5b);
+
+            shutdown_tx.send(()).unwrap();
+        };
+
+        let (result, ()) = tokio::join!(serve, client);
+        std::fs::remove_file(&file_path).unwrap();
+        result.unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use std::{fmt, time::Duration};
+
+use clap::Args;
+use futures::FutureExt;
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
+};
+use tunelo::{
+    client::{ClientHandshake, ProxySocket},
+    common::{HostAddress, ProxyHost},
+};
+
+use crate::{
+    error::{self, Error},
+    shutdown, signal_handler,
+};
+
+// CRLF is ignored by most line based protocols, e.g. HTTP
+const KEEPALIVE_PAYLOAD: &[u8] = b"\r\n";
+
+pub async fn run(options: Options) -> Result<(), Error> {
+    let Options { proxy, destination, timeout, keepalive_interval } = options;
+
+    let (mut stream, report) =
+        tokio::time::timeout(Duration::from_secs(timeout), probe(&proxy, &destination))
+            .await
+            .map_err(|_| Error::ProbeTimeout { proxy: Box::new(proxy.clone()) })??;
+    println!("{report}");
+
+    let Some(keepalive_interval) = keepalive_interval else {
+        let _unused = stream.shutdown().await;
+        return Ok(());
+    };
+
+    let (tx, mut rx) = shutdown::new();
+    signal_handler::start(Box::new(|| tx.shutdown()));
+
+    let mut interval = tokio::time::interval(Duration::from_secs(keepalive_interval));
+    interval.tick().await;
+    let mut buf = [0u8; 1024];
+    loop {
+        futures::select! {
+            _ = interval.tick().fuse() => {
+                stream.write_all(KEEPALIVE_PAYLOAD).await.context(error::ProbeKeepaliveSnafu)?;
+                println!("Keepalive sent to {destination}");
+            },
+            n = stream.read(&mut buf).fuse() => match n {
+                Ok(0) | Err(_) => {
+                    println!("Connection closed by remote");
+                    break;
+                }
+                Ok(n) => println!("Received {n} byte(s) from {destination}"),
+            },
+            _ = rx.wait().fuse() => break,
+        }
+    }
+
+    let _unused = stream.shutdown().await;
+    Ok(())
+}
+
+async fn probe(
+    proxy: &ProxyHost,
+    destination: &HostAddress,
+) -> Result<(ProxySocket, ProbeReport), Error> {
+    let started = Instant::now();
+    let stream = TcpStream::connect(proxy.host_address().to_string())
+        .await
+        .context(error::ConnectProxyServerSnafu { proxy: Box::new(proxy.clone()) })?;
+    let connect_time = started.elapsed();
+
+    // the TLS handshake with a proxy server over TLS is part of the handshake
+    let started = Instant::now();
+    let mut stream = ProxySocket::from(stream)
+        .start_tls(proxy, None)
+        .await
+        .context(error::ProxyHandshakeSnafu)?;
+    let mut handshake = ClientHandshake::new(&mut stream);
+    let (version, reply, bind_address) = match proxy {
+        ProxyHost::Socks4a { id, .. } => {
+            let id = id.as_deref().map(str::as_bytes);
+            let bind_address = handshake
+                .handshake_socks_v4_tcp_connect(destination, id)
+                .await
+                .map_err(tunelo::client::Error::from)
+                .context(error::ProxyHandshakeSnafu)?;
+            ("SOCKS4a", "Granted", Some(bind_address))
+        }
+        ProxyHost::Socks5 { username, password, .. } => {
+            let bind_address = handshake
+                .handshake_socks_v5_tcp_connect(
+                    destination,
+                    username.as_deref(),
+                    password.as_deref(),
+                )
+                .await
+                .map_err(tunelo::client::Error::from)
+                .context(error::ProxyHandshakeSnafu)?;
+            ("SOCKS5", "Succeeded", Some(bind_address))
+        }
+        ProxyHost::HttpTunnel { user_agent, .. } | ProxyHost::HttpsTunnel { user_agent, .. } => {
+            handshake
+                .handshake_http_tunnel(destination, user_agent.as_deref())
+                .await
+                .map_err(tunelo::client::Error::from)
+                .context(error::ProxyHandshakeSnafu)?;
+            let version =
+                if matches!(proxy, ProxyHost::HttpsTunnel { .. }) { "HTTPS" } else { "HTTP" };
+            (version, "200 Connection Established", None)
+        }
+    };
+    let handshake_time = started.elapsed();
+
+    let report = ProbeReport {
+        proxy: proxy.clone(),
+        destination: destination.clone(),
+        version,
+        reply,
+        bind_address,
+        connect_time,
+        handshake_time,
+    };
+    Ok((stream, report))
+}
+
+#[derive(Clone, Debug)]
+struct ProbeReport {
+    proxy: ProxyHost,
+    destination: HostAddress,
+    version: &'static str,
+    reply: &'static str,
+    bind_address: Option<HostAddress>,
+    connect_time: Duration,
+    handshake_time: Duration,
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Proxy:          {}", self.proxy)?;
+        writeln!(f, "Destination:    {}", self.destination)?;
+        writeln!(f, "Version:        {}", self.version)?;
+        writeln!(f, "Reply:          {}", self.reply)?;
+        match self.bind_address {
+            Some(ref bind_address) => writeln!(f, "Bind address:   {bind_address}")?,
+            None => writeln!(f, "Bind address:   -")?,
+        }
+        writeln!(f, "Connect time:   {:?}", self.connect_time)?;
+        writeln!(f, "Handshake time: {:?}", self.handshake_time)?;
+        write!(f, "RTT:            {:?}", self.connect_time + self.handshake_time)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Options {
+    #[arg(help = "Proxy server to probe, e.g. socks5://127.0.0.1:3128")]
+    proxy: ProxyHost,
+
+    #[arg(help = "Destination to connect through the proxy server, e.g. example.com:80")]
+    destination: HostAddress,
+
+    #[arg(long = "timeout", default_value = "10", help = "Timeout of connecting in seconds")]
+    timeout: u64,
+
+    #[arg(
+        long = "keepalive-interval",
+        help = "Hold the connection open and send keepalive in every given seconds"
+    )]
+    keepalive_interval: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use tokio::{net::TcpListener, sync::Mutex};
+    use tunelo::{
+        authentication::AuthenticationManager,
+        common::{HostAddress, ProxyHost},
+        filter::SimpleFilter,
+        protocol::socks::SocksVersion,
+        service::socks::Service,
+        transport::{TokioResolver, Transport},
+    };
+
+    #[tokio::test]
+    async fn probe_socks5_server() {
+        let destination = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            let transport = {
+                let filter = Arc::new(SimpleFilter::deny_list());
+                Arc::new(Transport::direct(Arc::new(TokioResolver::new()), filter))
+            };
+            let service = Service::new(
+                HashSet::from_iter([SocksVersion::V5]),
+                transport,
+                Arc::new(Mutex::new(AuthenticationManager::new())),
+                true,
+                false,
+                None,
+                false,
+            );
+            let (stream, peer_addr) = proxy.accept().await.unwrap();
+            let _unused = service.dispatch(stream, peer_addr).await;
+        });
+
+        let proxy_host = ProxyHost::Socks5 {
+            host: proxy_addr.ip().to_string(),
+            port: proxy_addr.port(),
+            username: None,
+            password: None,
+        };
+        let (_stream, report) =
+            super::probe(&proxy_host, &HostAddress::from(destination_addr)).await.unwrap();
+        assert_eq!(report.version, "SOCKS5");
+        assert_eq!(report.reply, "Succeeded");
+        assert_eq!(
+            report.bind_address,
+            Some(HostAddress::from(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))
+        );
+
+        let output = report.to_string();
+        assert!(output.contains(&format!("Destination:    {destination_addr}")));
+        assert!(output.contains("RTT:"));
+    }
+}
@@ -14,14 +14,15 @@ use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use tokio::sync::Mutex;
 use tunelo::{
-    authentication::AuthenticationManager,
-    common::{ProxyHost, ProxyStrategy},
+    client::DEFAULT_MAX_CHAIN_LENGTH,
+    common::{Policy, ProxyHost, ProxyStrategy},
     filter::SimpleFilter,
     server::{http, socks},
     transport::{Resolver, Transport},
 };
 
 use crate::{
+    command::{self, credentials},
     error::{self, Error},
     shutdown, signal_handler,
 };
@@ -30,18 +31,20 @@ pub async fn run<P: AsRef<Path>>(
     resolver: Arc<dyn Resolver>,
     options: Options,
     config_file: Option<P>,
+    config_dir: Option<P>,
 ) -> Result<(), Error> {
-    let config = match config_file {
-        Some(path) => Config::load(path)?.merge(options),
-        None => Config::default().merge(options),
+    let config = match (config_file, config_dir) {
+        (Some(path), _) => Config::load(path)?.merge(options),
+        (None, Some(dir)) => Config::load_dir(dir)?.merge(options),
+        (None, None) => Config::default().merge(options),
     };
 
-    let socks_opts = if config.enable_socks4a || config.enable_socks5 {
+    let socks_opts = if config.enable_socks4 || config.enable_socks4a || config.enable_socks5 {
         use tunelo::protocol::socks::{SocksCommand, SocksVersion};
 
         let supported_versions = {
             let mut v = HashSet::new();
-            if config.enable_socks4a {
+            if config.enable_socks4 || config.enable_socks4a {
                 v.insert(SocksVersion::V4);
             }
             if config.enable_socks5 {
@@ -57,13 +60,17 @@ pub async fn run<P: AsRef<Path>>(
 
         Some(socks::ServerOptions {
             supported_versions,
+            enable_socks4: config.enable_socks4,
+            enable_socks4a: config.enable_socks4a,
             supported_commands,
             listen_address,
             listen_port,
             udp_ports: HashSet::new(),
-            connection_timeout: Duration::from_secs(10),
+            udp_pin_client_source: false,
             tcp_keepalive: Duration::from_secs(10),
             udp_cache_expiry_duration: Duration::from_secs(10),
+            handshake_timeout: command::handshake_timeout(config.handshake_timeout),
+            ..Default::default()
         })
     } else {
         None
@@ -72,7 +79,12 @@ pub async fn run<P: AsRef<Path>>(
     let http_opts = if config.enable_http {
         let listen_address = config.http_ip.ok_or(Error::NoHttpListenAddress)?;
         let listen_port = config.http_port.ok_or(Error::NoHttpListenPort)?;
-        Some(http::ServerOptions { listen_address, listen_port })
+        Some(http::ServerOptions {
+            listen_address,
+            listen_port,
+            handshake_timeout: command::handshake_timeout(config.handshake_timeout),
+            ..Default::default()
+        })
     } else {
         None
     };
@@ -85,6 +97,15 @@ pub async fn run<P: AsRef<Path>>(
         };
 
         tracing::info!("Proxy chain: {}", strategy);
+        // UDP associate relays datagrams over the chain, every hop has to relay them
+        let relays_udp = socks_opts.as_ref().is_some_and(|opts| {
+            opts.supported_commands.contains(&tunelo::protocol::socks::SocksCommand::UdpAssociate)
+        });
+        if relays_udp {
+            strategy.validate_udp().context(error::InvalidProxyChainSnafu)?;
+        } else {
+            strategy.validate().context(error::InvalidProxyChainSnafu)?;
+        }
         Arc::new(strategy)
     };
 
@@ -99,10 +120,32 @@ pub async fn run<P: AsRef<Path>>(
         Arc::new(f)
     };
 
-    let transport = Arc::new(
-        Transport::proxy(resolver, filter, proxy_strategy).context(error::CreateTransportSnafu)?,
-    );
-    let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+    let transport = {
+        let max_chain_length = config.max_chain_length.unwrap_or(DEFAULT_MAX_CHAIN_LENGTH);
+        let default_policy = Policy::default();
+        let policy = Policy {
+            connect_timeout: config.proxy_connect_timeout.map(Duration::from_secs),
+            handshake_timeout: config.proxy_handshake_timeout.map(Duration::from_secs),
+            retries: config.retries.unwrap_or(default_policy.retries),
+            backoff: config.retry_backoff.map_or(default_policy.backoff, Duration::from_millis),
+            ..default_policy
+        };
+        let transport = Transport::proxy_with_max_chain_length(
+            resolver,
+            filter,
+            proxy_strategy,
+            max_chain_length,
+            policy,
+        )
+        .context(error::CreateTransportSnafu)?;
+        let resolved_filter =
+            command::resolved_filter(config.geoip_database.as_deref(), &config.deny_countries)?;
+        match resolved_filter {
+            Some(resolved_filter) => Arc::new(transport.with_resolved_filter(resolved_filter)),
+            None => Arc::new(transport),
+        }
+    };
+    let authentication_manager = Arc::new(Mutex::new(credentials::authentication_manager()?));
 
     let (shutdown_sender, mut shutdown_receiver) = shutdown::new();
 
@@ -160,6 +203,8 @@ pub async fn run<P: AsRef<Path>>(
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Config {
+    #[serde(default = "command::enabled_by_default")]
+    enable_socks4: bool,
     enable_socks4a: bool,
     enable_socks5: bool,
     enable_http: bool,
@@ -169,6 +214,21 @@ pub struct Config {
     http_port: Option<u16>,
     proxy_chain_file: Option<PathBuf>,
     proxy_chain: Option<Vec<ProxyHost>>,
+    max_chain_length: Option<usize>,
+    #[serde(default)]
+    handshake_timeout: Option<u64>,
+    #[serde(default)]
+    proxy_connect_timeout: Option<u64>,
+    #[serde(default)]
+    proxy_handshake_timeout: Option<u64>,
+    #[serde(default)]
+    retries: Option<usize>,
+    #[serde(default)]
+    retry_backoff: Option<u64>,
+    #[serde(default)]
+    geoip_database: Option<PathBuf>,
+    #[serde(default)]
+    deny_countries: Vec<String>,
 }
 
 impl Config {
@@ -176,6 +236,7 @@ impl Config {
 
     fn merge(mut self, opts: Options) -> Self {
         let Options {
+            disable_socks4,
             disable_socks4a,
             disable_socks5,
             disable_http,
@@ -185,6 +246,14 @@ impl Config {
             http_port,
             proxy_chain_file,
             proxy_chain,
+            max_chain_length,
+            handshake_timeout,
+            proxy_connect_timeout,
+            proxy_handshake_timeout,
+            retries,
+            retry_backoff,
+            geoip_database,
+            deny_countries,
         } = opts;
 
         macro_rules! merge_option {
@@ -195,6 +264,7 @@ impl Config {
             };
         }
 
+        self.enable_socks4 = !disable_socks4;
         self.enable_socks4a = !disable_socks4a;
         self.enable_socks5 = !disable_socks5;
         self.enable_http = !disable_http;
@@ -205,6 +275,16 @@ impl Config {
         merge_option!(self, http_port);
         merge_option!(self, proxy_chain_file);
         merge_option!(self, proxy_chain);
+        merge_option!(self, max_chain_length);
+        merge_option!(self, handshake_timeout);
+        merge_option!(self, proxy_connect_timeout);
+        merge_option!(self, proxy_handshake_timeout);
+        merge_option!(self, retries);
+        merge_option!(self, retry_backoff);
+        merge_option!(self, geoip_database);
+        if let Some(deny_countries) = deny_countries {
+            self.deny_countries = deny_countries;
+        }
 
         self
     }
@@ -213,6 +293,7 @@ impl Config {
 impl Default for Config {
     fn default() -> Self {
         Self {
+            enable_socks4: true,
             enable_socks4a: true,
             enable_socks5: true,
             enable_http: true,
@@ -222,12 +303,23 @@ impl Default for Config {
             http_port: Some(8118),
             proxy_chain_file: None,
             proxy_chain: None,
+            max_chain_length: None,
+            handshake_timeout: None,
+            proxy_connect_timeout: None,
+            proxy_handshake_timeout: None,
+            retries: None,
+            retry_backoff: None,
+            geoip_database: None,
+            deny_countries: Vec::new(),
         }
     }
 }
 
 #[derive(Args, Debug)]
 pub struct Options {
+    #[arg(long = "disable-socks4")]
+    disable_socks4: bool,
+
     #[arg(long = "disable-socks4a")]
     disable_socks4a: bool,
 
@@ -254,6 +346,54 @@ pub struct Options {
 
     #[arg(long = "proxy-chain")]
     proxy_chain: Option<Vec<ProxyHost>>,
+
+    #[arg(long = "max-chain-length", help = "Maximum number of hops in proxy chain")]
+    max_chain_length: Option<usize>,
+
+    #[arg(
+        long = "handshake-timeout",
+        help = "Disconnect clients not completing handshake and request in this many seconds, 0 \
+                disables, defaults to 10"
+    )]
+    handshake_timeout: Option<u64>,
+
+    #[arg(
+        long = "proxy-connect-timeout",
+        help = "Timeout of connecting each proxy server of the chain in seconds"
+    )]
+    proxy_connect_timeout: Option<u64>,
+
+    #[arg(
+        long = "proxy-handshake-timeout",
+        help = "Timeout of handshaking with each proxy server of the chain in seconds"
+    )]
+    proxy_handshake_timeout: Option<u64>,
+
+    #[arg(
+        long = "retries",
+        help = "Number of retries of establishing the proxy chain on connection errors"
+    )]
+    retries: Option<usize>,
+
+    #[arg(
+        long = "retry-backoff",
+        help = "Delay before the first retry in milliseconds, doubled before each further retry"
+    )]
+    retry_backoff: Option<u64>,
+
+    #[arg(
+        long = "geoip-database",
+        help = "MaxMind DB of countries of IP addresses, e.g. GeoLite2 Country"
+    )]
+    geoip_database: Option<PathBuf>,
+
+    #[arg(
+        long = "deny-countries",
+        requires = "geoip_database",
+        value_delimiter = ',',
+        help = "Country codes of destinations to deny, e.g. \"KP,IR\", checked after resolving"
+    )]
+    deny_countries: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -382,6 +522,7 @@ port = 1080
     #[test]
     fn config_from_toml() {
         let config = Config {
+            enable_socks4: true,
             enable_socks4a: true,
             enable_socks5: true,
             enable_http: true,
@@ -406,6 +547,14 @@ port = 1080
                     user_agent: None,
                 },
             ]),
+            max_chain_length: Some(4),
+            handshake_timeout: None,
+            proxy_connect_timeout: None,
+            proxy_handshake_timeout: None,
+            retries: None,
+            retry_backoff: None,
+            geoip_database: None,
+            deny_countries: Vec::new(),
         };
 
         let toml = r#"
@@ -417,6 +566,7 @@ socks_port = 3944
 http_ip = "127.0.83.1"
 http_port = 3293
 proxy_chain_file = "/tmp/proxy_file.json"
+max_chain_length = 4
 
 [[proxy_chain]]
 type = "socks5"
@@ -0,0 +1,366 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use bytes::BytesMut;
+use futures::FutureExt;
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::RwLock,
+};
+use tunelo::{
+    checker::{Prober, SimpleProxyChecker, TaskReport},
+    common::ProxyHost,
+};
+
+use super::ProxyServerFile;
+use crate::{
+    error::{self, Error},
+    shutdown, signal_handler,
+};
+
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+
+const HTML_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="30">
+<title>Tunelo Proxy Checker</title>
+</head>
+<body>
+<pre>
+"#;
+
+const HTML_FOOTER: &str = "</pre>\n</body>\n</html>\n";
+
+#[derive(Clone, Default)]
+struct Reports(Arc<RwLock<Vec<TaskReport>>>);
+
+impl Reports {
+    async fn update(&self, reports: Vec<TaskReport>) { *self.0.write().await = reports; }
+
+    async fn to_html(&self) -> String {
+        let mut table = Vec::new();
+        super::write_reports_to(&mut table, &self.0.read().await)
+            .expect("writing to Vec<u8> never fails");
+
+        [HTML_HEADER, &escape_html(&String::from_utf8_lossy(&table)), HTML_FOOTER].concat()
+    }
+
+    async fn to_json(&self) -> String {
+        serde_json::to_string(&*self.0.read().await).expect("TaskReport is serializable")
+    }
+}
+
+/// Proxy servers checked in every cycle, proxy servers newly added to the
+/// proxy server list file are merged before the next cycle.
+pub struct Rotation {
+    checkers: Vec<SimpleProxyChecker>,
+    probers: Vec<Prober>,
+    proxy_server_file: Option<ProxyServerFileWatcher>,
+    dedup: bool,
+}
+
+impl Rotation {
+    pub fn new(
+        proxy_servers: Vec<ProxyHost>,
+        probers: Vec<Prober>,
+        proxy_server_file: Option<PathBuf>,
+        dedup: bool,
+    ) -> Self {
+        let checkers = proxy_servers
+            .into_iter()
+            .map(|proxy_host| SimpleProxyChecker::with_probers(proxy_host, &probers))
+            .collect();
+        let proxy_server_file = proxy_server_file.map(ProxyServerFileWatcher::new);
+        Self { checkers, probers, proxy_server_file, dedup }
+    }
+
+    pub async fn check(&mut self, max_timeout_per_probe: Option<Duration>) -> Vec<TaskReport> {
+        self.reload_proxy_servers();
+        super::check_proxy_servers(&self.checkers, max_timeout_per_probe).await
+    }
+
+    fn reload_proxy_servers(&mut self) {
+        let Some(proxy_servers) =
+            self.proxy_server_file.as_mut().and_then(ProxyServerFileWatcher::reload_if_modified)
+        else {
+            return;
+        };
+
+        let dedup = self.dedup;
+        let key = |proxy_server: &ProxyHost| {
+            if dedup {
+                proxy_server.normalized()
+            } else {
+                proxy_server.clone()
+            }
+        };
+        let mut known: HashSet<_> =
+            self.checkers.iter().map(|checker| key(checker.proxy_server())).collect();
+        for proxy_server in proxy_servers {
+            if known.insert(key(&proxy_server)) {
+                tracing::info!("Add proxy server {proxy_server} to the rotation");
+                self.checkers.push(SimpleProxyChecker::with_probers(proxy_server, &self.probers));
+            }
+        }
+    }
+}
+
+/// Detects modifications of a proxy server list file by its modification time
+/// and length.
+struct ProxyServerFileWatcher {
+    file_path: PathBuf,
+    fingerprint: Option<(SystemTime, u64)>,
+}
+
+impl ProxyServerFileWatcher {
+    fn new(file_path: PathBuf) -> Self {
+        let fingerprint = Self::fingerprint(&file_path);
+        Self { file_path, fingerprint }
+    }
+
+    fn fingerprint(file_path: &Path) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(file_path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// Returns proxy servers in the file if it is modified since the last call.
+    fn reload_if_modified(&mut self) -> Option<Vec<ProxyHost>> {
+        let fingerprint = Self::fingerprint(&self.file_path);
+        if fingerprint.is_none() || fingerprint == self.fingerprint {
+            return None;
+        }
+
+        match ProxyServerFile::load(&self.file_path) {
+            Ok(file) => {
+                self.fingerprint = fingerprint;
+                Some(file.proxy_servers)
+            }
+            Err(err) => {
+                tracing::warn!("Failed to reload proxy server file, error: {err}");
+                None
+            }
+        }
+    }
+}
+
+pub async fn serve(
+    listen_address: SocketAddr,
+    mut rotation: Rotation,
+    max_timeout_per_probe: Option<Duration>,
+    refresh_interval: Duration,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(listen_address)
+        .await
+        .context(error::BindCheckerDashboardSnafu { listen_address })?;
+    tracing::info!("Serving proxy checker dashboard at http://{listen_address}");
+
+    let (tx, mut rx) = shutdown::new();
+    let mut check_shutdown = tx.subscribe();
+    signal_handler::start(Box::new(|| tx.shutdown()));
+
+    let reports = Reports::default();
+    let check_handle = tokio::spawn({
+        let reports = reports.clone();
+        async move {
+            loop {
+                let latest = rotation.check(max_timeout_per_probe).await;
+                reports.update(latest).await;
+
+                futures::select! {
+                    _ = tokio::time::sleep(refresh_interval).fuse() => {},
+                    _ = check_shutdown.wait().fuse() => break,
+                }
+            }
+        }
+    });
+
+    loop {
+        let stream = futures::select! {
+            stream = listener.accept().fuse() => stream,
+            _ = rx.wait().fuse() => break,
+        };
+
+        match stream {
+            Ok((stream, peer_addr)) => {
+                let reports = reports.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, &reports).await {
+                        tracing::debug!("Failed to serve dashboard to {peer_addr}, error: {err}");
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("Failed to accept dashboard connection, error: {err}"),
+        }
+    }
+
+    let _ = check_handle.await;
+    Ok(())
+}
+
+async fn handle_connection<S>(mut stream: S, reports: &Reports) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+    let path = loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                break request.path.unwrap_or_default().to_owned();
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_HEADER_SIZE => continue,
+            _ => return write_response(&mut stream, "400 Bad Request", "text/plain", "").await,
+        }
+    };
+
+    match path.as_str() {
+        "/" | "/index.html" => {
+            let body = reports.to_html().await;
+            write_response(&mut stream, "200 OK", "text/html; charset=utf-8", &body).await
+        }
+        "/reports.json" => {
+            let body = reports.to_json().await;
+            write_response(&mut stream, "200 OK", "application/json", &body).await
+        }
+        _ => write_response(&mut stream, "404 Not Found", "text/plain", "").await,
+    }
+}
+
+async fn write_response<S>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars().fold(String::with_capacity(text.len()), |mut escaped, ch| {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(ch),
+        }
+        escaped
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, str::FromStr, time::Duration};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tunelo::{
+        checker::{LivenessProber, LivenessProberReport, ReportError, TaskReport},
+        common::ProxyHost,
+    };
+
+    use super::{handle_connection, Reports, Rotation};
+
+    async fn get(reports: &Reports, path: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        handle_connection(server, reports).await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn report(proxy_server: &str, alive: bool) -> TaskReport {
+        TaskReport {
+            proxy_server: ProxyHost::from_str(proxy_server).unwrap(),
+            liveness_report: LivenessProberReport {
+                alive,
+                error: if alive { None } else { Some(ReportError::Timeout) },
+            },
+            prober_reports: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn json_endpoint_returns_latest_reports() {
+        let reports = Reports::default();
+        reports.update(vec![report("socks5://127.0.0.1:3128", false)]).await;
+
+        let latest =
+            vec![report("socks5://127.0.0.1:3128", true), report("http://127.0.0.1:8080", false)];
+        reports.update(latest.clone()).await;
+
+        let response = get(&reports, "/reports.json").await;
+        let (header, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(header.starts_with("HTTP/1.1 200 OK"));
+        assert!(header.contains("Content-Type: application/json"));
+
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body, serde_json::to_value(&latest).unwrap());
+        assert_eq!(body[0]["livenessReport"]["alive"], true);
+        assert_eq!(body[1]["livenessReport"]["error"], "Operation timed out");
+    }
+
+    #[tokio::test]
+    async fn unknown_path() {
+        let response = get(&Reports::default(), "/unknown").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn reload_proxy_server_file() {
+        let file_path = std::env::temp_dir()
+            .join(format!("tunelo-proxy-checker-reload-{}.txt", std::process::id()));
+        std::fs::write(&file_path, "socks5://127.0.0.1:1\n").unwrap();
+
+        let proxy_servers = vec![ProxyHost::from_str("socks5://127.0.0.1:1").unwrap()];
+        let probers = vec![LivenessProber.into()];
+        let mut rotation = Rotation::new(proxy_servers, probers, Some(file_path.clone()), false);
+        let timeout = Some(Duration::from_millis(500));
+
+        let reports = rotation.check(timeout).await;
+        assert_eq!(reports.len(), 1);
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&file_path)
+            .unwrap()
+            .write_all(b"socks5://127.0.0.1:2\n")
+            .unwrap();
+
+        let reports = rotation.check(timeout).await;
+        std::fs::remove_file(&file_path).unwrap();
+        let proxy_servers: Vec<_> =
+            reports.iter().map(|report| report.proxy_server.to_string()).collect();
+        assert_eq!(proxy_servers.len(), 2);
+        assert!(proxy_servers
+            .contains(&ProxyHost::from_str("socks5://127.0.0.1:2").unwrap().to_string()));
+    }
+}
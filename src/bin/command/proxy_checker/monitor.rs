@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::FutureExt;
+use snafu::ResultExt;
+use tunelo::{checker::TaskReport, common::ProxyHost};
+
+use super::{dashboard::Rotation, ProxyServerFile};
+use crate::{
+    error::{self, Error},
+    shutdown, signal_handler,
+};
+
+/// Change of liveness of a proxy server since the previous cycle.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change {
+    pub proxy_server: ProxyHost,
+    pub alive: bool,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.alive { "alive" } else { "dead" };
+        write!(f, "{} is {state} now", self.proxy_server)
+    }
+}
+
+/// Liveness of proxy servers in the previous cycle.
+#[derive(Debug, Default)]
+pub struct ChangeDetector {
+    previous: HashMap<ProxyHost, bool>,
+}
+
+impl ChangeDetector {
+    /// Records liveness in `reports` and returns proxy servers whose liveness
+    /// differs from the previous cycle, proxy servers not checked before are
+    /// always included.
+    pub fn update(&mut self, reports: &[TaskReport]) -> Vec<Change> {
+        reports
+            .iter()
+            .filter_map(|report| {
+                let alive = report.is_proxy_server_alive();
+                let previous = self.previous.insert(report.proxy_server.clone(), alive);
+                (previous != Some(alive))
+                    .then(|| Change { proxy_server: report.proxy_server.clone(), alive })
+            })
+            .collect()
+    }
+}
+
+/// Checks proxy servers in `rotation` every `interval` until a signal is
+/// received, alive proxy servers of each cycle are saved to `output_path`
+/// suffixed with the timestamp of the cycle. With `only_changes`, cycles
+/// without changes are neither printed nor saved.
+pub async fn run(
+    mut rotation: Rotation,
+    max_timeout_per_probe: Option<Duration>,
+    interval: Duration,
+    only_changes: bool,
+    output_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    let (tx, mut rx) = shutdown::new();
+    signal_handler::start(Box::new(|| tx.shutdown()));
+
+    let mut detector = ChangeDetector::default();
+    loop {
+        let reports = rotation.check(max_timeout_per_probe).await;
+        let changes = detector.update(&reports);
+        let checked_at = SystemTime::now();
+        write_cycle_to(
+            &mut std::io::stdout(),
+            checked_at,
+            &reports,
+            only_changes.then_some(&changes[..]),
+        )
+        .context(error::WriteProxyCheckerReportSnafu)?;
+
+        if let Some(ref path) = output_path {
+            if !only_changes || !changes.is_empty() {
+                let proxy_servers = reports
+                    .iter()
+                    .filter(|report| report.is_proxy_server_alive())
+                    .map(|report| report.proxy_server.clone())
+                    .collect();
+                let path = timestamped_path(path, unix_timestamp(checked_at));
+                ProxyServerFile { proxy_servers }.save(path)?;
+            }
+        }
+
+        futures::select! {
+            _ = tokio::time::sleep(interval).fuse() => {},
+            _ = rx.wait().fuse() => return Ok(()),
+        }
+    }
+}
+
+// writes all reports of a cycle, or only `changes` if it is given, each line
+// of changes is prefixed with the Unix timestamp of the cycle
+fn write_cycle_to<W>(
+    writer: &mut W,
+    checked_at: SystemTime,
+    reports: &[TaskReport],
+    changes: Option<&[Change]>,
+) -> Result<(), std::io::Error>
+where
+    W: Write,
+{
+    let timestamp = unix_timestamp(checked_at);
+    match changes {
+        Some(changes) => {
+            for change in changes {
+                writeln!(writer, "[{timestamp}] {change}")?;
+            }
+        }
+        None => {
+            writeln!(writer, "Checked at {timestamp}")?;
+            super::write_reports_to(writer, reports)?;
+        }
+    }
+    writer.flush()
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// inserts `timestamp` before the extensions of the file name, `alive.json.gz`
+// turns into `alive-1700000000.json.gz`
+fn timestamped_path(path: &Path, timestamp: u64) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let file_name = match file_name.split_once('.') {
+        Some((stem, extensions)) if !stem.is_empty() => format!("{stem}-{timestamp}.{extensions}"),
+        _ => format!("{file_name}-{timestamp}"),
+    };
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        str::FromStr,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use tunelo::{
+        checker::{LivenessProberReport, ReportError, TaskReport},
+        common::ProxyHost,
+    };
+
+    use super::{timestamped_path, write_cycle_to, Change, ChangeDetector};
+
+    fn report(proxy_server: &str, alive: bool) -> TaskReport {
+        TaskReport {
+            proxy_server: ProxyHost::from_str(proxy_server).unwrap(),
+            liveness_report: LivenessProberReport {
+                alive,
+                error: if alive { None } else { Some(ReportError::Timeout) },
+            },
+            prober_reports: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn emit_changes_between_cycles() {
+        let mut detector = ChangeDetector::default();
+        let first =
+            vec![report("socks5://192.0.2.1:1080", true), report("http://192.0.2.2:8080", false)];
+        assert_eq!(detector.update(&first).len(), 2);
+
+        // the SOCKS5 proxy server dies while the HTTP one stays dead
+        let second =
+            vec![report("socks5://192.0.2.1:1080", false), report("http://192.0.2.2:8080", false)];
+        let changes = detector.update(&second);
+        assert_eq!(
+            changes,
+            [Change {
+                proxy_server: ProxyHost::from_str("socks5://192.0.2.1:1080").unwrap(),
+                alive: false
+            }]
+        );
+
+        let mut output = Vec::new();
+        let checked_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        write_cycle_to(&mut output, checked_at, &second, Some(&changes)).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[1700000000] socks5://192.0.2.1:1080 is dead now\n"
+        );
+        assert!(detector.update(&second).is_empty());
+    }
+
+    #[test]
+    fn timestamp_output_path() {
+        let timestamp = 1_700_000_000;
+        for (path, expected) in [
+            ("out/alive.json.gz", "out/alive-1700000000.json.gz"),
+            ("alive.toml", "alive-1700000000.toml"),
+            ("alive", "alive-1700000000"),
+            (".alive", ".alive-1700000000"),
+        ] {
+            assert_eq!(timestamped_path(Path::new(path), timestamp), Path::new(expected));
+        }
+    }
+}
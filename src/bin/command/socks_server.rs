@@ -1,8 +1,8 @@
 use std::{
     collections::HashSet,
     convert::TryInto,
-    net::{IpAddr, Ipv4Addr},
-    path::Path,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
@@ -12,34 +12,56 @@ use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use tokio::sync::Mutex;
 use tunelo::{
-    authentication::AuthenticationManager,
-    filter::SimpleFilter,
+    common::utils::safe_duration,
     server::socks::{self, Server, ServerOptions},
-    transport::{Resolver, Transport},
+    service::{socks::DnsPolicy, ErrorVerbosity, LogPrivacy},
+    transport::{Resolver, Timeouts, Transport},
 };
 
-use crate::{error, error::Error, shutdown, signal_handler};
+use crate::{
+    command::{self, credentials, FilterEventLevel},
+    error::{self, Error},
+    shutdown, signal_handler,
+};
 
 pub async fn run<P: AsRef<Path>>(
     resolver: Arc<dyn Resolver>,
     options: Options,
     config_file: Option<P>,
+    config_dir: Option<P>,
 ) -> Result<(), Error> {
-    let config = match config_file {
-        Some(path) => Config::load(&path)?.merge(options),
-        None => Config::default().merge(options),
+    let debug_options = options.debug;
+    let config = match (config_file, config_dir) {
+        (Some(path), _) => Config::load(&path)?.merge(options),
+        (None, Some(dir)) => Config::load_dir(dir)?.merge(options),
+        (None, None) => Config::default().merge(options),
+    };
+    let allow_domains_file = config.allow_domains_file.clone();
+    let resolved_filter =
+        command::resolved_filter(config.geoip_database.as_deref(), &config.deny_countries)?;
+    let timeouts = Timeouts {
+        connect: safe_duration(Duration::from_secs(config.connection_timeout)),
+        ..Timeouts::default()
     };
+    let filter_events =
+        command::filter_events(config.filter_event_level, config.log_allowed_destinations);
     let server_config: ServerOptions = config.try_into()?;
 
     let socks_server = {
-        let filter = {
-            let mut f = SimpleFilter::deny_list();
-            f.add_socket(server_config.listen_socket());
-            Arc::new(f)
+        let filter =
+            command::server_filter([server_config.listen_socket()], allow_domains_file.as_deref())?;
+
+        let transport = Transport::direct(resolver, filter)
+            .with_timeouts(timeouts)
+            .with_log_privacy(server_config.log_privacy)
+            .with_filter_events(filter_events);
+        let transport = match resolved_filter {
+            Some(resolved_filter) => transport.with_resolved_filter(resolved_filter),
+            None => transport,
         };
-
-        let transport = Arc::new(Transport::direct(resolver, filter));
-        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let transport = debug_options.apply(transport);
+        let transport = Arc::new(transport);
+        let authentication_manager = Arc::new(Mutex::new(credentials::authentication_manager()?));
         Server::new(server_config, transport, authentication_manager)
     };
 
@@ -72,7 +94,7 @@ impl TryInto<socks::ServerOptions> for Config {
         let supported_versions = {
             let mut versions = HashSet::new();
 
-            if !self.disable_socks4a {
+            if !self.disable_socks4 || !self.disable_socks4a {
                 versions.insert(SocksVersion::V4);
             }
 
@@ -93,7 +115,8 @@ impl TryInto<socks::ServerOptions> for Config {
                 commands.insert(SocksCommand::TcpConnect);
             }
 
-            match (self.enable_udp_associate, udp_ports.is_empty()) {
+            match (self.enable_udp_associate, udp_ports.is_empty() && self.udp_addresses.is_empty())
+            {
                 (false, _) => {}
                 (true, false) => {
                     commands.insert(SocksCommand::UdpAssociate);
@@ -102,7 +125,6 @@ impl TryInto<socks::ServerOptions> for Config {
             }
 
             if self.enable_tcp_bind {
-                tracing::warn!("TCP bind is not supported yet");
                 commands.insert(SocksCommand::TcpBind);
             }
 
@@ -115,19 +137,46 @@ impl TryInto<socks::ServerOptions> for Config {
 
         Ok(ServerOptions {
             supported_versions,
+            enable_socks4: !self.disable_socks4,
+            enable_socks4a: !self.disable_socks4a,
             supported_commands,
             listen_address,
             listen_port,
             udp_ports,
-            udp_cache_expiry_duration: Duration::from_millis(30),
-            connection_timeout: Duration::from_secs(self.connection_timeout),
+            udp_addresses: self.udp_addresses,
+            udp_pin_client_source: self.udp_pin_client_source,
+            udp_strict_target: self.udp_strict_target,
+            log_connection_open: self.log_connection_open,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            connection_rate_limit: command::connection_rate_limit(
+                self.connection_rate,
+                self.connection_burst,
+            )?,
+            dns_policy: self.dns_policy,
+            log_privacy: self.log_privacy,
+            error_verbosity: self.error_verbosity,
+            max_hops: self.max_hops,
+            tls: command::tls_server_config(self.tls_certificate, self.tls_private_key)?,
+            udp_cache_expiry_duration: Duration::from_secs(30),
+            idle_timeout: self
+                .idle_timeout
+                .and_then(|secs| safe_duration(Duration::from_secs(secs))),
             tcp_keepalive: Duration::from_secs(5),
+            handshake_timeout: command::handshake_timeout(self.handshake_timeout),
+            drain_timeout: self.drain_timeout.map(Duration::from_secs),
+            bind_timeout: self
+                .bind_timeout
+                .and_then(|secs| safe_duration(Duration::from_secs(secs))),
+            ..Default::default()
         })
     }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default)]
+    disable_socks4: bool,
     disable_socks4a: bool,
     disable_socks5: bool,
     enable_tcp_connect: bool,
@@ -137,11 +186,58 @@ pub struct Config {
     ip: IpAddr,
     port: u16,
     udp_ports: Vec<u16>,
+    #[serde(default)]
+    udp_addresses: Vec<SocketAddr>,
+    #[serde(default)]
+    udp_pin_client_source: bool,
+    #[serde(default)]
+    udp_strict_target: bool,
+    #[serde(default)]
+    log_connection_open: bool,
+    #[serde(default)]
+    max_connections: Option<usize>,
+    #[serde(default)]
+    max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    connection_rate: Option<u32>,
+    #[serde(default)]
+    connection_burst: Option<u32>,
+    #[serde(default)]
+    dns_policy: DnsPolicy,
+    #[serde(default)]
+    log_privacy: LogPrivacy,
+    #[serde(default)]
+    filter_event_level: FilterEventLevel,
+    #[serde(default)]
+    log_allowed_destinations: bool,
+    #[serde(default)]
+    error_verbosity: ErrorVerbosity,
+    #[serde(default)]
+    max_hops: Option<u8>,
+    #[serde(default)]
+    tls_certificate: Option<PathBuf>,
+    #[serde(default)]
+    tls_private_key: Option<PathBuf>,
+    #[serde(default)]
+    handshake_timeout: Option<u64>,
+    #[serde(default)]
+    idle_timeout: Option<u64>,
+    #[serde(default)]
+    drain_timeout: Option<u64>,
+    #[serde(default)]
+    bind_timeout: Option<u64>,
+    #[serde(default)]
+    allow_domains_file: Option<PathBuf>,
+    #[serde(default)]
+    geoip_database: Option<PathBuf>,
+    #[serde(default)]
+    deny_countries: Vec<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            disable_socks4: false,
             disable_socks4a: false,
             disable_socks5: false,
             enable_tcp_connect: true,
@@ -151,6 +247,29 @@ impl Default for Config {
             ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             port: 3128,
             udp_ports: vec![3129],
+            udp_addresses: Vec::new(),
+            udp_pin_client_source: false,
+            udp_strict_target: false,
+            log_connection_open: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            connection_rate: None,
+            connection_burst: None,
+            dns_policy: DnsPolicy::default(),
+            log_privacy: LogPrivacy::default(),
+            filter_event_level: FilterEventLevel::default(),
+            log_allowed_destinations: false,
+            error_verbosity: ErrorVerbosity::default(),
+            max_hops: None,
+            tls_certificate: None,
+            tls_private_key: None,
+            handshake_timeout: None,
+            idle_timeout: None,
+            drain_timeout: None,
+            bind_timeout: None,
+            allow_domains_file: None,
+            geoip_database: None,
+            deny_countries: Vec::new(),
         }
     }
 }
@@ -160,6 +279,7 @@ impl Config {
 
     pub fn merge(mut self, opts: Options) -> Self {
         let Options {
+            mut disable_socks4,
             mut disable_socks4a,
             mut disable_socks5,
             mut enable_tcp_connect,
@@ -169,6 +289,30 @@ impl Config {
             mut ip,
             mut port,
             mut udp_ports,
+            mut udp_addresses,
+            mut udp_pin_client_source,
+            mut udp_strict_target,
+            mut log_connection_open,
+            max_connections,
+            max_connections_per_ip,
+            connection_rate,
+            connection_burst,
+            mut dns_policy,
+            mut log_privacy,
+            mut filter_event_level,
+            mut log_allowed_destinations,
+            mut error_verbosity,
+            max_hops,
+            tls_certificate,
+            tls_private_key,
+            handshake_timeout,
+            idle_timeout,
+            drain_timeout,
+            bind_timeout,
+            allow_domains_file,
+            geoip_database,
+            mut deny_countries,
+            debug: _,
         } = opts;
 
         merge_option_field!(self, disable_socks4a);
@@ -176,11 +320,60 @@ impl Config {
         merge_option_field!(self, enable_tcp_connect);
         merge_option_field!(self, enable_tcp_bind);
         merge_option_field!(self, enable_udp_associate);
-        merge_option_field!(self, disable_socks4a);
+        merge_option_field!(self, disable_socks4);
         merge_option_field!(self, connection_timeout);
         merge_option_field!(self, ip);
         merge_option_field!(self, port);
         merge_option_field!(self, udp_ports);
+        merge_option_field!(self, udp_addresses);
+        merge_option_field!(self, udp_pin_client_source);
+        merge_option_field!(self, udp_strict_target);
+        merge_option_field!(self, log_connection_open);
+        if max_connections.is_some() {
+            self.max_connections = max_connections;
+        }
+        if max_connections_per_ip.is_some() {
+            self.max_connections_per_ip = max_connections_per_ip;
+        }
+        if connection_rate.is_some() {
+            self.connection_rate = connection_rate;
+        }
+        if connection_burst.is_some() {
+            self.connection_burst = connection_burst;
+        }
+        merge_option_field!(self, dns_policy);
+        merge_option_field!(self, log_privacy);
+        merge_option_field!(self, filter_event_level);
+        merge_option_field!(self, log_allowed_destinations);
+        merge_option_field!(self, error_verbosity);
+        if max_hops.is_some() {
+            self.max_hops = max_hops;
+        }
+        if tls_certificate.is_some() {
+            self.tls_certificate = tls_certificate;
+        }
+        if tls_private_key.is_some() {
+            self.tls_private_key = tls_private_key;
+        }
+        if handshake_timeout.is_some() {
+            self.handshake_timeout = handshake_timeout;
+        }
+        if idle_timeout.is_some() {
+            self.idle_timeout = idle_timeout;
+        }
+        if drain_timeout.is_some() {
+            self.drain_timeout = drain_timeout;
+        }
+        if bind_timeout.is_some() {
+            self.bind_timeout = bind_timeout;
+        }
+        if allow_domains_file.is_some() {
+            self.allow_domains_file = allow_domains_file;
+        }
+        if geoip_database.is_some() {
+            self.geoip_database = geoip_database;
+        }
+        merge_option_field!(self, deny_countries);
 
         self
     }
@@ -194,6 +387,9 @@ pub struct Options {
     #[arg(long = "port", help = "Port number to listen")]
     port: Option<u16>,
 
+    #[arg(long = "disable-socks4", help = "Disable SOCKS4 support")]
+    disable_socks4: Option<bool>,
+
     #[arg(long = "disable-socks4a", help = "Disable SOCKS4a support")]
     disable_socks4a: Option<bool>,
 
@@ -212,6 +408,160 @@ pub struct Options {
     #[arg(long = "udp-ports", help = "UDP ports to provide UDP associate service")]
     udp_ports: Option<Vec<u16>>,
 
-    #[arg(long = "connection-timeout", help = "Connection timeout")]
+    #[arg(
+        long = "udp-addresses",
+        help = "Addresses to bind UDP associate sockets to, in addition to the UDP ports of the \
+                listen IP"
+    )]
+    udp_addresses: Option<Vec<SocketAddr>>,
+
+    #[arg(
+        long = "udp-pin-client-source",
+        help = "Pin UDP associate to the first observed client source, for clients behind NAT"
+    )]
+    udp_pin_client_source: Option<bool>,
+
+    #[arg(
+        long = "udp-strict-target",
+        help = "Relay UDP associate datagrams only to and from the declared address"
+    )]
+    udp_strict_target: Option<bool>,
+
+    #[arg(
+        long = "connection-timeout",
+        help = "Time limit of connecting destinations in seconds, 0 disables the timeout"
+    )]
     connection_timeout: Option<u64>,
+
+    #[arg(long = "log-connection-open", help = "Log at info level when a connection is opened")]
+    log_connection_open: Option<bool>,
+
+    #[arg(
+        long = "max-connections",
+        help = "Maximum number of concurrent connections, further connections are refused"
+    )]
+    max_connections: Option<usize>,
+
+    #[arg(
+        long = "max-connections-per-ip",
+        help = "Maximum number of concurrent connections from each source IP"
+    )]
+    max_connections_per_ip: Option<usize>,
+
+    #[arg(
+        long = "connection-rate",
+        help = "Maximum number of new connections per second from each source IP"
+    )]
+    connection_rate: Option<u32>,
+
+    #[arg(
+        long = "connection-burst",
+        requires = "connection_rate",
+        help = "Maximum number of new connections at once from each source IP, defaults to the \
+                connection rate"
+    )]
+    connection_burst: Option<u32>,
+
+    #[arg(
+        long = "dns-policy",
+        help = "Policy of destination addresses, one of \"any\", \"reject-domain-requests\" and \
+                \"require-remote-dns\""
+    )]
+    dns_policy: Option<DnsPolicy>,
+
+    #[arg(
+        long = "log-privacy",
+        help = "Policy of logging destinations, one of \"full\", \"domain-only\" and \"hashed\""
+    )]
+    log_privacy: Option<LogPrivacy>,
+
+    #[arg(
+        long = "filter-event-level",
+        value_enum,
+        help = "Level of events of destinations denied by filter, defaults to info"
+    )]
+    filter_event_level: Option<FilterEventLevel>,
+
+    #[arg(
+        long = "log-allowed-destinations",
+        help = "Emit events of destinations allowed by filter too, at the same level"
+    )]
+    log_allowed_destinations: Option<bool>,
+
+    #[arg(
+        long = "error-verbosity",
+        help = "Detail of errors of connecting destinations, one of \"terse\" and \"verbose\""
+    )]
+    error_verbosity: Option<ErrorVerbosity>,
+
+    #[arg(
+        long = "max-hops",
+        help = "Refuse connections passing through more than the given number of chained tunelo \
+                instances, upstream proxy servers must be tunelo instances with this option"
+    )]
+    max_hops: Option<u8>,
+
+    #[arg(
+        long = "tls-certificate",
+        requires = "tls_private_key",
+        help = "Certificate chain in PEM format, accept clients over TLS with it"
+    )]
+    tls_certificate: Option<PathBuf>,
+
+    #[arg(
+        long = "tls-private-key",
+        requires = "tls_certificate",
+        help = "Private key of the TLS certificate in PEM format"
+    )]
+    tls_private_key: Option<PathBuf>,
+
+    #[arg(
+        long = "handshake-timeout",
+        help = "Disconnect clients not completing handshake and request in this many seconds, 0 \
+                disables, defaults to 10"
+    )]
+    handshake_timeout: Option<u64>,
+
+    #[arg(
+        long = "idle-timeout",
+        help = "Close connections idle for this many seconds, 0 disables the timeout"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[arg(
+        long = "drain-timeout",
+        help = "Wait for this many seconds for connections to finish on shutdown"
+    )]
+    drain_timeout: Option<u64>,
+
+    #[arg(
+        long = "bind-timeout",
+        help = "Time limit of waiting for the inbound connection of TCP Bind in seconds, defaults \
+                to the connection timeout"
+    )]
+    bind_timeout: Option<u64>,
+
+    #[arg(
+        long = "allow-domains-file",
+        help = "File of domains allowed as destinations with their subdomains, one per line, \
+                other destinations are denied"
+    )]
+    allow_domains_file: Option<PathBuf>,
+
+    #[arg(
+        long = "geoip-database",
+        help = "MaxMind DB of countries of IP addresses, e.g. GeoLite2 Country"
+    )]
+    geoip_database: Option<PathBuf>,
+
+    #[arg(
+        long = "deny-countries",
+        requires = "geoip_database",
+        value_delimiter = ',',
+        help = "Country codes of destinations to deny, e.g. \"KP,IR\", checked after resolving"
+    )]
+    deny_countries: Option<Vec<String>>,
+
+    #[clap(flatten)]
+    debug: command::DebugOptions,
 }
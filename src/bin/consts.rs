@@ -1 +1,6 @@
+use std::time::Duration;
+
 pub const THREAD_NAME: &str = "tunelo";
+
+/// Interval of re-reading the system DNS configuration.
+pub const SYSTEM_RESOLVER_RELOAD_INTERVAL: Duration = Duration::from_secs(300);
@@ -1,7 +1,7 @@
 use std::{fmt, path::PathBuf};
 
 use snafu::Snafu;
-use tunelo::common::HostAddressError;
+use tunelo::common::{HostAddressError, ProxyHost};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
@@ -36,6 +36,21 @@ pub enum Error {
     #[snafu(display("Could not run HTTP proxy server, error: {source}"))]
     RunHttpServer { source: tunelo::server::Error },
 
+    #[snafu(display("Could not load allowed domains, error: {source}"))]
+    LoadAllowedDomains { source: tunelo::filter::PatternError },
+
+    #[cfg(feature = "geoip")]
+    #[snafu(display("Could not load GeoIP database, error: {source}"))]
+    LoadGeoIpDatabase { source: tunelo::filter::GeoIpError },
+
+    #[cfg(not(feature = "geoip"))]
+    #[snafu(display("GeoIP database is not supported without the `geoip` feature"))]
+    GeoIpUnsupported,
+
+    #[cfg(not(feature = "doh"))]
+    #[snafu(display("DNS over HTTPS is not supported without the `doh` feature"))]
+    DohUnsupported,
+
     #[snafu(display("Errors occurred: {}", Errors::from(errors)))]
     Collection { errors: Vec<Error> },
 
@@ -57,6 +72,9 @@ pub enum Error {
     #[snafu(display("No proxy chain provided"))]
     NoProxyChain,
 
+    #[snafu(display("Invalid proxy chain, error: {source}"))]
+    InvalidProxyChain { source: tunelo::common::IncompatibleChain },
+
     #[snafu(display("SOCKS listen address is missed"))]
     NoSocksListenAddress,
 
@@ -69,6 +87,18 @@ pub enum Error {
     #[snafu(display("HTTP listen port is missed"))]
     NoHttpListenPort,
 
+    #[snafu(display("TLS private key is missed for the TLS certificate"))]
+    NoTlsPrivateKey,
+
+    #[snafu(display("TLS certificate is missed for the TLS private key"))]
+    NoTlsCertificate,
+
+    #[snafu(display("Connection rate and burst must be greater than 0"))]
+    InvalidConnectionRate,
+
+    #[snafu(display("Interval between checks must be greater than 0"))]
+    InvalidCheckInterval,
+
     #[snafu(display("Proxy chain format is not supported: {format}"))]
     ProxyChainFormatNotSupported { format: String },
 
@@ -105,11 +135,34 @@ pub enum Error {
     #[snafu(display("Could not write proxy checker report, error: {source}"))]
     WriteProxyCheckerReport { source: std::io::Error },
 
+    #[snafu(display(
+        "Could not bind proxy checker dashboard at {listen_address}, error: {source}"
+    ))]
+    BindCheckerDashboard { listen_address: std::net::SocketAddr, source: std::io::Error },
+
+    #[snafu(display("Could not bind metrics endpoint at {listen_address}, error: {source}"))]
+    BindMetricsEndpoint { listen_address: std::net::SocketAddr, source: std::io::Error },
+
     #[snafu(display("Could not write available proxy hosts, error: {source}"))]
     WriteProxyHosts { source: std::io::Error },
 
     #[snafu(display("Could not parse host address, error: {source}"))]
     ParseHostAddress { source: HostAddressError },
+
+    #[snafu(display("Could not connect proxy server {proxy}, error: {source}"))]
+    ConnectProxyServer { proxy: Box<ProxyHost>, source: std::io::Error },
+
+    #[snafu(display("Could not handshake with proxy server, error: {source}"))]
+    ProxyHandshake { source: tunelo::client::Error },
+
+    #[snafu(display("Probing proxy server {proxy} timed out"))]
+    ProbeTimeout { proxy: Box<ProxyHost> },
+
+    #[snafu(display("Could not send keepalive, error: {source}"))]
+    ProbeKeepalive { source: std::io::Error },
+
+    #[snafu(display("Invalid credential entry #{index}, expected form of `user:pass`"))]
+    InvalidCredentials { index: usize },
 }
 
 impl From<HostAddressError> for Error {
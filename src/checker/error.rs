@@ -41,14 +41,24 @@ pub enum Error {
     #[snafu(display("Incomplete HTTP response"))]
     IncompleteHttpResponse,
 
+    #[snafu(display("HTTP response headers exceed {max_bytes} bytes"))]
+    ResponseTooLarge { max_bytes: usize },
+
     #[snafu(display("Could not construct a DNSNameRef from `{dns_name}`, error: {source}"))]
     InvalidDnsName { dns_name: String, source: rustls_pki_types::InvalidDnsNameError },
 
+    #[snafu(display("Could not transfer payload, error: {}", source))]
+    TransferPayload { source: std::io::Error },
+
+    #[snafu(display("Incomplete payload, received {received} of {expected} bytes"))]
+    IncompletePayload { received: usize, expected: usize },
+
     #[snafu(display("Operation timed out"))]
     Timeout,
 }
 
 mod report {
+    use serde::{Serialize, Serializer};
     use snafu::Snafu;
 
     use crate::checker::error::Error;
@@ -91,9 +101,18 @@ mod report {
         #[snafu(display("Incomplete HTTP response"))]
         IncompleteHttpResponse,
 
+        #[snafu(display("HTTP response headers exceed {max_bytes} bytes"))]
+        ResponseTooLarge { max_bytes: usize },
+
         #[snafu(display("Invalid DNS name `{dns_name}`"))]
         InvalidDnsName { dns_name: String },
 
+        #[snafu(display("Could not transfer payload, error: {message}"))]
+        TransferPayload { message: String },
+
+        #[snafu(display("Incomplete payload, received {received} of {expected} bytes"))]
+        IncompletePayload { received: usize, expected: usize },
+
         #[snafu(display("Operation timed out"))]
         Timeout,
     }
@@ -121,9 +140,22 @@ mod report {
                 Error::ParseHttpRequest { source } => Self::ParseHttpRequest { source },
                 Error::ParseHttpResponse { source } => Self::ParseHttpResponse { source },
                 Error::IncompleteHttpResponse => Self::IncompleteHttpResponse,
+                Error::ResponseTooLarge { max_bytes } => Self::ResponseTooLarge { max_bytes },
                 Error::InvalidDnsName { dns_name, .. } => Self::InvalidDnsName { dns_name },
+                Error::TransferPayload { source } => {
+                    Self::TransferPayload { message: source.to_string() }
+                }
+                Error::IncompletePayload { received, expected } => {
+                    Self::IncompletePayload { received, expected }
+                }
                 Error::Timeout => Self::Timeout,
             }
         }
     }
+
+    impl Serialize for ReportError {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
 }
@@ -7,7 +7,8 @@ pub use self::{
     error::{Error, ReportError},
     prober::{
         BasicProber, BasicProberReport, HttpMethod, HttpProber, HttpProberReport, LivenessProber,
-        LivenessProberReport, Prober, ProberReport,
+        LivenessProberReport, Prober, ProberReport, ThroughputProber, ThroughputProberReport,
+        DEFAULT_MAX_RESPONSE_BYTES, DEFAULT_PAYLOAD_SIZE,
     },
     report::TaskReport,
     simple::SimpleProxyChecker,
@@ -1,3 +1,4 @@
+use serde::Serialize;
 use snafu::ResultExt;
 use tokio::io::AsyncWriteExt;
 
@@ -7,7 +8,8 @@ use crate::{
     common::{HostAddress, ProxyHost},
 };
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct BasicProberReport {
     pub destination_reachable: bool,
     pub destination: Option<HostAddress>,
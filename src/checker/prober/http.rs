@@ -1,5 +1,13 @@
-use std::{fmt, sync::Arc};
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
+use bytes::BytesMut;
+use futures::Stream;
+use serde::Serialize;
 use snafu::ResultExt;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_rustls::{rustls, TlsConnector};
@@ -11,7 +19,11 @@ use crate::{
     common::{HostAddress, ProxyHost},
 };
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+/// Max number of bytes read while parsing the response headers by default.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum HttpMethod {
     Head,
     Get,
@@ -33,25 +45,61 @@ pub struct HttpProber {
     method: HttpMethod,
     url: Url,
     expected_response_code: u16,
+    tls_connector: TlsClient,
+    max_response_bytes: usize,
 }
 
 impl HttpProber {
     #[inline]
     #[must_use]
     pub const fn get(url: Url, expected_response_code: u16) -> Self {
-        Self { url, expected_response_code, method: HttpMethod::Get }
+        Self {
+            url,
+            expected_response_code,
+            method: HttpMethod::Get,
+            tls_connector: TlsClient::Default,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
     }
 
     #[inline]
     #[must_use]
     pub const fn head(url: Url, expected_response_code: u16) -> Self {
-        Self { url, expected_response_code, method: HttpMethod::Head }
+        Self {
+            url,
+            expected_response_code,
+            method: HttpMethod::Head,
+            tls_connector: TlsClient::Default,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
     }
 
     #[inline]
     #[must_use]
     pub const fn delete(url: Url, expected_response_code: u16) -> Self {
-        Self { url, expected_response_code, method: HttpMethod::Delete }
+        Self {
+            url,
+            expected_response_code,
+            method: HttpMethod::Delete,
+            tls_connector: TlsClient::Default,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    /// Verifies certificates of HTTPS servers with `root_store` instead of
+    /// the bundled Mozilla root certificates, e.g. to trust a private CA.
+    #[must_use]
+    pub fn with_root_store(mut self, root_store: rustls::RootCertStore) -> Self {
+        self.tls_connector = TlsClient::custom(root_store);
+        self
+    }
+
+    /// Sets the max number of bytes read while parsing the response headers,
+    /// so that a server never completing them can not exhaust the memory.
+    #[must_use]
+    pub const fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
     }
 
     pub async fn probe(
@@ -62,6 +110,105 @@ impl HttpProber {
         report.url = Some(self.url.clone());
         report.method = Some(self.method);
 
+        let stream = self.open_tunnel(proxy_server, report).await?;
+        self.check_http(stream, report).await
+    }
+
+    /// Probes `probers` one after another through `proxy_server` and yields
+    /// their reports in order.
+    ///
+    /// Consecutive probers of the same scheme, host and port share one tunnel
+    /// with keep-alive. A new tunnel is established for another destination,
+    /// or once the previous one can not be reused, e.g. the server closes it
+    /// or does not delimit the response body with `Content-Length`.
+    ///
+    /// Each prober is given `timeout`, a tunnel is not reused after a timeout.
+    pub fn probe_many<I>(
+        probers: I,
+        proxy_server: &ProxyHost,
+        timeout: Option<Duration>,
+    ) -> impl Stream<Item = HttpProberReport> + Send + 'static
+    where
+        I: IntoIterator<Item = Self>,
+        I::IntoIter: Send + 'static,
+    {
+        let state = (probers.into_iter(), proxy_server.clone(), None);
+        futures::stream::unfold(state, move |(mut probers, proxy_server, tunnel)| async move {
+            let prober = probers.next()?;
+            let timeout_report = HttpProberReport::timeout(prober.method, prober.url.clone());
+            let mut report = HttpProberReport::default();
+            let probe = prober.probe_reusing(&proxy_server, tunnel, &mut report);
+            let tunnel = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, probe).await {
+                    Ok(tunnel) => tunnel,
+                    Err(_err) => {
+                        report = timeout_report;
+                        None
+                    }
+                },
+                None => probe.await,
+            };
+            Some((report, (probers, proxy_server, tunnel)))
+        })
+    }
+
+    // probes through `tunnel` if it leads to the same origin, returns the
+    // tunnel if it can be reused by the next prober
+    async fn probe_reusing(
+        self,
+        proxy_server: &ProxyHost,
+        tunnel: Option<Tunnel>,
+        report: &mut HttpProberReport,
+    ) -> Option<Tunnel> {
+        report.url = Some(self.url.clone());
+        report.method = Some(self.method);
+
+        let origin = match self.origin() {
+            Ok(origin) => origin,
+            Err(err) => {
+                report.error = Some(err.into());
+                return None;
+            }
+        };
+
+        if let Some(mut tunnel) = tunnel.filter(|tunnel| tunnel.origin == origin) {
+            report.destination_reachable = true;
+            match self.exchange(&mut tunnel.stream, report).await {
+                Ok(true) => return Some(tunnel),
+                Ok(false) => {
+                    drop(tunnel.stream.shutdown().await);
+                    return None;
+                }
+                // the server may close an idle connection at any time, retry
+                // with a new tunnel
+                Err(_) => report.response_code = None,
+            }
+        }
+
+        let result = async {
+            let mut stream = self.open_tunnel(proxy_server, report).await?;
+            let reusable = self.exchange(&mut stream, report).await?;
+            Ok::<_, Error>((stream, reusable))
+        }
+        .await;
+        match result {
+            Ok((stream, true)) => Some(Tunnel { origin, stream }),
+            Ok((mut stream, false)) => {
+                drop(stream.shutdown().await);
+                None
+            }
+            Err(err) => {
+                report.error = Some(err.into());
+                None
+            }
+        }
+    }
+
+    async fn open_tunnel(
+        &self,
+        proxy_server: &ProxyHost,
+        report: &mut HttpProberReport,
+    ) -> Result<Box<dyn TunnelStream>, Error> {
         let destination = self.destination_address()?;
         let stream = ProxyStream::connect_with_proxy(proxy_server, &destination)
             .await
@@ -70,7 +217,7 @@ impl HttpProber {
 
         let stream = stream.into_inner();
         match self.url.scheme() {
-            "http" => self.check_http(stream, report).await,
+            "http" => Ok(Box::new(stream)),
             "https" => {
                 let stream = {
                     let server_name = {
@@ -82,25 +229,14 @@ impl HttpProber {
                             .to_owned()
                     };
 
-                    let connector = {
-                        // TODO: use `lazy_static` to initialize?
-                        let mut root_store = rustls::RootCertStore::empty();
-                        root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-
-                        let config = rustls::ClientConfig::builder()
-                            .with_root_certificates(root_store)
-                            .with_no_client_auth();
-
-                        TlsConnector::from(Arc::new(config))
-                    };
-
-                    connector
+                    self.tls_connector
+                        .connector()
                         .connect(server_name, stream)
                         .await
                         .context(error::InitializeTlsStreamSnafu)?
                 };
 
-                self.check_http(stream, report).await
+                Ok(Box::new(stream))
             }
             scheme => Err(Error::UnknownScheme { scheme: scheme.to_owned() }),
         }
@@ -111,26 +247,96 @@ impl HttpProber {
         mut stream: Stream,
         report: &mut HttpProberReport,
     ) -> Result<(), Error>
+    where
+        Stream: Unpin + AsyncRead + AsyncWrite,
+    {
+        let _reusable = self.exchange(&mut stream, report).await?;
+        drop(stream.shutdown().await);
+        Ok(())
+    }
+
+    // sends the request and reads the response, returns whether `stream` can
+    // be reused for another request, i.e. the response body is read entirely
+    async fn exchange<Stream>(
+        &self,
+        stream: &mut Stream,
+        report: &mut HttpProberReport,
+    ) -> Result<bool, Error>
     where
         Stream: Unpin + AsyncRead + AsyncWrite,
     {
         let request = self.build_request()?;
         stream.write(&request).await.context(error::WriteHttpRequestSnafu)?;
 
-        let mut buf = vec![0u8; 1024];
-        stream.read(&mut buf[..]).await.context(error::ReadHttpResponseSnafu)?;
+        let mut buf = BytesMut::with_capacity(1024.min(self.max_response_bytes));
+        loop {
+            let remaining = self.max_response_bytes.saturating_sub(buf.len());
+            if remaining == 0 {
+                return Err(Error::ResponseTooLarge { max_bytes: self.max_response_bytes });
+            }
+            buf.reserve(remaining.min(1024));
+
+            let n = (&mut *stream)
+                .take(remaining as u64)
+                .read_buf(&mut buf)
+                .await
+                .context(error::ReadHttpResponseSnafu)?;
+            if n == 0 {
+                return Err(Error::IncompleteHttpResponse);
+            }
 
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut response = httparse::Response::new(&mut headers);
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut response = httparse::Response::new(&mut headers);
 
-        let res = response.parse(&buf).context(error::ParseHttpResponseSnafu)?;
-        if res.is_complete() {
-            drop(stream.shutdown().await);
-            report.response_code = response.code;
-            return Ok(());
+            let res = response.parse(&buf).context(error::ParseHttpResponseSnafu)?;
+            if let httparse::Status::Complete(headers_len) = res {
+                report.response_code = response.code;
+
+                // bodies larger than the limit are not worth draining
+                let Some(body_len) = self
+                    .body_length(&response)
+                    .filter(|&len| len <= self.max_response_bytes as u64)
+                else {
+                    return Ok(false);
+                };
+                let Some(remaining) = body_len.checked_sub((buf.len() - headers_len) as u64) else {
+                    return Ok(false);
+                };
+                let drained =
+                    tokio::io::copy(&mut (&mut *stream).take(remaining), &mut tokio::io::sink())
+                        .await
+                        .context(error::ReadHttpResponseSnafu)?;
+                return Ok(drained == remaining);
+            }
         }
+    }
+
+    // returns the length of the body following `response`, `None` if it is
+    // delimited by closing the connection or the connection is closed anyway
+    fn body_length(&self, response: &httparse::Response<'_, '_>) -> Option<u64> {
+        let header = |name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .and_then(|header| std::str::from_utf8(header.value).ok())
+        };
+        let has_token = |name: &str, token: &str| {
+            header(name).is_some_and(|value| {
+                value.split(',').any(|value| value.trim().eq_ignore_ascii_case(token))
+            })
+        };
 
-        Err(Error::IncompleteHttpResponse)
+        if response.version != Some(1) || has_token("Connection", "close") {
+            return None;
+        }
+        if self.method == HttpMethod::Head || matches!(response.code, Some(100..=199 | 204 | 304)) {
+            return Some(0);
+        }
+        if header("Transfer-Encoding").is_some() {
+            return None;
+        }
+        header("Content-Length")?.trim().parse().ok()
     }
 
     fn build_request(&self) -> Result<Vec<u8>, Error> {
@@ -150,6 +356,14 @@ impl HttpProber {
         Ok(req)
     }
 
+    fn origin(&self) -> Result<Origin, Error> {
+        Ok(Origin {
+            scheme: self.url.scheme().to_owned(),
+            destination: self.destination_address()?,
+            tls_connector: self.tls_connector.clone(),
+        })
+    }
+
     #[inline]
     pub fn destination_address(&self) -> Result<HostAddress, Error> {
         Ok(HostAddress::new(&self.host()?, self.port()?))
@@ -177,7 +391,84 @@ impl HttpProber {
     pub fn url(&self) -> &Url { &self.url }
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+trait TunnelStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> TunnelStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[derive(Debug, Eq, PartialEq)]
+struct Origin {
+    scheme: String,
+    destination: HostAddress,
+    tls_connector: TlsClient,
+}
+
+/// Tunnel to an origin kept alive between probers of
+/// [`HttpProber::probe_many`].
+struct Tunnel {
+    origin: Origin,
+    stream: Box<dyn TunnelStream>,
+}
+
+/// TLS client configuration of [`HttpProber`], the one with the bundled
+/// Mozilla root certificates is built once and shared by all probers.
+#[derive(Clone)]
+enum TlsClient {
+    Default,
+    Custom(Arc<rustls::ClientConfig>),
+}
+
+impl TlsClient {
+    fn custom(root_store: rustls::RootCertStore) -> Self {
+        Self::Custom(Self::build_config(root_store))
+    }
+
+    fn config(&self) -> &Arc<rustls::ClientConfig> {
+        static DEFAULT: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+
+        match self {
+            Self::Default => DEFAULT.get_or_init(|| {
+                let mut root_store = rustls::RootCertStore::empty();
+                root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                Self::build_config(root_store)
+            }),
+            Self::Custom(config) => config,
+        }
+    }
+
+    fn connector(&self) -> TlsConnector { TlsConnector::from(self.config().clone()) }
+
+    fn build_config(root_store: rustls::RootCertStore) -> Arc<rustls::ClientConfig> {
+        Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth(),
+        )
+    }
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => f.write_str("Default"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+// configurations are compared by identity, `rustls::ClientConfig` is not
+// comparable
+impl PartialEq for TlsClient {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(self.config(), other.config()) }
+}
+
+impl Eq for TlsClient {}
+
+impl Hash for TlsClient {
+    fn hash<H: Hasher>(&self, state: &mut H) { Arc::as_ptr(self.config()).hash(state); }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct HttpProberReport {
     pub destination_reachable: bool,
     pub method: Option<HttpMethod>,
@@ -203,3 +494,220 @@ impl HttpProberReport {
     #[must_use]
     pub fn has_error(&self) -> bool { self.error.is_some() }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        net::{IpAddr, Ipv4Addr},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::StreamExt;
+    use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::{rustls, TlsAcceptor};
+    use url::Url;
+
+    use super::{HttpProber, HttpProberReport};
+    use crate::{
+        authentication::AuthenticationManager,
+        checker::{error::ReportError, Error},
+        common::ProxyHost,
+        filter::SimpleFilter,
+        test_util::{serve_socks5_with, socks5_service},
+        transport::{self, Resolver, Transport},
+    };
+
+    const CA_CERT: &[u8] = include_bytes!("testdata/ca.pem");
+    const SERVER_CERT: &[u8] = include_bytes!("testdata/localhost.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("testdata/localhost.key");
+
+    struct LocalhostResolver;
+
+    impl Resolver for LocalhostResolver {
+        fn resolve(
+            &self,
+            _host: &str,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, transport::Error>> + Send>> {
+            Box::pin(futures::future::ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]))
+        }
+    }
+
+    async fn serve_https() -> u16 {
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from_pem_slice(SERVER_CERT).unwrap()],
+                PrivateKeyDer::from_pem_slice(SERVER_KEY).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(mut stream) = acceptor.accept(stream).await else { continue };
+                let mut buf = [0u8; 1024];
+                let _unused = stream.read(&mut buf).await;
+                let _unused =
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+                let _unused = stream.shutdown().await;
+            }
+        });
+        port
+    }
+
+    // answers requests of each connection with keep-alive, `/missing` is not
+    // found, counts accepted connections
+    async fn serve_http() -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _unused = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut chunk).await {
+                        buf.extend_from_slice(&chunk[..n]);
+                        while let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                            let request: Vec<u8> = buf.drain(..end + 4).collect();
+                            let response: &[u8] = if request.starts_with(b"GET /missing ") {
+                                b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found"
+                            } else {
+                                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                            };
+                            stream.write_all(response).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        (port, connections)
+    }
+
+    // resolves every host to localhost
+    async fn serve_socks5() -> ProxyHost {
+        let transport = {
+            let filter = Arc::new(SimpleFilter::deny_list());
+            Arc::new(Transport::direct(Arc::new(LocalhostResolver), filter))
+        };
+        serve_socks5_with(socks5_service(transport, AuthenticationManager::new())).await
+    }
+
+    #[tokio::test]
+    async fn probe_https_with_custom_root_store() {
+        let port = serve_https().await;
+        let proxy = serve_socks5().await;
+        let url = Url::parse(&format!("https://localhost:{port}/")).unwrap();
+
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add(CertificateDer::from_pem_slice(CA_CERT).unwrap()).unwrap();
+        let prober = HttpProber::get(url.clone(), 200).with_root_store(root_store);
+        let mut report = HttpProberReport::default();
+        prober.probe(&proxy, &mut report).await.unwrap();
+        assert_eq!(report.response_code, Some(200));
+
+        // the private CA is not trusted by the bundled root certificates
+        let mut report = HttpProberReport::default();
+        let err = HttpProber::get(url, 200).probe(&proxy, &mut report).await.unwrap_err();
+        assert!(matches!(err, Error::InitializeTlsStream { .. }));
+    }
+
+    #[tokio::test]
+    async fn reuse_tunnel() {
+        let (port, connections) = serve_http().await;
+        let proxy = serve_socks5().await;
+        let url =
+            |host: &str, path: &str| Url::parse(&format!("http://{host}:{port}{path}")).unwrap();
+
+        let probers = [
+            HttpProber::get(url("localhost", "/"), 200),
+            HttpProber::get(url("localhost", "/missing"), 404),
+        ];
+        let reports: Vec<_> = HttpProber::probe_many(probers, &proxy, None).collect().await;
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].response_code, Some(200));
+        assert_eq!(reports[1].response_code, Some(404));
+        assert!(reports.iter().all(|report| !report.has_error()));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // another host is probed through a new tunnel
+        let probers = [
+            HttpProber::get(url("localhost", "/"), 200),
+            HttpProber::get(url("127.0.0.1", "/"), 200),
+        ];
+        let reports: Vec<_> = HttpProber::probe_many(probers, &proxy, None).collect().await;
+        assert!(reports.iter().all(|report| report.response_code == Some(200)));
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn time_out_each_probe() {
+        // accepts connections without ever answering
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let silent_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let (port, _connections) = serve_http().await;
+        let proxy = serve_socks5().await;
+
+        let probers = [
+            HttpProber::get(Url::parse(&format!("http://localhost:{silent_port}/")).unwrap(), 200),
+            HttpProber::get(Url::parse(&format!("http://localhost:{port}/")).unwrap(), 200),
+        ];
+        let timeout = Some(Duration::from_millis(200));
+        let reports: Vec<_> = HttpProber::probe_many(probers, &proxy, timeout).collect().await;
+        assert!(matches!(reports[0].error, Some(ReportError::Timeout)));
+        assert_eq!(reports[1].response_code, Some(200));
+    }
+
+    #[tokio::test]
+    async fn limit_response_headers() {
+        let url = Url::parse("http://192.0.2.1/").unwrap();
+
+        // headers split across writes are still parsed
+        let (stream, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _unused = server.read(&mut buf).await;
+            for chunk in ["HTTP/1.1 204 No Content\r\n", "Server: test\r\n", "\r\n"] {
+                server.write_all(chunk.as_bytes()).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let mut report = HttpProberReport::default();
+        HttpProber::get(url.clone(), 204).check_http(stream, &mut report).await.unwrap();
+        assert_eq!(report.response_code, Some(204));
+
+        // the server never completes its headers
+        let (stream, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _unused = server.read(&mut buf).await;
+            server.write_all(b"HTTP/1.1 200 OK\r\nX-Padding: ").await.unwrap();
+            while server.write_all(b"tunelo").await.is_ok() {}
+        });
+        let prober = HttpProber::get(url, 200).with_max_response_bytes(4096);
+        let mut report = HttpProberReport::default();
+        let err = prober.check_http(stream, &mut report).await.unwrap_err();
+        assert!(matches!(err, Error::ResponseTooLarge { max_bytes: 4096 }));
+        assert_eq!(report.response_code, None);
+    }
+}
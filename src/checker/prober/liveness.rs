@@ -1,7 +1,9 @@
+use serde::Serialize;
+
 use crate::{
     checker::{Error, ReportError},
     client::ProxyConnector,
-    common::{ProxyHost, ProxyStrategy},
+    common::{Policy, ProxyHost, ProxyStrategy},
 };
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -14,10 +16,17 @@ impl Default for LivenessProber {
 impl LivenessProber {
     #[inline]
     pub async fn probe(self, proxy_server: &ProxyHost) -> LivenessProberReport {
+        self.probe_with_policy(proxy_server, &Policy::default()).await
+    }
+
+    pub async fn probe_with_policy(
+        self,
+        proxy_server: &ProxyHost,
+        policy: &Policy,
+    ) -> LivenessProberReport {
         let mut report = LivenessProberReport::default();
-        let alive =
-            ProxyConnector::probe_liveness(&ProxyStrategy::Single(proxy_server.clone()), None)
-                .await;
+        let strategy = ProxyStrategy::Single(proxy_server.clone());
+        let alive = ProxyConnector::probe_liveness_with_policy(&strategy, policy).await;
 
         match alive {
             Ok(alive) => {
@@ -34,7 +43,8 @@ impl LivenessProber {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LivenessProberReport {
     pub alive: bool,
     pub error: Option<ReportError>,
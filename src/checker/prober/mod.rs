@@ -1,15 +1,19 @@
 use std::time::Duration;
 
+use serde::Serialize;
+
 use crate::common::ProxyHost;
 
 mod basic;
 mod http;
 mod liveness;
+mod throughput;
 
 pub use self::{
     basic::{BasicProber, BasicProberReport},
-    http::{HttpMethod, HttpProber, HttpProberReport},
+    http::{HttpMethod, HttpProber, HttpProberReport, DEFAULT_MAX_RESPONSE_BYTES},
     liveness::{LivenessProber, LivenessProberReport},
+    throughput::{ThroughputProber, ThroughputProberReport, DEFAULT_PAYLOAD_SIZE},
 };
 
 #[derive(Clone, Debug, Hash)]
@@ -17,6 +21,7 @@ pub enum Prober {
     Liveness(LivenessProber),
     Basic(BasicProber),
     Http(HttpProber),
+    Throughput(ThroughputProber),
 }
 
 impl Prober {
@@ -26,6 +31,7 @@ impl Prober {
             Self::Liveness(_) => 0,
             Self::Basic(_) => 1,
             Self::Http(_) => 2,
+            Self::Throughput(_) => 3,
         }
     }
 
@@ -34,6 +40,9 @@ impl Prober {
             Self::Liveness(_) => LivenessProberReport::timeout().into(),
             Self::Basic(p) => BasicProberReport::timeout(p.destination().clone()).into(),
             Self::Http(p) => HttpProberReport::timeout(p.method(), p.url().clone()).into(),
+            Self::Throughput(p) => {
+                ThroughputProberReport::timeout(p.destination().clone(), p.payload_size()).into()
+            }
         }
     }
 
@@ -73,6 +82,16 @@ impl Prober {
                     }
                 }
             }
+            Self::Throughput(prober) => {
+                let mut report = ThroughputProberReport::default();
+                match prober.probe(proxy_server, &mut report).await {
+                    Ok(()) => ProberReport::Throughput(report),
+                    Err(err) => {
+                        report.error = Some(err.into());
+                        ProberReport::Throughput(report)
+                    }
+                }
+            }
         }
     }
 }
@@ -88,6 +107,7 @@ macro_rules! impl_from_prober {
 impl_from_prober!(LivenessProber, Liveness);
 impl_from_prober!(BasicProber, Basic);
 impl_from_prober!(HttpProber, Http);
+impl_from_prober!(ThroughputProber, Throughput);
 
 // impl Ord for Prober {
 //     fn cmp(&self, other: &Prober) -> std::cmp::Ordering {
@@ -101,11 +121,13 @@ impl_from_prober!(HttpProber, Http);
 //     }
 // }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase", tag = "prober")]
 pub enum ProberReport {
     Liveness(LivenessProberReport),
     Basic(BasicProberReport),
     Http(HttpProberReport),
+    Throughput(ThroughputProberReport),
 }
 
 impl ProberReport {
@@ -115,6 +137,7 @@ impl ProberReport {
             Self::Liveness(_) => 0,
             Self::Basic(_) => 1,
             Self::Http(_) => 2,
+            Self::Throughput(_) => 3,
         }
     }
 
@@ -124,6 +147,7 @@ impl ProberReport {
             Self::Liveness(r) => r.has_error(),
             Self::Basic(r) => r.has_error(),
             Self::Http(r) => r.has_error(),
+            Self::Throughput(r) => r.has_error(),
         }
     }
 }
@@ -139,6 +163,7 @@ macro_rules! impl_from_prober_report {
 impl_from_prober_report!(LivenessProberReport, Liveness);
 impl_from_prober_report!(BasicProberReport, Basic);
 impl_from_prober_report!(HttpProberReport, Http);
+impl_from_prober_report!(ThroughputProberReport, Throughput);
 
 // impl Ord for ProberReport {
 //     fn cmp(&self, other: &ProberReport) -> std::cmp::Ordering {
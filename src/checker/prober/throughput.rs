@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::{
+    checker::{error, Error, ReportError},
+    client::ProxyStream,
+    common::{HostAddress, ProxyHost},
+};
+
+pub const DEFAULT_PAYLOAD_SIZE: usize = 1024 * 1024;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThroughputProberReport {
+    pub destination: Option<HostAddress>,
+    pub payload_size: usize,
+    pub upload_bytes_per_second: Option<u64>,
+    pub download_bytes_per_second: Option<u64>,
+    pub error: Option<ReportError>,
+}
+
+impl ThroughputProberReport {
+    #[inline]
+    #[must_use]
+    pub fn timeout(destination: HostAddress, payload_size: usize) -> Self {
+        Self {
+            destination: Some(destination),
+            payload_size,
+            error: Some(ReportError::Timeout),
+            ..Self::default()
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn has_error(&self) -> bool { self.error.is_some() }
+}
+
+/// Measures throughput of proxy servers by sending a payload through them to
+/// an echo endpoint, e.g. an Echo Protocol (RFC 862) server, and receiving it
+/// back.
+///
+/// Upload and download are measured separately, the upload throughput counts
+/// until the whole payload is written, the download throughput counts from the
+/// first byte echoed back until the whole payload is received.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ThroughputProber {
+    destination: HostAddress,
+    payload_size: usize,
+}
+
+impl ThroughputProber {
+    #[inline]
+    #[must_use]
+    pub fn new(destination: HostAddress, payload_size: usize) -> Self {
+        Self { destination, payload_size }
+    }
+
+    pub async fn probe(
+        self,
+        proxy_server: &ProxyHost,
+        report: &mut ThroughputProberReport,
+    ) -> Result<(), Error> {
+        report.destination = Some(self.destination.clone());
+        report.payload_size = self.payload_size;
+
+        let stream = ProxyStream::connect_with_proxy(proxy_server, &self.destination)
+            .await
+            .context(error::ConnectProxyServerSnafu)?;
+        let (mut reader, mut writer) = tokio::io::split(stream.into_inner());
+
+        // the echo endpoint stops reading once we stop receiving, upload and download
+        // must run concurrently
+        let payload_size = self.payload_size;
+        let upload = async move {
+            let started = Instant::now();
+            let chunk = [0u8; CHUNK_SIZE];
+            let mut remaining = payload_size;
+            while remaining > 0 {
+                let n = remaining.min(CHUNK_SIZE);
+                writer.write_all(&chunk[..n]).await.context(error::TransferPayloadSnafu)?;
+                remaining -= n;
+            }
+            writer.flush().await.context(error::TransferPayloadSnafu)?;
+            Ok((writer, started.elapsed()))
+        };
+        let download = async move {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let mut started = None;
+            let mut remaining = payload_size;
+            while remaining > 0 {
+                let n = reader.read(&mut buf).await.context(error::TransferPayloadSnafu)?;
+                if n == 0 {
+                    return Err(Error::IncompletePayload {
+                        received: payload_size - remaining,
+                        expected: payload_size,
+                    });
+                }
+                let _ = started.get_or_insert_with(Instant::now);
+                remaining = remaining.saturating_sub(n);
+            }
+            Ok((reader, started.map_or(Duration::ZERO, |started| started.elapsed())))
+        };
+
+        // relays of proxy servers may close both directions once one of them is
+        // closed, the stream is shut down after the payload is echoed back
+        let ((writer, upload_time), (reader, download_time)) =
+            futures::try_join!(upload, download)?;
+        reader.unsplit(writer).shutdown().await.context(error::ShutdownSnafu)?;
+
+        report.upload_bytes_per_second = Some(bytes_per_second(payload_size, upload_time));
+        report.download_bytes_per_second = Some(bytes_per_second(payload_size, download_time));
+
+        Ok(())
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn destination(&self) -> &HostAddress { &self.destination }
+
+    #[inline]
+    #[must_use]
+    pub const fn payload_size(&self) -> usize { self.payload_size }
+}
+
+fn bytes_per_second(bytes: usize, elapsed: Duration) -> u64 {
+    // payloads transferred in less than a microsecond are reported as if they take
+    // a microsecond
+    let micros = elapsed.as_micros().max(1);
+    u64::try_from(bytes as u128 * 1_000_000 / micros).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::{ThroughputProber, ThroughputProberReport};
+    use crate::{common::HostAddress, test_util::serve_socks5};
+
+    #[tokio::test]
+    async fn measure_throughput() {
+        let echo = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            let _unused = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let proxy_host = serve_socks5().await;
+        // larger than socket buffers, so that it can not be transferred at once
+        let payload_size = 4 * 1024 * 1024;
+        let prober = ThroughputProber::new(HostAddress::from(echo_addr), payload_size);
+        let mut report = ThroughputProberReport::default();
+        prober.probe(&proxy_host, &mut report).await.unwrap();
+
+        assert_eq!(report.payload_size, payload_size);
+        assert!(report.upload_bytes_per_second.is_some_and(|n| n > 0));
+        assert!(report.download_bytes_per_second.is_some_and(|n| n > 0));
+        assert!(!report.has_error());
+    }
+}
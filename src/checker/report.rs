@@ -1,9 +1,15 @@
+use serde::Serialize;
+
 use crate::{
-    checker::prober::{BasicProberReport, HttpProberReport, LivenessProberReport, ProberReport},
+    checker::prober::{
+        BasicProberReport, HttpProberReport, LivenessProberReport, ProberReport,
+        ThroughputProberReport,
+    },
     common::ProxyHost,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TaskReport {
     pub proxy_server: ProxyHost,
     pub liveness_report: LivenessProberReport,
@@ -31,9 +37,19 @@ impl TaskReport {
         })
     }
 
+    pub fn throughput_reports(&self) -> impl Iterator<Item = &ThroughputProberReport> {
+        self.prober_reports.iter().filter_map(|p| match p {
+            ProberReport::Throughput(p) => Some(p),
+            _ => None,
+        })
+    }
+
     #[must_use]
     pub fn basic_report_count(&self) -> usize { self.basic_reports().count() }
 
     #[must_use]
     pub fn http_report_count(&self) -> usize { self.http_reports().count() }
+
+    #[must_use]
+    pub fn throughput_report_count(&self) -> usize { self.throughput_reports().count() }
 }
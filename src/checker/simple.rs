@@ -1,27 +1,44 @@
 use std::time::Duration;
 
+use futures::StreamExt;
+
 pub use crate::checker::{
     prober::{LivenessProber, LivenessProberReport, Prober},
     report::TaskReport,
 };
-use crate::common::ProxyHost;
+use crate::{
+    checker::prober::{HttpProber, ProberReport},
+    common::{Policy, ProxyHost},
+};
 
 #[derive(Clone, Debug)]
 pub struct SimpleProxyChecker {
     proxy_server: ProxyHost,
     probers: Vec<Prober>,
+    policy: Policy,
 }
 
 impl SimpleProxyChecker {
     #[inline]
     #[must_use]
-    pub fn new(proxy_server: ProxyHost) -> Self { Self { proxy_server, probers: Vec::new() } }
+    pub fn new(proxy_server: ProxyHost) -> Self {
+        Self { proxy_server, probers: Vec::new(), policy: Policy::default() }
+    }
 
     #[inline]
     #[must_use]
     pub fn with_probers(proxy_server: ProxyHost, probers: &[Prober]) -> Self {
         let probers = probers.to_vec();
-        Self { proxy_server, probers }
+        Self { proxy_server, probers, policy: Policy::default() }
+    }
+
+    /// Checks liveness of the proxy server within the timeouts of `policy`,
+    /// with retries.
+    #[inline]
+    #[must_use]
+    pub const fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
     }
 
     #[inline]
@@ -47,7 +64,7 @@ impl SimpleProxyChecker {
 
     pub async fn check_liveness(&self) -> LivenessProberReport {
         let liveness_prober = LivenessProber;
-        liveness_prober.probe(&self.proxy_server).await
+        liveness_prober.probe_with_policy(&self.proxy_server, &self.policy).await
     }
 
     pub async fn run(self, timeout: Option<Duration>) -> TaskReport {
@@ -65,6 +82,9 @@ impl SimpleProxyChecker {
         task_report
     }
 
+    /// Runs probers in parallel, except HTTP probers which run one after
+    /// another to share tunnels, see [`HttpProber::probe_many`]. Reports are
+    /// in the order of probers.
     pub async fn run_parallel(self, timeout_per_probe: Option<Duration>) -> TaskReport {
         let (proxy_server, probers, mut task_report) = self.prepare(timeout_per_probe).await;
 
@@ -72,13 +92,28 @@ impl SimpleProxyChecker {
             return task_report;
         }
 
-        let futs: Vec<_> = probers
-            .into_iter()
-            .map(|checker| checker.probe(&proxy_server, timeout_per_probe))
-            .collect();
+        let proxy_server = &proxy_server;
+        let mut http_probers = Vec::new();
+        let mut futs = Vec::new();
+        for (index, prober) in probers.into_iter().enumerate() {
+            match prober {
+                Prober::Http(prober) => http_probers.push((index, prober)),
+                prober => futs.push(async move {
+                    (index, prober.probe(proxy_server, timeout_per_probe).await)
+                }),
+            }
+        }
+
+        let (http_indices, http_probers): (Vec<_>, Vec<_>) = http_probers.into_iter().unzip();
+        let http_reports = HttpProber::probe_many(http_probers, proxy_server, timeout_per_probe)
+            .map(ProberReport::Http)
+            .collect::<Vec<_>>();
+        let (mut reports, http_reports) =
+            futures::join!(futures::future::join_all(futs), http_reports);
+        reports.extend(http_indices.into_iter().zip(http_reports));
+        reports.sort_by_key(|(index, _)| *index);
 
-        let mut reports: Vec<_> = futures::future::join_all(futs).await.into_iter().collect();
-        task_report.prober_reports.append(&mut reports);
+        task_report.prober_reports.extend(reports.into_iter().map(|(_, report)| report));
         task_report
     }
 
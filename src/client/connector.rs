@@ -1,84 +1,230 @@
-use std::{sync::Arc, time::Duration};
+use std::{future::Future, sync::Arc, time::Duration};
 
 use snafu::ResultExt;
 use tokio::{
     io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
+use tokio_rustls::rustls;
 
 use crate::{
-    client::{error, handshake::ClientHandshake, Error, ProxyStream},
-    common::{HostAddress, ProxyHost, ProxyStrategy},
+    client::{
+        error,
+        handshake::{self, ClientHandshake},
+        Error, NoProxy, ProxySocket, ProxyStream,
+    },
+    common::{HostAddress, Policy, ProxyHost, ProxyStrategy},
+    protocol::socks::HopCount,
 };
 
+pub const DEFAULT_MAX_CHAIN_LENGTH: usize = 8;
+
 #[derive(Clone)]
 pub struct ProxyConnector {
     strategy: Arc<ProxyStrategy>,
+    no_proxy: Arc<NoProxy>,
+    policy: Policy,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
 }
 
 impl ProxyConnector {
-    pub fn new(strategy: Arc<ProxyStrategy>) -> Result<Self, Error> { Ok(Self { strategy }) }
+    #[inline]
+    pub fn new(strategy: Arc<ProxyStrategy>) -> Result<Self, Error> {
+        Self::with_max_chain_length(strategy, DEFAULT_MAX_CHAIN_LENGTH)
+    }
+
+    pub fn with_max_chain_length(
+        strategy: Arc<ProxyStrategy>,
+        max_chain_length: usize,
+    ) -> Result<Self, Error> {
+        let length = strategy.chain_length();
+        if length > max_chain_length {
+            return Err(Error::ProxyChainTooLong { length, max_length: max_chain_length });
+        }
+
+        Ok(Self { strategy, no_proxy: Arc::default(), policy: Policy::default(), tls_config: None })
+    }
+
+    /// Connects destinations listed in `no_proxy` directly instead of via
+    /// proxy servers.
+    #[must_use]
+    pub fn with_no_proxy(mut self, no_proxy: NoProxy) -> Self {
+        self.no_proxy = Arc::new(no_proxy);
+        self
+    }
+
+    /// Connects and handshakes with proxy servers within the timeouts of
+    /// `policy`, failed attempts are retried as a whole.
+    #[must_use]
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn policy(&self) -> Policy { self.policy }
+
+    /// Verifies certificates of proxy servers over TLS with `root_store`
+    /// instead of the bundled Mozilla root certificates, e.g. to trust a
+    /// private CA.
+    #[must_use]
+    pub fn with_root_store(mut self, root_store: rustls::RootCertStore) -> Self {
+        self.tls_config = Some(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth(),
+        ));
+        self
+    }
 
     pub async fn connect(&self, host: &HostAddress) -> Result<ProxyStream, Error> {
+        self.policy.retry(|| self.connect_once(host)).await
+    }
+
+    async fn connect_once(&self, host: &HostAddress) -> Result<ProxyStream, Error> {
         let strategy = self.strategy.clone();
-        let mut socket = Self::build_socket(&strategy).await?;
+        if self.no_proxy.matches(host) {
+            let socket = with_timeout(self.policy.connect_timeout, async {
+                TcpStream::connect(host.to_string())
+                    .await
+                    .with_context(|_| error::ConnectRemoteHostSnafu { addr: host.clone() })
+            })
+            .await?;
+            return Ok(ProxyStream::direct(socket, strategy));
+        }
+
+        let mut socket =
+            Self::build_socket(&strategy, &self.policy, self.tls_config.clone()).await?;
 
-        let res = match self.strategy.as_ref() {
-            ProxyStrategy::Single(proxy) => Self::handshake(&mut socket, proxy, host).await,
+        let timeout = self.policy.handshake_timeout;
+        let bind_addr = match self.strategy.as_ref() {
+            ProxyStrategy::Single(proxy) => {
+                Self::handshake(&mut socket, proxy, host, timeout).await
+            }
             ProxyStrategy::Chained(proxies) => match proxies.last() {
-                Some(proxy_host) => Self::handshake(&mut socket, proxy_host, host).await,
+                Some(proxy_host) => Self::handshake(&mut socket, proxy_host, host, timeout)
+                    .await
+                    .map_err(|err| Error::chain(proxies.len() - 1, proxy_host, err)),
                 None => return Err(Error::NoProxyServiceProvided),
             },
         };
 
-        if let Err(err) = res {
-            socket.shutdown().await.context(error::ShutdownSnafu)?;
-            return Err(err);
-        }
+        let bind_addr = match bind_addr {
+            Ok(bind_addr) => bind_addr,
+            Err(err) => {
+                socket.shutdown().await.context(error::ShutdownSnafu)?;
+                return Err(err);
+            }
+        };
 
-        Ok(ProxyStream::from_raw(socket, strategy))
+        Ok(ProxyStream::from_raw(socket, strategy).with_bind_addr(bind_addr))
     }
 
     pub async fn probe_liveness(
         strategy: &ProxyStrategy,
         timeout: Option<Duration>,
     ) -> Result<bool, Error> {
+        let policy = Policy::default();
+        let build_socket = Self::build_socket(strategy, &policy, None);
         let mut socket = match timeout {
-            Some(t) => tokio::time::timeout(t, Self::build_socket(strategy))
-                .await
-                .map_err(|_| Error::Timeout)??,
-            None => Self::build_socket(strategy).await?,
+            Some(t) => {
+                tokio::time::timeout(t, build_socket).await.map_err(|_| Error::Timeout)??
+            }
+            None => build_socket.await?,
         };
         socket.shutdown().await.context(error::ShutdownSnafu)?;
         Ok(true)
     }
 
+    /// Connects the proxy servers of `strategy` without handshaking with the
+    /// last one, within the timeouts of `policy` and with retries.
+    pub async fn probe_liveness_with_policy(
+        strategy: &ProxyStrategy,
+        policy: &Policy,
+    ) -> Result<bool, Error> {
+        let mut socket = policy.retry(|| Self::build_socket(strategy, policy, None)).await?;
+        socket.shutdown().await.context(error::ShutdownSnafu)?;
+        Ok(true)
+    }
+
+    // connections made while handling a connection taking part in loop
+    // detection carry the incremented hop count to the first proxy server,
+    // inside TLS if the proxy server is over TLS; only SOCKS5 proxy servers
+    // receive it, HTTP and SOCKS4a proxy servers would reject the marker
+    async fn connect_proxy_server(
+        proxy_host: &ProxyHost,
+        policy: &Policy,
+        tls_config: Option<Arc<rustls::ClientConfig>>,
+    ) -> Result<ProxySocket, Error> {
+        let socket = with_timeout(policy.connect_timeout, async {
+            TcpStream::connect(proxy_host.host_address().to_string())
+                .await
+                .context(error::ConnectProxyServerSnafu)
+        })
+        .await?;
+        let mut socket = with_timeout(
+            policy.handshake_timeout,
+            ProxySocket::from(socket).start_tls(proxy_host, tls_config),
+        )
+        .await?;
+        if let (ProxyHost::Socks5 { .. }, Some(hops)) = (proxy_host, HopCount::current()) {
+            socket
+                .write_all(&hops.next().to_bytes())
+                .await
+                .context(error::ConnectProxyServerSnafu)?;
+        }
+        Ok(socket)
+    }
+
     #[inline]
-    async fn build_socket(strategy: &ProxyStrategy) -> Result<TcpStream, Error> {
+    async fn build_socket(
+        strategy: &ProxyStrategy,
+        policy: &Policy,
+        tls_config: Option<Arc<rustls::ClientConfig>>,
+    ) -> Result<ProxySocket, Error> {
         let socket = match strategy {
             ProxyStrategy::Single(proxy) => {
-                let host = proxy.host_address();
-                TcpStream::connect(host.to_string())
-                    .await
-                    .context(error::ConnectProxyServerSnafu)?
+                Self::connect_proxy_server(proxy, policy, tls_config).await?
             }
             ProxyStrategy::Chained(proxies) => match proxies.len() {
                 0 => return Err(Error::NoProxyServiceProvided),
                 len => {
-                    let proxy_host = proxies[0].host_address();
-                    let mut socket = TcpStream::connect(proxy_host.to_string())
-                        .await
-                        .context(error::ConnectProxyServerSnafu)?;
+                    let mut socket =
+                        Self::connect_proxy_server(&proxies[0], policy, tls_config.clone())
+                            .await
+                            .map_err(|err| Error::chain(0, &proxies[0], err))?;
 
+                    // the chain is aborted at the first failed hop
                     for i in 0..(len - 1) {
                         let proxy_host = &proxies[i];
                         let target_host = proxies[i + 1].host_address();
-                        if let Err(err) =
-                            Self::handshake(&mut socket, proxy_host, &target_host).await
-                        {
+                        let handshake = Self::handshake(
+                            &mut socket,
+                            proxy_host,
+                            &target_host,
+                            policy.handshake_timeout,
+                        );
+                        if let Err(err) = handshake.await {
                             drop(socket.shutdown().await);
-                            return Err(err);
+                            // the proxy server could not reach the next one,
+                            // which is the failed hop
+                            return Err(match err {
+                                Error::Handshake { source: handshake::Error::HostUnreachable } => {
+                                    Error::chain(i + 1, &proxies[i + 1], err)
+                                }
+                                err => Error::chain(i, proxy_host, err),
+                            });
                         };
+
+                        // TLS with the next proxy server is nested in the tunnel
+                        let next_host = &proxies[i + 1];
+                        socket = with_timeout(
+                            policy.handshake_timeout,
+                            socket.start_tls(next_host, tls_config.clone()),
+                        )
+                        .await
+                        .map_err(|err| Error::chain(i + 1, next_host, err))?;
                     }
 
                     socket
@@ -93,29 +239,188 @@ impl ProxyConnector {
         stream: &mut Stream,
         proxy_host: &ProxyHost,
         target_host: &HostAddress,
-    ) -> Result<(), Error>
+        timeout: Option<Duration>,
+    ) -> Result<Option<HostAddress>, Error>
+    where
+        Stream: Unpin + Send + Sync + AsyncRead + AsyncWrite,
+    {
+        with_timeout(timeout, Self::handshake_without_timeout(stream, proxy_host, target_host))
+            .await
+    }
+
+    async fn handshake_without_timeout<Stream>(
+        stream: &mut Stream,
+        proxy_host: &ProxyHost,
+        target_host: &HostAddress,
+    ) -> Result<Option<HostAddress>, Error>
     where
         Stream: Unpin + Send + Sync + AsyncRead + AsyncWrite,
     {
         let mut handshake = ClientHandshake::new(stream);
-        match proxy_host {
+        let bind_addr = match proxy_host {
             ProxyHost::Socks4a { .. } => {
-                handshake.handshake_socks_v4_tcp_connect(target_host, None).await?;
+                Some(handshake.handshake_socks_v4_tcp_connect(target_host, None).await?)
             }
-            ProxyHost::Socks5 { username, password, .. } => {
+            ProxyHost::Socks5 { username, password, .. } => Some(
                 handshake
                     .handshake_socks_v5_tcp_connect(
                         target_host,
                         username.as_deref(),
                         password.as_deref(),
                     )
-                    .await?;
-            }
-            ProxyHost::HttpTunnel { user_agent, .. } => {
+                    .await?,
+            ),
+            ProxyHost::HttpTunnel { user_agent, .. }
+            | ProxyHost::HttpsTunnel { user_agent, .. } => {
                 handshake.handshake_http_tunnel(target_host, user_agent.as_deref()).await?;
+                None
             }
+        };
+
+        Ok(bind_addr)
+    }
+}
+
+async fn with_timeout<F, T>(timeout: Option<Duration>, fut: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    match timeout {
+        Some(t) => tokio::time::timeout(t, fut).await.map_err(|_| Error::Timeout)?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+    use crate::test_util::serve_socks5;
+
+    fn proxy_chain(length: usize) -> Arc<ProxyStrategy> {
+        let proxies = (0..length)
+            .map(|i| ProxyHost::Socks5 {
+                host: format!("127.99.0.{}", i + 1),
+                port: 3128,
+                username: None,
+                password: None,
+            })
+            .collect();
+        Arc::new(ProxyStrategy::Chained(proxies))
+    }
+
+    #[test]
+    fn max_chain_length() {
+        assert!(ProxyConnector::new(proxy_chain(DEFAULT_MAX_CHAIN_LENGTH)).is_ok());
+
+        match ProxyConnector::new(proxy_chain(DEFAULT_MAX_CHAIN_LENGTH + 1)) {
+            Err(Error::ProxyChainTooLong { length, max_length }) => {
+                assert_eq!(length, DEFAULT_MAX_CHAIN_LENGTH + 1);
+                assert_eq!(max_length, DEFAULT_MAX_CHAIN_LENGTH);
+            }
+            _ => panic!("over-length proxy chain should be rejected"),
         }
 
-        Ok(())
+        assert!(ProxyConnector::with_max_chain_length(proxy_chain(3), 3).is_ok());
+        assert!(ProxyConnector::with_max_chain_length(proxy_chain(4), 3).is_err());
+    }
+
+    #[tokio::test]
+    async fn bypass_proxy_for_no_proxy_destinations() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = HostAddress::from(listener.local_addr().unwrap());
+
+        // no proxy server is listening on the chain
+        let connector =
+            ProxyConnector::new(proxy_chain(1)).unwrap().with_no_proxy(NoProxy::parse("127.0.0.1"));
+        let stream = connector.connect(&destination).await.unwrap();
+        assert!(stream.is_direct());
+    }
+
+    #[tokio::test]
+    async fn send_hop_count_to_socks5_proxy_servers_only() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accept = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let proxy_host = ProxyHost::HttpTunnel {
+            host: "127.0.0.1".to_owned(),
+            port,
+            user_agent: None,
+            username: None,
+            password: None,
+        };
+        let policy = Policy::default();
+        let mut socket = HopCount::new(1)
+            .scope(ProxyConnector::connect_proxy_server(&proxy_host, &policy, None))
+            .await
+            .unwrap();
+
+        // the first bytes the HTTP proxy server receives belong to the request
+        socket.write_all(b"PING").await.unwrap();
+        assert_eq!(&accept.await.unwrap(), b"PING");
+    }
+
+    #[tokio::test]
+    async fn report_failed_hop_of_chain() {
+        let first = serve_socks5().await;
+
+        // the second hop accepts connections but is not a proxy server
+        let second = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let second_addr = second.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = second.accept().await.unwrap();
+            drop(stream);
+        });
+
+        let chain = vec![first, socks5(second_addr), socks5(([192, 0, 2, 1], 1080).into())];
+        match connect_chain(chain).await {
+            Err(Error::ChainError { hop_index, hop, .. }) => {
+                assert_eq!(hop_index, 1);
+                assert_eq!(*hop, socks5(second_addr));
+            }
+            _ => panic!("failed hop should be reported"),
+        }
+    }
+
+    #[tokio::test]
+    async fn report_unreachable_hop_of_chain() {
+        let first = serve_socks5().await;
+
+        // nothing listens on the second hop, the first hop fails to reach it
+        let second_addr =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap().local_addr().unwrap();
+
+        let chain = vec![first, socks5(second_addr), socks5(([192, 0, 2, 1], 1080).into())];
+        match connect_chain(chain).await {
+            Err(Error::ChainError { hop_index, hop, .. }) => {
+                assert_eq!(hop_index, 1);
+                assert_eq!(*hop, socks5(second_addr));
+            }
+            _ => panic!("unreachable hop should be reported"),
+        }
+    }
+
+    fn socks5(addr: SocketAddr) -> ProxyHost {
+        ProxyHost::Socks5 {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            username: None,
+            password: None,
+        }
+    }
+
+    async fn connect_chain(chain: Vec<ProxyHost>) -> Result<ProxyStream, Error> {
+        let connector = ProxyConnector::new(Arc::new(ProxyStrategy::Chained(chain))).unwrap();
+        let destination = HostAddress::from(SocketAddr::from(([192, 0, 2, 2], 80)));
+        connector.connect(&destination).await
     }
 }
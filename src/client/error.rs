@@ -1,6 +1,9 @@
 use snafu::Snafu;
 
-use crate::{client::handshake, common::HostAddress};
+use crate::{
+    client::handshake,
+    common::{HostAddress, ProxyHost},
+};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
@@ -20,12 +23,27 @@ pub enum Error {
     ))]
     GetLocalAddress { source: std::io::Error },
 
+    #[snafu(display(
+        "Could not get the remote address that this socket is connected to, error: {}",
+        source
+    ))]
+    GetPeerAddress { source: std::io::Error },
+
     #[snafu(display("Could not connect proxy server, error: {}", source))]
     ConnectProxyServer { source: std::io::Error },
 
+    #[snafu(display("Could not connect remote host {}, error: {}", addr, source))]
+    ConnectRemoteHost { addr: HostAddress, source: std::io::Error },
+
     #[snafu(display("Could not connect UDP socket {}, error: {}", addr, source))]
     ConnectUdpSocket { addr: HostAddress, source: std::io::Error },
 
+    #[snafu(display("Invalid server name of proxy server: {server_name}"))]
+    InvalidServerName { server_name: String },
+
+    #[snafu(display("Could not initialize TLS stream with proxy server, error: {source}"))]
+    InitializeTlsStream { source: std::io::Error },
+
     #[snafu(display("Connection timed out"))]
     Timeout,
 
@@ -41,6 +59,22 @@ pub enum Error {
     #[snafu(display("Remote host does not provide proxy service"))]
     NoProxyServiceProvided,
 
+    #[snafu(display(
+        "Proxy server {} at hop {} of proxy chain failed, error: {}",
+        hop,
+        hop_index + 1,
+        source
+    ))]
+    ChainError {
+        /// Zero-based index of the failed proxy server in the chain
+        hop_index: usize,
+        hop: Box<ProxyHost>,
+        source: Box<Error>,
+    },
+
+    #[snafu(display("Proxy chain is too long, length: {length}, max length: {max_length}"))]
+    ProxyChainTooLong { length: usize, max_length: usize },
+
     #[snafu(display("Datagram endpoint is closed"))]
     DatagramClosed,
 
@@ -51,6 +85,51 @@ pub enum Error {
     SerializeDatagram { source: std::io::Error },
 }
 
+impl Error {
+    /// Returns whether a proxy server requires authentication, or rejects the
+    /// credentials provided.
+    #[inline]
+    #[must_use]
+    pub fn is_authentication_failure(&self) -> bool {
+        match self {
+            Self::Handshake { source } => source.is_authentication_failure(),
+            Self::ChainError { source, .. } => source.is_authentication_failure(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether connecting a proxy server fails, times out or the
+    /// connection breaks, which may succeed if attempted again.
+    #[inline]
+    #[must_use]
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Self::ConnectProxyServer { .. }
+            | Self::ConnectRemoteHost { .. }
+            | Self::InitializeTlsStream { .. }
+            | Self::Timeout => true,
+            Self::Handshake { source } => source.is_connection_error(),
+            Self::ChainError { source, .. } => source.is_connection_error(),
+            _ => false,
+        }
+    }
+
+    /// Returns the zero-based index of the proxy server failed in a proxy
+    /// chain, `None` if this is not a proxy chain error.
+    #[inline]
+    #[must_use]
+    pub const fn chain_hop_index(&self) -> Option<usize> {
+        match self {
+            Self::ChainError { hop_index, .. } => Some(*hop_index),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn chain(hop_index: usize, hop: &ProxyHost, source: Self) -> Self {
+        Self::ChainError { hop_index, hop: Box::new(hop.clone()), source: Box::new(source) }
+    }
+}
+
 impl From<handshake::Error> for Error {
     fn from(source: handshake::Error) -> Self { Self::Handshake { source } }
 }
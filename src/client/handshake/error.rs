@@ -36,6 +36,12 @@ pub enum Error {
     ))]
     AccessDenied { user_name: Vec<u8>, password: Vec<u8> },
 
+    #[snafu(display("Proxy server requires authentication"))]
+    ProxyAuthenticationRequired,
+
+    #[snafu(display("Proxy server accepts none of the authentication methods offered"))]
+    NoAcceptableAuthMethod,
+
     #[snafu(display("Unsupported SOCKS method: {}", method))]
     UnsupportedSocksMethod { method: SocksV5Method },
 
@@ -60,3 +66,29 @@ pub enum Error {
     #[snafu(display("Could not build HTTP request, error: {}", source))]
     BuildHttpRequest { source: std::fmt::Error },
 }
+
+impl Error {
+    /// Returns whether the proxy server requires authentication, or rejects
+    /// the credentials provided.
+    #[inline]
+    #[must_use]
+    pub const fn is_authentication_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::ProxyAuthenticationRequired
+                | Self::AccessDenied { .. }
+                | Self::NoAcceptableAuthMethod
+        )
+    }
+
+    /// Returns whether the connection breaks during the handshake, rather than
+    /// the proxy server refusing it.
+    #[inline]
+    #[must_use]
+    pub const fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadStream { .. } | Self::WriteStream { .. } | Self::ShutdownStream { .. }
+        )
+    }
+}
@@ -59,6 +59,7 @@ where
 
         match msg.status_code {
             200 => Ok(()),
+            407 => Err(Error::ProxyAuthenticationRequired),
             401..=404 => Err(Error::HostUnreachable),
             _ => Err(Error::HostUnreachable),
         }
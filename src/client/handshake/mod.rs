@@ -7,9 +7,12 @@ use snafu::ResultExt;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 pub use self::error::Error;
+use crate::protocol::socks::v5::Method as SocksV5Method;
 
 pub struct ClientHandshake<Stream> {
     stream: Stream,
+    socks_v5_methods: Option<Vec<SocksV5Method>>,
+    socks_v5_method: Option<SocksV5Method>,
 }
 
 impl<Stream> ClientHandshake<Stream>
@@ -17,7 +20,24 @@ where
     Stream: Unpin + Send + Sync + AsyncRead + AsyncWrite,
 {
     #[inline]
-    pub fn new(stream: Stream) -> Self { Self { stream } }
+    pub fn new(stream: Stream) -> Self {
+        Self { stream, socks_v5_methods: None, socks_v5_method: None }
+    }
+
+    /// Offers `methods` to SOCKS5 servers instead of the method matching the
+    /// credentials provided.
+    #[must_use]
+    #[inline]
+    pub fn with_socks_v5_methods(mut self, methods: Vec<SocksV5Method>) -> Self {
+        self.socks_v5_methods = Some(methods);
+        self
+    }
+
+    /// Returns the method selected by the SOCKS5 server, `None` before the
+    /// SOCKS5 handshake.
+    #[must_use]
+    #[inline]
+    pub const fn socks_v5_method(&self) -> Option<SocksV5Method> { self.socks_v5_method }
 
     #[allow(dead_code)]
     #[inline]
@@ -19,21 +19,22 @@ where
         command: Command,
         destination_socket: &HostAddress,
         id: Option<&[u8]>,
-    ) -> Result<(), Error> {
+    ) -> Result<HostAddress, Error> {
         let id = match id {
             Some(id) => id.to_vec(),
             None => vec![],
         };
 
         let destination_socket = Address::from(destination_socket.clone());
-        let req = Request { command, destination_socket, id: id.clone() };
+        let req = Request::new(command, destination_socket, id.clone())
+            .map_err(|_| Error::InvalidSocks4aId { id: id.clone() })?;
 
         let _ = self.stream.write(&req.into_bytes()).await.context(error::WriteStreamSnafu)?;
 
         let reply =
             Reply::from_reader(&mut self.stream).await.context(error::ParseSocks4ReplySnafu)?;
         match reply.reply {
-            ReplyField::Granted => Ok(()),
+            ReplyField::Granted => Ok(HostAddress::from(reply.destination_socket)),
             ReplyField::Rejected => Err(Error::ProxyRejected),
             ReplyField::Unreachable => Err(Error::HostUnreachable),
             ReplyField::InvalidId => Err(Error::InvalidSocks4aId { id }),
@@ -45,7 +46,7 @@ where
         &mut self,
         destination_socket: &HostAddress,
         id: Option<&[u8]>,
-    ) -> Result<(), Error> {
+    ) -> Result<HostAddress, Error> {
         self.handshake_socks_v4(Command::TcpConnect, destination_socket, id).await
     }
 
@@ -54,7 +55,7 @@ where
         &mut self,
         destination_socket: &HostAddress,
         id: Option<&[u8]>,
-    ) -> Result<(), Error> {
+    ) -> Result<HostAddress, Error> {
         self.handshake_socks_v4(Command::TcpBind, destination_socket, id).await
     }
 }
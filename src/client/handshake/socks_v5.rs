@@ -18,6 +18,8 @@ impl<Stream> ClientHandshake<Stream>
 where
     Stream: Unpin + Send + Sync + AsyncRead + AsyncWrite,
 {
+    /// Offers the methods set by [`Self::with_socks_v5_methods`] to the
+    /// server, or the method matching credentials provided.
     async fn handshake_socks_v5(
         &mut self,
         command: Command,
@@ -27,27 +29,35 @@ where
     ) -> Result<HostAddress, Error> {
         use tokio::io::AsyncWriteExt;
 
-        let method = if user_name.is_some() && password.is_some() {
-            Method::UsernamePassword
-        } else {
-            Method::NoAuthentication
+        let methods = match self.socks_v5_methods.clone() {
+            Some(methods) => methods,
+            None if user_name.is_some() && password.is_some() => vec![Method::UsernamePassword],
+            None => vec![Method::NoAuthentication],
         };
 
-        let handshake_request = HandshakeRequest::new(vec![method]);
+        let handshake_request = HandshakeRequest::new(methods.clone());
         self.stream.write(&handshake_request.to_bytes()).await.context(error::WriteStreamSnafu)?;
 
         let handshake_reply = HandshakeReply::from_reader(&mut self.stream)
             .await
             .context(error::ParseSocks5ReplySnafu)?;
 
-        if handshake_reply.method != method {
+        let method = handshake_reply.method;
+        if method == Method::NotAcceptable {
+            self.stream.shutdown().await.context(error::ShutdownStreamSnafu)?;
+            return Err(Error::NoAcceptableAuthMethod);
+        }
+        if !methods.contains(&method) {
             return Err(Error::UnsupportedSocksMethod { method });
         }
+        self.socks_v5_method = Some(method);
 
         if method == Method::UsernamePassword {
-            let user_name = user_name.expect("user name is some; qed").as_bytes().to_vec();
-
-            let password = password.expect("password is some; qed").as_bytes().to_vec();
+            let (Some(user_name), Some(password)) = (user_name, password) else {
+                return Err(Error::UnsupportedSocksMethod { method });
+            };
+            let user_name = user_name.as_bytes().to_vec();
+            let password = password.as_bytes().to_vec();
 
             let req = UserPasswordHandshakeRequest {
                 version: UserPasswordVersion::V1,
@@ -61,6 +71,8 @@ where
             if reply.status != UserPasswordStatus::Success {
                 return Err(Error::AccessDenied { user_name, password });
             }
+        } else if method != Method::NoAuthentication {
+            return Err(Error::UnsupportedSocksMethod { method });
         }
 
         let destination_socket = Address::from(destination_socket.clone());
@@ -68,6 +80,10 @@ where
 
         let _ = self.stream.write(&req.into_bytes()).await.context(error::WriteStreamSnafu)?;
 
+        self.read_socks_v5_reply().await
+    }
+
+    async fn read_socks_v5_reply(&mut self) -> Result<HostAddress, Error> {
         let reply =
             Reply::from_reader(&mut self.stream).await.context(error::ParseSocks5ReplySnafu)?;
         if reply.reply != ReplyField::Success {
@@ -98,6 +114,11 @@ where
             .await
     }
 
+    /// Requests the server to listen for `destination_socket`, returns the
+    /// address bound by the server from the first reply.
+    ///
+    /// The address is meant to be passed to the peer, then
+    /// [`Self::accept_socks_v5_tcp_bind`] waits for the peer to connect.
     #[inline]
     pub async fn handshake_socks_v5_tcp_bind(
         &mut self,
@@ -107,4 +128,130 @@ where
     ) -> Result<HostAddress, Error> {
         self.handshake_socks_v5(Command::TcpBind, destination_socket, user_name, password).await
     }
+
+    /// Waits for the peer to connect to the address bound by
+    /// [`Self::handshake_socks_v5_tcp_bind`], returns the address of the peer
+    /// from the second reply.
+    #[inline]
+    pub async fn accept_socks_v5_tcp_bind(&mut self) -> Result<HostAddress, Error> {
+        self.read_socks_v5_reply().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+    };
+
+    use crate::{
+        authentication::{AuthenticationManager, NoopGssapi},
+        client::handshake::{ClientHandshake, Error},
+        common::HostAddress,
+        protocol::socks::{
+            v5::{Command, Method, Reply, Request},
+            Address,
+        },
+        test_util::{serve_socks5_with, socks5_service, unfiltered_transport},
+    };
+
+    #[tokio::test]
+    async fn offer_methods() {
+        let remote = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let destination = HostAddress::from(remote.local_addr().unwrap());
+
+        // the server supports both GSSAPI and username/password, and prefers GSSAPI
+        let mut authentication_manager = AuthenticationManager::new();
+        authentication_manager.add_user(b"alice".to_vec(), b"secret".to_vec());
+        authentication_manager.set_gssapi(Arc::new(NoopGssapi));
+        let proxy =
+            serve_socks5_with(socks5_service(unfiltered_transport(), authentication_manager)).await;
+        let proxy_addr = proxy.host_address().to_string();
+
+        let mut handshake = ClientHandshake::new(TcpStream::connect(&proxy_addr).await.unwrap())
+            .with_socks_v5_methods(vec![Method::NoAuthentication, Method::UsernamePassword]);
+        let result = handshake
+            .handshake_socks_v5_tcp_connect(&destination, Some("alice"), Some("secret"))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(handshake.socks_v5_method(), Some(Method::UsernamePassword));
+
+        let mut handshake = ClientHandshake::new(TcpStream::connect(&proxy_addr).await.unwrap())
+            .with_socks_v5_methods(vec![Method::GSSAPI, Method::UsernamePassword]);
+        let result = handshake
+            .handshake_socks_v5_tcp_connect(&destination, Some("alice"), Some("secret"))
+            .await;
+        // the server selects GSSAPI, which the client does not carry out
+        assert!(matches!(result, Err(Error::UnsupportedSocksMethod { method: Method::GSSAPI })));
+        assert_eq!(handshake.socks_v5_method(), Some(Method::GSSAPI));
+    }
+
+    #[tokio::test]
+    async fn tcp_bind() {
+        let bind_socket = SocketAddr::from((Ipv4Addr::new(198, 51, 100, 1), 40000));
+        let peer_socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2), 50000));
+        let destination = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2), 0)));
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (peer_tx, peer_rx) = oneshot::channel();
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&[0x05, Method::NoAuthentication.into()]).await.unwrap();
+
+            let request = Request::from_reader(&mut server).await.unwrap();
+            let reply = Reply::success(Address::from(bind_socket));
+            server.write_all(&reply.into_bytes()).await.unwrap();
+
+            // the peer connects once the client has got the address bound
+            peer_rx.await.unwrap();
+            let reply = Reply::success(Address::from(peer_socket));
+            server.write_all(&reply.into_bytes()).await.unwrap();
+            request
+        });
+
+        let mut handshake = ClientHandshake::new(client);
+        let bound = handshake.handshake_socks_v5_tcp_bind(&destination, None, None).await.unwrap();
+        assert_eq!(bound, HostAddress::from(bind_socket));
+        peer_tx.send(()).unwrap();
+        let peer = handshake.accept_socks_v5_tcp_bind().await.unwrap();
+        assert_eq!(peer, HostAddress::from(peer_socket));
+
+        let request = server.await.unwrap();
+        assert_eq!(request.command, Command::TcpBind);
+        assert_eq!(request.destination_socket.as_ref(), &destination);
+    }
+
+    #[tokio::test]
+    async fn no_acceptable_method() {
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [0x05, 0x01, 0x00]);
+            stream.write_all(&[0x05, 0xff]).await.unwrap();
+
+            // the client aborts instead of sending a request
+            let mut rest = Vec::new();
+            let _n = stream.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        let destination = HostAddress::new("192.0.2.1", 80);
+        let mut handshake = ClientHandshake::new(TcpStream::connect(proxy_addr).await.unwrap());
+        let result = handshake.handshake_socks_v5_tcp_connect(&destination, None, None).await;
+        let Err(err) = result else { panic!("handshake should fail") };
+        assert!(matches!(err, Error::NoAcceptableAuthMethod));
+        assert!(err.is_authentication_failure());
+        assert!(server.await.unwrap().is_empty());
+    }
 }
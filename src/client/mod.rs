@@ -4,14 +4,18 @@ mod connector;
 pub mod error;
 mod handshake;
 mod listener;
+mod no_proxy;
+mod socket;
 mod stream;
 
 pub use self::{
-    connector::ProxyConnector,
+    connector::{ProxyConnector, DEFAULT_MAX_CHAIN_LENGTH},
     // FIXME: uncomment this
     // datagram::{ProxyDatagram, Socks5Datagram},
     error::Error,
     handshake::ClientHandshake,
     listener::{ProxyListener, Socks5Listener},
+    no_proxy::NoProxy,
+    socket::ProxySocket,
     stream::ProxyStream,
 };
@@ -0,0 +1,159 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+
+use crate::common::HostAddress;
+
+/// Destinations which bypass proxy servers, following the `NO_PROXY`
+/// convention.
+///
+/// Entries are separated by commas or whitespaces, each of them is one of
+/// - `*`, which matches all destinations,
+/// - a CIDR range like `10.0.0.0/8` or `fe80::/10`,
+/// - an IP address like `127.0.0.1` or `::1`,
+/// - a domain name like `example.com` or `.example.com`, which matches the
+///   domain and all of its subdomains.
+///
+/// Ports in entries, e.g. `example.com:8080`, are ignored.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NoProxy {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Rule {
+    Any,
+    Network(IpNet),
+    Domain(String),
+}
+
+impl NoProxy {
+    /// Reads the list from `NO_PROXY`, or `no_proxy` if `NO_PROXY` is not set.
+    #[must_use]
+    pub fn from_env() -> Self {
+        std::env::var("NO_PROXY")
+            .or_else(|_| std::env::var("no_proxy"))
+            .map(|list| Self::parse(&list))
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn parse(list: &str) -> Self {
+        let rules = list
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|entry| !entry.is_empty())
+            .map(Rule::parse)
+            .collect();
+        Self { rules }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool { self.rules.is_empty() }
+
+    /// Returns whether connections to `host` should bypass proxy servers.
+    #[must_use]
+    pub fn matches(&self, host: &HostAddress) -> bool {
+        let ip = match host {
+            HostAddress::Socket(addr) => Some(addr.ip()),
+            HostAddress::DomainName(domain, _) => domain.parse().ok(),
+        };
+        let domain = match host {
+            HostAddress::Socket(_) => None,
+            HostAddress::DomainName(domain, _) => Some(domain.trim_end_matches('.')),
+        };
+
+        self.rules.iter().any(|rule| match rule {
+            Rule::Any => true,
+            Rule::Network(net) => ip.is_some_and(|ip| net.contains(&ip.to_canonical())),
+            Rule::Domain(suffix) => domain.is_some_and(|domain| {
+                domain.len() >= suffix.len()
+                    && domain[domain.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                    && (domain.len() == suffix.len()
+                        || domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.')
+            }),
+        })
+    }
+}
+
+impl FromStr for NoProxy {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> { Ok(Self::parse(s)) }
+}
+
+impl Rule {
+    fn parse(entry: &str) -> Self {
+        if entry == "*" {
+            return Self::Any;
+        }
+        if let Ok(net) = entry.parse::<IpNet>() {
+            return Self::Network(net);
+        }
+
+        let ip = entry
+            .parse::<IpAddr>()
+            .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+            .or_else(|_| entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>());
+        if let Ok(ip) = ip {
+            return Self::Network(IpNet::from(ip.to_canonical()));
+        }
+
+        let domain = entry.rsplit_once(':').map_or(entry, |(domain, _port)| domain);
+        let domain = domain.trim_start_matches("*.").trim_start_matches('.');
+        Self::Domain(domain.trim_end_matches('.').to_ascii_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::NoProxy;
+    use crate::common::HostAddress;
+
+    fn ip(ip: impl Into<std::net::IpAddr>) -> HostAddress {
+        HostAddress::from(SocketAddr::new(ip.into(), 443))
+    }
+
+    #[test]
+    fn matches() {
+        let no_proxy =
+            NoProxy::parse("localhost, .internal.example.com,example.org:8080 10.0.0.0/8,::1");
+
+        assert!(no_proxy.matches(&HostAddress::new("localhost", 80)));
+        assert!(no_proxy.matches(&HostAddress::new("LOCALHOST.", 80)));
+        assert!(no_proxy.matches(&HostAddress::new("internal.example.com", 80)));
+        assert!(no_proxy.matches(&HostAddress::new("api.internal.example.com", 80)));
+        assert!(no_proxy.matches(&HostAddress::new("www.example.org", 443)));
+        assert!(no_proxy.matches(&ip(Ipv4Addr::new(10, 1, 2, 3))));
+        assert!(no_proxy.matches(&HostAddress::DomainName("10.0.0.1".to_string(), 80)));
+        assert!(no_proxy.matches(&ip(Ipv6Addr::LOCALHOST)));
+        // IPv4-mapped IPv6 addresses are matched as IPv4 addresses
+        assert!(no_proxy.matches(&ip(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped())));
+    }
+
+    #[test]
+    fn does_not_match() {
+        let no_proxy = NoProxy::parse("localhost,.internal.example.com,10.0.0.0/8,::1");
+
+        assert!(!no_proxy.matches(&HostAddress::new("example.com", 80)));
+        assert!(!no_proxy.matches(&HostAddress::new("notlocalhost", 80)));
+        assert!(!no_proxy.matches(&HostAddress::new("external.example.com", 80)));
+        assert!(!no_proxy.matches(&ip(Ipv4Addr::new(11, 0, 0, 1))));
+        assert!(!no_proxy.matches(&ip(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1))));
+
+        assert!(NoProxy::parse("").is_empty());
+        assert!(!NoProxy::parse("").matches(&HostAddress::new("localhost", 80)));
+    }
+
+    #[test]
+    fn wildcard() {
+        let no_proxy = NoProxy::parse("*");
+        assert!(no_proxy.matches(&HostAddress::new("example.com", 80)));
+        assert!(no_proxy.matches(&ip(Ipv4Addr::new(192, 0, 2, 1))));
+    }
+}
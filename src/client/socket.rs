@@ -0,0 +1,141 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+};
+
+use snafu::ResultExt;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+
+use crate::{
+    client::{error, Error},
+    common::ProxyHost,
+};
+
+/// Connection to a proxy server, which is wrapped in TLS for each
+/// [`ProxyHost::HttpsTunnel`] on the way, so that it may be nested in a proxy
+/// chain.
+#[derive(Debug)]
+pub enum ProxySocket {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<ProxySocket>>),
+}
+
+impl ProxySocket {
+    /// Completes the TLS handshake with `proxy_host` if it is a
+    /// [`ProxyHost::HttpsTunnel`], certificates are verified with `tls_config`
+    /// or the bundled Mozilla root certificates if it is `None`. Other proxy
+    /// servers are returned as is.
+    pub async fn start_tls(
+        self,
+        proxy_host: &ProxyHost,
+        tls_config: Option<Arc<rustls::ClientConfig>>,
+    ) -> Result<Self, Error> {
+        let ProxyHost::HttpsTunnel { host, sni, .. } = proxy_host else {
+            return Ok(self);
+        };
+
+        let server_name = sni.as_deref().unwrap_or(host);
+        let server_name = rustls_pki_types::ServerName::try_from(server_name)
+            .map_err(|_| Error::InvalidServerName { server_name: server_name.to_owned() })?
+            .to_owned();
+        let tls_config = tls_config.unwrap_or_else(default_tls_config);
+        let stream = TlsConnector::from(tls_config)
+            .connect(server_name, self)
+            .await
+            .context(error::InitializeTlsStreamSnafu)?;
+        Ok(Self::Tls(Box::new(stream)))
+    }
+
+    /// Returns the TCP connection to the first proxy server.
+    #[must_use]
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Self::Tcp(stream) => stream,
+            Self::Tls(stream) => stream.get_ref().0.tcp_stream(),
+        }
+    }
+
+    /// Returns the TCP connection to the first proxy server, writing to it
+    /// directly breaks TLS.
+    pub fn tcp_stream_mut(&mut self) -> &mut TcpStream {
+        match self {
+            Self::Tcp(stream) => stream,
+            Self::Tls(stream) => stream.get_mut().0.tcp_stream_mut(),
+        }
+    }
+
+    /// Returns the TCP connection if no TLS is involved.
+    #[must_use]
+    pub fn into_tcp_stream(self) -> Option<TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Tls(_) => None,
+        }
+    }
+}
+
+impl From<TcpStream> for ProxySocket {
+    fn from(stream: TcpStream) -> Self { Self::Tcp(stream) }
+}
+
+impl AsyncRead for ProxySocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxySocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+fn default_tls_config() -> Arc<rustls::ClientConfig> {
+    static DEFAULT: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+
+    DEFAULT
+        .get_or_init(|| {
+            let mut root_store = rustls::RootCertStore::empty();
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            Arc::new(
+                rustls::ClientConfig::builder()
+                    .with_root_certificates(root_store)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
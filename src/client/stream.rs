@@ -1,22 +1,40 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
+use snafu::ResultExt;
 use tokio::net::TcpStream;
 
 use crate::{
-    client::{Error, ProxyConnector},
+    client::{error, Error, ProxyConnector, ProxySocket},
     common::{HostAddress, ProxyHost, ProxyStrategy},
 };
 
 #[derive(Debug)]
 pub struct ProxyStream {
-    socket: TcpStream,
+    socket: ProxySocket,
     strategy: Arc<ProxyStrategy>,
+    direct: bool,
+    bind_addr: Option<HostAddress>,
 }
 
 impl ProxyStream {
     #[inline]
-    pub fn from_raw(socket: TcpStream, strategy: Arc<ProxyStrategy>) -> Self {
-        Self { socket, strategy }
+    pub fn from_raw(socket: ProxySocket, strategy: Arc<ProxyStrategy>) -> Self {
+        Self { socket, strategy, direct: false, bind_addr: None }
+    }
+
+    /// Sets the bind address reported by the last proxy server.
+    #[inline]
+    #[must_use]
+    pub(crate) fn with_bind_addr(mut self, bind_addr: Option<HostAddress>) -> Self {
+        self.bind_addr = bind_addr;
+        self
+    }
+
+    /// Creates a stream connected to the destination directly, bypassing
+    /// `strategy`.
+    #[inline]
+    pub(crate) fn direct(socket: TcpStream, strategy: Arc<ProxyStrategy>) -> Self {
+        Self { socket: ProxySocket::Tcp(socket), strategy, direct: true, bind_addr: None }
     }
 
     #[inline]
@@ -37,17 +55,202 @@ impl ProxyStream {
         ProxyConnector::new(strategy)?.connect(host).await
     }
 
+    /// Returns the connection, which is wrapped in TLS if there is a
+    /// [`ProxyHost::HttpsTunnel`] in the proxy strategy.
     #[inline]
-    pub fn into_inner(self) -> TcpStream { self.socket }
+    pub fn into_inner(self) -> ProxySocket { self.socket }
 
     #[inline]
     pub fn proxy_strategy(&self) -> &ProxyStrategy { &self.strategy }
-}
 
-impl AsMut<TcpStream> for ProxyStream {
-    fn as_mut(&mut self) -> &mut TcpStream { &mut self.socket }
+    /// Returns the local address of the connection to the first proxy server,
+    /// or to the destination if it is connected directly.
+    #[inline]
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.tcp_stream().local_addr().context(error::GetLocalAddressSnafu)
+    }
+
+    /// Returns the address of the first proxy server, or of the destination if
+    /// it is connected directly.
+    #[inline]
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.tcp_stream().peer_addr().context(error::GetPeerAddressSnafu)
+    }
+
+    /// Returns whether the destination is connected directly because it is
+    /// listed in [`NoProxy`](crate::client::NoProxy).
+    #[inline]
+    #[must_use]
+    pub const fn is_direct(&self) -> bool { self.direct }
+
+    /// Returns the bind address reported by the last proxy server in reply to
+    /// the connect request, i.e. the address which the proxy server connects
+    /// the destination from, `None` if it is connected directly or through an
+    /// HTTP tunnel which does not report it.
+    #[inline]
+    #[must_use]
+    pub const fn bind_addr(&self) -> Option<&HostAddress> { self.bind_addr.as_ref() }
 }
 
 impl AsRef<TcpStream> for ProxyStream {
-    fn as_ref(&self) -> &TcpStream { &self.socket }
+    fn as_ref(&self) -> &TcpStream { self.socket.tcp_stream() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::{rustls, TlsAcceptor};
+
+    use super::ProxyStream;
+    use crate::{
+        authentication::AuthenticationManager,
+        client::{Error, ProxyConnector},
+        common::{HostAddress, ProxyHost, ProxyStrategy},
+        test_util::{socks5_service, unfiltered_transport},
+    };
+
+    #[tokio::test]
+    async fn addresses_of_proxy_stream() {
+        let remote = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let (accepted_tx, accepted_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let service = socks5_service(unfiltered_transport(), AuthenticationManager::new());
+            let (stream, peer_addr) = proxy.accept().await.unwrap();
+            let _unused = accepted_tx.send(peer_addr);
+            let _unused = service.dispatch(stream, peer_addr).await;
+        });
+
+        let proxy_host = ProxyHost::Socks5 {
+            host: proxy_addr.ip().to_string(),
+            port: proxy_addr.port(),
+            username: None,
+            password: None,
+        };
+        let stream = ProxyStream::connect_with_proxy(&proxy_host, &HostAddress::from(remote_addr))
+            .await
+            .unwrap();
+        let _accepted = remote.accept().await.unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap(), proxy_addr);
+        assert_eq!(stream.local_addr().unwrap(), accepted_rx.await.unwrap());
+    }
+
+    const CA_CERT: &[u8] = include_bytes!("../checker/prober/testdata/ca.pem");
+    const SERVER_CERT: &[u8] = include_bytes!("../checker/prober/testdata/localhost.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../checker/prober/testdata/localhost.key");
+
+    // terminates TLS, accepts any CONNECT request and echoes the tunnel
+    async fn serve_https_tunnel() -> SocketAddr {
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from_pem_slice(SERVER_CERT).unwrap()],
+                PrivateKeyDer::from_pem_slice(SERVER_KEY).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(mut stream) = acceptor.accept(stream).await else { continue };
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                assert!(request.starts_with(b"CONNECT 192.0.2.1:80 "));
+                stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await.unwrap();
+
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let _unused = tokio::io::copy(&mut reader, &mut writer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn connect_https_tunnel() {
+        let proxy_addr = serve_https_tunnel().await;
+        let destination = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        let https_tunnel = |host: &str, sni: Option<&str>| {
+            Arc::new(ProxyStrategy::Single(ProxyHost::HttpsTunnel {
+                host: host.to_owned(),
+                port: proxy_addr.port(),
+                user_agent: None,
+                username: None,
+                password: None,
+                sni: sni.map(ToOwned::to_owned),
+            }))
+        };
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add(CertificateDer::from_pem_slice(CA_CERT).unwrap()).unwrap();
+
+        // the certificate is issued for `localhost`, which is sent as SNI instead of
+        // the IP
+        for strategy in
+            [https_tunnel("localhost", None), https_tunnel("127.0.0.1", Some("localhost"))]
+        {
+            let connector =
+                ProxyConnector::new(strategy).unwrap().with_root_store(root_store.clone());
+            let stream = connector.connect(&destination).await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), proxy_addr);
+
+            let mut socket = stream.into_inner();
+            assert!(socket.tcp_stream().peer_addr().is_ok());
+            socket.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+
+        // the private CA is not trusted by the bundled root certificates
+        let connector = ProxyConnector::new(https_tunnel("localhost", None)).unwrap();
+        let err = connector.connect(&destination).await.unwrap_err();
+        assert!(matches!(err, Error::InitializeTlsStream { .. }), "{err}");
+    }
+
+    #[tokio::test]
+    async fn expose_bind_address() {
+        // replies to the connect request with a bind address of its own
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 198, 51, 100, 7, 0x10, 0x92]).await.unwrap();
+            let _unused = stream.read(&mut [0u8; 1]).await;
+        });
+
+        let proxy_host = ProxyHost::Socks5 {
+            host: proxy_addr.ip().to_string(),
+            port: proxy_addr.port(),
+            username: None,
+            password: None,
+        };
+        let destination = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        let stream = ProxyStream::connect_with_proxy(&proxy_host, &destination).await.unwrap();
+        assert_eq!(
+            stream.bind_addr(),
+            Some(&HostAddress::from(SocketAddr::from((Ipv4Addr::new(198, 51, 100, 7), 4242))))
+        );
+        assert!(!stream.is_direct());
+    }
 }
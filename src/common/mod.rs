@@ -1,8 +1,13 @@
 mod host_address;
+mod policy;
 mod proxy;
 pub mod utils;
 
 pub use self::{
     host_address::{HostAddress, HostAddressError},
-    proxy::{ProxyHost, ProxyHostError, ProxyStrategy},
+    policy::Policy,
+    proxy::{
+        IncompatibleChain, IncompatibleReason, ProxyHopInfo, ProxyHost, ProxyHostError, ProxyKind,
+        ProxyStrategy,
+    },
 };
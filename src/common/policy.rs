@@ -0,0 +1,208 @@
+use std::time::Duration;
+
+use futures::Future;
+
+/// Timeouts and retries of connections shared by the transport, the client and
+/// the checker, `None` means no timeout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Policy {
+    /// Timeout of establishing a TCP connection.
+    pub connect_timeout: Option<Duration>,
+
+    /// Timeout of a handshake with a proxy server or a client.
+    pub handshake_timeout: Option<Duration>,
+
+    /// Timeout of relaying nothing in either direction.
+    pub idle_timeout: Option<Duration>,
+
+    /// Number of retries after the first failed attempt.
+    pub retries: usize,
+
+    /// Delay before the first retry, doubled before each further retry.
+    pub backoff: Duration,
+
+    /// Upper bound of the doubled delay.
+    pub max_backoff: Duration,
+
+    /// Upper bound of a random delay added to each delay, so that clients
+    /// failed at the same time do not retry at the same time.
+    pub jitter: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: None,
+            handshake_timeout: None,
+            idle_timeout: None,
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+impl Policy {
+    /// Returns the delay before the retry following `attempt` failed
+    /// attempts, jitter excluded.
+    #[must_use]
+    pub fn backoff_delay(&self, attempt: usize) -> Duration {
+        let Some(shift) = attempt.checked_sub(1) else {
+            return Duration::ZERO;
+        };
+        u32::try_from(shift)
+            .ok()
+            .and_then(|shift| 1u32.checked_shl(shift))
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+
+    /// Same as [`Policy::backoff_delay`], with a random jitter added.
+    #[must_use]
+    pub fn delay(&self, attempt: usize) -> Duration {
+        use rand::Rng;
+
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+        self.backoff_delay(attempt).saturating_add(jitter)
+    }
+
+    /// Runs `f` until it succeeds or `retries` are exhausted, returns the
+    /// error of the last attempt.
+    pub async fn retry<F, Fut, T, E>(&self, mut f: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt >= self.retries => return Err(err),
+                Err(_) => {
+                    attempt += 1;
+                    tokio::time::sleep(self.delay(attempt)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use futures::FutureExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::Policy;
+    use crate::{
+        client::{self, ProxyConnector},
+        common::{HostAddress, ProxyHost, ProxyStrategy},
+        filter::SimpleFilter,
+        test_util::direct_transport,
+        transport::{self, Connect, Connector, TimeoutPhase, TokioResolver, Transport},
+    };
+
+    // counts attempts of connecting and never completes them
+    #[derive(Default)]
+    struct StalledConnector {
+        attempts: AtomicUsize,
+    }
+
+    impl Connector for StalledConnector {
+        type Error = transport::Error;
+        type Stream = TcpStream;
+
+        fn connect(&self, _host: &HostAddress) -> Connect<Self::Stream, Self::Error> {
+            let _unused = self.attempts.fetch_add(1, Ordering::SeqCst);
+            futures::future::pending().boxed()
+        }
+    }
+
+    #[test]
+    fn backoff_delay() {
+        let policy = Policy {
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(30),
+            jitter: Duration::from_millis(5),
+            ..Policy::default()
+        };
+        assert_eq!(policy.backoff_delay(0), Duration::ZERO);
+        assert_eq!(policy.backoff_delay(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff_delay(2), Duration::from_millis(20));
+        assert_eq!(policy.backoff_delay(3), Duration::from_millis(30));
+        assert_eq!(policy.backoff_delay(usize::MAX), Duration::from_millis(30));
+
+        let delay = policy.delay(2);
+        assert!((Duration::from_millis(20)..=Duration::from_millis(25)).contains(&delay));
+    }
+
+    #[tokio::test]
+    async fn apply_policy() {
+        let policy = Policy {
+            connect_timeout: Some(Duration::from_millis(50)),
+            handshake_timeout: Some(Duration::from_millis(50)),
+            idle_timeout: Some(Duration::from_millis(50)),
+            retries: 2,
+            backoff: Duration::from_millis(20),
+            ..Policy::default()
+        };
+
+        // connect timeout and retries of transport
+        let connector = Arc::new(StalledConnector::default());
+        let transport = Transport::with_connector(
+            Arc::new(TokioResolver::new()),
+            Arc::new(SimpleFilter::deny_list()),
+            connector.clone(),
+        )
+        .with_policy(policy);
+        let started = Instant::now();
+        let host = HostAddress::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+        let err = transport.connect(&host).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Connect));
+        assert_eq!(connector.attempts.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_millis(3 * 50 + 20 + 40));
+
+        // idle timeout of transport
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_client_peer, _) = listener.accept().await.unwrap();
+        let remote = TcpStream::connect(addr).await.unwrap();
+        let (_remote_peer, _) = listener.accept().await.unwrap();
+        let transport = direct_transport(SimpleFilter::deny_list()).with_policy(policy);
+        let err = transport.relay_bidirectional(client, remote, None).await.unwrap_err();
+        assert!(matches!(err, transport::Error::Timeout { phase: TimeoutPhase::Idle }));
+
+        // handshake timeout and retries of client, the proxy server never replies
+        let proxy_server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy_server.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok(Ok((socket, _))) =
+                tokio::time::timeout(Duration::from_millis(500), proxy_server.accept()).await
+            {
+                sockets.push(socket);
+            }
+            sockets.len()
+        });
+        let strategy = Arc::new(ProxyStrategy::Single(ProxyHost::Socks5 {
+            host: proxy_addr.ip().to_string(),
+            port: proxy_addr.port(),
+            username: None,
+            password: None,
+        }));
+        let connector = ProxyConnector::new(strategy).unwrap().with_policy(policy);
+        let destination = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        assert!(matches!(connector.connect(&destination).await, Err(client::Error::Timeout)));
+        assert_eq!(accepted.await.unwrap(), 3);
+    }
+}
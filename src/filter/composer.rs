@@ -3,27 +3,108 @@ use std::{
     sync::Arc,
 };
 
-use crate::filter::{FilterAction, HostFilter};
+use crate::{
+    common::HostAddress,
+    filter::{FilterAction, HostFilter},
+};
+
+/// How [`ComposerFilter`] combines actions of its filters.
+///
+/// An action of a filter is decisive if it differs from the
+/// [`HostFilter::default_action`] of the filter, i.e. a rule of the filter
+/// applies. Every action of a filter without default action is decisive.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum CombinePolicy {
+    /// The first decisive action wins and later filters are not consulted. If
+    /// no action is decisive, the destination is denied if any filter denies
+    /// it.
+    FirstMatch,
+
+    /// Allows only if every filter allows, filters after the first deny are
+    /// not consulted.
+    #[default]
+    AllMustAllow,
+
+    /// Denies if any filter denies. Filters are consulted until one decisively
+    /// denies, filters after it are not consulted; if no deny is decisive, the
+    /// first filter denying by default, e.g. an allow list without matching
+    /// rules, decides.
+    AnyMayDeny,
+}
 
+/// Combines an ordered list of filters per [`CombinePolicy`], e.g. to stack a
+/// CIDR filter, a pattern filter and a port filter.
 #[derive(Default)]
 pub struct ComposerFilter {
     filters: Vec<Arc<dyn HostFilter>>,
+    policy: CombinePolicy,
 }
 
 impl ComposerFilter {
+    #[deprecated(note = "use `ComposerFilter::with_policy` instead")]
     #[inline]
     #[must_use]
     pub fn new() -> Self { Self::default() }
 
     #[inline]
-    pub fn add_filter(&mut self, filter: Arc<dyn HostFilter>) { self.filters.push(filter); }
+    #[must_use]
+    pub fn with_policy(policy: CombinePolicy) -> Self { Self { filters: Vec::new(), policy } }
+
+    /// Appends `filter` which is consulted after filters pushed before.
+    #[inline]
+    pub fn push(&mut self, filter: Arc<dyn HostFilter>) { self.filters.push(filter); }
 
+    #[deprecated(note = "use `ComposerFilter::push` instead")]
     #[inline]
-    fn filter<F: FnMut(&Arc<dyn HostFilter>) -> bool>(&self, predictor: F) -> FilterAction {
-        if self.filters.iter().any(predictor) {
-            return FilterAction::Deny;
+    pub fn add_filter(&mut self, filter: Arc<dyn HostFilter>) { self.push(filter); }
+
+    #[inline]
+    #[must_use]
+    pub const fn policy(&self) -> CombinePolicy { self.policy }
+
+    /// Returns the combined action and the filter deciding it, `None` if no
+    /// filter decides, e.g. there are no filters.
+    fn decide<F>(&self, action_of: F) -> (FilterAction, Option<&Arc<dyn HostFilter>>)
+    where
+        F: Fn(&dyn HostFilter) -> FilterAction,
+    {
+        let is_decisive =
+            |filter: &Arc<dyn HostFilter>, action| filter.default_action() != Some(action);
+
+        match self.policy {
+            CombinePolicy::FirstMatch => {
+                let mut fallback = (FilterAction::Allow, None);
+                for filter in &self.filters {
+                    let action = action_of(filter.as_ref());
+                    if is_decisive(filter, action) {
+                        return (action, Some(filter));
+                    }
+                    if action == FilterAction::Deny && fallback.1.is_none() {
+                        fallback = (FilterAction::Deny, Some(filter));
+                    }
+                }
+                fallback
+            }
+            CombinePolicy::AllMustAllow => self
+                .filters
+                .iter()
+                .find(|filter| action_of(filter.as_ref()) == FilterAction::Deny)
+                .map_or((FilterAction::Allow, None), |filter| (FilterAction::Deny, Some(filter))),
+            CombinePolicy::AnyMayDeny => {
+                let mut fallback = (FilterAction::Allow, None);
+                for filter in &self.filters {
+                    if action_of(filter.as_ref()) == FilterAction::Deny {
+                        if is_decisive(filter, FilterAction::Deny) {
+                            return (FilterAction::Deny, Some(filter));
+                        }
+                        if fallback.1.is_none() {
+                            fallback = (FilterAction::Deny, Some(filter));
+                        }
+                    }
+                }
+                fallback
+            }
         }
-        FilterAction::Allow
     }
 
     #[inline]
@@ -34,37 +115,87 @@ impl ComposerFilter {
 impl HostFilter for ComposerFilter {
     #[inline]
     fn filter_port(&self, port: u16) -> FilterAction {
-        self.filter(|filter| filter.filter_port(port) == FilterAction::Deny)
+        self.decide(|filter| filter.filter_port(port)).0
     }
 
     #[inline]
     fn filter_hostname(&self, hostname: &str) -> FilterAction {
-        self.filter(|filter| filter.filter_hostname(hostname) == FilterAction::Deny)
+        self.decide(|filter| filter.filter_hostname(hostname)).0
     }
 
     #[inline]
     fn filter_address(&self, addr: &IpAddr) -> FilterAction {
-        self.filter(|filter| filter.filter_address(addr) == FilterAction::Deny)
+        self.decide(|filter| filter.filter_address(addr)).0
     }
 
     #[inline]
     fn filter_socket(&self, socket: &SocketAddr) -> FilterAction {
-        self.filter(|filter| filter.filter_socket(socket) == FilterAction::Deny)
+        self.decide(|filter| filter.filter_socket(socket)).0
     }
 
     #[inline]
     fn filter_host(&self, host: &str, port: u16) -> FilterAction {
-        self.filter(|filter| filter.filter_host(host, port) == FilterAction::Deny)
+        self.decide(|filter| filter.filter_host(host, port)).0
+    }
+
+    fn matched_rule(&self, addr: &HostAddress) -> Option<String> {
+        self.decide(|filter| filter.filter_host_address(addr)).1?.matched_rule(addr)
+    }
+
+    /// Every policy denies destinations no rule applies to if a filter denies
+    /// them by default, `None` if a filter has no default action.
+    fn default_action(&self) -> Option<FilterAction> {
+        self.filters.iter().try_fold(FilterAction::Allow, |action, filter| {
+            Some(match filter.default_action()? {
+                FilterAction::Deny => FilterAction::Deny,
+                FilterAction::Allow => action,
+            })
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::{IpAddr, SocketAddr};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
     use super::*;
     use crate::filter::{FilterMode, SimpleFilter};
 
+    // panics once consulted, for filters after the deciding one
+    struct Unreachable;
+
+    impl HostFilter for Unreachable {
+        fn filter_socket(&self, _socket: &SocketAddr) -> FilterAction { unreachable!() }
+
+        fn filter_host(&self, _host: &str, _port: u16) -> FilterAction { unreachable!() }
+
+        fn filter_hostname(&self, _hostname: &str) -> FilterAction { unreachable!() }
+
+        fn filter_address(&self, _addr: &IpAddr) -> FilterAction { unreachable!() }
+
+        fn filter_port(&self, _port: u16) -> FilterAction { unreachable!() }
+    }
+
+    // allows 192.0.2.0/24 and denies 192.0.2.1 in this order
+    fn conflicting(policy: CombinePolicy, short_circuit: bool) -> ComposerFilter {
+        let mut allow = SimpleFilter::allow_list();
+        allow.add_cidr("192.0.2.0/24".parse().unwrap());
+        let mut deny = SimpleFilter::deny_list();
+        deny.add_address(Ipv4Addr::new(192, 0, 2, 1).into());
+
+        let mut composer = ComposerFilter::with_policy(policy);
+        composer.push(Arc::new(allow));
+        composer.push(Arc::new(deny));
+        if short_circuit {
+            composer.push(Arc::new(Unreachable));
+        }
+        composer
+    }
+
+    fn action(filter: &ComposerFilter, addr: [u8; 4]) -> FilterAction {
+        filter.filter_address(&IpAddr::from(addr))
+    }
+
     #[test]
     fn construct() {
         let port = 10001;
@@ -81,9 +212,9 @@ mod tests {
             FilterMode::DenyList,
         );
 
-        let mut composer = ComposerFilter::new();
-        composer.add_filter(Arc::new(simple_filter));
-        composer.add_filter(Arc::new(ComposerFilter::new()));
+        let mut composer = ComposerFilter::with_policy(CombinePolicy::default());
+        composer.push(Arc::new(simple_filter));
+        composer.push(Arc::new(ComposerFilter::default()));
     }
 
     #[test]
@@ -100,8 +231,8 @@ mod tests {
         simple.add_hostname(hostname);
         simple.add_host(hostname, port);
 
-        let mut composer = ComposerFilter::new();
-        composer.add_filter(Arc::new(simple));
+        let mut composer = ComposerFilter::with_policy(CombinePolicy::default());
+        composer.push(Arc::new(simple));
 
         assert_eq!(composer.filter_port(port), FilterAction::Deny);
         assert_eq!(composer.filter_hostname(hostname), FilterAction::Deny);
@@ -111,4 +242,88 @@ mod tests {
 
         assert_eq!(composer.filter_port(port + 1), FilterAction::Allow);
     }
+
+    #[test]
+    fn first_match() {
+        // the allow list decides before the deny list
+        let composer = conflicting(CombinePolicy::FirstMatch, true);
+        assert_eq!(action(&composer, [192, 0, 2, 1]), FilterAction::Allow);
+        assert_eq!(action(&composer, [192, 0, 2, 2]), FilterAction::Allow);
+        let addr = HostAddress::from(SocketAddr::from(([192, 0, 2, 1], 80)));
+        assert_eq!(composer.matched_rule(&addr), Some("cidr 192.0.2.0/24".to_owned()));
+
+        // no filter is decisive, the allow list denies by default
+        let composer = conflicting(CombinePolicy::FirstMatch, false);
+        assert_eq!(action(&composer, [198, 51, 100, 1]), FilterAction::Deny);
+        let addr = HostAddress::from(SocketAddr::from(([198, 51, 100, 1], 80)));
+        assert_eq!(composer.matched_rule(&addr), Some("not in allow list".to_owned()));
+
+        assert_eq!(
+            ComposerFilter::with_policy(CombinePolicy::FirstMatch).filter_port(80),
+            FilterAction::Allow
+        );
+    }
+
+    #[test]
+    fn all_must_allow() {
+        let composer = conflicting(CombinePolicy::AllMustAllow, false);
+        assert_eq!(action(&composer, [192, 0, 2, 2]), FilterAction::Allow);
+        assert_eq!(action(&composer, [198, 51, 100, 1]), FilterAction::Deny);
+
+        let composer = conflicting(CombinePolicy::AllMustAllow, true);
+        assert_eq!(action(&composer, [192, 0, 2, 1]), FilterAction::Deny);
+        let addr = HostAddress::from(SocketAddr::from(([192, 0, 2, 1], 80)));
+        assert_eq!(composer.matched_rule(&addr), Some("address 192.0.2.1".to_owned()));
+    }
+
+    #[test]
+    fn any_may_deny() {
+        let composer = conflicting(CombinePolicy::AnyMayDeny, false);
+        assert_eq!(action(&composer, [192, 0, 2, 2]), FilterAction::Allow);
+        // the allow list denies without a matching rule
+        assert_eq!(action(&composer, [198, 51, 100, 1]), FilterAction::Deny);
+        let addr = HostAddress::from(SocketAddr::from(([198, 51, 100, 1], 80)));
+        assert_eq!(composer.matched_rule(&addr), Some("not in allow list".to_owned()));
+
+        let composer = conflicting(CombinePolicy::AnyMayDeny, true);
+        assert_eq!(action(&composer, [192, 0, 2, 1]), FilterAction::Deny);
+
+        // a decisive deny of a later filter is preferred to a deny by default
+        let mut deny = SimpleFilter::deny_list();
+        deny.add_address(Ipv4Addr::new(198, 51, 100, 1).into());
+        let mut composer = conflicting(CombinePolicy::AnyMayDeny, false);
+        composer.push(Arc::new(deny));
+        assert_eq!(composer.matched_rule(&addr), Some("address 198.51.100.1".to_owned()));
+    }
+
+    #[test]
+    fn default_action() {
+        for policy in
+            [CombinePolicy::FirstMatch, CombinePolicy::AllMustAllow, CombinePolicy::AnyMayDeny]
+        {
+            assert_eq!(
+                ComposerFilter::with_policy(policy).default_action(),
+                Some(FilterAction::Allow)
+            );
+
+            let mut composer = ComposerFilter::with_policy(policy);
+            composer.push(Arc::new(SimpleFilter::deny_list()));
+            assert_eq!(composer.default_action(), Some(FilterAction::Allow));
+
+            composer.push(Arc::new(SimpleFilter::allow_list()));
+            assert_eq!(composer.default_action(), Some(FilterAction::Deny));
+
+            composer.push(Arc::new(Unreachable));
+            assert_eq!(composer.default_action(), None);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_constructors() {
+        let mut composer = ComposerFilter::new();
+        assert_eq!(composer.policy(), CombinePolicy::AllMustAllow);
+        composer.add_filter(Arc::new(SimpleFilter::allow_list()));
+        assert_eq!(action(&composer, [192, 0, 2, 1]), FilterAction::Deny);
+    }
 }
@@ -0,0 +1,157 @@
+use std::{future::Future, net::SocketAddr};
+
+use tracing::Level;
+
+use crate::{
+    common::HostAddress,
+    filter::{FilterAction, HostFilter},
+    service::LogPrivacy,
+};
+
+/// Target of filter decision events, so that they can be routed separately,
+/// e.g. to a SIEM.
+pub const FILTER_EVENT_TARGET: &str = "tunelo::filter";
+
+tokio::task_local! {
+    static CURRENT: ClientIdentity;
+}
+
+/// Identity of the client on whose behalf connections are made by the current
+/// task.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ClientIdentity {
+    pub addr: SocketAddr,
+}
+
+impl ClientIdentity {
+    #[inline]
+    #[must_use]
+    pub const fn new(addr: SocketAddr) -> Self { Self { addr } }
+
+    /// Returns the client of the connection handled by the current task.
+    #[must_use]
+    pub fn current() -> Option<Self> { CURRENT.try_with(|client| *client).ok() }
+
+    /// Runs `fut` with `self` as the client of the current connection.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output { CURRENT.scope(self, fut).await }
+}
+
+/// Emits a structured `tracing` event with target [`FILTER_EVENT_TARGET`] for
+/// each decision of [`HostFilter`], including the action, the destination, the
+/// matched rule and the client. The destination is logged in the form of the
+/// [`LogPrivacy`] of the transport.
+///
+/// Only denies are emitted by default to limit the volume.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FilterEvents {
+    pub level: Level,
+    pub log_allowed: bool,
+}
+
+impl Default for FilterEvents {
+    fn default() -> Self { Self { level: Level::INFO, log_allowed: false } }
+}
+
+// levels of `tracing` events must be constant
+macro_rules! filter_event {
+    ($level:expr, $($fields:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::event!(target: FILTER_EVENT_TARGET, Level::ERROR, $($fields)*),
+            Level::WARN => tracing::event!(target: FILTER_EVENT_TARGET, Level::WARN, $($fields)*),
+            Level::INFO => tracing::event!(target: FILTER_EVENT_TARGET, Level::INFO, $($fields)*),
+            Level::DEBUG => tracing::event!(target: FILTER_EVENT_TARGET, Level::DEBUG, $($fields)*),
+            Level::TRACE => tracing::event!(target: FILTER_EVENT_TARGET, Level::TRACE, $($fields)*),
+        }
+    };
+}
+
+impl FilterEvents {
+    pub(crate) fn emit(
+        &self,
+        filter: &dyn HostFilter,
+        destination: &HostAddress,
+        action: FilterAction,
+        log_privacy: LogPrivacy,
+    ) {
+        let action = match action {
+            FilterAction::Deny => "deny",
+            FilterAction::Allow if self.log_allowed => "allow",
+            FilterAction::Allow => return,
+        };
+        let rule = filter.matched_rule(destination).unwrap_or_else(|| "-".to_owned());
+        let client = ClientIdentity::current()
+            .map_or_else(|| "-".to_owned(), |client| client.addr.to_string());
+
+        filter_event!(
+            self.level,
+            action,
+            destination = %log_privacy.anonymize(destination),
+            rule = %rule,
+            client = %client,
+            "Filter decision"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tracing::Level;
+
+    use super::{ClientIdentity, FilterEvents};
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        service::LogPrivacy,
+        test_util::{direct_transport, CapturedLogs},
+        transport::Error,
+    };
+
+    #[tokio::test]
+    async fn emit_deny_event() {
+        let (logs, _guard) = CapturedLogs::capture(Level::TRACE);
+
+        let denied = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80));
+        let transport = {
+            let mut filter = SimpleFilter::deny_list();
+            filter.add_socket(denied);
+            direct_transport(filter)
+                .with_filter_events(FilterEvents { level: Level::WARN, log_allowed: false })
+        };
+
+        let client = ClientIdentity::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)));
+        let err = client.scope(transport.connect(&HostAddress::from(denied))).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectForbiddenHosts { .. }));
+
+        let contents = logs.contents();
+        let events = filter_events(&contents);
+        assert_eq!(events.len(), 1, "{contents}");
+        for field in [
+            " WARN ",
+            "action=\"deny\"",
+            "destination=192.0.2.1:80",
+            "rule=socket 192.0.2.1:80",
+            "client=127.0.0.1:40000",
+        ] {
+            assert!(events[0].contains(field), "{field} is missing in {}", events[0]);
+        }
+
+        // allows are not emitted by default
+        let allowed = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2), 80)));
+        let _unused = transport.check_filter(&allowed);
+        assert_eq!(filter_events(&logs.contents()).len(), 1);
+
+        // destinations are logged in the form of log privacy
+        let transport = transport.with_log_privacy(LogPrivacy::DomainOnly);
+        let _unused = transport.check_filter(&HostAddress::from(denied));
+        let contents = logs.contents();
+        let events = filter_events(&contents);
+        assert_eq!(events.len(), 2, "{contents}");
+        assert!(events[1].contains("destination=192.0.2.0 "), "{}", events[1]);
+    }
+
+    fn filter_events(logs: &str) -> Vec<&str> {
+        logs.lines().filter(|line| line.contains("tunelo::filter")).collect()
+    }
+}
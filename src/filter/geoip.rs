@@ -0,0 +1,203 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use maxminddb::{geoip2, Reader};
+use snafu::{ResultExt, Snafu};
+
+use crate::{
+    common::HostAddress,
+    filter::{FilterAction, FilterMode, HostFilter},
+};
+
+#[derive(Debug, Snafu)]
+pub enum GeoIpError {
+    #[snafu(display("Could not open GeoIP database {}, error: {source}", file_path.display()))]
+    OpenDatabase { file_path: PathBuf, source: maxminddb::MaxMindDbError },
+}
+
+/// Filters IP addresses by their country in a MaxMind DB, e.g. GeoLite2
+/// Country, with ISO 3166-1 alpha-2 country codes like `US`.
+///
+/// Host names never match as they are not resolved yet, so that it is meant
+/// for addresses resolved, see [`Transport::with_resolved_filter`].
+/// Addresses without a country in the database never match either.
+///
+/// [`Transport::with_resolved_filter`]: crate::transport::Transport::with_resolved_filter
+#[derive(Clone, Debug)]
+pub struct GeoIpFilter {
+    reader: Arc<Reader<Vec<u8>>>,
+    countries: HashSet<String>,
+    mode: FilterMode,
+}
+
+impl GeoIpFilter {
+    pub fn open<P, I, S>(file_path: P, countries: I, mode: FilterMode) -> Result<Self, GeoIpError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let file_path = file_path.as_ref();
+        let reader = Reader::open_readfile(file_path)
+            .with_context(|_| OpenDatabaseSnafu { file_path: file_path.to_owned() })?;
+        let countries =
+            countries.into_iter().map(|country| country.as_ref().to_ascii_uppercase()).collect();
+        Ok(Self { reader: Arc::new(reader), countries, mode })
+    }
+
+    /// Denies addresses in `countries`.
+    pub fn deny_countries<P, I, S>(file_path: P, countries: I) -> Result<Self, GeoIpError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::open(file_path, countries, FilterMode::DenyList)
+    }
+
+    /// Allows only addresses in `countries`.
+    pub fn allow_countries<P, I, S>(file_path: P, countries: I) -> Result<Self, GeoIpError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::open(file_path, countries, FilterMode::AllowList)
+    }
+
+    /// Returns the country code of `addr` in the database.
+    #[must_use]
+    pub fn country(&self, addr: IpAddr) -> Option<String> {
+        let country = self.reader.lookup(addr.to_canonical()).ok()?.decode::<geoip2::Country>();
+        country.ok()??.country.iso_code.map(str::to_owned)
+    }
+
+    // country of `addr` which is listed
+    fn matched_country(&self, addr: IpAddr) -> Option<String> {
+        self.country(addr).filter(|country| self.countries.contains(country))
+    }
+
+    #[inline]
+    const fn filter(&self, matched: bool) -> FilterAction {
+        match (self.mode, matched) {
+            (FilterMode::DenyList, true) | (FilterMode::AllowList, false) => FilterAction::Deny,
+            (FilterMode::DenyList, false) | (FilterMode::AllowList, true) => FilterAction::Allow,
+        }
+    }
+}
+
+impl HostFilter for GeoIpFilter {
+    #[inline]
+    fn filter_port(&self, _port: u16) -> FilterAction { self.filter(false) }
+
+    #[inline]
+    fn filter_hostname(&self, _hostname: &str) -> FilterAction { self.filter(false) }
+
+    #[inline]
+    fn filter_address(&self, addr: &IpAddr) -> FilterAction {
+        self.filter(self.matched_country(*addr).is_some())
+    }
+
+    #[inline]
+    fn filter_socket(&self, socket: &SocketAddr) -> FilterAction {
+        self.filter_address(&socket.ip())
+    }
+
+    #[inline]
+    fn filter_host(&self, host: &str, _port: u16) -> FilterAction { self.filter_hostname(host) }
+
+    fn matched_rule(&self, addr: &HostAddress) -> Option<String> {
+        let matched = match addr {
+            HostAddress::Socket(socket) => self.matched_country(socket.ip()),
+            HostAddress::DomainName(..) => None,
+        };
+        match (self.mode, matched) {
+            (_, Some(country)) => Some(format!("country {country}")),
+            (FilterMode::AllowList, None) => Some("country not in allow list".to_owned()),
+            (FilterMode::DenyList, None) => None,
+        }
+    }
+
+    #[inline]
+    fn default_action(&self) -> Option<FilterAction> { Some(self.mode.default_action()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::GeoIpFilter;
+    use crate::{
+        common::HostAddress,
+        filter::{FilterAction, HostFilter, SimpleFilter},
+        transport::{Error, StaticResolver, Transport},
+    };
+
+    // maps 192.0.2.0/24 to `XA` and 198.51.100.0/24 to `XB`, generated by
+    // `dev-support/bin/generate-country-mmdb`
+    const DATABASE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/filter/testdata/country.mmdb");
+
+    #[test]
+    fn deny_countries() {
+        let filter = GeoIpFilter::deny_countries(DATABASE, ["xb"]).unwrap();
+        let addr = |a, b, c, d| IpAddr::from(Ipv4Addr::new(a, b, c, d));
+        assert_eq!(filter.country(addr(192, 0, 2, 1)).as_deref(), Some("XA"));
+        assert_eq!(filter.country(addr(198, 51, 100, 1)).as_deref(), Some("XB"));
+        assert_eq!(filter.country(addr(203, 0, 113, 1)), None);
+
+        assert_eq!(filter.filter_address(&addr(198, 51, 100, 1)), FilterAction::Deny);
+        assert_eq!(filter.filter_address(&addr(192, 0, 2, 1)), FilterAction::Allow);
+        assert_eq!(filter.filter_address(&addr(203, 0, 113, 1)), FilterAction::Allow);
+        assert_eq!(filter.filter_host("example.com", 443), FilterAction::Allow);
+
+        let denied = HostAddress::from(SocketAddr::from((addr(198, 51, 100, 1), 443)));
+        assert_eq!(filter.matched_rule(&denied).as_deref(), Some("country XB"));
+
+        let filter = GeoIpFilter::allow_countries(DATABASE, ["XA"]).unwrap();
+        assert_eq!(filter.filter_address(&addr(192, 0, 2, 1)), FilterAction::Allow);
+        assert_eq!(filter.filter_address(&addr(203, 0, 113, 1)), FilterAction::Deny);
+    }
+
+    #[tokio::test]
+    async fn filter_resolved_addresses() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let resolver = StaticResolver::builder()
+            .insert("blocked.example", Ipv4Addr::new(198, 51, 100, 1))
+            .insert("allowed.example", Ipv4Addr::LOCALHOST)
+            .build();
+        let transport = Transport::direct(Arc::new(resolver), Arc::new(SimpleFilter::deny_list()))
+            .with_resolved_filter(Arc::new(GeoIpFilter::deny_countries(DATABASE, ["XB"]).unwrap()));
+
+        // the country is known only after resolving the host name
+        let blocked = HostAddress::new("blocked.example", port);
+        let err = transport.connect(&blocked).await.unwrap_err();
+        assert!(
+            matches!(err, Error::ConnectForbiddenHosts { hosts } if hosts == [blocked.clone()])
+        );
+
+        let addr = SocketAddr::from((Ipv4Addr::new(198, 51, 100, 1), port));
+        let err = transport.connect_addr(&addr).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectForbiddenHosts { .. }));
+
+        let allowed = HostAddress::new("allowed.example", port);
+        assert!(transport.connect(&allowed).await.is_ok());
+
+        // BIND and datagrams of UDP associate are filtered as well
+        let err = transport.listen(&blocked).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectForbiddenHosts { .. }));
+        assert!(transport.listen(&allowed).await.is_ok());
+        let destination_filter = transport.destination_filter();
+        assert!(destination_filter.is_denied_addr(&addr));
+        assert!(!destination_filter.is_denied_addr(&SocketAddr::from((Ipv4Addr::LOCALHOST, port))));
+    }
+}
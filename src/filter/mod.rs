@@ -1,9 +1,20 @@
 mod composer;
+mod event;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod pattern;
 mod simple;
 
 use std::net::{IpAddr, SocketAddr};
 
-pub use self::{composer::ComposerFilter, simple::SimpleFilter};
+#[cfg(feature = "geoip")]
+pub use self::geoip::{GeoIpError, GeoIpFilter};
+pub use self::{
+    composer::{CombinePolicy, ComposerFilter},
+    event::{ClientIdentity, FilterEvents, FILTER_EVENT_TARGET},
+    pattern::{PatternError, PatternFilter, REGEX_PATTERN_PREFIX},
+    simple::SimpleFilter,
+};
 use crate::common::{HostAddress, ProxyHost, ProxyStrategy};
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -13,6 +24,18 @@ pub enum FilterMode {
     DenyList,
 }
 
+impl FilterMode {
+    /// Returns the action of destinations no rule applies to.
+    #[inline]
+    #[must_use]
+    pub const fn default_action(self) -> FilterAction {
+        match self {
+            Self::AllowList => FilterAction::Deny,
+            Self::DenyList => FilterAction::Allow,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum FilterAction {
     Allow,
@@ -37,6 +60,14 @@ pub trait HostFilter: Send + Sync {
 
     fn filter_port(&self, port: u16) -> FilterAction;
 
+    /// Returns a description of the rule deciding the action of `addr`,
+    /// `None` if it is unknown or no rule applies.
+    fn matched_rule(&self, _addr: &HostAddress) -> Option<String> { None }
+
+    /// Returns the action of destinations no rule applies to, `None` if every
+    /// action is decided by a rule.
+    fn default_action(&self) -> Option<FilterAction> { None }
+
     fn check_proxy_strategy(&self, strategy: &ProxyStrategy) -> (bool, Vec<HostAddress>) {
         match strategy {
             ProxyStrategy::Single(proxy) => {
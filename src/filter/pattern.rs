@@ -0,0 +1,290 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use regex::{RegexSet, RegexSetBuilder};
+use snafu::{ResultExt, Snafu};
+
+use crate::{
+    common::HostAddress,
+    filter::{FilterAction, FilterMode, HostFilter},
+};
+
+/// Prefix of patterns which are regular expressions, other patterns are globs.
+pub const REGEX_PATTERN_PREFIX: &str = "regex:";
+
+#[derive(Debug, Snafu)]
+pub enum PatternError {
+    #[snafu(display("Invalid host pattern `{pattern}`, error: {source}"))]
+    InvalidPattern { pattern: String, source: regex::Error },
+
+    #[snafu(display("Invalid domain `{domain}`, only domains and globs are allowed"))]
+    InvalidDomain { domain: String },
+
+    #[snafu(display("Could not read domains file {}, error: {source}", file_path.display()))]
+    ReadDomainsFile { file_path: PathBuf, source: std::io::Error },
+}
+
+/// Filters host names by patterns, e.g. to deny whole domains.
+///
+/// A pattern is either a glob matching the whole host name, `*` matches any
+/// characters and `?` matches a single character, e.g. `*.doubleclick.net`,
+/// or a regular expression prefixed with `regex:` matching any part of the host
+/// name unless it is anchored, e.g. `regex:^ads\.`. Host names are matched
+/// case-insensitively and IP addresses never match.
+#[derive(Clone, Debug)]
+pub struct PatternFilter {
+    patterns: Vec<String>,
+    set: RegexSet,
+    mode: FilterMode,
+}
+
+impl PatternFilter {
+    pub fn new<I, S>(patterns: I, mode: FilterMode) -> Result<Self, PatternError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let patterns: Vec<_> = patterns.into_iter().map(|p| p.as_ref().to_owned()).collect();
+        let regexes = patterns
+            .iter()
+            .map(|pattern| {
+                let regex = to_regex(pattern);
+                // compile each pattern alone to report the invalid one
+                let _unused = regex::Regex::new(&regex)
+                    .with_context(|_| InvalidPatternSnafu { pattern: pattern.clone() })?;
+                Ok(regex)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let set = RegexSetBuilder::new(regexes)
+            .case_insensitive(true)
+            .build()
+            .with_context(|_| InvalidPatternSnafu { pattern: patterns.join(" ") })?;
+
+        Ok(Self { patterns, set, mode })
+    }
+
+    pub fn allow_list<I, S>(patterns: I) -> Result<Self, PatternError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::new(patterns, FilterMode::AllowList)
+    }
+
+    pub fn deny_list<I, S>(patterns: I) -> Result<Self, PatternError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::new(patterns, FilterMode::DenyList)
+    }
+
+    /// Allows only `domains` and their subdomains, e.g. `example.com` allows
+    /// `www.example.com` but not `badexample.com`. Domains may be globs, but
+    /// not regular expressions.
+    pub fn allow_domains<I, S>(domains: I) -> Result<Self, PatternError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let patterns = domains
+            .into_iter()
+            .map(|domain| {
+                let domain = domain.as_ref().trim_end_matches('.');
+                if domain.starts_with(REGEX_PATTERN_PREFIX) {
+                    return Err(PatternError::InvalidDomain { domain: domain.to_owned() });
+                }
+                Ok([domain.to_owned(), format!("*.{domain}")])
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::allow_list(patterns.into_iter().flatten())
+    }
+
+    /// Allows only domains listed in a file and their subdomains, one domain
+    /// per line, text after `#` is ignored.
+    pub fn load_allowed_domains<P: AsRef<Path>>(file_path: P) -> Result<Self, PatternError> {
+        let file_path = file_path.as_ref();
+        let text = std::fs::read_to_string(file_path)
+            .with_context(|_| ReadDomainsFileSnafu { file_path: file_path.to_owned() })?;
+        Self::allow_domains(
+            text.lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|domain| !domain.is_empty()),
+        )
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize { self.patterns.len() }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool { self.patterns.is_empty() }
+
+    // pattern which `hostname` matches first
+    fn matched_pattern(&self, hostname: &str) -> Option<&str> {
+        let hostname = hostname.trim_end_matches('.');
+        self.set.matches(hostname).iter().next().map(|index| self.patterns[index].as_str())
+    }
+
+    #[inline]
+    const fn filter(&self, matched: bool) -> FilterAction {
+        match (self.mode, matched) {
+            (FilterMode::DenyList, true) | (FilterMode::AllowList, false) => FilterAction::Deny,
+            (FilterMode::DenyList, false) | (FilterMode::AllowList, true) => FilterAction::Allow,
+        }
+    }
+}
+
+impl HostFilter for PatternFilter {
+    #[inline]
+    fn filter_port(&self, _port: u16) -> FilterAction { self.filter(false) }
+
+    #[inline]
+    fn filter_hostname(&self, hostname: &str) -> FilterAction {
+        self.filter(self.matched_pattern(hostname).is_some())
+    }
+
+    #[inline]
+    fn filter_address(&self, _addr: &IpAddr) -> FilterAction { self.filter(false) }
+
+    #[inline]
+    fn filter_socket(&self, _socket: &SocketAddr) -> FilterAction { self.filter(false) }
+
+    #[inline]
+    fn filter_host(&self, host: &str, _port: u16) -> FilterAction { self.filter_hostname(host) }
+
+    fn matched_rule(&self, addr: &HostAddress) -> Option<String> {
+        let matched = match addr {
+            HostAddress::DomainName(host, _) => self.matched_pattern(host),
+            HostAddress::Socket(_) => None,
+        };
+        match (self.mode, matched) {
+            (_, Some(pattern)) => Some(format!("pattern {pattern}")),
+            (FilterMode::AllowList, None) => Some("not in allow list".to_owned()),
+            (FilterMode::DenyList, None) => None,
+        }
+    }
+
+    #[inline]
+    fn default_action(&self) -> Option<FilterAction> { Some(self.mode.default_action()) }
+}
+
+fn to_regex(pattern: &str) -> String {
+    if let Some(regex) = pattern.strip_prefix(REGEX_PATTERN_PREFIX) {
+        return regex.to_owned();
+    }
+
+    let mut regex = String::with_capacity(pattern.len() + 2);
+    regex.push('^');
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0u8; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use super::{PatternError, PatternFilter};
+    use crate::{
+        common::HostAddress,
+        filter::{CombinePolicy, ComposerFilter, FilterAction, HostFilter, SimpleFilter},
+    };
+
+    #[test]
+    fn match_patterns() {
+        let filter = PatternFilter::deny_list(["*.doubleclick.net", r"regex:^ads\."]).unwrap();
+        assert_eq!(filter.len(), 2);
+
+        // wildcard
+        assert_eq!(filter.filter_hostname("ad.g.DoubleClick.net."), FilterAction::Deny);
+        assert_eq!(filter.filter_host("stats.doubleclick.net", 443), FilterAction::Deny);
+        assert_eq!(filter.filter_hostname("doubleclick.net.example"), FilterAction::Allow);
+
+        // anchored regex
+        assert_eq!(filter.filter_hostname("ads.example.com"), FilterAction::Deny);
+        assert_eq!(filter.filter_hostname("uploads.example.com"), FilterAction::Allow);
+
+        // non-matching hosts and addresses pass through
+        assert_eq!(filter.filter_hostname("example.com"), FilterAction::Allow);
+        let socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80));
+        assert_eq!(filter.filter_socket(&socket), FilterAction::Allow);
+
+        assert_eq!(
+            filter.matched_rule(&HostAddress::new("ads.example.com", 80)).as_deref(),
+            Some(r"pattern regex:^ads\.")
+        );
+
+        // the same patterns allow only matching hosts in allow list mode
+        let filter = PatternFilter::allow_list(["*.example.com"]).unwrap();
+        assert_eq!(filter.filter_hostname("www.example.com"), FilterAction::Allow);
+        assert_eq!(filter.filter_hostname("example.org"), FilterAction::Deny);
+        assert_eq!(filter.filter_socket(&socket), FilterAction::Deny);
+
+        assert!(matches!(
+            PatternFilter::deny_list(["regex:(ads"]),
+            Err(PatternError::InvalidPattern { pattern, .. }) if pattern == "regex:(ads"
+        ));
+    }
+
+    #[test]
+    fn compose_with_simple_filter() {
+        let mut simple = SimpleFilter::deny_list();
+        simple.add_hostname("tracker.example");
+        let mut filter = ComposerFilter::with_policy(CombinePolicy::AllMustAllow);
+        filter.push(Arc::new(simple));
+        filter.push(Arc::new(PatternFilter::deny_list(["*.doubleclick.net"]).unwrap()));
+
+        let denied = HostAddress::new("ad.doubleclick.net", 443);
+        assert_eq!(filter.filter_host_address(&denied), FilterAction::Deny);
+        assert_eq!(filter.matched_rule(&denied).as_deref(), Some("pattern *.doubleclick.net"));
+        assert_eq!(filter.filter_hostname("tracker.example"), FilterAction::Deny);
+        assert_eq!(filter.filter_hostname("example.com"), FilterAction::Allow);
+    }
+
+    #[test]
+    fn load_allowed_domains() {
+        let file_path =
+            std::env::temp_dir().join(format!("tunelo-allowed-domains-{}.txt", std::process::id()));
+        std::fs::write(&file_path, "# allowed\nexample.com\n\nexample.org. # trailing\n").unwrap();
+        let filter = PatternFilter::load_allowed_domains(&file_path);
+        std::fs::remove_file(&file_path).unwrap();
+        let filter = filter.unwrap();
+
+        for allowed in ["example.com", "www.example.com", "a.b.Example.org"] {
+            let addr = HostAddress::new(allowed, 443);
+            assert_eq!(filter.filter_host_address(&addr), FilterAction::Allow, "{allowed}");
+        }
+        for denied in ["badexample.com", "example.net", "example.com.evil"] {
+            let addr = HostAddress::new(denied, 443);
+            assert_eq!(filter.filter_host_address(&addr), FilterAction::Deny, "{denied}");
+        }
+        let socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 443));
+        assert_eq!(filter.filter_socket(&socket), FilterAction::Deny);
+
+        assert!(matches!(
+            PatternFilter::load_allowed_domains(&file_path),
+            Err(PatternError::ReadDomainsFile { .. })
+        ));
+
+        // regular expressions would allow any host they match a part of
+        assert!(matches!(
+            PatternFilter::allow_domains(["example.com", "regex:^"]),
+            Err(PatternError::InvalidDomain { domain }) if domain == "regex:^"
+        ));
+        let filter = PatternFilter::allow_domains(["*.example.net"]).unwrap();
+        assert_eq!(filter.filter_hostname("www.example.net"), FilterAction::Allow);
+    }
+}
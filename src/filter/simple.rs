@@ -1,8 +1,11 @@
 use std::{
     collections::HashSet,
     net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
 };
 
+use ipnet::IpNet;
+
 use crate::{
     common::HostAddress,
     filter::{FilterAction, FilterMode, HostFilter},
@@ -15,6 +18,8 @@ pub struct SimpleFilter {
     hosts: HashSet<(String, u16)>,
     sockets: HashSet<SocketAddr>,
     ports: HashSet<u16>,
+    port_ranges: Vec<RangeInclusive<u16>>,
+    cidrs: Vec<IpNet>,
     mode: FilterMode,
 }
 
@@ -29,7 +34,16 @@ impl SimpleFilter {
         ports: HashSet<u16>,
         mode: FilterMode,
     ) -> Self {
-        Self { hostnames, addresses, hosts, sockets, ports, mode }
+        Self {
+            hostnames,
+            addresses,
+            hosts,
+            sockets,
+            ports,
+            port_ranges: Vec::new(),
+            cidrs: Vec::new(),
+            mode,
+        }
     }
 
     #[inline]
@@ -53,12 +67,57 @@ impl SimpleFilter {
     #[inline]
     pub fn add_hostname(&mut self, host: &str) { self.hostnames.insert(host.to_owned()); }
 
+    /// Adds a destination port, e.g. `25` to block SMTP. Ports are matched
+    /// for sockets and hosts as well: a listed port is denied for every host
+    /// in deny list mode, and only listed ports are allowed in allow list mode
+    /// if any port is listed.
     #[inline]
     pub fn add_port(&mut self, port: u16) { self.ports.insert(port); }
 
+    /// Adds a range of destination ports, e.g. `6660..=6669`, matched like
+    /// ports added with [`add_port`](Self::add_port).
+    #[inline]
+    pub fn add_port_range(&mut self, ports: RangeInclusive<u16>) {
+        if !ports.is_empty() && !self.port_ranges.contains(&ports) {
+            self.port_ranges.push(ports);
+        }
+    }
+
+    // range of `port_ranges` containing `port`
+    fn matched_port_range(&self, port: u16) -> Option<&RangeInclusive<u16>> {
+        self.port_ranges.iter().find(|ports| ports.contains(&port))
+    }
+
+    #[inline]
+    fn contains_port(&self, port: u16) -> bool {
+        self.ports.contains(&port) || self.matched_port_range(port).is_some()
+    }
+
+    // whether `port` is allowed in allow list mode, every port is allowed
+    // unless a port is listed
+    #[inline]
+    fn allows_port(&self, port: u16) -> bool {
+        (self.ports.is_empty() && self.port_ranges.is_empty()) || self.contains_port(port)
+    }
+
     #[inline]
     pub fn add_address(&mut self, addr: IpAddr) { self.addresses.insert(addr); }
 
+    /// Adds a range of addresses, e.g. `10.0.0.0/8` or `fe80::/10`, matched
+    /// like addresses added with [`add_address`](Self::add_address).
+    #[inline]
+    pub fn add_cidr(&mut self, cidr: IpNet) {
+        let cidr = cidr.trunc();
+        if !self.cidrs.contains(&cidr) {
+            self.cidrs.push(cidr);
+        }
+    }
+
+    // range of `cidrs` containing `addr`
+    fn matched_cidr(&self, addr: &IpAddr) -> Option<&IpNet> {
+        self.cidrs.iter().find(|cidr| cidr.contains(addr))
+    }
+
     #[inline]
     pub fn add_host_address(&mut self, addr: HostAddress) {
         match addr {
@@ -67,6 +126,36 @@ impl SimpleFilter {
         }
     }
 
+    // entry of the lists which `addr` matches
+    fn matched_entry(&self, addr: &HostAddress) -> Option<String> {
+        match addr {
+            HostAddress::Socket(socket) if self.addresses.contains(&socket.ip()) => {
+                Some(format!("address {}", socket.ip()))
+            }
+            HostAddress::Socket(socket) if self.sockets.contains(socket) => {
+                Some(format!("socket {socket}"))
+            }
+            HostAddress::Socket(socket) => {
+                self.matched_cidr(&socket.ip()).map(|cidr| format!("cidr {cidr}"))
+            }
+            HostAddress::DomainName(host, _) if self.hostnames.contains(host) => {
+                Some(format!("hostname {host}"))
+            }
+            HostAddress::DomainName(host, port) if self.hosts.contains(&(host.clone(), *port)) => {
+                Some(format!("host {host}:{port}"))
+            }
+            HostAddress::DomainName(..) => None,
+        }
+    }
+
+    fn matched_port(&self, port: u16) -> Option<String> {
+        if self.ports.contains(&port) {
+            return Some(format!("port {port}"));
+        }
+        self.matched_port_range(port)
+            .map(|ports| format!("ports {}-{}", ports.start(), ports.end()))
+    }
+
     #[inline]
     const fn filter(&self, b: bool) -> FilterAction {
         match self.mode {
@@ -75,6 +164,17 @@ impl SimpleFilter {
         }
     }
 
+    // action of a destination whose host is matched as `b` and whose port is
+    // `port`, ports are denied in addition to hosts in deny list mode and
+    // allowed hosts are restricted to the allowed ports in allow list mode
+    #[inline]
+    fn filter_with_port(&self, b: bool, port: u16) -> FilterAction {
+        match self.mode {
+            FilterMode::DenyList => Self::deny(b || self.contains_port(port)),
+            FilterMode::AllowList => Self::allow(b && self.allows_port(port)),
+        }
+    }
+
     #[inline]
     const fn allow(b: bool) -> FilterAction {
         if b {
@@ -96,7 +196,12 @@ impl SimpleFilter {
 
 impl HostFilter for SimpleFilter {
     #[inline]
-    fn filter_port(&self, port: u16) -> FilterAction { self.filter(self.ports.contains(&port)) }
+    fn filter_port(&self, port: u16) -> FilterAction {
+        match self.mode {
+            FilterMode::DenyList => Self::deny(self.contains_port(port)),
+            FilterMode::AllowList => Self::allow(self.allows_port(port)),
+        }
+    }
 
     #[inline]
     fn filter_hostname(&self, hostname: &str) -> FilterAction {
@@ -105,23 +210,54 @@ impl HostFilter for SimpleFilter {
 
     #[inline]
     fn filter_address(&self, addr: &IpAddr) -> FilterAction {
-        self.filter(self.addresses.contains(addr))
+        self.filter(self.addresses.contains(addr) || self.matched_cidr(addr).is_some())
     }
 
     #[inline]
     fn filter_socket(&self, socket: &SocketAddr) -> FilterAction {
-        self.filter(self.addresses.contains(&socket.ip()) || self.sockets.contains(socket))
+        self.filter_with_port(
+            self.addresses.contains(&socket.ip())
+                || self.sockets.contains(socket)
+                || self.matched_cidr(&socket.ip()).is_some(),
+            socket.port(),
+        )
     }
 
     #[inline]
     fn filter_host(&self, host: &str, port: u16) -> FilterAction {
-        self.filter(self.hostnames.contains(host) || self.hosts.contains(&(host.to_owned(), port)))
+        self.filter_with_port(
+            self.hostnames.contains(host) || self.hosts.contains(&(host.to_owned(), port)),
+            port,
+        )
     }
+
+    fn matched_rule(&self, addr: &HostAddress) -> Option<String> {
+        let port = addr.port();
+        match self.mode {
+            FilterMode::DenyList => self.matched_entry(addr).or_else(|| self.matched_port(port)),
+            FilterMode::AllowList if !self.allows_port(port) => {
+                Some(format!("port {port} not in allow list"))
+            }
+            FilterMode::AllowList => {
+                Some(self.matched_entry(addr).unwrap_or_else(|| "not in allow list".to_owned()))
+            }
+        }
+    }
+
+    #[inline]
+    fn default_action(&self) -> Option<FilterAction> { Some(self.mode.default_action()) }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv4Addr;
+
     use super::*;
+    use crate::{
+        common::{ProxyHost, ProxyStrategy},
+        test_util::direct_transport,
+        transport,
+    };
 
     #[test]
     fn constructors() {
@@ -175,7 +311,126 @@ mod tests {
         assert_eq!(filter.filter_port(port), FilterAction::Allow);
         assert_eq!(filter.filter_hostname(hostname), FilterAction::Allow);
         assert_eq!(filter.filter_address(&ip), FilterAction::Allow);
-        assert_eq!(filter.filter_socket(&socket), FilterAction::Allow);
+        // the port of `socket` is not listed
+        assert_eq!(filter.filter_socket(&socket), FilterAction::Deny);
+        assert_eq!(filter.filter_socket(&SocketAddr::new(ip, port)), FilterAction::Allow);
         assert_eq!(filter.filter_host(hostname, port), FilterAction::Allow);
     }
+
+    #[test]
+    fn cidrs() {
+        let mut filter = SimpleFilter::deny_list();
+        filter.add_cidr("10.0.0.0/8".parse().unwrap());
+        filter.add_cidr("fe80::1/10".parse().unwrap());
+
+        for addr in ["10.0.0.0", "10.255.255.255", "10.1.2.3", "fe80::", "febf:ffff::1"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(filter.filter_address(&addr), FilterAction::Deny, "{addr}");
+            assert_eq!(filter.filter_socket(&SocketAddr::new(addr, 80)), FilterAction::Deny);
+        }
+        for addr in ["9.255.255.255", "11.0.0.0", "fe7f:ffff::1", "fec0::"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(filter.filter_address(&addr), FilterAction::Allow, "{addr}");
+            assert_eq!(filter.filter_socket(&SocketAddr::new(addr, 80)), FilterAction::Allow);
+        }
+
+        let socket = SocketAddr::new("10.1.2.3".parse().unwrap(), 1080);
+        assert_eq!(
+            filter.matched_rule(&HostAddress::from(socket)).as_deref(),
+            Some("cidr 10.0.0.0/8")
+        );
+
+        // proxy servers are checked against the same ranges
+        let proxy = |host: &str| ProxyHost::Socks5 {
+            host: host.to_owned(),
+            port: 1080,
+            username: None,
+            password: None,
+        };
+        let (allowed, denied) =
+            filter.check_proxy_strategy(&ProxyStrategy::Single(proxy("10.1.2.3")));
+        assert!(!allowed);
+        assert_eq!(denied, vec![HostAddress::from(socket)]);
+        let (allowed, _) = filter.check_proxy_strategy(&ProxyStrategy::Single(proxy("11.0.0.1")));
+        assert!(allowed);
+
+        filter.set_mode(FilterMode::AllowList);
+        assert_eq!(filter.filter_socket(&socket), FilterAction::Allow);
+        let outside = SocketAddr::new("11.0.0.0".parse().unwrap(), 80);
+        assert_eq!(filter.filter_socket(&outside), FilterAction::Deny);
+    }
+
+    #[tokio::test]
+    async fn ports() {
+        let mut filter = SimpleFilter::deny_list();
+        filter.add_port(25);
+        filter.add_port_range(6660..=6669);
+
+        for port in [25, 6660, 6665, 6669] {
+            assert_eq!(filter.filter_port(port), FilterAction::Deny, "{port}");
+            let socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), port));
+            assert_eq!(filter.filter_socket(&socket), FilterAction::Deny, "{port}");
+            assert_eq!(filter.filter_host("example.com", port), FilterAction::Deny, "{port}");
+        }
+        for port in [24, 26, 6659, 6670] {
+            assert_eq!(filter.filter_port(port), FilterAction::Allow, "{port}");
+            let socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), port));
+            assert_eq!(filter.filter_socket(&socket), FilterAction::Allow, "{port}");
+        }
+        assert_eq!(
+            filter.matched_rule(&HostAddress::new("example.com", 6662)).as_deref(),
+            Some("ports 6660-6669")
+        );
+
+        // connections to denied ports are forbidden by transport
+        let transport = direct_transport(filter.clone());
+        let socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 25));
+        assert!(matches!(
+            transport.connect_addr(&socket).await,
+            Err(transport::Error::ConnectForbiddenHosts { .. })
+        ));
+        assert!(matches!(
+            transport.connect(&HostAddress::new("example.com", 6666)).await,
+            Err(transport::Error::ConnectForbiddenHosts { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn allowed_ports() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let allowed = listener.local_addr().unwrap();
+
+        // only listed ports of listed hosts are allowed
+        let mut filter = SimpleFilter::allow_list();
+        filter.add_address(allowed.ip());
+        filter.add_port(allowed.port());
+        filter.add_port_range(6660..=6669);
+        assert_eq!(filter.filter_port(6666), FilterAction::Allow);
+        assert_eq!(filter.filter_port(443), FilterAction::Deny);
+        assert_eq!(
+            filter.matched_rule(&HostAddress::new("localhost", 443)).as_deref(),
+            Some("port 443 not in allow list")
+        );
+
+        let transport = direct_transport(filter);
+        assert!(transport.connect_addr(&allowed).await.is_ok());
+
+        // listed host on a port not listed
+        let socket = SocketAddr::new(allowed.ip(), 443);
+        assert!(matches!(
+            transport.connect_addr(&socket).await,
+            Err(transport::Error::ConnectForbiddenHosts { .. })
+        ));
+
+        // listed port of a host not listed
+        let socket = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), allowed.port()));
+        assert!(matches!(
+            transport.connect_addr(&socket).await,
+            Err(transport::Error::ConnectForbiddenHosts { .. })
+        ));
+        assert!(matches!(
+            transport.connect(&HostAddress::new("example.com", allowed.port())).await,
+            Err(transport::Error::ConnectForbiddenHosts { .. })
+        ));
+    }
 }
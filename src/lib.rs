@@ -1,3 +1,7 @@
+// artificial latency must never reach release builds
+#[cfg(all(feature = "debug", not(debug_assertions)))]
+compile_error!("feature `debug` is only available in debug builds");
+
 pub mod authentication;
 pub mod checker;
 pub mod client;
@@ -7,3 +11,6 @@ pub mod protocol;
 pub mod server;
 pub mod service;
 pub mod transport;
+
+#[cfg(test)]
+mod test_util;
@@ -14,6 +14,16 @@ pub const SOCKS5_AUTH_METHOD_GSSAPI: u8 = 0x01;
 pub const SOCKS5_AUTH_METHOD_PASSWORD: u8 = 0x02;
 pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
 
+pub const SOCKS5_GSSAPI_VERSION: u8 = 0x01;
+pub const SOCKS5_GSSAPI_MSG_AUTHENTICATION: u8 = 0x01;
+pub const SOCKS5_GSSAPI_MSG_PROTECTION_LEVEL: u8 = 0x02;
+pub const SOCKS5_GSSAPI_MSG_ENCAPSULATION: u8 = 0x03;
+pub const SOCKS5_GSSAPI_MSG_ABORT: u8 = 0xFF;
+
+pub const SOCKS5_GSSAPI_PROTECTION_INTEGRITY: u8 = 0x01;
+pub const SOCKS5_GSSAPI_PROTECTION_CONFIDENTIALITY: u8 = 0x02;
+pub const SOCKS5_GSSAPI_PROTECTION_SELECTIVE: u8 = 0x03;
+
 pub const SOCKS5_CMD_TCP_CONNECT: u8 = 0x01;
 pub const SOCKS5_CMD_TCP_BIND: u8 = 0x02;
 pub const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
@@ -29,9 +29,32 @@ pub enum Error {
     #[snafu(display("Invalid user password version: {}", version))]
     InvalidUserPasswordVersion { version: u8 },
 
+    #[snafu(display("Invalid GSS-API message version: {}", version))]
+    InvalidGssapiVersion { version: u8 },
+
+    #[snafu(display("Invalid GSS-API message type: {}", ty))]
+    InvalidGssapiMessageType { ty: u8 },
+
+    #[snafu(display("Invalid GSS-API protection level: {}", level))]
+    InvalidProtectionLevel { level: u8 },
+
+    #[snafu(display("Unexpected end of stream"))]
+    UnexpectedEof,
+
     #[snafu(display("Bad request"))]
     BadRequest,
 
     #[snafu(display("Bad reply"))]
     BadReply,
 }
+
+impl Error {
+    /// Maps a premature end of stream to [`Error::UnexpectedEof`], so that a
+    /// truncated message can be told apart from other I/O errors.
+    pub(crate) fn from_read_error(source: std::io::Error) -> Self {
+        match source.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::UnexpectedEof,
+            _ => Self::ReadStream { source },
+        }
+    }
+}
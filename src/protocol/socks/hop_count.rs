@@ -0,0 +1,102 @@
+//! Tunelo-specific hop count marker, which is not part of SOCKS.
+//!
+//! SOCKS carries no hop count, so a misconfigured chain of tunelo instances
+//! can relay a connection in a loop forever. When loop detection is enabled,
+//! a tunelo server accepts a marker sent before the SOCKS version byte
+//!
+//! ```text
+//! +--------+-------+
+//! | MARKER | HOPS  |
+//! +--------+-------+
+//! | "TNLH" |   1   |
+//! +--------+-------+
+//! ```
+//!
+//! and refuses the connection if `HOPS` exceeds its limit. Connections made
+//! to upstream proxy servers while handling such a connection carry the marker
+//! with `HOPS` incremented, so upstream proxy servers of a tunelo server with
+//! loop detection enabled must be tunelo servers with loop detection enabled.
+//!
+//! The first byte of the marker, `T`, is never a valid SOCKS version, so the
+//! marker can not be confused with a SOCKS request.
+
+use std::future::Future;
+
+use snafu::ResultExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::protocol::socks::{error, Error};
+
+tokio::task_local! {
+    static CURRENT: HopCount;
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct HopCount(u8);
+
+impl HopCount {
+    pub const MARKER: [u8; 4] = *b"TNLH";
+
+    #[inline]
+    #[must_use]
+    pub const fn new(hops: u8) -> Self { Self(hops) }
+
+    #[inline]
+    #[must_use]
+    pub const fn get(self) -> u8 { self.0 }
+
+    #[inline]
+    #[must_use]
+    pub const fn next(self) -> Self { Self(self.0.saturating_add(1)) }
+
+    #[must_use]
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Self::MARKER.to_vec();
+        buf.push(self.0);
+        buf
+    }
+
+    /// Reads the marker following its first byte, which is consumed by the
+    /// caller to tell the marker from SOCKS version.
+    pub async fn from_reader<R>(rdr: &mut R) -> Result<Self, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut marker = [0u8; 3];
+        let _ = rdr.read_exact(&mut marker).await.map_err(Error::from_read_error)?;
+        if marker != Self::MARKER[1..] {
+            return Err(Error::BadRequest);
+        }
+
+        let hops = rdr.read_u8().await.context(error::ReadStreamSnafu)?;
+        Ok(Self(hops))
+    }
+
+    /// Returns the hop count of the connection handled by the current task,
+    /// `None` if the connection does not take part in loop detection.
+    #[must_use]
+    pub fn current() -> Option<Self> { CURRENT.try_with(|hops| *hops).ok() }
+
+    /// Runs `fut` with `self` as the hop count of the current connection.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output { CURRENT.scope(self, fut).await }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HopCount;
+    use crate::protocol::socks::Error;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let buf = HopCount::new(3).to_bytes();
+        assert_eq!(buf, b"TNLH\x03");
+
+        let hops = HopCount::from_reader(&mut &buf[1..]).await.unwrap();
+        assert_eq!(hops, HopCount::new(3));
+        assert_eq!(hops.next().get(), 4);
+        assert_eq!(HopCount::new(u8::MAX).next().get(), u8::MAX);
+
+        let result = HopCount::from_reader(&mut &b"XYZ\x03"[..]).await;
+        assert!(matches!(result, Err(Error::BadRequest)));
+    }
+}
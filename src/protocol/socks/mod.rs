@@ -1,5 +1,6 @@
 pub mod consts;
 pub mod error;
+mod hop_count;
 pub mod v4;
 pub mod v5;
 
@@ -12,7 +13,7 @@ use std::{
 use snafu::ResultExt;
 use tokio::io::AsyncRead;
 
-pub use self::error::Error;
+pub use self::{error::Error, hop_count::HopCount};
 use crate::common::HostAddress;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -180,29 +181,32 @@ impl Address {
         use tokio::io::AsyncReadExt;
 
         let address_type =
-            AddressType::try_from(rdr.read_u8().await.context(error::ReadStreamSnafu)?)?;
+            AddressType::try_from(rdr.read_u8().await.map_err(Error::from_read_error)?)?;
         match address_type {
             AddressType::Ipv4 => {
                 let mut buf = [0u8; 4];
-                rdr.read_exact(&mut buf).await.context(error::ReadStreamSnafu)?;
+                rdr.read_exact(&mut buf).await.map_err(Error::from_read_error)?;
 
-                let port = rdr.read_u16().await.context(error::ReadStreamSnafu)?;
+                let port = rdr.read_u16().await.map_err(Error::from_read_error)?;
                 Ok(SocketAddr::new(buf.into(), port).into())
             }
             AddressType::Ipv6 => {
                 let mut buf = [0u8; 16];
-                rdr.read_exact(&mut buf).await.context(error::ReadStreamSnafu)?;
+                rdr.read_exact(&mut buf).await.map_err(Error::from_read_error)?;
 
-                let port = rdr.read_u16().await.context(error::ReadStreamSnafu)?;
+                let port = rdr.read_u16().await.map_err(Error::from_read_error)?;
                 Ok(SocketAddr::new(buf.into(), port).into())
             }
             AddressType::Domain => {
-                let len = rdr.read_u8().await.context(error::ReadStreamSnafu)? as usize;
+                let len = rdr.read_u8().await.map_err(Error::from_read_error)? as usize;
+                if len == 0 {
+                    return Err(Error::BadRequest);
+                }
 
                 let mut host = vec![0u8; len];
-                rdr.read_exact(&mut host).await.context(error::ReadStreamSnafu)?;
+                rdr.read_exact(&mut host).await.map_err(Error::from_read_error)?;
 
-                let port = rdr.read_u16().await.context(error::ReadStreamSnafu)?;
+                let port = rdr.read_u16().await.map_err(Error::from_read_error)?;
                 Ok(Self::new_domain(&host, port))
             }
         }
@@ -306,6 +310,12 @@ impl AddressRef<'_> {
     }
 
     fn to_bytes(&self, socks_version: SocksVersion) -> Vec<u8> {
+        let buf = self.to_bytes_unchecked(socks_version);
+        debug_assert_eq!(buf.len(), self.serialized_len(socks_version));
+        buf
+    }
+
+    fn to_bytes_unchecked(&self, socks_version: SocksVersion) -> Vec<u8> {
         use byteorder::{BigEndian, WriteBytesExt};
 
         let mut buf = Vec::with_capacity(self.serialized_len(socks_version));
@@ -344,8 +354,8 @@ impl AsRef<HostAddress> for Address {
     fn as_ref(&self) -> &HostAddress { &self.0 }
 }
 
-impl ToString for Address {
-    fn to_string(&self) -> String { self.0.to_string() }
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { self.0.fmt(f) }
 }
 
 impl From<HostAddress> for Address {
@@ -369,4 +379,72 @@ impl From<SocketAddrV6> for Address {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::{v4, v5, Address, Error};
+    use crate::transport::{self, TimeoutPhase};
+
+    #[tokio::test]
+    async fn truncated_domain_address() {
+        // declares a 11 bytes domain name, but closes after "exam"
+        let buf = [0x03, 0x0b, b'e', b'x', b'a', b'm'];
+        let result = Address::from_reader(&mut &buf[..]).await;
+        assert!(matches!(result, Err(Error::UnexpectedEof)));
+
+        // port is missing
+        let mut buf = vec![0x03, 0x0b];
+        buf.extend_from_slice(b"example.com");
+        let result = Address::from_reader(&mut &buf[..]).await;
+        assert!(matches!(result, Err(Error::UnexpectedEof)));
+
+        let buf = [0x03, 0x00, 0x00, 0x50];
+        let result = Address::from_reader(&mut &buf[..]).await;
+        assert!(matches!(result, Err(Error::BadRequest)));
+    }
+
+    #[tokio::test]
+    async fn stalled_domain_address() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0x03, 0x0b, b'e', b'x', b'a', b'm']).await.unwrap();
+
+        // keep `client` open, so that the stream stalls instead of ending
+        let timeout = Some(Duration::from_millis(50));
+        let result = transport::with_timeout(
+            timeout,
+            TimeoutPhase::Handshake,
+            Address::from_reader(&mut server),
+        )
+        .await;
+        assert!(matches!(result, Err(TimeoutPhase::Handshake)));
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn stalled_requests() {
+        let timeout = Some(Duration::from_millis(50));
+
+        // two methods are declared, only one is sent
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0x02, 0x00]).await.unwrap();
+        let read = v5::HandshakeRequest::from_reader(&mut server);
+        let result = transport::with_timeout(timeout, TimeoutPhase::Handshake, read).await;
+        assert!(matches!(result, Err(TimeoutPhase::Handshake)));
+
+        // USERID is not terminated
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0x01, 0x00, 0x50, 192, 0, 2, 1, b'u']).await.unwrap();
+        let read = v4::Request::from_reader(&mut server);
+        let result = transport::with_timeout(timeout, TimeoutPhase::Handshake, read).await;
+        assert!(matches!(result, Err(TimeoutPhase::Handshake)));
+
+        // completed requests are not affected
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0x01, 0x00]).await.unwrap();
+        let read = v5::HandshakeRequest::from_reader(&mut server);
+        let request = transport::with_timeout(timeout, TimeoutPhase::Handshake, read).await;
+        assert!(request.unwrap().unwrap().contains_method(v5::Method::NoAuthentication));
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     convert::TryFrom,
+    fmt,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
 };
 
@@ -11,6 +12,12 @@ use crate::{
     protocol::socks::{consts, error, Address, Error, SocksVersion},
 };
 
+/// Max length of USERID of SOCKS4 request, NUL excluded.
+pub const MAX_USER_ID_LEN: usize = 255;
+
+/// Max length of the domain name of SOCKS4a request, NUL excluded.
+pub const MAX_DOMAIN_NAME_LEN: usize = 255;
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Command {
     TcpConnect,
@@ -38,6 +45,35 @@ impl TryFrom<u8> for Command {
     }
 }
 
+/// Variant of SOCKS version 4 request, SOCKS4a delegates resolving the
+/// destination to the proxy server by sending a domain name with DSTIP of
+/// `0.0.0.x`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Socks4Variant {
+    Socks4,
+    Socks4a,
+}
+
+impl Socks4Variant {
+    #[inline]
+    #[must_use]
+    pub fn of(destination_socket: &Address) -> Self {
+        match destination_socket.as_ref() {
+            HostAddress::Socket(_) => Self::Socks4,
+            HostAddress::DomainName(..) => Self::Socks4a,
+        }
+    }
+}
+
+impl fmt::Display for Socks4Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Socks4 => write!(f, "SOCKS4"),
+            Self::Socks4a => write!(f, "SOCKS4a"),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ReplyField {
     Granted,
@@ -71,14 +107,59 @@ impl TryFrom<u8> for ReplyField {
     }
 }
 
+// USERID of SOCKS4 request, it is terminated by NUL and can not contain NUL
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct UserId(Vec<u8>);
+
+impl UserId {
+    pub fn new(id: Vec<u8>) -> Result<Self, Error> {
+        if id.contains(&0x00) {
+            return Err(Error::BadRequest);
+        }
+        Ok(Self(id))
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] { &self.0 }
+
+    #[inline]
+    #[must_use]
+    pub fn as_str(&self) -> Option<&str> { std::str::from_utf8(&self.0).ok() }
+
+    #[inline]
+    #[must_use]
+    pub fn into_bytes(self) -> Vec<u8> { self.0 }
+}
+
+impl TryFrom<Vec<u8>> for UserId {
+    type Error = Error;
+
+    fn try_from(id: Vec<u8>) -> Result<Self, Self::Error> { Self::new(id) }
+}
+
+impl TryFrom<&[u8]> for UserId {
+    type Error = Error;
+
+    fn try_from(id: &[u8]) -> Result<Self, Self::Error> { Self::new(id.to_vec()) }
+}
+
 #[derive(Clone, Debug)]
 pub struct Request {
     pub command: Command,
+    pub variant: Socks4Variant,
     pub destination_socket: Address,
-    pub id: Vec<u8>,
+    pub id: UserId,
 }
 
 impl Request {
+    /// Creates a request, it is a SOCKS4a request if `destination_socket` is a
+    /// domain name.
+    pub fn new(command: Command, destination_socket: Address, id: Vec<u8>) -> Result<Self, Error> {
+        let variant = Socks4Variant::of(&destination_socket);
+        Ok(Self { command, variant, destination_socket, id: UserId::new(id)? })
+    }
+
     pub async fn from_reader<R>(rdr: &mut R) -> Result<Self, Error>
     where
         R: AsyncRead + Unpin,
@@ -86,37 +167,47 @@ impl Request {
         let command = Command::try_from(rdr.read_u8().await.context(error::ReadStreamSnafu)?)?;
         let port = rdr.read_u16().await.context(error::ReadStreamSnafu)?;
         let mut ip_buf = [0u8; 4];
-        let _ = rdr.read(&mut ip_buf).await.context(error::ReadStreamSnafu)?;
+        let _ = rdr.read_exact(&mut ip_buf).await.context(error::ReadStreamSnafu)?;
 
-        let (id, host) = {
-            let mut buf = [0u8; 128];
-            let _ = rdr.read(&mut buf).await.context(error::ReadStreamSnafu)?;
-
-            let parts: Vec<_> = buf.split(|ch| *ch == 0x00).collect();
-
-            match parts.len() {
-                0 => (Vec::new(), Vec::new()),
-                1 => (parts[0].to_vec(), Vec::new()),
-                _ => (parts[0].to_vec(), parts[1].to_vec()),
-            }
-        };
+        // USERID must be terminated by NUL
+        let id = UserId::new(read_until_nul(rdr, MAX_USER_ID_LEN).await?)?;
 
+        // SOCKS4a, DSTIP of 0.0.0.x is followed by the domain name
         let has_domain_name =
             ip_buf[0] == 0x00 && ip_buf[1] == 0x00 && ip_buf[2] == 0x00 && ip_buf[3] != 0x00;
-        let destination_socket = if has_domain_name {
-            Address::new_domain(&host, port)
+        let (variant, destination_socket) = if has_domain_name {
+            let host = read_until_nul(rdr, MAX_DOMAIN_NAME_LEN).await?;
+            if host.is_empty() {
+                return Err(Error::BadRequest);
+            }
+            (Socks4Variant::Socks4a, Address::new_domain(&host, port))
         } else {
             let host = Ipv4Addr::from(ip_buf);
-            Address::from(SocketAddrV4::new(host, port))
+            (Socks4Variant::Socks4, Address::from(SocketAddrV4::new(host, port)))
         };
 
-        Ok(Self { command, destination_socket, id })
+        Ok(Self { command, variant, destination_socket, id })
     }
 
     #[inline]
     #[must_use]
     pub fn into_bytes(self) -> Vec<u8> { self.to_bytes() }
 
+    #[must_use]
+    pub fn serialized_len(&self) -> usize {
+        let host_len = match self.destination_socket.as_ref() {
+            HostAddress::Socket(_) => 0,
+            HostAddress::DomainName(host, _) => host.len() + 1,
+        };
+        SocksVersion::serialized_len()
+            + std::mem::size_of::<u8>()
+            + std::mem::size_of::<u16>()
+            + 4
+            + self.id.as_bytes().len()
+            + 1
+            + host_len
+    }
+
     #[must_use]
     pub fn to_bytes(&self) -> Vec<u8> {
         // +----+----+----+----+----+----+----+----+----+----+....+----+
@@ -126,7 +217,7 @@ impl Request {
         //
         use byteorder::{BigEndian, WriteBytesExt};
 
-        let mut buf = Vec::with_capacity(128);
+        let mut buf = Vec::with_capacity(self.serialized_len());
 
         // version
         buf.push(SocksVersion::V4.into());
@@ -143,7 +234,7 @@ impl Request {
                 SocketAddr::V4(socket) => {
                     buf.extend(&socket.ip().octets());
                     // user ID
-                    buf.extend(&self.id);
+                    buf.extend(self.id.as_bytes());
                     buf.push(0x00);
                 }
                 SocketAddr::V6(_) => unreachable!(),
@@ -152,7 +243,7 @@ impl Request {
                 buf.extend(&[0x00, 0x00, 0x00, 0x07]);
 
                 // user ID
-                buf.extend(&self.id);
+                buf.extend(self.id.as_bytes());
                 buf.push(0x00);
 
                 // host
@@ -161,6 +252,7 @@ impl Request {
             }
         };
 
+        debug_assert_eq!(buf.len(), self.serialized_len());
         buf
     }
 }
@@ -221,6 +313,12 @@ impl Reply {
     #[must_use]
     pub fn into_bytes(self) -> Vec<u8> { self.to_bytes() }
 
+    #[inline]
+    #[must_use]
+    pub const fn serialized_len() -> usize {
+        std::mem::size_of::<u8>() + std::mem::size_of::<u8>() + std::mem::size_of::<u16>() + 4
+    }
+
     #[must_use]
     pub fn to_bytes(&self) -> Vec<u8> {
         // +----+----+----+----+----+----+----+----+
@@ -229,7 +327,7 @@ impl Reply {
         //   1    1      2              4
         use byteorder::{BigEndian, WriteBytesExt};
 
-        let mut buf = Vec::with_capacity(8);
+        let mut buf = Vec::with_capacity(Self::serialized_len());
 
         // version
         buf.push(0x00);
@@ -243,9 +341,146 @@ impl Reply {
         // destination IP
         buf.extend(&self.destination_socket.ip().octets());
 
+        debug_assert_eq!(buf.len(), Self::serialized_len());
         buf
     }
 }
 
+// reads a field terminated by NUL one byte at a time, so that nothing after
+// the NUL is consumed, fails if the field is longer than `max_len` or the
+// stream ends before the NUL
+async fn read_until_nul<R>(rdr: &mut R, max_len: usize) -> Result<Vec<u8>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        match rdr.read_u8().await {
+            Ok(0x00) => return Ok(field),
+            Ok(_) if field.len() >= max_len => return Err(Error::BadRequest),
+            Ok(byte) => field.push(byte),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::BadRequest)
+            }
+            Err(source) => return Err(Error::ReadStream { source }),
+        }
+    }
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::{
+        Command, Reply, Request, Socks4Variant, UserId, MAX_DOMAIN_NAME_LEN, MAX_USER_ID_LEN,
+    };
+    use crate::{
+        common::HostAddress,
+        protocol::socks::{Address, Error},
+    };
+
+    fn destination_socket() -> Address { Address::from(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80)) }
+
+    #[test]
+    fn reject_user_id_with_nul() {
+        assert!(matches!(UserId::new(b"us\0er".to_vec()), Err(Error::BadRequest)));
+        assert!(matches!(
+            Request::new(Command::TcpConnect, destination_socket(), b"user\0".to_vec()),
+            Err(Error::BadRequest)
+        ));
+    }
+
+    #[test]
+    fn user_id_as_str() {
+        assert_eq!(UserId::new(b"user".to_vec()).unwrap().as_str(), Some("user"));
+        assert_eq!(UserId::new(vec![0xff, 0xfe]).unwrap().as_str(), None);
+    }
+
+    #[tokio::test]
+    async fn user_id_round_trip() {
+        for destination_socket in [destination_socket(), Address::new_domain(b"localhost", 80)] {
+            let request =
+                Request::new(Command::TcpConnect, destination_socket, b"user".to_vec()).unwrap();
+
+            // skip the version byte, it is consumed before parsing request
+            let buf = request.to_bytes();
+            let parsed = Request::from_reader(&mut &buf[1..]).await.unwrap();
+            assert_eq!(parsed.command, request.command);
+            assert_eq!(parsed.variant, request.variant);
+            assert_eq!(parsed.destination_socket, request.destination_socket);
+            assert_eq!(parsed.id.as_str(), Some("user"));
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_socks4_request() {
+        let buf = [0x01, 0x00, 0x50, 192, 0, 2, 1, b'u', b's', b'e', b'r', 0x00, b'G', b'E', b'T'];
+        let mut reader = &buf[..];
+        let request = Request::from_reader(&mut reader).await.unwrap();
+        assert_eq!(request.command, Command::TcpConnect);
+        assert_eq!(request.variant, Socks4Variant::Socks4);
+        assert_eq!(request.destination_socket.to_string(), "192.0.2.1:80");
+        assert_eq!(request.id.as_str(), Some("user"));
+        // data following the request is left for the next phase
+        assert_eq!(reader, b"GET");
+    }
+
+    #[tokio::test]
+    async fn parse_socks4a_request_with_long_host_name() {
+        let host = format!("{}.example.com", "a".repeat(200));
+        let mut buf = vec![0x01, 0x01, 0xbb, 0x00, 0x00, 0x00, 0x01, 0x00];
+        buf.extend(host.as_bytes());
+        buf.push(0x00);
+        buf.extend(b"trailing");
+
+        let mut reader = &buf[..];
+        let request = Request::from_reader(&mut reader).await.unwrap();
+        assert_eq!(request.variant, Socks4Variant::Socks4a);
+        assert_eq!(
+            request.destination_socket.as_ref(),
+            &HostAddress::DomainName(host.clone(), 443)
+        );
+        assert_eq!(request.id.as_bytes(), b"");
+        assert_eq!(reader, b"trailing");
+
+        let mut buf = vec![0x01, 0x01, 0xbb, 0x00, 0x00, 0x00, 0x01, 0x00];
+        buf.extend(vec![b'a'; MAX_DOMAIN_NAME_LEN + 1]);
+        buf.push(0x00);
+        assert!(matches!(Request::from_reader(&mut &buf[..]).await, Err(Error::BadRequest)));
+    }
+
+    #[tokio::test]
+    async fn reject_over_length_user_id() {
+        let mut buf = vec![0x01, 0x00, 0x50, 192, 0, 2, 1];
+        buf.extend(vec![b'u'; MAX_USER_ID_LEN]);
+        buf.push(0x00);
+        let request = Request::from_reader(&mut &buf[..]).await.unwrap();
+        assert_eq!(request.id.as_bytes().len(), MAX_USER_ID_LEN);
+
+        let mut buf = vec![0x01, 0x00, 0x50, 192, 0, 2, 1];
+        buf.extend(vec![b'u'; MAX_USER_ID_LEN + 1]);
+        buf.push(0x00);
+        assert!(matches!(Request::from_reader(&mut &buf[..]).await, Err(Error::BadRequest)));
+    }
+
+    #[tokio::test]
+    async fn reject_unterminated_user_id() {
+        let buf = [0x01, 0x00, 0x50, 0x7f, 0x00, 0x00, 0x01, b'u', b's', b'e', b'r'];
+        assert!(matches!(Request::from_reader(&mut &buf[..]).await, Err(Error::BadRequest)));
+    }
+
+    #[test]
+    fn serialized_len_matches_bytes() {
+        let addresses = [
+            destination_socket(),
+            Address::from(HostAddress::DomainName("example.com".to_string(), 80)),
+        ];
+        for address in addresses {
+            let request = Request::new(Command::TcpConnect, address, b"user".to_vec()).unwrap();
+            assert_eq!(request.serialized_len(), request.to_bytes().len());
+        }
+
+        let reply = Reply::granted(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80));
+        assert_eq!(Reply::serialized_len(), reply.to_bytes().len());
+    }
+}
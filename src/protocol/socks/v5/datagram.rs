@@ -8,8 +8,36 @@ use crate::{
     protocol::socks::{error, Address, AddressRef, AddressType, Error, SocksVersion},
 };
 
-// Datagram is the UDP packet
-#[derive(Clone, Debug)]
+/// Encodes and decodes packets relayed by UDP associate, the default is
+/// [`PlainDatagramCodec`] defined in RFC 1928. Alternative codecs allow
+/// wrapping packets in another layer, e.g. for obfuscation.
+pub trait DatagramCodec: Send + Sync {
+    fn decode(&self, buf: &[u8]) -> Result<Datagram, Error>;
+
+    fn encode(&self, datagram: Datagram) -> Vec<u8>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlainDatagramCodec;
+
+impl DatagramCodec for PlainDatagramCodec {
+    #[inline]
+    fn decode(&self, buf: &[u8]) -> Result<Datagram, Error> { Datagram::from_bytes(buf) }
+
+    #[inline]
+    fn encode(&self, datagram: Datagram) -> Vec<u8> { datagram.into_bytes() }
+}
+
+/// UDP packet relayed by UDP associate, which is encoded as
+///
+/// ```text
+/// +-----+------+------+----------+----------+----------+
+/// | RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +-----+------+------+----------+----------+----------+
+/// |  2  |  1   |  1   | Variable |    2     | Variable |
+/// +-----+------+------+----------+----------+----------+
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Datagram {
     frag: u8,
     destination_socket: Address,
@@ -23,6 +51,14 @@ impl Datagram {
         Self { frag, destination_socket, data }
     }
 
+    /// Creates a datagram sending `data` to `destination`.
+    #[inline]
+    #[must_use]
+    pub fn with_destination(frag: u8, destination: HostAddress, data: &[u8]) -> Self {
+        Self::new(frag, Address::from(destination), BytesMut::from(data))
+    }
+
+    /// Decodes a datagram, `RSV` must be zero.
     pub fn from_bytes(input: &[u8]) -> Result<Self, Error> {
         use std::io::{Cursor, Read};
 
@@ -65,16 +101,29 @@ impl Datagram {
                 }
             };
 
-        let mut data = BytesMut::new();
-        input.read(&mut data[..]).context(error::ReadStreamSnafu)?;
+        let position = usize::try_from(input.position()).map_err(|_| Error::BadRequest)?;
+        let data = BytesMut::from(&input.into_inner()[position..]);
         Ok(Self { frag, destination_socket, data })
     }
 
     #[inline]
     #[must_use]
-    pub fn into_bytes(self) -> Vec<u8> {
+    pub fn serialized_len(&self) -> usize {
+        std::mem::size_of::<u16>()
+            + std::mem::size_of_val(&self.frag)
+            + self.destination_socket.serialized_len(SocksVersion::V5)
+            + self.data.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn into_bytes(self) -> Vec<u8> { self.to_bytes() }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
         let mut buf = self.header_internal(true);
         buf.extend(&self.data);
+        debug_assert_eq!(buf.len(), self.serialized_len());
         buf
     }
 
@@ -0,0 +1,204 @@
+use std::convert::TryFrom;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::protocol::socks::{consts, Error};
+
+/// Type of GSS-API messages exchanged after method `GSSAPI` is selected, see
+/// RFC 1961.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum GssapiMessageType {
+    /// Token of security context establishment
+    Authentication,
+
+    /// Token of per-message protection level negotiation
+    ProtectionLevel,
+
+    /// User data encapsulated after negotiation
+    Encapsulation,
+
+    /// Context establishment or negotiation failed
+    Abort,
+}
+
+impl TryFrom<u8> for GssapiMessageType {
+    type Error = Error;
+
+    fn try_from(ty: u8) -> Result<Self, Error> {
+        match ty {
+            consts::SOCKS5_GSSAPI_MSG_AUTHENTICATION => Ok(Self::Authentication),
+            consts::SOCKS5_GSSAPI_MSG_PROTECTION_LEVEL => Ok(Self::ProtectionLevel),
+            consts::SOCKS5_GSSAPI_MSG_ENCAPSULATION => Ok(Self::Encapsulation),
+            consts::SOCKS5_GSSAPI_MSG_ABORT => Ok(Self::Abort),
+            ty => Err(Error::InvalidGssapiMessageType { ty }),
+        }
+    }
+}
+
+impl From<GssapiMessageType> for u8 {
+    fn from(val: GssapiMessageType) -> Self {
+        match val {
+            GssapiMessageType::Authentication => consts::SOCKS5_GSSAPI_MSG_AUTHENTICATION,
+            GssapiMessageType::ProtectionLevel => consts::SOCKS5_GSSAPI_MSG_PROTECTION_LEVEL,
+            GssapiMessageType::Encapsulation => consts::SOCKS5_GSSAPI_MSG_ENCAPSULATION,
+            GssapiMessageType::Abort => consts::SOCKS5_GSSAPI_MSG_ABORT,
+        }
+    }
+}
+
+/// Per-message protection applied to user data after context establishment.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ProtectionLevel {
+    /// Per-message integrity
+    Integrity,
+
+    /// Per-message integrity and confidentiality
+    Confidentiality,
+
+    /// Selective per-message protection, decided by the sender of each message
+    SelectivePerMessage,
+}
+
+impl TryFrom<u8> for ProtectionLevel {
+    type Error = Error;
+
+    fn try_from(level: u8) -> Result<Self, Error> {
+        match level {
+            consts::SOCKS5_GSSAPI_PROTECTION_INTEGRITY => Ok(Self::Integrity),
+            consts::SOCKS5_GSSAPI_PROTECTION_CONFIDENTIALITY => Ok(Self::Confidentiality),
+            consts::SOCKS5_GSSAPI_PROTECTION_SELECTIVE => Ok(Self::SelectivePerMessage),
+            level => Err(Error::InvalidProtectionLevel { level }),
+        }
+    }
+}
+
+impl From<ProtectionLevel> for u8 {
+    fn from(val: ProtectionLevel) -> Self {
+        match val {
+            ProtectionLevel::Integrity => consts::SOCKS5_GSSAPI_PROTECTION_INTEGRITY,
+            ProtectionLevel::Confidentiality => consts::SOCKS5_GSSAPI_PROTECTION_CONFIDENTIALITY,
+            ProtectionLevel::SelectivePerMessage => consts::SOCKS5_GSSAPI_PROTECTION_SELECTIVE,
+        }
+    }
+}
+
+impl std::fmt::Display for ProtectionLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integrity => write!(f, "integrity"),
+            Self::Confidentiality => write!(f, "confidentiality"),
+            Self::SelectivePerMessage => write!(f, "selective per-message"),
+        }
+    }
+}
+
+// GssapiMessage is the message exchanged in GSS-API method
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GssapiMessage {
+    pub message_type: GssapiMessageType,
+    pub token: Vec<u8>,
+}
+
+impl GssapiMessage {
+    /// Header of messages except `Abort`, which consists of version and type
+    /// only.
+    pub const HEADER_LEN: usize = 4;
+
+    #[inline]
+    #[must_use]
+    pub const fn new(message_type: GssapiMessageType, token: Vec<u8>) -> Self {
+        Self { message_type, token }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn abort() -> Self {
+        Self { message_type: GssapiMessageType::Abort, token: Vec::new() }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn serialized_len(&self) -> usize {
+        match self.message_type {
+            GssapiMessageType::Abort => 2,
+            _ => Self::HEADER_LEN + self.token.len(),
+        }
+    }
+
+    /// Serializes the message, token longer than 65535 bytes is truncated.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // +------+------+------+.......................+
+        // + ver  | mtyp | len  |       token           |
+        // +------+------+------+.......................+
+        // + 0x01 | 0x01 | 0x02 | up to 2^16 - 1 octets |
+        // +------+------+------+.......................+
+        let mut buf = Vec::with_capacity(self.serialized_len());
+        buf.push(consts::SOCKS5_GSSAPI_VERSION);
+        buf.push(self.message_type.into());
+        if self.message_type != GssapiMessageType::Abort {
+            let len = self.token.len().min(usize::from(u16::MAX));
+            buf.extend((len as u16).to_be_bytes());
+            buf.extend(&self.token[..len]);
+        }
+        debug_assert_eq!(buf.len(), self.serialized_len());
+        buf
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn into_bytes(self) -> Vec<u8> { self.to_bytes() }
+
+    pub async fn from_reader<R>(reader: &mut R) -> Result<Self, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        reader.read_exact(&mut buf).await.map_err(Error::from_read_error)?;
+        if buf[0] != consts::SOCKS5_GSSAPI_VERSION {
+            return Err(Error::InvalidGssapiVersion { version: buf[0] });
+        }
+
+        let message_type = GssapiMessageType::try_from(buf[1])?;
+        if message_type == GssapiMessageType::Abort {
+            return Ok(Self::abort());
+        }
+
+        let len = reader.read_u16().await.map_err(Error::from_read_error)?;
+        let mut token = vec![0u8; usize::from(len)];
+        reader.read_exact(&mut token).await.map_err(Error::from_read_error)?;
+        Ok(Self { message_type, token })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GssapiMessage, GssapiMessageType, ProtectionLevel};
+    use crate::protocol::socks::Error;
+
+    #[tokio::test]
+    async fn message_round_trip() {
+        for message in [
+            GssapiMessage::new(GssapiMessageType::Authentication, b"token".to_vec()),
+            GssapiMessage::new(GssapiMessageType::ProtectionLevel, vec![0x01]),
+            GssapiMessage::new(GssapiMessageType::Encapsulation, Vec::new()),
+            GssapiMessage::abort(),
+        ] {
+            let buf = message.to_bytes();
+            assert_eq!(buf.len(), message.serialized_len());
+            assert_eq!(GssapiMessage::from_reader(&mut &buf[..]).await.unwrap(), message);
+        }
+
+        let buf = [0x01, 0x04, 0x00, 0x00];
+        assert!(matches!(
+            GssapiMessage::from_reader(&mut &buf[..]).await,
+            Err(Error::InvalidGssapiMessageType { ty: 0x04 })
+        ));
+        let buf = [0x01, 0x01, 0x00, 0x02, 0x00];
+        assert!(matches!(
+            GssapiMessage::from_reader(&mut &buf[..]).await,
+            Err(Error::UnexpectedEof)
+        ));
+        assert!(ProtectionLevel::try_from(0x04).is_err());
+    }
+}
@@ -1,11 +1,15 @@
 mod datagram;
+mod gssapi;
 
 use std::{collections::HashSet, convert::TryFrom};
 
 use snafu::ResultExt;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-pub use self::datagram::Datagram;
+pub use self::{
+    datagram::{Datagram, DatagramCodec, PlainDatagramCodec},
+    gssapi::{GssapiMessage, GssapiMessageType, ProtectionLevel},
+};
 use crate::{
     authentication::AuthenticationMethod,
     protocol::socks::{consts, error, Address, AddressType, Error, SocksCommand, SocksVersion},
@@ -18,7 +22,7 @@ pub enum Method {
     NoAuthentication,
 
     /// GSSAPI is gssapi method
-    GSSAPI, // MUST support // todo
+    GSSAPI, // MUST support
 
     /// UsernamePassword is username/assword auth method
     UsernamePassword, // SHOULD support
@@ -42,7 +46,10 @@ impl From<AuthenticationMethod> for Method {
     fn from(method: AuthenticationMethod) -> Self {
         match method {
             AuthenticationMethod::NoAuthentication => Self::NoAuthentication,
-            AuthenticationMethod::UsernamePassword => Self::UsernamePassword,
+            AuthenticationMethod::Gssapi => Self::GSSAPI,
+            AuthenticationMethod::UsernamePassword | AuthenticationMethod::Token => {
+                Self::UsernamePassword
+            }
         }
     }
 }
@@ -141,6 +148,11 @@ impl HandshakeRequest {
         Self { methods }
     }
 
+    #[must_use]
+    pub fn from_auth_methods(methods: &[AuthenticationMethod]) -> Self {
+        Self { methods: methods.iter().copied().map(Method::from).collect() }
+    }
+
     pub async fn from_reader<R>(client: &mut R) -> Result<Self, Error>
     where
         R: AsyncRead + Unpin,
@@ -151,9 +163,17 @@ impl HandshakeRequest {
         }
 
         let mut buf = vec![0u8; nmethods as usize];
-        client.read_exact(&mut buf).await.context(error::ReadStreamSnafu)?;
+        client.read_exact(&mut buf).await.map_err(Error::from_read_error)?;
+
+        let methods: HashSet<_> = buf
+            .into_iter()
+            .map(Method::from)
+            .filter(|method| *method != Method::NotAcceptable)
+            .collect();
+        if methods.is_empty() {
+            return Err(Error::BadRequest);
+        }
 
-        let methods = buf.into_iter().map(Method::from).collect();
         tracing::debug!(
             "Got NegotiationRequest: {:?} {} {:?}",
             SocksVersion::V5,
@@ -181,6 +201,7 @@ impl HandshakeRequest {
         buf.push(SocksVersion::V5.into());
         buf.push(nmethods);
         buf.extend(methods_vec);
+        debug_assert_eq!(buf.len(), self.serialized_len());
         buf
     }
 
@@ -231,7 +252,11 @@ impl HandshakeReply {
 
     #[inline]
     #[must_use]
-    pub fn to_bytes(&self) -> Vec<u8> { Vec::from([SocksVersion::V5.into(), self.method.into()]) }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let buf = Vec::from([SocksVersion::V5.into(), self.method.into()]);
+        debug_assert_eq!(buf.len(), Self::serialized_len());
+        buf
+    }
 
     #[inline]
     #[must_use]
@@ -251,16 +276,27 @@ impl UserPasswordHandshakeRequest {
     #[inline]
     #[must_use]
     pub fn serialized_len(&self) -> usize {
-        SocksVersion::serialized_len() + self.user_name.len() + self.password.len()
+        UserPasswordVersion::serialized_len()
+            + std::mem::size_of::<u8>()
+            + self.user_name.len()
+            + std::mem::size_of::<u8>()
+            + self.password.len()
     }
 
-    #[inline]
     #[must_use]
     pub fn to_bytes(&self) -> Vec<u8> {
+        // +----+------+----------+------+----------+
+        // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+        // +----+------+----------+------+----------+
+        // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+        // +----+------+----------+------+----------+
         let mut buf = Vec::with_capacity(self.serialized_len());
         buf.push(self.version.into());
+        buf.push(self.user_name.len() as u8);
         buf.extend(&self.user_name);
+        buf.push(self.password.len() as u8);
         buf.extend(&self.password);
+        debug_assert_eq!(buf.len(), self.serialized_len());
         buf
     }
 
@@ -335,12 +371,16 @@ impl UserPasswordHandshakeReply {
     #[inline]
     #[must_use]
     pub const fn serialized_len() -> usize {
-        SocksVersion::serialized_len() + UserPasswordStatus::serialized_len()
+        UserPasswordVersion::serialized_len() + UserPasswordStatus::serialized_len()
     }
 
     #[inline]
     #[must_use]
-    pub fn to_bytes(&self) -> Vec<u8> { vec![self.version.into(), self.status.into()] }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let buf = vec![self.version.into(), self.status.into()];
+        debug_assert_eq!(buf.len(), Self::serialized_len());
+        buf
+    }
 
     #[inline]
     #[must_use]
@@ -402,6 +442,7 @@ impl Request {
         buf.push(self.command.into());
         buf.push(0x00);
         buf.extend(socket_vec);
+        debug_assert_eq!(buf.len(), self.serialized_len());
         buf
     }
 
@@ -455,6 +496,7 @@ impl Reply {
         buf.push(self.reply.into());
         buf.push(0x00);
         buf.extend(socket_vec);
+        debug_assert_eq!(buf.len(), self.serialized_len());
         buf
     }
 
@@ -466,11 +508,26 @@ impl Reply {
         Self { reply: ReplyField::Success, bind_socket }
     }
 
+    #[must_use]
+    pub fn success_empty(address_type: AddressType) -> Self {
+        Self::success(Self::empty_socket(address_type))
+    }
+
     #[must_use]
     pub fn unreachable(address_type: AddressType) -> Self {
         Self { reply: ReplyField::HostUnreachable, bind_socket: Self::empty_socket(address_type) }
     }
 
+    #[must_use]
+    pub fn not_allowed(address_type: AddressType) -> Self {
+        Self { reply: ReplyField::NotAllowed, bind_socket: Self::empty_socket(address_type) }
+    }
+
+    #[must_use]
+    pub fn ttl_expired(address_type: AddressType) -> Self {
+        Self { reply: ReplyField::TTLExpired, bind_socket: Self::empty_socket(address_type) }
+    }
+
     #[must_use]
     pub fn not_supported(address_type: AddressType) -> Self {
         Self {
@@ -479,6 +536,14 @@ impl Reply {
         }
     }
 
+    #[must_use]
+    pub fn address_not_supported(address_type: AddressType) -> Self {
+        Self {
+            reply: ReplyField::AddressNotSupported,
+            bind_socket: Self::empty_socket(address_type),
+        }
+    }
+
     #[inline]
     fn empty_socket(address_type: AddressType) -> Address {
         match address_type {
@@ -622,4 +687,145 @@ impl UserPasswordStatus {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use bytes::BytesMut;
+
+    use super::{
+        Command, Datagram, HandshakeReply, HandshakeRequest, Method, Reply, Request,
+        UserPasswordHandshakeReply, UserPasswordHandshakeRequest, UserPasswordVersion,
+    };
+    use crate::{
+        authentication::AuthenticationMethod,
+        common::HostAddress,
+        protocol::socks::{Address, Error},
+    };
+
+    fn addresses() -> Vec<Address> {
+        vec![
+            Address::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80))),
+            Address::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 443))),
+            Address::from(HostAddress::DomainName("example.com".to_string(), 8080)),
+        ]
+    }
+
+    #[tokio::test]
+    async fn request_with_invalid_address_type() {
+        let buf = [0x05, 0x01, 0x00, 0x07, 192, 0, 2, 1, 0x00, 0x50];
+        let mut reader = &buf[..];
+        let result = Request::from_reader(&mut reader).await;
+        assert!(matches!(result, Err(Error::InvalidAddressType { ty: 0x07 })));
+        // the address type is rejected before any address bytes are consumed
+        assert_eq!(reader, &buf[4..]);
+
+        let buf = [0x05, 0x03, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50];
+        let request = Request::from_reader(&mut &buf[..]).await.unwrap();
+        assert_eq!(request.command, Command::UdpAssociate);
+        assert_eq!(request.destination_socket.to_string(), "192.0.2.1:80");
+    }
+
+    #[tokio::test]
+    async fn truncated_handshake_request() {
+        // claims 3 methods but only 1 is sent before closing
+        let buf = [0x03, 0x00];
+        let result = HandshakeRequest::from_reader(&mut &buf[..]).await;
+        assert!(matches!(result, Err(Error::UnexpectedEof)));
+    }
+
+    #[tokio::test]
+    async fn handshake_request_without_valid_methods() {
+        let buf = [0x02, 0x80, 0xff];
+        let result = HandshakeRequest::from_reader(&mut &buf[..]).await;
+        assert!(matches!(result, Err(Error::BadRequest)));
+    }
+
+    #[test]
+    fn handshake_request_from_auth_methods() {
+        let request = HandshakeRequest::from_auth_methods(&[
+            AuthenticationMethod::NoAuthentication,
+            AuthenticationMethod::UsernamePassword,
+            AuthenticationMethod::NoAuthentication,
+        ]);
+        assert!(request.contains_method(Method::NoAuthentication));
+        assert!(request.contains_method(Method::UsernamePassword));
+        assert!(!request.contains_method(Method::GSSAPI));
+        assert_eq!(request.into_bytes(), vec![0x05, 0x02, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn serialized_len_matches_bytes() {
+        let request =
+            HandshakeRequest::new(vec![Method::NoAuthentication, Method::UsernamePassword]);
+        assert_eq!(request.serialized_len(), request.to_bytes().len());
+
+        let reply = HandshakeReply::new(Method::UsernamePassword);
+        assert_eq!(HandshakeReply::serialized_len(), reply.to_bytes().len());
+
+        let request = UserPasswordHandshakeRequest {
+            version: UserPasswordVersion::V1,
+            user_name: b"user".to_vec(),
+            password: b"secret".to_vec(),
+        };
+        assert_eq!(request.serialized_len(), request.to_bytes().len());
+
+        let reply = UserPasswordHandshakeReply::success();
+        assert_eq!(UserPasswordHandshakeReply::serialized_len(), reply.to_bytes().len());
+
+        for address in addresses() {
+            let request =
+                Request { command: Command::TcpConnect, destination_socket: address.clone() };
+            assert_eq!(request.serialized_len(), request.to_bytes().len(), "{address}");
+
+            let reply = Reply::success(address.clone());
+            assert_eq!(reply.serialized_len(), reply.to_bytes().len(), "{address}");
+
+            let datagram = Datagram::new(0, address.clone(), BytesMut::from(&b"payload"[..]));
+            assert_eq!(datagram.serialized_len(), datagram.into_bytes().len(), "{address}");
+        }
+    }
+
+    #[test]
+    fn datagram_round_trip() {
+        for (frag, address) in addresses().into_iter().enumerate() {
+            let frag = u8::try_from(frag).unwrap();
+            let datagram = Datagram::with_destination(frag, address.as_ref().clone(), b"payload");
+            let buf = datagram.to_bytes();
+            assert_eq!(&buf[..3], &[0x00, 0x00, frag], "{address}");
+            assert!(buf.ends_with(b"payload"), "{address}");
+
+            let parsed = Datagram::from_bytes(&buf).unwrap();
+            assert_eq!(parsed, datagram, "{address}");
+            assert_eq!(parsed.frag(), frag);
+            assert_eq!(parsed.destination_address(), address.as_ref());
+            assert_eq!(parsed.data(), b"payload");
+        }
+
+        let datagram = Datagram::with_destination(
+            0,
+            HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80))),
+            b"",
+        );
+        assert_eq!(datagram.to_bytes(), [0x00, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50]);
+
+        // RSV must be zero
+        let buf = [0x00, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50];
+        assert!(matches!(Datagram::from_bytes(&buf), Err(Error::BadRequest)));
+        // truncated address
+        assert!(Datagram::from_bytes(&[0x00, 0x00, 0x00, 0x01, 192, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn user_password_handshake_request_round_trip() {
+        let request = UserPasswordHandshakeRequest {
+            version: UserPasswordVersion::V1,
+            user_name: b"user".to_vec(),
+            password: b"secret".to_vec(),
+        };
+        let buf = request.to_bytes();
+        assert_eq!(buf, b"\x01\x04user\x06secret");
+
+        let parsed = UserPasswordHandshakeRequest::from_reader(&mut &buf[..]).await.unwrap();
+        assert_eq!(parsed, request);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::server::Error;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AcceptBackoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        Self { initial_delay: Duration::from_millis(5), max_delay: Duration::from_secs(1) }
+    }
+}
+
+/// Handle to pause and resume accepting new connections of a server, e.g. for
+/// load shedding. Established connections are not affected.
+#[derive(Clone, Debug, Default)]
+pub struct AcceptControl {
+    paused: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl AcceptControl {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self { Self::default() }
+
+    #[inline]
+    pub fn pause(&self) { self.paused.store(true, Ordering::SeqCst); }
+
+    #[inline]
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_paused(&self) -> bool { self.paused.load(Ordering::SeqCst) }
+
+    pub(crate) async fn wait_resumed(&self) {
+        loop {
+            let notified = self.notify.notified();
+            futures::pin_mut!(notified);
+            // register before checking the flag, so that a `resume` in between is not
+            // missed
+            notified.as_mut().enable();
+            if !self.is_paused() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Errors like `EMFILE` tend to persist for a while, retrying immediately only
+// spins the CPU.
+pub(crate) async fn accept_with_backoff<F, Fut, Stream, Address>(
+    mut accept: F,
+    backoff: AcceptBackoff,
+) -> (Stream, Address)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(Stream, Address), std::io::Error>>,
+{
+    let mut delay = backoff.initial_delay;
+    loop {
+        match accept().await {
+            Ok(accepted) => return accepted,
+            Err(source) => {
+                let err = Error::AcceptTcpStream { source };
+                tracing::warn!("Server error: {err}, retry accepting in {delay:?}");
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2).min(backoff.max_delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use super::{accept_with_backoff, AcceptBackoff};
+
+    #[tokio::test]
+    async fn backoff_on_accept_errors() {
+        let calls = AtomicUsize::new(0);
+        let backoff = AcceptBackoff {
+            initial_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(20),
+        };
+
+        let accept = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<((), ()), _>(std::io::Error::from_raw_os_error(24))
+        };
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), accept_with_backoff(accept, backoff))
+                .await;
+        assert!(result.is_err());
+
+        // 5ms + 10ms + 20ms + 20ms + 20ms + 20ms ...
+        let calls = calls.load(Ordering::SeqCst);
+        assert!((2..=8).contains(&calls), "accept is called {calls} times");
+    }
+
+    #[tokio::test]
+    async fn reset_after_accept() {
+        let calls = AtomicUsize::new(0);
+        let backoff = AcceptBackoff {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+        };
+        // 4 errors before the first accept, 1 error before the second one
+        let accept = || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0..=3 | 5 => Err(std::io::Error::from_raw_os_error(24)),
+                n => Ok((n, ())),
+            }
+        };
+
+        // 10ms + 20ms + 40ms + 80ms
+        let started = Instant::now();
+        assert_eq!(accept_with_backoff(accept, backoff).await, (4, ()));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        // the delay starts over from 10ms instead of growing to 160ms
+        let started = Instant::now();
+        assert_eq!(accept_with_backoff(accept, backoff).await, (6, ()));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(10));
+        assert!(elapsed < Duration::from_millis(100), "accept is retried in {elapsed:?}");
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Limits number of concurrent connections from each source IP.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionLimiter {
+    max_connections_per_ip: Option<usize>,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn new(max_connections_per_ip: Option<usize>) -> Self {
+        Self { max_connections_per_ip, connections: Arc::default() }
+    }
+
+    /// Returns `None` if `ip` already reaches the limit, otherwise the returned
+    /// guard holds a connection slot until it is dropped.
+    pub(crate) fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let ip = ip.to_canonical();
+        let mut connections = self.connections.lock().expect("connection table is poisoned");
+        // refused IPs are not inserted, which would be never removed
+        let count = connections.get(&ip).copied().unwrap_or_default();
+        if self.max_connections_per_ip.is_some_and(|max| count >= max) {
+            return None;
+        }
+
+        let _prev_count = connections.insert(ip, count + 1);
+        Some(ConnectionGuard { ip, connections: self.connections.clone() })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().expect("connection table is poisoned");
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::ConnectionLimiter;
+
+    #[test]
+    fn refuse_connections_beyond_limit() {
+        let limiter = ConnectionLimiter::new(Some(2));
+        let abusive = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::from(Ipv4Addr::new(192, 0, 2, 2));
+
+        let first = limiter.try_acquire(abusive).unwrap();
+        let _second = limiter.try_acquire(abusive).unwrap();
+        assert!(limiter.try_acquire(abusive).is_none());
+
+        let _other = limiter.try_acquire(other).unwrap();
+
+        // closing a connection releases its slot
+        drop(first);
+        assert!(limiter.try_acquire(abusive).is_some());
+    }
+
+    #[test]
+    fn refuse_all_connections() {
+        let limiter = ConnectionLimiter::new(Some(0));
+        let ip = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(limiter.try_acquire(ip).is_none());
+        assert!(limiter.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remove_released_ips() {
+        let limiter = ConnectionLimiter::new(Some(1));
+        let ip = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let guard = limiter.try_acquire(ip).unwrap();
+        assert!(limiter.try_acquire(ip).is_none());
+        drop(guard);
+        assert!(limiter.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = ConnectionLimiter::new(None);
+        let ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let guards: Vec<_> = (0..100).map(|_| limiter.try_acquire(ip)).collect();
+        assert!(guards.iter().all(Option::is_some));
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+/// Waits for `connections` to finish within `drain_timeout` after the server
+/// stops accepting, and aborts the remaining ones. Connections are aborted at
+/// once if `drain_timeout` is `None`.
+pub(crate) async fn drain_connections(
+    mut connections: JoinSet<()>,
+    drain_timeout: Option<Duration>,
+) {
+    if let Some(drain_timeout) = drain_timeout {
+        if !connections.is_empty() {
+            tracing::info!("Draining {} connections", connections.len());
+        }
+        let drained = tokio::time::timeout(drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                "Abort {} connections which are not finished in {drain_timeout:?}",
+                connections.len()
+            );
+        }
+    }
+    connections.shutdown().await;
+}
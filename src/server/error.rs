@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use snafu::Snafu;
 
 #[derive(Debug, Snafu)]
@@ -8,4 +10,19 @@ pub enum Error {
 
     #[snafu(display("Could not accept TCP connection, error: {}", source))]
     AcceptTcpStream { source: std::io::Error },
+
+    #[snafu(display("Could not open access log {}, error: {source}", file_path.display()))]
+    OpenAccessLog { source: std::io::Error, file_path: PathBuf },
+
+    #[snafu(display("Could not open block page {}, error: {source}", file_path.display()))]
+    OpenBlockPage { source: std::io::Error, file_path: PathBuf },
+
+    #[snafu(display("Could not load TLS certificate {}, error: {source}", file_path.display()))]
+    LoadTlsCertificate { source: rustls_pki_types::pem::Error, file_path: PathBuf },
+
+    #[snafu(display("Could not load TLS private key {}, error: {source}", file_path.display()))]
+    LoadTlsPrivateKey { source: rustls_pki_types::pem::Error, file_path: PathBuf },
+
+    #[snafu(display("Could not configure TLS, error: {source}"))]
+    ConfigureTls { source: tokio_rustls::rustls::Error },
 }
@@ -1,31 +1,90 @@
+mod original_destination;
+
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 use futures::FutureExt;
 use snafu::ResultExt;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
 };
 
 use crate::{
     authentication::AuthenticationManager,
-    server::error::{self, Error},
-    service::http::Service,
-    transport::Transport,
+    server::{
+        accept_tls, accept_with_backoff, bind_tcp_listener, drain_connections,
+        error::{self, Error},
+        AcceptBackoff, AcceptControl, ConnectionLimiter, RateLimit, RateLimiter, TlsServerConfig,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    },
+    service::{
+        http::{AccessLog, BlockPage, HttpMetrics, Service},
+        ErrorVerbosity, LogPrivacy,
+    },
+    transport::{TimedStream, Transport},
 };
 
+const TOO_MANY_REQUESTS_RESPONSE: &[u8] =
+    b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n";
+
+const SERVICE_UNAVAILABLE_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ServerOptions {
     pub listen_address: IpAddr,
     pub listen_port: u16,
+    pub accept_backoff: AcceptBackoff,
+    pub log_connection_open: bool,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub connection_rate_limit: Option<RateLimit>,
+    pub access_log: Option<PathBuf>,
+    pub transparent: bool,
+    pub max_uri_length: Option<usize>,
+    pub log_privacy: LogPrivacy,
+    pub error_verbosity: ErrorVerbosity,
+    pub block_page: Option<PathBuf>,
+    pub suppress_identification: bool,
+    /// Time limit of receiving the request header from clients, defaults to
+    /// [`DEFAULT_HANDSHAKE_TIMEOUT`], `None` waits however long it takes.
+    pub handshake_timeout: Option<Duration>,
+    /// Closes connections of clients idle for this duration, connections are
+    /// kept however long they are idle if it is `None`.
+    pub idle_timeout: Option<Duration>,
+    pub drain_timeout: Option<Duration>,
+    pub tls: Option<TlsServerConfig>,
 }
 
 impl Default for ServerOptions {
     fn default() -> Self {
-        Self { listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST), listen_port: 8118 }
+        Self {
+            listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listen_port: 8118,
+            accept_backoff: AcceptBackoff::default(),
+            log_connection_open: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            connection_rate_limit: None,
+            access_log: None,
+            transparent: false,
+            max_uri_length: None,
+            log_privacy: LogPrivacy::default(),
+            error_verbosity: ErrorVerbosity::default(),
+            block_page: None,
+            suppress_identification: false,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            idle_timeout: None,
+            drain_timeout: None,
+            tls: None,
+        }
     }
 }
 
@@ -36,60 +95,201 @@ impl ServerOptions {
     }
 }
 
-pub struct Server {
+pub struct Server<TransportStream = TcpStream> {
     tcp_address: SocketAddr,
+    accept_backoff: AcceptBackoff,
+    accept_control: AcceptControl,
+    log_connection_open: bool,
+    connection_permits: Option<Arc<Semaphore>>,
+    connection_limiter: ConnectionLimiter,
+    rate_limiter: RateLimiter,
+    access_log: Option<PathBuf>,
+    transparent: bool,
+    max_uri_length: Option<usize>,
+    log_privacy: LogPrivacy,
+    error_verbosity: ErrorVerbosity,
+    block_page: Option<PathBuf>,
+    suppress_identification: bool,
+    handshake_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    tls: Option<TlsServerConfig>,
+    metrics: HttpMetrics,
 
-    transport: Arc<Transport<TcpStream>>,
+    transport: Arc<Transport<TransportStream>>,
     authentication_manager: Arc<Mutex<AuthenticationManager>>,
 }
 
-impl Server {
+impl<TransportStream> Server<TransportStream>
+where
+    TransportStream: Unpin + AsyncRead + AsyncWrite + Send + Sync + 'static,
+{
     pub fn new(
         config: ServerOptions,
-        transport: Arc<Transport<TcpStream>>,
+        transport: Arc<Transport<TransportStream>>,
         authentication_manager: Arc<Mutex<AuthenticationManager>>,
     ) -> Self {
         let tcp_address = SocketAddr::new(config.listen_address, config.listen_port);
 
-        Self { tcp_address, transport, authentication_manager }
+        Self {
+            tcp_address,
+            accept_backoff: config.accept_backoff,
+            accept_control: AcceptControl::new(),
+            log_connection_open: config.log_connection_open,
+            connection_permits: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            connection_limiter: ConnectionLimiter::new(config.max_connections_per_ip),
+            rate_limiter: RateLimiter::new(config.connection_rate_limit),
+            access_log: config.access_log,
+            transparent: config.transparent,
+            max_uri_length: config.max_uri_length,
+            log_privacy: config.log_privacy,
+            error_verbosity: config.error_verbosity,
+            block_page: config.block_page,
+            suppress_identification: config.suppress_identification,
+            handshake_timeout: config.handshake_timeout,
+            idle_timeout: config.idle_timeout,
+            drain_timeout: config.drain_timeout,
+            tls: config.tls,
+            metrics: HttpMetrics::new(),
+            transport,
+            authentication_manager,
+        }
     }
 
+    /// Returns a handle to pause and resume accepting new connections.
+    #[must_use]
+    pub fn accept_control(&self) -> AcceptControl { self.accept_control.clone() }
+
+    /// Returns counters of requests handled by this server.
+    #[must_use]
+    pub fn metrics(&self) -> HttpMetrics { self.metrics.clone() }
+
     pub async fn serve_with_shutdown<F: std::future::Future<Output = ()>>(
         self,
         shutdown_signal: F,
     ) -> Result<(), Error> {
-        let tcp_listener =
-            TcpListener::bind(self.tcp_address).await.context(error::BindTcpListenerSnafu)?;
-        tracing::info!("Starting HTTP proxy server at {}", self.tcp_address);
+        let tcp_listener = bind_tcp_listener(self.tcp_address).await?;
+        self.serve_with_listener(tcp_listener, shutdown_signal).await
+    }
+
+    /// Serves connections accepted by `tcp_listener` instead of binding the
+    /// listen address.
+    pub async fn serve_with_listener<F: std::future::Future<Output = ()>>(
+        self,
+        tcp_listener: TcpListener,
+        shutdown_signal: F,
+    ) -> Result<(), Error> {
+        let tls_acceptor = self.tls.as_ref().map(TlsServerConfig::acceptor).transpose()?;
+        let tcp_address = tcp_listener.local_addr().unwrap_or(self.tcp_address);
+        tracing::info!("Starting HTTP proxy server at {tcp_address}");
+
+        let access_log = match self.access_log {
+            Some(file_path) => Some(Arc::new(
+                AccessLog::open(&file_path)
+                    .await
+                    .context(error::OpenAccessLogSnafu { file_path })?,
+            )),
+            None => None,
+        };
 
-        let service = Arc::new(Service::new(self.transport, self.authentication_manager));
+        let service = {
+            let service = Service::new(self.transport, self.authentication_manager)
+                .with_log_connection_open(self.log_connection_open)
+                .with_transparent(self.transparent)
+                .with_log_privacy(self.log_privacy)
+                .with_error_verbosity(self.error_verbosity)
+                .with_suppress_identification(self.suppress_identification)
+                .with_metrics(self.metrics);
+            let service = match access_log {
+                Some(access_log) => service.with_access_log(access_log),
+                None => service,
+            };
+            let service = match self.max_uri_length {
+                Some(max_uri_length) => service.with_max_uri_length(max_uri_length),
+                None => service,
+            };
+            let service = match self.handshake_timeout {
+                Some(handshake_timeout) => service.with_handshake_timeout(handshake_timeout),
+                None => service,
+            };
+            match self.block_page {
+                Some(file_path) => service.with_block_page(Arc::new(
+                    BlockPage::open(&file_path).context(error::OpenBlockPageSnafu { file_path })?,
+                )),
+                None => service,
+            }
+        };
+        let service = Arc::new(service);
 
         let shutdown = shutdown_signal.fuse();
         futures::pin_mut!(shutdown);
 
+        let mut connections = JoinSet::new();
         loop {
-            let stream = futures::select! {
-                stream = tcp_listener.accept().fuse() => stream,
+            let accept = async {
+                self.accept_control.wait_resumed().await;
+                accept_with_backoff(|| tcp_listener.accept(), self.accept_backoff).await
+            };
+            let (socket, socket_addr) = futures::select! {
+                stream = accept.fuse() => stream,
                 _ = shutdown => {
                     tracing::info!("Stopping HTTP server");
                     break;
                 },
             };
 
-            match stream {
-                Ok((socket, socket_addr)) => {
-                    let service = service.clone();
-                    tokio::spawn(async move {
-                        let _n = service.handle(socket, socket_addr).await;
-                    });
-                }
-                Err(source) => {
-                    let err = Error::AcceptTcpStream { source };
-                    tracing::warn!("Server error: {}", err);
+            // reap finished connections
+            while connections.try_join_next().is_some() {}
+
+            if !self.rate_limiter.try_acquire(socket_addr.ip()) {
+                tracing::warn!("Refuse connection from {socket_addr}, too many new connections");
+                // a best effort reply which never blocks accepting, clients over TLS can not
+                // read it
+                if tls_acceptor.is_none() {
+                    let _unused = socket.try_write(TOO_MANY_REQUESTS_RESPONSE);
                 }
+                continue;
             }
+
+            let Some(connection_guard) = self.connection_limiter.try_acquire(socket_addr.ip())
+            else {
+                tracing::warn!("Refuse connection from {socket_addr}, too many connections");
+                continue;
+            };
+
+            let original_destination = if self.transparent {
+                original_destination::original_destination(&socket)
+            } else {
+                None
+            };
+
+            // permits are only tried, so that shutdown never waits for them
+            let Ok(permit) =
+                self.connection_permits.clone().map(Semaphore::try_acquire_owned).transpose()
+            else {
+                tracing::warn!("Refuse connection from {socket_addr}, server is at capacity");
+                if tls_acceptor.is_none() {
+                    let _unused = socket.try_write(SERVICE_UNAVAILABLE_RESPONSE);
+                }
+                continue;
+            };
+            let service = service.clone();
+            let socket = TimedStream::new(socket, self.idle_timeout);
+            let tls_acceptor = tls_acceptor.clone();
+            connections.spawn(async move {
+                let (_connection_guard, _permit) = (connection_guard, permit);
+                let Some(socket) = accept_tls(tls_acceptor.as_ref(), socket, socket_addr).await
+                else {
+                    return;
+                };
+                let _n = service
+                    .handle_with_original_destination(socket, socket_addr, original_destination)
+                    .await;
+            });
         }
 
+        drain_connections(connections, self.drain_timeout).await;
+
         tracing::info!("HTTP Proxy Server stopped");
         Ok(())
     }
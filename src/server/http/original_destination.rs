@@ -0,0 +1,77 @@
+use std::{io, net::SocketAddr};
+
+use tokio::net::TcpStream;
+
+/// Returns destination of a connection redirected by netfilter, e.g. iptables
+/// `REDIRECT` target, via `SO_ORIGINAL_DST`.
+///
+/// Returns `None` if the connection is not redirected or the platform does not
+/// support `SO_ORIGINAL_DST`.
+#[cfg(target_os = "linux")]
+pub(crate) fn original_destination(stream: &TcpStream) -> Option<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let local_addr = stream.local_addr().ok()?;
+    let original_dst = match local_addr {
+        SocketAddr::V4(_) => socket.original_dst(),
+        SocketAddr::V6(_) => socket.original_dst_ipv6(),
+    };
+    redirected_destination(local_addr, original_dst.map(|addr| addr.as_socket()))
+}
+
+// derives the destination from `SO_ORIGINAL_DST` of a connection accepted at
+// `local_addr`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn redirected_destination(
+    local_addr: SocketAddr,
+    original_dst: io::Result<Option<SocketAddr>>,
+) -> Option<SocketAddr> {
+    let addr = match original_dst {
+        Ok(addr) => addr?,
+        Err(err) => {
+            tracing::debug!("Could not get original destination of connection, error: {err}");
+            return None;
+        }
+    };
+
+    // connections not being redirected report their local address
+    (addr != local_addr).then_some(addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn original_destination(_stream: &TcpStream) -> Option<SocketAddr> { None }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{Ipv4Addr, SocketAddr},
+    };
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{original_destination, redirected_destination};
+
+    #[test]
+    fn derive_redirected_destination() {
+        let local_addr = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 3128));
+        let destination = SocketAddr::from((Ipv4Addr::new(198, 51, 100, 1), 80));
+
+        assert_eq!(redirected_destination(local_addr, Ok(Some(destination))), Some(destination));
+        // not redirected
+        assert_eq!(redirected_destination(local_addr, Ok(Some(local_addr))), None);
+        // not an IP socket address
+        assert_eq!(redirected_destination(local_addr, Ok(None)), None);
+        // no entry of netfilter connection tracking
+        let err = io::Error::from_raw_os_error(2);
+        assert_eq!(redirected_destination(local_addr, Err(err)), None);
+    }
+
+    #[tokio::test]
+    async fn connection_not_redirected() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        assert_eq!(original_destination(&stream), None);
+    }
+}
@@ -1,5 +1,25 @@
+mod accept;
+mod connection_limit;
+mod drain;
 pub mod error;
 pub mod http;
+mod rate_limit;
+mod socket_activation;
 pub mod socks;
+mod tls;
 
-pub use self::error::Error;
+use std::time::Duration;
+
+pub(crate) use self::{
+    accept::accept_with_backoff, connection_limit::ConnectionLimiter, drain::drain_connections,
+    rate_limit::RateLimiter, socket_activation::bind_tcp_listener, tls::accept as accept_tls,
+};
+pub use self::{
+    accept::{AcceptBackoff, AcceptControl},
+    error::Error,
+    rate_limit::RateLimit,
+    tls::TlsServerConfig,
+};
+
+/// Default time limit of receiving handshake and request from clients.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
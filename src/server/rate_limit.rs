@@ -0,0 +1,141 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use lru::LruCache;
+
+/// Maximum rate of new connections from each source IP.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct RateLimit {
+    /// Number of connections allowed per second on average.
+    pub per_second: u32,
+
+    /// Number of connections allowed at once after being idle.
+    pub burst: u32,
+}
+
+/// Maximum number of sources tracked by default.
+const DEFAULT_MAX_ENTRIES: usize = 65536;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits the rate of new connections from each source with token buckets,
+/// `None` means unlimited.
+///
+/// IPv6 sources are keyed by their /64 prefix, as a single host usually owns
+/// the whole prefix. At most `max_entries` sources are tracked, the least
+/// recently used one starts over with a full bucket once it is evicted.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Arc<Mutex<LruCache<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Option<RateLimit>) -> Self {
+        Self::with_max_entries(limit, DEFAULT_MAX_ENTRIES)
+    }
+
+    fn with_max_entries(limit: Option<RateLimit>, max_entries: usize) -> Self {
+        let max_entries = NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self { limit, buckets: Arc::new(Mutex::new(LruCache::new(max_entries))) }
+    }
+
+    /// Returns `false` if a new connection from `ip` exceeds the limit.
+    pub(crate) fn try_acquire(&self, ip: IpAddr) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        let now = Instant::now();
+        let burst = f64::from(limit.burst);
+
+        let mut buckets = self.buckets.lock().expect("rate limiter is poisoned");
+        let bucket =
+            buckets.get_or_insert_mut(source_key(ip), || Bucket { tokens: burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * f64::from(limit.per_second)).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize { self.buckets.lock().expect("rate limiter is poisoned").len() }
+}
+
+fn source_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(ip.to_bits() & !u128::from(u64::MAX))),
+        ip @ IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use super::{RateLimit, RateLimiter};
+
+    #[tokio::test]
+    async fn limit_rate_per_ip() {
+        let limiter = RateLimiter::new(Some(RateLimit { per_second: 20, burst: 3 }));
+        let abusive = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::from(Ipv4Addr::new(192, 0, 2, 2));
+
+        let accepted = (0..10).filter(|_| limiter.try_acquire(abusive)).count();
+        assert_eq!(accepted, 3);
+        assert!(limiter.try_acquire(other));
+
+        // tokens are refilled over time
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(limiter.try_acquire(abusive));
+
+        let limiter = RateLimiter::new(None);
+        assert!((0..100).all(|_| limiter.try_acquire(abusive)));
+    }
+
+    #[test]
+    fn bound_tracked_ips() {
+        let limiter = RateLimiter::with_max_entries(Some(RateLimit { per_second: 1, burst: 1 }), 2);
+        for i in 1..=10 {
+            assert!(limiter.try_acquire(IpAddr::from(Ipv4Addr::new(192, 0, 2, i))));
+            assert!(limiter.len() <= 2);
+        }
+
+        // evicted IPs start over with a full bucket
+        assert!(limiter.try_acquire(IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))));
+    }
+
+    #[test]
+    fn share_ipv6_prefix() {
+        let limiter = RateLimiter::new(Some(RateLimit { per_second: 1, burst: 2 }));
+        let first = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 1));
+        let same_prefix = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0xffff, 0, 0, 2));
+        let other_prefix = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 1));
+
+        assert!(limiter.try_acquire(first));
+        assert!(limiter.try_acquire(same_prefix));
+        assert!(!limiter.try_acquire(first));
+        assert!(limiter.try_acquire(other_prefix));
+        assert_eq!(limiter.len(), 2);
+
+        // IPv4-mapped addresses share the bucket of the IPv4 address
+        let ipv4 = Ipv4Addr::new(192, 0, 2, 1);
+        assert!(limiter.try_acquire(IpAddr::from(ipv4)));
+        assert!(limiter.try_acquire(IpAddr::from(ipv4.to_ipv6_mapped())));
+        assert!(!limiter.try_acquire(IpAddr::from(ipv4)));
+    }
+}
@@ -0,0 +1,168 @@
+use std::net::SocketAddr;
+
+use snafu::ResultExt;
+use tokio::net::TcpListener;
+
+use crate::server::{error, Error};
+
+/// Returns the listener passed by systemd socket activation which is bound to
+/// `addr` if there is one, binds `addr` otherwise.
+///
+/// Inherited sockets are matched by their local address, so the
+/// `ListenStream=` of the socket unit has to be the address the server is
+/// configured with; file descriptors which are not TCP listening sockets are
+/// never used.
+pub(crate) async fn bind_tcp_listener(addr: SocketAddr) -> Result<TcpListener, Error> {
+    if let Some(listener) = inherited_listener(addr).context(error::BindTcpListenerSnafu)? {
+        return Ok(listener);
+    }
+
+    TcpListener::bind(addr).await.context(error::BindTcpListenerSnafu)
+}
+
+#[cfg(unix)]
+fn inherited_listener(addr: SocketAddr) -> std::io::Result<Option<TcpListener>> {
+    use std::{
+        os::unix::io::RawFd,
+        sync::{Mutex, OnceLock},
+    };
+
+    static INHERITED: OnceLock<Mutex<Vec<RawFd>>> = OnceLock::new();
+
+    // the variables are only read, modifying the environment while the runtime
+    // runs other threads is unsound; child processes ignore them as
+    // `LISTEN_PID` does not match theirs
+    let inherited = INHERITED.get_or_init(|| {
+        let fds = inherited_fds(std::env::var("LISTEN_PID").ok(), std::env::var("LISTEN_FDS").ok());
+        Mutex::new(fds.collect())
+    });
+
+    let mut fds = inherited.lock().expect("inherited sockets are poisoned");
+    let Some(index) = fds.iter().position(|&fd| is_tcp_listener_on(fd, addr)) else {
+        return Ok(None);
+    };
+    let fd = fds.swap_remove(index);
+
+    // SAFETY: systemd passes `LISTEN_FDS` sockets starting from
+    // `SD_LISTEN_FDS_START` to the process identified by `LISTEN_PID`, which is
+    // this process, `fd` is a TCP listening socket and it is taken only once
+    let listener = unsafe { listener_from_fd(fd)? };
+    tracing::info!("Using listener on {addr} passed by socket activation");
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn inherited_listener(_addr: SocketAddr) -> std::io::Result<Option<TcpListener>> { Ok(None) }
+
+/// Returns the file descriptors passed by systemd if `LISTEN_PID` is the
+/// current process.
+#[cfg(unix)]
+fn inherited_fds(
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+) -> std::ops::Range<std::os::unix::io::RawFd> {
+    // `SD_LISTEN_FDS_START` of `sd_listen_fds(3)`
+    const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+    let fds = listen_pid
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .filter(|&pid| pid == std::process::id())
+        .and_then(|_| listen_fds?.parse::<std::os::unix::io::RawFd>().ok())
+        .unwrap_or(0);
+    LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(fds.max(0))
+}
+
+/// Returns `true` if `fd` is a TCP listening socket bound to `addr`.
+#[cfg(unix)]
+fn is_tcp_listener_on(fd: std::os::unix::io::RawFd, addr: SocketAddr) -> bool {
+    // SAFETY: `fd` is only borrowed for the duration of this call, querying a
+    // file descriptor which is not a socket fails without side effects
+    let fd = unsafe { std::os::unix::io::BorrowedFd::borrow_raw(fd) };
+    let socket = socket2::SockRef::from(&fd);
+
+    let is_bound_stream = socket.r#type().is_ok_and(|ty| ty == socket2::Type::STREAM)
+        && socket.local_addr().ok().and_then(|local| local.as_socket()) == Some(addr);
+    #[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux"))]
+    let is_bound_stream = is_bound_stream && socket.is_listener().unwrap_or(false);
+    is_bound_stream
+}
+
+/// # Safety
+///
+/// `fd` must be an open TCP listening socket owned by nobody else.
+#[cfg(unix)]
+unsafe fn listener_from_fd(fd: std::os::unix::io::RawFd) -> std::io::Result<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let listener = std::net::TcpListener::from_raw_fd(fd);
+    // inherited sockets do not have `FD_CLOEXEC` set
+    socket2::SockRef::from(&listener).set_cloexec(true)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        os::unix::io::{AsRawFd, IntoRawFd},
+    };
+
+    use tokio::net::TcpStream;
+
+    use super::{inherited_fds, is_tcp_listener_on, listener_from_fd};
+
+    #[test]
+    fn parse_environment() {
+        let pid = Some(std::process::id().to_string());
+        assert_eq!(inherited_fds(pid.clone(), Some("1".to_string())), 3..4);
+        assert_eq!(inherited_fds(pid.clone(), Some("2".to_string())), 3..5);
+        assert!(inherited_fds(pid.clone(), Some("0".to_string())).is_empty());
+        assert!(inherited_fds(pid.clone(), Some("-1".to_string())).is_empty());
+        assert!(inherited_fds(pid, None).is_empty());
+        assert!(inherited_fds(None, Some("1".to_string())).is_empty());
+
+        // sockets passed to another process
+        let other_pid = Some(std::process::id().wrapping_add(1).to_string());
+        assert!(inherited_fds(other_pid, Some("1".to_string())).is_empty());
+    }
+
+    #[test]
+    fn match_listening_sockets_by_address() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(is_tcp_listener_on(listener.as_raw_fd(), addr));
+
+        // another address or family
+        let other_port = SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port().wrapping_add(1)));
+        assert!(!is_tcp_listener_on(listener.as_raw_fd(), other_port));
+        let other_family = SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port()));
+        assert!(!is_tcp_listener_on(listener.as_raw_fd(), other_family));
+
+        // not a TCP socket
+        let udp_socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(!is_tcp_listener_on(udp_socket.as_raw_fd(), udp_socket.local_addr().unwrap()));
+
+        // a connected TCP socket is not listening
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert!(!is_tcp_listener_on(accepted.as_raw_fd(), accepted.local_addr().unwrap()));
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn accept_on_inherited_fd() {
+        // simulates the socket passed by systemd
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+
+        // SAFETY: `fd` is released by `listener` above
+        let listener = unsafe { listener_from_fd(fd) }.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, client.local_addr().unwrap());
+    }
+}
@@ -6,45 +6,116 @@ use std::{
 };
 
 use futures::FutureExt;
-use snafu::ResultExt;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
 };
 
 use crate::{
     authentication::AuthenticationManager,
     common::utils::safe_duration,
-    protocol::socks::{SocksCommand, SocksVersion},
-    server::error::{self, Error},
-    service::socks::Service,
-    transport::Transport,
+    protocol::socks::{
+        v5::{DatagramCodec, PlainDatagramCodec},
+        SocksCommand, SocksVersion,
+    },
+    server::{
+        accept_tls, accept_with_backoff, bind_tcp_listener, drain_connections, error::Error,
+        AcceptBackoff, AcceptControl, ConnectionLimiter, RateLimit, RateLimiter, TlsServerConfig,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    },
+    service::{
+        socks::{v5::UdpAssociateManager, DnsPolicy, PortPolicy, Service},
+        ErrorVerbosity, LogPrivacy,
+    },
+    transport::{TimedStream, Transport},
 };
 
 #[derive(Clone, Debug)]
 pub struct ServerOptions {
     pub supported_versions: HashSet<SocksVersion>,
+    /// Accepts plain SOCKS4 requests if `supported_versions` contains
+    /// `SocksVersion::V4`.
+    pub enable_socks4: bool,
+    /// Accepts SOCKS4a requests, i.e. SOCKS4 with domain names, if
+    /// `supported_versions` contains `SocksVersion::V4`.
+    pub enable_socks4a: bool,
     pub supported_commands: HashSet<SocksCommand>,
     pub listen_address: IpAddr,
     pub listen_port: u16,
     pub udp_ports: HashSet<u16>,
+    /// Addresses which UDP sockets of UDP associate are bound to, in addition
+    /// to `udp_ports` of `listen_address`, e.g. addresses of other interfaces
+    /// of a multi-homed host.
+    pub udp_addresses: Vec<SocketAddr>,
+    pub udp_pin_client_source: bool,
+    /// Relays UDP associate datagrams only to and from the declared address if
+    /// it is not the wildcard, see `UdpAssociateManager::with_strict_target`.
+    /// The declared address is then not the client source, which is pinned to
+    /// the first datagram from the client IP regardless of
+    /// `udp_pin_client_source`.
+    pub udp_strict_target: bool,
 
-    pub connection_timeout: Duration,
+    /// Closes connections of clients idle for this duration, connections are
+    /// kept however long they are idle if it is `None`.
+    pub idle_timeout: Option<Duration>,
     pub tcp_keepalive: Duration,
+    /// Relay sockets of UDP associations are closed within this duration after
+    /// the association is torn down.
     pub udp_cache_expiry_duration: Duration,
+    pub accept_backoff: AcceptBackoff,
+    pub log_connection_open: bool,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub connection_rate_limit: Option<RateLimit>,
+    pub dns_policy: DnsPolicy,
+    pub port_policy: PortPolicy,
+    pub log_privacy: LogPrivacy,
+    pub error_verbosity: ErrorVerbosity,
+    pub max_hops: Option<u8>,
+    /// Time limit of receiving handshake and request from clients, clients
+    /// stalling in the middle of them are disconnected, defaults to
+    /// [`DEFAULT_HANDSHAKE_TIMEOUT`], `None` waits however long it takes.
+    pub handshake_timeout: Option<Duration>,
+    /// Time limit of waiting for the inbound connection of TCP Bind, clients
+    /// are replied with TTL expired if it expires, falls back to the connect
+    /// timeout of the transport if it is `None`.
+    pub bind_timeout: Option<Duration>,
+    pub drain_timeout: Option<Duration>,
+    pub tls: Option<TlsServerConfig>,
 }
 
 impl Default for ServerOptions {
     fn default() -> Self {
         Self {
             supported_versions: HashSet::from_iter([SocksVersion::V4, SocksVersion::V5]),
+            enable_socks4: true,
+            enable_socks4a: true,
             supported_commands: HashSet::from_iter([SocksCommand::TcpConnect]),
             listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
             listen_port: 3128,
             udp_ports: HashSet::from_iter([3129]),
-            connection_timeout: Duration::from_secs(10),
+            udp_addresses: Vec::new(),
+            udp_pin_client_source: false,
+            udp_strict_target: false,
+            idle_timeout: None,
             tcp_keepalive: Duration::from_secs(10),
             udp_cache_expiry_duration: Duration::from_secs(10),
+            accept_backoff: AcceptBackoff::default(),
+            log_connection_open: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            connection_rate_limit: None,
+            dns_policy: DnsPolicy::default(),
+            port_policy: PortPolicy::default(),
+            log_privacy: LogPrivacy::default(),
+            error_verbosity: ErrorVerbosity::default(),
+            max_hops: None,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            bind_timeout: None,
+            drain_timeout: None,
+            tls: None,
         }
     }
 }
@@ -56,39 +127,57 @@ impl ServerOptions {
     }
 }
 
-pub struct Server {
+pub struct Server<TransportStream = TcpStream> {
     authentication_manager: Arc<Mutex<AuthenticationManager>>,
-    transport: Arc<Transport<TcpStream>>,
+    transport: Arc<Transport<TransportStream>>,
 
     supported_versions: HashSet<SocksVersion>,
+    enable_socks4: bool,
+    enable_socks4a: bool,
     supported_commands: HashSet<SocksCommand>,
 
     tcp_address: SocketAddr,
-    connection_timeout: Option<Duration>,
+    accept_backoff: AcceptBackoff,
+    accept_control: AcceptControl,
+    log_connection_open: bool,
+    connection_permits: Option<Arc<Semaphore>>,
+    connection_limiter: ConnectionLimiter,
+    rate_limiter: RateLimiter,
+    dns_policy: DnsPolicy,
+    port_policy: PortPolicy,
+    log_privacy: LogPrivacy,
+    error_verbosity: ErrorVerbosity,
+    max_hops: Option<u8>,
+    handshake_timeout: Option<Duration>,
+    bind_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    tls: Option<TlsServerConfig>,
     #[allow(dead_code)]
     tcp_keepalive: Option<Duration>,
 
+    udp_bind_addrs: Vec<SocketAddr>,
+    udp_pin_client_source: bool,
+    udp_strict_target: bool,
+    udp_datagram_codec: Arc<dyn DatagramCodec>,
+    udp_cache_expiry_duration: Duration,
     // FIXME: use `udp_*` fields
     #[allow(dead_code)]
-    udp_address: IpAddr,
-    #[allow(dead_code)]
-    udp_ports: HashSet<u16>,
-    #[allow(dead_code)]
     udp_timeout: Option<Duration>,
     #[allow(dead_code)]
     udp_session_time: Duration,
-    #[allow(dead_code)]
-    udp_cache_expiry_duration: Duration,
 }
 
-impl Server {
+impl<TransportStream> Server<TransportStream>
+where
+    TransportStream: Unpin + AsyncRead + AsyncWrite + Send + Sync + 'static,
+{
     pub fn new(
         config: ServerOptions,
-        transport: Arc<Transport<TcpStream>>,
+        transport: Arc<Transport<TransportStream>>,
         authentication_manager: Arc<Mutex<AuthenticationManager>>,
     ) -> Self {
         let tcp_address = config.listen_socket();
-        let connection_timeout = safe_duration(config.connection_timeout);
         let tcp_keepalive = safe_duration(config.tcp_keepalive);
         let udp_cache_expiry_duration = config.udp_cache_expiry_duration;
 
@@ -100,93 +189,185 @@ impl Server {
             transport,
 
             supported_versions: config.supported_versions,
+            enable_socks4: config.enable_socks4,
+            enable_socks4a: config.enable_socks4a,
             supported_commands: config.supported_commands,
 
             tcp_address,
-            connection_timeout,
+            accept_backoff: config.accept_backoff,
+            accept_control: AcceptControl::new(),
+            log_connection_open: config.log_connection_open,
+            connection_permits: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            connection_limiter: ConnectionLimiter::new(config.max_connections_per_ip),
+            rate_limiter: RateLimiter::new(config.connection_rate_limit),
+            dns_policy: config.dns_policy,
+            port_policy: config.port_policy,
+            log_privacy: config.log_privacy,
+            error_verbosity: config.error_verbosity,
+            max_hops: config.max_hops,
+            handshake_timeout: config.handshake_timeout,
+            bind_timeout: config.bind_timeout,
+            drain_timeout: config.drain_timeout,
+            idle_timeout: config.idle_timeout,
+            tls: config.tls,
             tcp_keepalive,
 
-            udp_address: config.listen_address,
-            udp_ports: config.udp_ports,
+            udp_bind_addrs: config
+                .udp_ports
+                .iter()
+                .map(|port| SocketAddr::new(config.listen_address, *port))
+                .chain(config.udp_addresses)
+                .collect(),
+            udp_pin_client_source: config.udp_pin_client_source,
+            udp_strict_target: config.udp_strict_target,
+            udp_datagram_codec: Arc::new(PlainDatagramCodec),
+            udp_cache_expiry_duration,
             udp_timeout,
             udp_session_time,
-
-            udp_cache_expiry_duration,
         }
     }
 
+    /// Returns a handle to pause and resume accepting new connections.
+    #[must_use]
+    pub fn accept_control(&self) -> AcceptControl { self.accept_control.clone() }
+
+    #[must_use]
+    pub fn with_udp_datagram_codec(mut self, codec: Arc<dyn DatagramCodec>) -> Self {
+        self.udp_datagram_codec = codec;
+        self
+    }
+
     pub async fn serve_with_shutdown<F: std::future::Future<Output = ()>>(
         self,
         shutdown_signal: F,
     ) -> Result<(), Error> {
-        let tcp_listener =
-            TcpListener::bind(self.tcp_address).await.context(error::BindTcpListenerSnafu)?;
-        tracing::info!("Starting SOCKS server at {}", self.tcp_address);
-
-        // FIXME: re-implement `UdpAssociateManager`
-        // let (udp_associate_join_handle, udp_associate_stream_tx) =
-        //     if self.supported_commands.contains(&SocksCommand::UdpAssociate) {
-        //         let resolver = self.transport.resolver().clone();
-        //         let udp_associate_manager = UdpAssociateManager::new(
-        //             self.udp_address,
-        //             self.udp_ports,
-        //             resolver,
-        //             self.udp_cache_expiry_duration,
-        //         );
-        //
-        //         let (tx, join_handle) = udp_associate_manager.serve();
-        //         (Some(join_handle), Some(Mutex::new(tx)))
-        //     } else {
-        //         (None, None)
-        //     };
+        let tcp_listener = bind_tcp_listener(self.tcp_address).await?;
+        self.serve_with_listener(tcp_listener, shutdown_signal).await
+    }
+
+    /// Serves connections accepted by `tcp_listener` instead of binding the
+    /// listen address, UDP associations are still bound to the listen
+    /// address.
+    pub async fn serve_with_listener<F: std::future::Future<Output = ()>>(
+        self,
+        tcp_listener: TcpListener,
+        shutdown_signal: F,
+    ) -> Result<(), Error> {
+        let tls_acceptor = self.tls.as_ref().map(TlsServerConfig::acceptor).transpose()?;
+        let tcp_address = tcp_listener.local_addr().unwrap_or(self.tcp_address);
+        tracing::info!("Starting SOCKS server at {tcp_address}");
+
+        let (udp_associate_join_handle, udp_associate_stream_tx) = if self
+            .supported_commands
+            .contains(&SocksCommand::UdpAssociate)
+        {
+            let resolver = self.transport.resolver();
+            let udp_associate_manager =
+                UdpAssociateManager::new(self.udp_bind_addrs, resolver, self.udp_pin_client_source)
+                    .with_datagram_codec(self.udp_datagram_codec)
+                    .with_destination_filter(self.transport.destination_filter())
+                    .with_port_policy(self.port_policy.clone())
+                    .with_log_privacy(self.log_privacy)
+                    .with_strict_target(self.udp_strict_target)
+                    .with_cache_expiry(self.udp_cache_expiry_duration);
+
+            let (tx, join_handle) = udp_associate_manager.serve();
+            (Some(join_handle), Some(Mutex::new(tx)))
+        } else {
+            (None, None)
+        };
 
         let enable_tcp_connect = self.supported_commands.contains(&SocksCommand::TcpConnect);
         let enable_tcp_bind = self.supported_commands.contains(&SocksCommand::TcpBind);
-        let service = Arc::new(Service::new(
-            self.supported_versions,
-            self.transport.clone(),
-            self.authentication_manager,
-            enable_tcp_connect,
-            enable_tcp_bind,
-            None, // udp_associate_stream_tx
-        ));
+        let service = {
+            let service = Service::new(
+                self.supported_versions,
+                self.transport.clone(),
+                self.authentication_manager,
+                enable_tcp_connect,
+                enable_tcp_bind,
+                udp_associate_stream_tx,
+                self.log_connection_open,
+            )
+            .with_socks4_variants(self.enable_socks4, self.enable_socks4a)
+            .with_dns_policy(self.dns_policy)
+            .with_port_policy(self.port_policy)
+            .with_log_privacy(self.log_privacy)
+            .with_error_verbosity(self.error_verbosity);
+            let service = match self.handshake_timeout {
+                Some(handshake_timeout) => service.with_handshake_timeout(handshake_timeout),
+                None => service,
+            };
+            let service = match self.bind_timeout {
+                Some(bind_timeout) => service.with_bind_timeout(bind_timeout),
+                None => service,
+            };
+            match self.max_hops {
+                Some(max_hops) => Arc::new(service.with_max_hops(max_hops)),
+                None => Arc::new(service),
+            }
+        };
 
         let shutdown = shutdown_signal.fuse();
         futures::pin_mut!(shutdown);
 
+        let mut connections = JoinSet::new();
         loop {
-            let stream = futures::select! {
-                stream = tcp_listener.accept().fuse() => stream,
+            let accept = async {
+                self.accept_control.wait_resumed().await;
+                accept_with_backoff(|| tcp_listener.accept(), self.accept_backoff).await
+            };
+            let (socket, socket_addr) = futures::select! {
+                stream = accept.fuse() => stream,
                 _ = shutdown => {
                     tracing::info!("Stopping SOCKS server");
                     break;
                 },
             };
 
-            match stream {
-                Ok((socket, socket_addr)) => {
-                    let service = service.clone();
-                    let _connection_timeout = self.connection_timeout;
-                    let _stat_monitor = self.transport.stat_monitor();
-                    tokio::spawn(async move {
-                        // let _ = socket.set_keepalive(Some(tcp_keepalive));
-                        // FIXME: enable `TimedStream`, `MonitoredStream`
-                        // let socket = TimedStream::new(socket, connection_timeout);
-                        // let socket = MonitoredStream::new(socket, stat_monitor);
-                        let _unused = service.dispatch(socket, socket_addr).await;
-                    });
-                }
-                Err(source) => {
-                    let err = Error::AcceptTcpStream { source };
-                    tracing::warn!("Server error: {:?}", err);
-                }
+            // reap finished connections
+            while connections.try_join_next().is_some() {}
+
+            if !self.rate_limiter.try_acquire(socket_addr.ip()) {
+                tracing::warn!("Refuse connection from {socket_addr}, too many new connections");
+                continue;
             }
+
+            let Some(connection_guard) = self.connection_limiter.try_acquire(socket_addr.ip())
+            else {
+                tracing::warn!("Refuse connection from {socket_addr}, too many connections");
+                continue;
+            };
+
+            // permits are only tried, so that shutdown never waits for them, a
+            // SOCKS reply depends on the version the client is yet to send, so
+            // connections beyond the cap are closed without one
+            let Ok(permit) =
+                self.connection_permits.clone().map(Semaphore::try_acquire_owned).transpose()
+            else {
+                tracing::warn!("Refuse connection from {socket_addr}, server is at capacity");
+                continue;
+            };
+            let service = service.clone();
+            let idle_timeout = self.idle_timeout;
+            let tls_acceptor = tls_acceptor.clone();
+            connections.spawn(async move {
+                let (_connection_guard, _permit) = (connection_guard, permit);
+                // let _ = socket.set_keepalive(Some(tcp_keepalive));
+                let socket = TimedStream::new(socket, idle_timeout);
+                let Some(socket) = accept_tls(tls_acceptor.as_ref(), socket, socket_addr).await
+                else {
+                    return;
+                };
+                let _unused = service.dispatch(socket, socket_addr).await;
+            });
         }
 
-        // FIXME: re-implement `UdpAssociateManager`
-        // if let Some(join_handle) = udp_associate_join_handle {
-        //     join_handle.shutdown_and_wait().await;
-        // }
+        drain_connections(connections, self.drain_timeout).await;
+
+        if let Some(join_handle) = udp_associate_join_handle {
+            join_handle.shutdown_and_wait().await;
+        }
 
         tracing::info!("SOCKS Server stopped");
         Ok(())
@@ -194,4 +375,401 @@ impl Server {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{oneshot, Mutex},
+        task::JoinHandle,
+    };
+    use tokio_rustls::{rustls, TlsConnector};
+
+    use crate::{
+        authentication::AuthenticationManager,
+        protocol::socks::{SocksCommand, SocksVersion},
+        server::{
+            socks::{Server, ServerOptions},
+            Error, RateLimit, TlsServerConfig,
+        },
+        test_util::unfiltered_transport,
+    };
+
+    const TESTDATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/checker/prober/testdata");
+
+    fn new_server(options: ServerOptions) -> Server {
+        Server::new(
+            options,
+            unfiltered_transport(),
+            Arc::new(Mutex::new(AuthenticationManager::new())),
+        )
+    }
+
+    /// Serves `server` on a listener bound beforehand, so that connecting to
+    /// the returned address succeeds right away.
+    async fn spawn_server(
+        server: Server,
+    ) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<Result<(), Error>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(server.serve_with_listener(listener, async {
+            let _ = shutdown_rx.await;
+        }));
+        (listen_addr, shutdown_tx, server)
+    }
+
+    async fn handshake(listen_addr: SocketAddr) -> [u8; 2] {
+        let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+        handshake_on(&mut stream).await
+    }
+
+    async fn handshake_on(stream: &mut TcpStream) -> [u8; 2] {
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn bind_udp_associate_addresses() {
+        let udp_addresses = vec![
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), 0)),
+        ];
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            supported_commands: HashSet::from_iter([SocksCommand::UdpAssociate]),
+            udp_ports: HashSet::new(),
+            udp_addresses: udp_addresses.clone(),
+            ..ServerOptions::default()
+        };
+        let (listen_addr, shutdown_tx, server) = spawn_server(new_server(options)).await;
+
+        // UDP associate requests are spread over sockets of all addresses
+        let mut streams = Vec::new();
+        let mut relay_ips = HashSet::new();
+        for _ in &udp_addresses {
+            let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+            assert_eq!(handshake_on(&mut stream).await, [0x05, 0x00]);
+            stream.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut reply = [0u8; 10];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x01]);
+            let _ = relay_ips.insert(Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]));
+            streams.push(stream);
+        }
+        assert_eq!(
+            relay_ips,
+            HashSet::from_iter([Ipv4Addr::LOCALHOST, Ipv4Addr::new(127, 0, 0, 2)])
+        );
+
+        drop(streams);
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn expire_tcp_bind() {
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            supported_commands: HashSet::from_iter([SocksCommand::TcpBind]),
+            bind_timeout: Some(Duration::from_millis(50)),
+            ..ServerOptions::default()
+        };
+        let (listen_addr, shutdown_tx, server) = spawn_server(new_server(options)).await;
+
+        let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+        assert_eq!(handshake_on(&mut stream).await, [0x05, 0x00]);
+        stream.write_all(&[0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        // no peer connects to the listening socket in time
+        let expired = tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut reply));
+        expired.await.unwrap().unwrap();
+        assert_eq!(reply[1], 0x06);
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_accepting() {
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            ..ServerOptions::default()
+        };
+        let server = new_server(options);
+
+        let accept_control = server.accept_control();
+        accept_control.pause();
+        let (listen_addr, shutdown_tx, server) = spawn_server(server).await;
+
+        // TCP handshake is completed by kernel even if paused
+        let pending = tokio::spawn(handshake(listen_addr));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pending.is_finished());
+
+        accept_control.resume();
+        let reply = tokio::time::timeout(Duration::from_secs(5), pending).await.unwrap().unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_idle_connections() {
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ServerOptions::default()
+        };
+        let server = new_server(options);
+        let (listen_addr, shutdown_tx, server) = spawn_server(server).await;
+
+        let mut stalled = TcpStream::connect(listen_addr).await.unwrap();
+        let mut active = TcpStream::connect(listen_addr).await.unwrap();
+
+        // the active client sends its handshake in pieces, each within the timeout
+        for byte in [0x05, 0x01, 0x00] {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            active.write_all(&[byte]).await.unwrap();
+        }
+        let mut reply = [0u8; 2];
+        active.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        // the stalled client is disconnected
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stalled.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0) | Err(_))));
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_over_tls() {
+        let echo = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            tls: Some(
+                TlsServerConfig::new(
+                    format!("{TESTDATA_DIR}/localhost.pem").into(),
+                    format!("{TESTDATA_DIR}/localhost.key").into(),
+                )
+                .with_handshake_timeout(Duration::from_millis(200)),
+            ),
+            ..ServerOptions::default()
+        };
+        let server = new_server(options);
+        let (listen_addr, shutdown_tx, server) = spawn_server(server).await;
+
+        // a client never starting the TLS handshake is dropped after the timeout
+        let mut silent = TcpStream::connect(listen_addr).await.unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0))));
+
+        // a client without TLS is dropped, the server keeps accepting
+        let mut plain = TcpStream::connect(listen_addr).await.unwrap();
+        plain.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), plain.read_to_end(&mut buf)).await;
+        assert!(read.is_ok());
+        assert!(!buf.starts_with(&[0x05]));
+
+        let connector = {
+            let mut root_store = rustls::RootCertStore::empty();
+            let ca = CertificateDer::from_pem_file(format!("{TESTDATA_DIR}/ca.pem")).unwrap();
+            root_store.add(ca).unwrap();
+            let config = rustls::ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth();
+            TlsConnector::from(Arc::new(config))
+        };
+        let stream = TcpStream::connect(listen_addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        let [port_high, port_low] = echo_port.to_be_bytes();
+        stream
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x00]);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn limit_connection_rate() {
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            connection_rate_limit: Some(RateLimit { per_second: 1, burst: 3 }),
+            ..ServerOptions::default()
+        };
+        let server = new_server(options);
+        let (listen_addr, shutdown_tx, server) = spawn_server(server).await;
+
+        // refused connections are closed without a reply
+        let try_handshake = |mut stream: TcpStream| async move {
+            stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await.is_ok()
+        };
+        let first = TcpStream::connect(listen_addr).await.unwrap();
+        let mut accepted = usize::from(try_handshake(first).await);
+        for _ in 0..9 {
+            let stream = TcpStream::connect(listen_addr).await.unwrap();
+            accepted += usize::from(try_handshake(stream).await);
+        }
+        assert_eq!(accepted, 3);
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cap_concurrent_connections() {
+        let destination = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let [port_high, port_low] = destination.local_addr().unwrap().port().to_be_bytes();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = destination.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let options = ServerOptions {
+            supported_versions: HashSet::from_iter([SocksVersion::V5]),
+            max_connections: Some(2),
+            ..ServerOptions::default()
+        };
+        let server = new_server(options);
+        let (listen_addr, shutdown_tx, server) = spawn_server(server).await;
+
+        let connect = |mut stream: TcpStream| async move {
+            stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x05, 0x00]);
+            stream
+                .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+                .await
+                .unwrap();
+            let mut reply = [0u8; 10];
+            stream.read_exact(&mut reply).await.unwrap();
+            (stream, reply[1])
+        };
+        let first = TcpStream::connect(listen_addr).await.unwrap();
+        let (first, reply) = connect(first).await;
+        assert_eq!(reply, 0x00);
+        let (_second, reply) = connect(TcpStream::connect(listen_addr).await.unwrap()).await;
+        assert_eq!(reply, 0x00);
+
+        // the connection beyond the cap is closed before any handshake
+        let mut refused = TcpStream::connect(listen_addr).await.unwrap();
+        let _unused = refused.write_all(&[0x05, 0x01, 0x00]).await;
+        let mut reply = Vec::new();
+        let _unused = refused.read_to_end(&mut reply).await;
+        assert!(reply.is_empty());
+
+        // a new connection is served once an active one is closed
+        drop(first);
+        let accepted = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (stream, reply) = connect(TcpStream::connect(listen_addr).await.unwrap()).await;
+                if reply == 0x00 {
+                    break stream;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(accepted.is_ok());
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn drain_connections_on_shutdown() {
+        // the remote host replies after `reply_delay`, the relay is finished
+        // only if it is within `drain_timeout`
+        for (reply_delay, drain_timeout, finished) in [
+            (Duration::from_millis(100), Duration::from_secs(5), true),
+            (Duration::from_secs(10), Duration::from_millis(100), false),
+        ] {
+            let remote = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let [port_high, port_low] = remote.local_addr().unwrap().port().to_be_bytes();
+            tokio::spawn(async move {
+                let (mut stream, _) = remote.accept().await.unwrap();
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                tokio::time::sleep(reply_delay).await;
+                let _unused = stream.write_all(b"pong").await;
+            });
+
+            let options = ServerOptions {
+                supported_versions: HashSet::from_iter([SocksVersion::V5]),
+                drain_timeout: Some(drain_timeout),
+                ..ServerOptions::default()
+            };
+            let server = new_server(options);
+            let (listen_addr, shutdown_tx, server) = spawn_server(server).await;
+
+            let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+            stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await.unwrap();
+            stream
+                .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+                .await
+                .unwrap();
+            let mut reply = [0u8; 10];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..2], [0x05, 0x00]);
+            stream.write_all(b"ping").await.unwrap();
+
+            // the relay is in flight while shutting down
+            shutdown_tx.send(()).unwrap();
+            tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+
+            let mut buf = Vec::new();
+            let _unused = stream.read_to_end(&mut buf).await;
+            assert_eq!(buf == b"pong", finished, "{reply_delay:?} {drain_timeout:?}");
+        }
+    }
+}
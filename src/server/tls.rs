@@ -0,0 +1,153 @@
+use std::{
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use snafu::ResultExt;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+
+use crate::server::error::{self, Error};
+
+/// Default limit of the time of TLS handshakes with clients.
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate and private key of servers terminating TLS before the SOCKS or
+/// HTTP handshake, both in PEM format.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TlsServerConfig {
+    /// Certificate chain, starting with the certificate of the server.
+    pub certificate_file: PathBuf,
+    pub private_key_file: PathBuf,
+    /// Limit of the time of the TLS handshake, connections not completing it
+    /// in time are dropped.
+    pub handshake_timeout: Duration,
+}
+
+impl TlsServerConfig {
+    #[inline]
+    #[must_use]
+    pub const fn new(certificate_file: PathBuf, private_key_file: PathBuf) -> Self {
+        Self {
+            certificate_file,
+            private_key_file,
+            handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub(crate) fn acceptor(&self) -> Result<TlsServerAcceptor, Error> {
+        let certificates = CertificateDer::pem_file_iter(&self.certificate_file)
+            .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+            .with_context(|_| error::LoadTlsCertificateSnafu {
+                file_path: self.certificate_file.clone(),
+            })?;
+        let private_key =
+            PrivateKeyDer::from_pem_file(&self.private_key_file).with_context(|_| {
+                error::LoadTlsPrivateKeySnafu { file_path: self.private_key_file.clone() }
+            })?;
+
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, private_key)
+            .context(error::ConfigureTlsSnafu)?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        Ok(TlsServerAcceptor { acceptor, handshake_timeout: self.handshake_timeout })
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct TlsServerAcceptor {
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+}
+
+// completes the TLS handshake with the client if `acceptor` is provided,
+// failures and handshakes not completed in time are logged and the connection
+// is dropped
+pub(crate) async fn accept<Stream>(
+    acceptor: Option<&TlsServerAcceptor>,
+    stream: Stream,
+    peer_addr: SocketAddr,
+) -> Option<MaybeTlsStream<Stream>>
+where
+    Stream: Unpin + AsyncRead + AsyncWrite,
+{
+    let Some(TlsServerAcceptor { acceptor, handshake_timeout }) = acceptor else {
+        return Some(MaybeTlsStream::Plain(stream));
+    };
+    match tokio::time::timeout(*handshake_timeout, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(MaybeTlsStream::Tls(Box::new(stream))),
+        Ok(Err(err)) => {
+            tracing::info!("Drop connection from {peer_addr}, TLS handshake failed, error: {err}");
+            None
+        }
+        Err(_) => {
+            tracing::info!("Drop connection from {peer_addr}, TLS handshake timed out");
+            None
+        }
+    }
+}
+
+/// Stream of a client connected with or without TLS.
+pub(crate) enum MaybeTlsStream<Stream> {
+    Plain(Stream),
+    Tls(Box<TlsStream<Stream>>),
+}
+
+impl<Stream> AsyncRead for MaybeTlsStream<Stream>
+where
+    Stream: Unpin + AsyncRead + AsyncWrite,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<Stream> AsyncWrite for MaybeTlsStream<Stream>
+where
+    Stream: Unpin + AsyncRead + AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tracing::Span;
+
+/// Short id unique to each accepted connection within the process.
+///
+/// It is recorded as the `id` field of the `connection` span covering the
+/// handshake, the connection to the remote host and the relay, so that log
+/// events of concurrent connections can be told apart.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Returns the id of a new connection.
+    #[must_use]
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub(crate) fn span(self) -> Span { tracing::info_span!("connection", id = %self) }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{:08x}", self.0) }
+}
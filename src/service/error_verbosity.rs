@@ -0,0 +1,45 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Detail of errors of connecting destinations given to clients and logs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorVerbosity {
+    /// Reply the status only, e.g. `502 Bad Gateway` or a SOCKS reject, the
+    /// underlying error is not revealed to clients.
+    #[default]
+    Terse,
+
+    /// Reply and log the underlying error as well, e.g. the DNS or connect
+    /// error, for debugging. HTTP clients receive it in the response body,
+    /// SOCKS replies have no room for it, it is logged only.
+    Verbose,
+}
+
+impl ErrorVerbosity {
+    #[inline]
+    #[must_use]
+    pub const fn is_verbose(self) -> bool { matches!(self, Self::Verbose) }
+}
+
+impl fmt::Display for ErrorVerbosity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Terse => write!(f, "terse"),
+            Self::Verbose => write!(f, "verbose"),
+        }
+    }
+}
+
+impl FromStr for ErrorVerbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "terse" => Ok(Self::Terse),
+            "verbose" => Ok(Self::Verbose),
+            _ => Err(format!("invalid error verbosity: {s}")),
+        }
+    }
+}
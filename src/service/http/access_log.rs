@@ -0,0 +1,328 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc::{self, error::TrySendError},
+};
+
+/// Maximum number of entries waiting to be written, further entries are
+/// dropped until the writer catches up.
+const MAX_PENDING_ENTRIES: usize = 4096;
+
+/// Writes access logs in Apache Combined Log Format.
+///
+/// Entries are written by a background task, so that a slow writer never
+/// blocks serving clients.
+pub struct AccessLog {
+    entries: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    /// Spawns the task writing entries to `writer`, must be called within a
+    /// tokio runtime.
+    #[must_use]
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (entries, receiver) = mpsc::channel(MAX_PENDING_ENTRIES);
+        tokio::spawn(write_entries(writer, receiver));
+        Self { entries }
+    }
+
+    pub async fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(Self::new(file))
+    }
+
+    pub(crate) fn write(&self, entry: &AccessLogEntry) {
+        match self.entries.try_send(format!("{entry}\n")) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("Access log writer falls behind, an entry is dropped");
+            }
+            Err(TrySendError::Closed(_)) => {
+                tracing::warn!("Access log writer is stopped, an entry is dropped");
+            }
+        }
+    }
+}
+
+async fn write_entries<W>(mut writer: W, mut entries: mpsc::Receiver<String>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(entry) = entries.recv().await {
+        let result = async {
+            writer.write_all(entry.as_bytes()).await?;
+            writer.flush().await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::warn!("Failed to write access log, error: {err}");
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AccessLogEntry {
+    pub client_addr: SocketAddr,
+    pub time: SystemTime,
+    pub request_line: String,
+    pub status: Option<u16>,
+    pub bytes: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl fmt::Display for AccessLogEntry {
+    // %h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} - - [{}] \"{}\" ",
+            self.client_addr.ip(),
+            ClfTime(self.time),
+            Escaped(&self.request_line)
+        )?;
+        match self.status {
+            Some(status) => write!(f, "{status} ")?,
+            None => write!(f, "- ")?,
+        }
+        match self.bytes {
+            0 => write!(f, "-")?,
+            bytes => write!(f, "{bytes}")?,
+        }
+        write!(
+            f,
+            " \"{}\" \"{}\"",
+            Escaped(self.referer.as_deref().unwrap_or("-")),
+            Escaped(self.user_agent.as_deref().unwrap_or("-"))
+        )
+    }
+}
+
+// escapes quotes and backslashes like Apache does, so that a quoted field can
+// not be terminated by client-supplied values
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ch in self.0.chars() {
+            match ch {
+                '"' | '\\' => write!(f, "\\{ch}")?,
+                _ => write!(f, "{ch}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// time in format of `10/Oct/2000:13:55:36 +0000`, always in UTC
+struct ClfTime(SystemTime);
+
+impl fmt::Display for ClfTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MONTHS: [&str; 12] =
+            ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+        let secs = self.0.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+        // convert days since epoch to civil date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+
+        write!(
+            f,
+            "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+            MONTHS[(month - 1) as usize],
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ResponseStats {
+    body_bytes: AtomicU64,
+    status: AtomicU16,
+}
+
+impl ResponseStats {
+    /// Returns bytes written after the head of the response, i.e. `%b`.
+    pub(crate) fn body_bytes(&self) -> u64 { self.body_bytes.load(Ordering::Relaxed) }
+
+    pub(crate) fn status(&self) -> Option<u16> {
+        match self.status.load(Ordering::Relaxed) {
+            0 => None,
+            status => Some(status),
+        }
+    }
+}
+
+/// Records body bytes and status code of the response written to client,
+/// everything after the head of the first response counts as body.
+pub(crate) struct ResponseRecorder<S> {
+    stream: S,
+    stats: Arc<ResponseStats>,
+    status_line: Vec<u8>,
+    // length of the matched prefix of the blank line ending the head
+    head_end_matched: usize,
+}
+
+impl<S> ResponseRecorder<S> {
+    const HEAD_END: &'static [u8] = b"\r\n\r\n";
+    // "HTTP/1.1 200"
+    const STATUS_LINE_PREFIX_LEN: usize = 12;
+
+    pub(crate) fn new(stream: S, stats: Arc<ResponseStats>) -> Self {
+        Self {
+            stream,
+            stats,
+            status_line: Vec::with_capacity(Self::STATUS_LINE_PREFIX_LEN),
+            head_end_matched: 0,
+        }
+    }
+
+    // returns the part of `buf` after the head
+    fn skip_head<'a>(&mut self, buf: &'a [u8]) -> &'a [u8] {
+        for (i, &byte) in buf.iter().enumerate() {
+            if self.head_end_matched == Self::HEAD_END.len() {
+                return &buf[i..];
+            }
+            self.head_end_matched = if byte == Self::HEAD_END[self.head_end_matched] {
+                self.head_end_matched + 1
+            } else {
+                usize::from(byte == b'\r')
+            };
+        }
+        &[]
+    }
+
+    fn record(&mut self, buf: &[u8]) {
+        let body = self.skip_head(buf);
+        self.stats.body_bytes.fetch_add(body.len() as u64, Ordering::Relaxed);
+
+        let remaining = Self::STATUS_LINE_PREFIX_LEN - self.status_line.len();
+        if remaining == 0 {
+            return;
+        }
+
+        self.status_line.extend_from_slice(&buf[..remaining.min(buf.len())]);
+        if self.status_line.len() == Self::STATUS_LINE_PREFIX_LEN {
+            let status = std::str::from_utf8(&self.status_line[9..])
+                .ok()
+                .and_then(|status| status.parse().ok())
+                .unwrap_or_default();
+            self.stats.status.store(status, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ResponseRecorder<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ResponseRecorder<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.record(&buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::{AccessLogEntry, ResponseRecorder, ResponseStats};
+
+    #[tokio::test]
+    async fn count_body_bytes() {
+        let stats = Arc::new(ResponseStats::default());
+        let mut recorder = ResponseRecorder::new(tokio::io::sink(), stats.clone());
+        // the blank line ending the head is split across writes
+        for chunk in ["HTTP/1.1 404 Not", " Found\r\nContent-Length: 9\r\n\r", "\nnot ", "found"] {
+            recorder.write_all(chunk.as_bytes()).await.unwrap();
+        }
+        assert_eq!(stats.status(), Some(404));
+        assert_eq!(stats.body_bytes(), 9);
+
+        let stats = Arc::new(ResponseStats::default());
+        let mut recorder = ResponseRecorder::new(tokio::io::sink(), stats.clone());
+        recorder.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        assert_eq!(stats.body_bytes(), 0);
+    }
+
+    #[test]
+    fn combined_log_format() {
+        let entry = AccessLogEntry {
+            client_addr: SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 40000)),
+            // 2000-10-10T13:55:36Z
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request_line: "GET http://example.com/ HTTP/1.1".to_string(),
+            status: Some(200),
+            bytes: 2326,
+            referer: Some("http://example.com/start.html".to_string()),
+            user_agent: None,
+        };
+        assert_eq!(
+            entry.to_string(),
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET http://example.com/ HTTP/1.1\" 200 \
+             2326 \"http://example.com/start.html\" \"-\""
+        );
+
+        let entry = AccessLogEntry { status: None, bytes: 0, ..entry };
+        assert!(entry.to_string().contains("\" - - \""));
+
+        let entry = AccessLogEntry { user_agent: Some("evil\" agent".to_string()), ..entry };
+        assert!(entry.to_string().ends_with(" \"evil\\\" agent\""));
+    }
+}
@@ -0,0 +1,101 @@
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use bytes::{Bytes, BytesMut};
+use http::HeaderValue;
+
+/// Value of `Proxy-Authenticate` replied with `407 Proxy Authentication
+/// Required`.
+pub(crate) const PROXY_AUTHENTICATE: &str = "Basic realm=\"tunelo\"";
+
+/// Value of `Proxy-Authenticate` without the product name, for servers
+/// suppressing identification.
+pub(crate) const ANONYMOUS_PROXY_AUTHENTICATE: &str = "Basic realm=\"proxy\"";
+
+// standard alphabet with optional padding, whitespaces are not allowed
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Credentials of `Proxy-Authorization: Basic ...`, see RFC 7617.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct BasicCredentials {
+    pub user_name: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+impl BasicCredentials {
+    /// Parses the value of `Proxy-Authorization`, returns `None` if it is not
+    /// of scheme `Basic` or the credentials are malformed.
+    pub(crate) fn parse(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?.trim();
+        let (scheme, credentials) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let decoded = BASE64.decode(credentials.trim()).ok()?;
+        let colon = decoded.iter().position(|&b| b == b':')?;
+        Some(Self { user_name: decoded[..colon].to_vec(), password: decoded[colon + 1..].to_vec() })
+    }
+}
+
+/// Returns the request header without fields named `name`, the request line
+/// and the other fields are kept as they are.
+pub(crate) fn strip_header_field(header_buf: &[u8], name: &str) -> Bytes {
+    let mut buf = BytesMut::with_capacity(header_buf.len());
+    let mut lines = header_buf.split_inclusive(|&b| b == b'\n');
+    if let Some(request_line) = lines.next() {
+        buf.extend_from_slice(request_line);
+    }
+    for line in lines {
+        let field_name = line.split(|&b| b == b':').next().unwrap_or_default();
+        if line.contains(&b':') && field_name.eq_ignore_ascii_case(name.as_bytes()) {
+            continue;
+        }
+        buf.extend_from_slice(line);
+    }
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::{strip_header_field, BasicCredentials};
+
+    #[test]
+    fn parse_basic_credentials() {
+        // `tune:lo`, padding is optional
+        for value in ["Basic dHVuZTpsbw==", "Basic dHVuZTpsbw", "basic  dHVuZTpsbw=="] {
+            assert_eq!(
+                BasicCredentials::parse(&HeaderValue::from_static(value)),
+                Some(BasicCredentials { user_name: b"tune".to_vec(), password: b"lo".to_vec() })
+            );
+        }
+        assert!(BasicCredentials::parse(&HeaderValue::from_static("Basic dHVuZ")).is_none());
+        assert!(BasicCredentials::parse(&HeaderValue::from_static("Basic dHVu*Q==")).is_none());
+
+        // `user:pass:word`, colons after the first one belong to password
+        let value = HeaderValue::from_static("Basic dXNlcjpwYXNzOndvcmQ=");
+        assert_eq!(
+            BasicCredentials::parse(&value),
+            Some(BasicCredentials { user_name: b"user".to_vec(), password: b"pass:word".to_vec() })
+        );
+        assert!(BasicCredentials::parse(&HeaderValue::from_static("Bearer dXNlcjpwYXNz")).is_none());
+        assert!(BasicCredentials::parse(&HeaderValue::from_static("Basic dXNlcg==")).is_none());
+    }
+
+    #[test]
+    fn strip_proxy_authorization() {
+        let header = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+                       proxy-authorization: Basic dXNlcjpwYXNz\r\nAccept: */*\r\n\r\n";
+        assert_eq!(
+            strip_header_field(header, "Proxy-Authorization").as_ref(),
+            b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::Path;
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Body replied with `403 Forbidden` to requests of destinations denied by
+/// filter, `{host}` in the body is replaced with the denied host.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockPage {
+    body: String,
+    content_type: &'static str,
+}
+
+impl BlockPage {
+    /// Creates a plain text block page.
+    #[must_use]
+    pub fn text(body: impl Into<String>) -> Self {
+        Self { body: body.into(), content_type: TEXT_CONTENT_TYPE }
+    }
+
+    /// Creates an HTML block page, the denied host is HTML-escaped.
+    #[must_use]
+    pub fn html(body: impl Into<String>) -> Self {
+        Self { body: body.into(), content_type: HTML_CONTENT_TYPE }
+    }
+
+    /// Loads a block page from file, files with extension `.html` or `.htm`
+    /// are served as HTML, others as plain text.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let body = std::fs::read_to_string(path)?;
+        let is_html = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"));
+        Ok(if is_html { Self::html(body) } else { Self::text(body) })
+    }
+
+    /// Returns the full response denying `host`.
+    pub(crate) fn response(&self, host: &str) -> String {
+        let host = if self.content_type == HTML_CONTENT_TYPE {
+            escape_html(host)
+        } else {
+            host.to_owned()
+        };
+        let body = self.body.replace("{host}", &host);
+        format!(
+            "HTTP/1.1 403 Forbidden\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{body}",
+            self.content_type,
+            body.len()
+        )
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockPage;
+
+    #[test]
+    fn escape_host_in_html() {
+        let response = BlockPage::html("{host}").response("<script>");
+        assert!(response.ends_with("\r\n\r\n&lt;script&gt;"));
+
+        let response = BlockPage::text("{host}").response("<script>");
+        assert!(response.ends_with("\r\n\r\n<script>"));
+        assert!(response.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    }
+}
@@ -1,6 +1,9 @@
 use snafu::Snafu;
 
-use crate::{common::HostAddress, transport};
+use crate::{
+    common::HostAddress,
+    transport::{self, TimeoutPhase},
+};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
@@ -17,12 +20,24 @@ pub enum Error {
     #[snafu(display("HTTP request is too large"))]
     RequestTooLarge,
 
+    #[snafu(display("Request URI is too long, length: {length}, limit: {limit}"))]
+    UriTooLong { length: usize, limit: usize },
+
+    #[snafu(display("Timed out during {phase}"))]
+    Timeout { phase: TimeoutPhase },
+
     #[snafu(display("Error occurred while relaying stream, error: {}", source))]
     RelayStream { source: transport::Error },
 
     #[snafu(display("Could not establish connection with {host}, error: {source}"))]
     ConnectRemoteHost { host: HostAddress, source: Box<transport::Error> },
 
+    #[snafu(display("Proxy authentication is required"))]
+    ProxyAuthenticationRequired,
+
+    #[snafu(display("Access denied, user: {}", String::from_utf8_lossy(user_name)))]
+    AccessDenied { user_name: Vec<u8> },
+
     #[snafu(display("Unsupported method: {}", method))]
     UnsupportedMethod { method: String },
 
@@ -65,3 +80,17 @@ pub enum Error {
     #[snafu(display("No URL is provided"))]
     NoUrlProvided,
 }
+
+impl Error {
+    /// Returns the phase in which a timeout occurs, `None` if this is not a
+    /// timeout error.
+    #[must_use]
+    pub fn timeout_phase(&self) -> Option<TimeoutPhase> {
+        match self {
+            Self::Timeout { phase } => Some(*phase),
+            Self::RelayStream { source } => source.timeout_phase(),
+            Self::ConnectRemoteHost { source, .. } => source.timeout_phase(),
+            _ => None,
+        }
+    }
+}
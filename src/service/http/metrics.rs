@@ -0,0 +1,81 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Counts requests handled by HTTP proxy service, `CONNECT` tunnels and plain
+/// forward requests are counted separately.
+#[derive(Clone, Debug, Default)]
+pub struct HttpMetrics {
+    connect_requests: Arc<AtomicUsize>,
+    forward_requests: Arc<AtomicUsize>,
+}
+
+impl HttpMetrics {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self { Self::default() }
+
+    #[inline]
+    #[must_use]
+    pub fn connect_requests(&self) -> usize { self.connect_requests.load(Ordering::Acquire) }
+
+    #[inline]
+    #[must_use]
+    pub fn forward_requests(&self) -> usize { self.forward_requests.load(Ordering::Acquire) }
+
+    #[inline]
+    pub(crate) fn count_connect(&self) { self.connect_requests.fetch_add(1, Ordering::SeqCst); }
+
+    #[inline]
+    pub(crate) fn count_forward(&self) { self.forward_requests.fetch_add(1, Ordering::SeqCst); }
+}
+
+/// Renders `metrics` in the Prometheus text exposition format.
+#[must_use]
+pub fn render_prometheus(metrics: &HttpMetrics) -> String {
+    let families = [
+        (
+            "tunelo_http_connect_requests_total",
+            "CONNECT requests handled.",
+            metrics.connect_requests(),
+        ),
+        (
+            "tunelo_http_forward_requests_total",
+            "Plain forward requests handled.",
+            metrics.forward_requests(),
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, help, value) in families {
+        // writing to `String` never fails
+        let _unused = writeln!(output, "# HELP {name} {help}");
+        let _unused = writeln!(output, "# TYPE {name} counter");
+        let _unused = writeln!(output, "{name} {value}");
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_prometheus, HttpMetrics};
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = HttpMetrics::new();
+        metrics.count_connect();
+        metrics.count_forward();
+        metrics.count_forward();
+
+        let output = render_prometheus(&metrics);
+        assert!(output.contains(
+            "# TYPE tunelo_http_connect_requests_total \
+             counter\ntunelo_http_connect_requests_total 1\n"
+        ));
+        assert!(output.contains("tunelo_http_forward_requests_total 2\n"));
+    }
+}
@@ -1,4 +1,14 @@
+mod access_log;
+mod authorization;
+mod block_page;
 pub mod error;
+mod metrics;
 mod service;
 
-pub use self::{error::Error, service::Service};
+pub use self::{
+    access_log::AccessLog,
+    block_page::BlockPage,
+    error::Error,
+    metrics::{render_prometheus, HttpMetrics},
+    service::Service,
+};
@@ -1,4 +1,9 @@
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use bytes::{Bytes, BytesMut};
 use http::{header::HeaderName, HeaderMap, HeaderValue, Method, StatusCode};
@@ -7,22 +12,55 @@ use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::Mutex,
 };
+use tracing::Instrument;
 use url::Url;
 
 use crate::{
-    authentication::AuthenticationManager,
+    authentication::{Authentication, AuthenticationManager},
     common::HostAddress,
-    service::http::{error, Error},
-    transport::Transport,
+    filter::ClientIdentity,
+    service::{
+        http::{
+            access_log::{AccessLogEntry, ResponseRecorder, ResponseStats},
+            authorization::{self, BasicCredentials},
+            error, AccessLog, BlockPage, Error, HttpMetrics,
+        },
+        ConnectionId, ErrorVerbosity, LogPrivacy,
+    },
+    transport::{self, TimeoutPhase, Transport},
 };
 
 const INITIAL_BUF_SIZE: usize = 256;
 const BUF_ADDITIONAL_SIZE: usize = 128;
 const MAX_HEADER_BUF_SIZE: usize = 10240;
+const DEFAULT_HTTP_PORT: u16 = 80;
+
+// methods which can be tunneled or forwarded, others like `TRACE` are rejected
+// with `405 Method Not Allowed`
+const SUPPORTED_METHODS: [Method; 8] = [
+    Method::CONNECT,
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::OPTIONS,
+    Method::PATCH,
+];
 
 pub struct Service<TransportStream> {
     transport: Arc<Transport<TransportStream>>,
-    _authentication_manager: Arc<Mutex<AuthenticationManager>>,
+    authentication_manager: Arc<Mutex<AuthenticationManager>>,
+    log_connection_open: bool,
+    access_log: Option<Arc<AccessLog>>,
+    transparent: bool,
+    max_uri_length: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    log_privacy: LogPrivacy,
+    error_verbosity: ErrorVerbosity,
+    block_page: Option<Arc<BlockPage>>,
+    suppress_identification: bool,
+    metrics: HttpMetrics,
 }
 
 impl<TransportStream> Service<TransportStream>
@@ -33,10 +71,108 @@ where
         transport: Arc<Transport<TransportStream>>,
         authentication_manager: Arc<Mutex<AuthenticationManager>>,
     ) -> Self {
-        Self { transport, _authentication_manager: authentication_manager }
+        Self {
+            transport,
+            authentication_manager,
+            log_connection_open: false,
+            access_log: None,
+            transparent: false,
+            max_uri_length: None,
+            handshake_timeout: None,
+            log_privacy: LogPrivacy::default(),
+            error_verbosity: ErrorVerbosity::default(),
+            block_page: None,
+            suppress_identification: false,
+            metrics: HttpMetrics::new(),
+        }
+    }
+
+    /// Logs each connection to remote hosts when it is opened.
+    #[must_use]
+    pub const fn with_log_connection_open(mut self, log_connection_open: bool) -> Self {
+        self.log_connection_open = log_connection_open;
+        self
+    }
+
+    /// Writes a line of each handled request to `access_log`.
+    #[must_use]
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Accepts origin-form requests of clients redirected to this service, the
+    /// destination is the original destination of the connection if it is
+    /// known, or the `Host` header otherwise.
+    #[must_use]
+    pub const fn with_transparent(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Replies `414 URI Too Long` to requests with a target longer than
+    /// `max_uri_length` bytes.
+    #[must_use]
+    pub const fn with_max_uri_length(mut self, max_uri_length: usize) -> Self {
+        self.max_uri_length = Some(max_uri_length);
+        self
+    }
+
+    /// Sets the time limit of receiving request header from client, client is
+    /// replied with `408 Request Timeout` if the limit is exceeded.
+    #[must_use]
+    pub const fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = Some(handshake_timeout);
+        self
+    }
+
+    /// Sets how destinations are written to logs, in anonymized forms the
+    /// request target and `Referer` of access logs are reduced to hosts.
+    #[must_use]
+    pub const fn with_log_privacy(mut self, log_privacy: LogPrivacy) -> Self {
+        self.log_privacy = log_privacy;
+        self
+    }
+
+    /// Sets the detail of errors of connecting destinations, in verbose form
+    /// the underlying error is logged and replied in the body of the error
+    /// response unless identification is suppressed.
+    #[must_use]
+    pub const fn with_error_verbosity(mut self, error_verbosity: ErrorVerbosity) -> Self {
+        self.error_verbosity = error_verbosity;
+        self
+    }
+
+    /// Sets the body replied with `403 Forbidden` to requests of destinations
+    /// denied by filter, instead of a bare status line.
+    #[must_use]
+    pub fn with_block_page(mut self, block_page: Arc<BlockPage>) -> Self {
+        self.block_page = Some(block_page);
+        self
+    }
+
+    /// Replies without strings identifying the server, i.e. the product name
+    /// in the realm of `Proxy-Authenticate` and diagnostic bodies of error
+    /// responses are left out.
+    #[must_use]
+    pub const fn with_suppress_identification(mut self, suppress_identification: bool) -> Self {
+        self.suppress_identification = suppress_identification;
+        self
+    }
+
+    /// Counts requests to `metrics`, which is shared with its clones, e.g.
+    /// to count requests of a service created by a server.
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: HttpMetrics) -> Self {
+        self.metrics = metrics;
+        self
     }
 
-    fn parse_header(buf: &mut BytesMut) -> Result<Option<ParsedMessage>, Error> {
+    #[inline]
+    #[must_use]
+    pub const fn metrics(&self) -> &HttpMetrics { &self.metrics }
+
+    fn parse_header(buf: &mut BytesMut, transparent: bool) -> Result<Option<ParsedMessage>, Error> {
         if buf.is_empty() {
             return Ok(None);
         }
@@ -54,10 +190,15 @@ where
                         .map_err(|_| Error::InvalidMethod { method: method.to_owned() })?
                 };
 
-                let url = match request.path {
-                    Some(p) => Url::from_str(p).context(error::ParseUrlSnafu)?,
+                let (path, url) = match request.path {
+                    // intercepted requests are in origin-form, e.g. `GET /index.html HTTP/1.1`
+                    Some(p) if transparent && p.starts_with('/') => (p.to_owned(), None),
+                    Some(p) => {
+                        (p.to_owned(), Some(Url::from_str(p).context(error::ParseUrlSnafu)?))
+                    }
                     None => return Err(Error::NoPathProvided),
                 };
+                let version = request.version.unwrap_or(1);
 
                 let mut headers = HeaderMap::with_capacity(request.headers.len());
                 for header in request.headers {
@@ -72,41 +213,138 @@ where
                 }
 
                 let header_buf = buf.split_to(parsed_len).freeze();
-                Ok(Some(ParsedMessage { req_method: method, headers, url, header_buf }))
+                Ok(Some(ParsedMessage {
+                    req_method: method,
+                    path,
+                    version,
+                    headers,
+                    url,
+                    header_buf,
+                }))
             }
         }
     }
 
-    pub async fn handle(
+    #[inline]
+    pub async fn handle<ClientStream>(
         &self,
-        mut client_stream: TransportStream,
-        _client_addr: SocketAddr,
-    ) -> Result<(), Error> {
-        let mut buf = BytesMut::with_capacity(INITIAL_BUF_SIZE);
-        let msg = loop {
-            let _n = client_stream.read_buf(&mut buf).await.context(error::ReadBufSnafu)?;
-            match Self::parse_header(&mut buf) {
-                Ok(Some(msg)) => break msg,
-                Ok(None) => {
-                    if !buf.is_empty() && buf.capacity() < MAX_HEADER_BUF_SIZE {
-                        let additional_size = std::cmp::min(
-                            BUF_ADDITIONAL_SIZE,
-                            MAX_HEADER_BUF_SIZE - buf.capacity(),
-                        );
-                        buf.reserve(additional_size);
-                        continue;
-                    }
-                    Self::shutdown_with_status(client_stream, StatusCode::BAD_REQUEST).await?;
-                    return Err(Error::RequestTooLarge);
-                }
-                Err(err) => {
-                    Self::shutdown_with_status(client_stream, StatusCode::BAD_REQUEST).await?;
-                    return Err(err);
-                }
+        client_stream: ClientStream,
+        client_addr: SocketAddr,
+    ) -> Result<(), Error>
+    where
+        ClientStream: Unpin + AsyncRead + AsyncWrite,
+    {
+        self.handle_with_original_destination(client_stream, client_addr, None).await
+    }
+
+    /// Handles a connection, `original_destination` is the destination of a
+    /// redirected connection, it is used as upstream of origin-form requests
+    /// in transparent mode.
+    pub async fn handle_with_original_destination<ClientStream>(
+        &self,
+        client_stream: ClientStream,
+        client_addr: SocketAddr,
+        original_destination: Option<SocketAddr>,
+    ) -> Result<(), Error>
+    where
+        ClientStream: Unpin + AsyncRead + AsyncWrite,
+    {
+        ClientIdentity::new(client_addr)
+            .scope(self.handle_client(client_stream, client_addr, original_destination))
+            .instrument(ConnectionId::next().span())
+            .await
+    }
+
+    async fn handle_client<ClientStream>(
+        &self,
+        client_stream: ClientStream,
+        client_addr: SocketAddr,
+        original_destination: Option<SocketAddr>,
+    ) -> Result<(), Error>
+    where
+        ClientStream: Unpin + AsyncRead + AsyncWrite,
+    {
+        let Some(ref access_log) = self.access_log else {
+            return self
+                .handle_request(client_stream, client_addr, original_destination, &mut None)
+                .await;
+        };
+
+        let time = SystemTime::now();
+        let stats = Arc::new(ResponseStats::default());
+        let client_stream = ResponseRecorder::new(client_stream, stats.clone());
+        let mut request = None;
+        let result = self
+            .handle_request(client_stream, client_addr, original_destination, &mut request)
+            .await;
+
+        if let Some(RequestInfo { request_line, referer, user_agent }) = request {
+            access_log.write(&AccessLogEntry {
+                client_addr,
+                time,
+                request_line,
+                status: stats.status(),
+                bytes: stats.body_bytes(),
+                referer,
+                user_agent,
+            });
+        }
+
+        result
+    }
+
+    async fn handle_request<ClientStream>(
+        &self,
+        mut client_stream: ClientStream,
+        client_addr: SocketAddr,
+        original_destination: Option<SocketAddr>,
+        request: &mut Option<RequestInfo>,
+    ) -> Result<(), Error>
+    where
+        ClientStream: Unpin + AsyncRead + AsyncWrite,
+    {
+        let read_request = self.read_request(&mut client_stream);
+        let msg = match transport::with_timeout(
+            self.handshake_timeout,
+            TimeoutPhase::Handshake,
+            read_request,
+        )
+        .await
+        {
+            Ok(msg) => msg?,
+            Err(phase) => {
+                Self::shutdown_with_status(client_stream, StatusCode::REQUEST_TIMEOUT).await?;
+                return Err(Error::Timeout { phase });
             }
         };
+        *request = Some(msg.request_info(original_destination, self.log_privacy));
+
+        if !SUPPORTED_METHODS.contains(&msg.req_method) {
+            let allow = SUPPORTED_METHODS.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+            let response = format!(
+                "HTTP/1.1 405 Method Not Allowed\r\nAllow: {allow}\r\nContent-Length: 0\r\n\r\n"
+            );
+            client_stream.write_all(response.as_bytes()).await.context(error::WriteStreamSnafu)?;
+            client_stream.shutdown().await.context(error::ShutdownSnafu)?;
+            return Err(Error::UnsupportedMethod { method: msg.req_method.to_string() });
+        }
 
-        let remote_host = match msg.host_address() {
+        if let Err(err) = self.authorize(&msg.headers).await {
+            let response = format!(
+                "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: \
+                 {}\r\nContent-Length: 0\r\n\r\n",
+                if self.suppress_identification {
+                    authorization::ANONYMOUS_PROXY_AUTHENTICATE
+                } else {
+                    authorization::PROXY_AUTHENTICATE
+                }
+            );
+            client_stream.write_all(response.as_bytes()).await.context(error::WriteStreamSnafu)?;
+            client_stream.shutdown().await.context(error::ShutdownSnafu)?;
+            return Err(err);
+        }
+
+        let remote_host = match msg.host_address(original_destination) {
             Some(r) => r,
             None => {
                 Self::shutdown_with_status(client_stream, StatusCode::NOT_FOUND).await?;
@@ -114,10 +352,19 @@ where
             }
         };
 
+        if self.log_connection_open {
+            tracing::info!(
+                "Connection opened from {} to {}",
+                client_addr,
+                self.log_privacy.anonymize(&remote_host)
+            );
+        }
+
         let (remote_socket, _remote_addr) = match self.transport.connect(&remote_host).await {
             Ok((mut remote_socket, addr)) => {
                 match msg.req_method {
                     Method::CONNECT => {
+                        self.metrics.count_connect();
                         const ESTABLISHED_RESPONSE: &[u8] =
                             b"HTTP/1.1 200 Connection Established\r\n\r\n";
                         let _n = client_stream
@@ -126,35 +373,176 @@ where
                             .context(error::WriteStreamSnafu)?;
                     }
                     _ => {
-                        let _n = remote_socket.write(msg.header_buf.as_ref()).await;
+                        self.metrics.count_forward();
+                        // credentials of this proxy are not for upstream
+                        let header_buf = authorization::strip_header_field(
+                            &msg.header_buf,
+                            http::header::PROXY_AUTHORIZATION.as_str(),
+                        );
+                        let _n = remote_socket.write(header_buf.as_ref()).await;
                     }
                 }
                 (remote_socket, addr)
             }
+            Err(source) if source.is_upstream_authentication_failure() => {
+                tracing::warn!(
+                    "Upstream proxy server requires authentication to connect {}, error: {}",
+                    self.log_privacy.anonymize(&remote_host),
+                    self.log_privacy.anonymize_error(&source)
+                );
+                if self.suppress_identification {
+                    Self::shutdown_with_status(client_stream, StatusCode::BAD_GATEWAY).await?;
+                } else {
+                    const BODY: &str = "Upstream proxy server requires authentication\n";
+                    let response = format!(
+                        "HTTP/1.1 502 Bad Gateway\r\nContent-Type: text/plain\r\nContent-Length: \
+                         {}\r\n\r\n{BODY}",
+                        BODY.len()
+                    );
+                    client_stream
+                        .write_all(response.as_bytes())
+                        .await
+                        .context(error::WriteStreamSnafu)?;
+                    client_stream.shutdown().await.context(error::ShutdownSnafu)?;
+                }
+                return Err(Error::ConnectRemoteHost {
+                    host: remote_host,
+                    source: Box::new(source),
+                });
+            }
             Err(source) => {
+                match self.block_page {
+                    Some(ref block_page) if source.is_forbidden() => {
+                        let response = block_page.response(&remote_host.host());
+                        client_stream
+                            .write_all(response.as_bytes())
+                            .await
+                            .context(error::WriteStreamSnafu)?;
+                        client_stream.shutdown().await.context(error::ShutdownSnafu)?;
+                    }
+                    _ => {
+                        let status_code = if source.is_forbidden() {
+                            StatusCode::FORBIDDEN
+                        } else if source.timeout_phase().is_some() {
+                            StatusCode::GATEWAY_TIMEOUT
+                        } else {
+                            StatusCode::BAD_GATEWAY
+                        };
+                        if self.error_verbosity.is_verbose() {
+                            tracing::warn!(
+                                "Failed to connect {}, error: {}",
+                                self.log_privacy.anonymize(&remote_host),
+                                self.log_privacy.anonymize_error(&source)
+                            );
+                        }
+                        if self.error_verbosity.is_verbose() && !self.suppress_identification {
+                            let detail = format!("{source}\n");
+                            Self::shutdown_with_detail(client_stream, status_code, &detail).await?;
+                        } else {
+                            Self::shutdown_with_status(client_stream, status_code).await?;
+                        }
+                    }
+                }
                 return Err(Error::ConnectRemoteHost {
                     host: remote_host,
                     source: Box::new(source),
-                })
+                });
             }
         };
 
+        let destination = remote_host.clone();
+        let remote_host = self.log_privacy.anonymize(&remote_host);
         let on_finished = Box::new(move || {
-            tracing::info!("Remote host {} is disconnected", remote_host.to_string());
+            tracing::info!("Remote host {} is disconnected", remote_host);
         });
         self.transport
-            .relay(client_stream, remote_socket, Some(on_finished))
+            .relay_to(client_stream, remote_socket, &destination, Some(on_finished))
             .await
             .context(error::RelayStreamSnafu)?;
 
         Ok(())
     }
 
+    /// Checks `Proxy-Authorization` of the request if users or tokens are
+    /// configured, the password of Basic credentials is accepted as token as
+    /// well. `GSSAPI` is not offered over HTTP, so it alone requires nothing.
+    async fn authorize(&self, headers: &HeaderMap) -> Result<(), Error> {
+        let manager = self.authentication_manager.lock().await;
+        if manager.user_count() == 0 && manager.token_count() == 0 {
+            return Ok(());
+        }
+
+        let Some(BasicCredentials { user_name, password }) =
+            headers.get(http::header::PROXY_AUTHORIZATION).and_then(BasicCredentials::parse)
+        else {
+            return Err(Error::ProxyAuthenticationRequired);
+        };
+
+        tracing::info!(
+            "Received authentication from user: {}",
+            String::from_utf8_lossy(&user_name)
+        );
+        let token = Authentication::Token { token: password.clone() };
+        let auth = Authentication::UsernamePassword { user_name: user_name.clone(), password };
+        if manager.authenticate(auth).await || manager.authenticate(token).await {
+            return Ok(());
+        }
+
+        tracing::warn!("Invalid authentication from user: {}", String::from_utf8_lossy(&user_name));
+        Err(Error::AccessDenied { user_name })
+    }
+
+    async fn read_request<ClientStream>(
+        &self,
+        client_stream: &mut ClientStream,
+    ) -> Result<ParsedMessage, Error>
+    where
+        ClientStream: Unpin + AsyncRead + AsyncWrite,
+    {
+        let mut buf = BytesMut::with_capacity(INITIAL_BUF_SIZE);
+        loop {
+            let _n = client_stream.read_buf(&mut buf).await.context(error::ReadBufSnafu)?;
+
+            // check before the whole header is received, the request target may not
+            // fit in header buffer
+            if let Some(limit) = self.max_uri_length {
+                let length = request_target_len(&buf);
+                if length > limit {
+                    Self::shutdown_with_status(&mut *client_stream, StatusCode::URI_TOO_LONG)
+                        .await?;
+                    return Err(Error::UriTooLong { length, limit });
+                }
+            }
+
+            match Self::parse_header(&mut buf, self.transparent) {
+                Ok(Some(msg)) => return Ok(msg),
+                Ok(None) => {
+                    if !buf.is_empty() && buf.capacity() < MAX_HEADER_BUF_SIZE {
+                        let additional_size = std::cmp::min(
+                            BUF_ADDITIONAL_SIZE,
+                            MAX_HEADER_BUF_SIZE - buf.capacity(),
+                        );
+                        buf.reserve(additional_size);
+                        continue;
+                    }
+                    Self::shutdown_with_status(&mut *client_stream, StatusCode::BAD_REQUEST)
+                        .await?;
+                    return Err(Error::RequestTooLarge);
+                }
+                Err(err) => {
+                    Self::shutdown_with_status(&mut *client_stream, StatusCode::BAD_REQUEST)
+                        .await?;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
     #[inline]
-    async fn shutdown_with_status(
-        mut stream: TransportStream,
-        status_code: StatusCode,
-    ) -> Result<(), Error> {
+    async fn shutdown_with_status<S>(mut stream: S, status_code: StatusCode) -> Result<(), Error>
+    where
+        S: Unpin + AsyncWrite,
+    {
         stream
             .write(status_code.status_line().as_bytes())
             .await
@@ -162,6 +550,33 @@ where
         stream.shutdown().await.context(error::ShutdownSnafu)?;
         Ok(())
     }
+
+    // replies `status_code` with `detail` as plain text body
+    async fn shutdown_with_detail<S>(
+        mut stream: S,
+        status_code: StatusCode,
+        detail: &str,
+    ) -> Result<(), Error>
+    where
+        S: Unpin + AsyncWrite,
+    {
+        let status_line = status_code.status_line();
+        let response = format!(
+            "{}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{detail}",
+            status_line.trim_end(),
+            detail.len()
+        );
+        stream.write_all(response.as_bytes()).await.context(error::WriteStreamSnafu)?;
+        stream.shutdown().await.context(error::ShutdownSnafu)?;
+        Ok(())
+    }
+}
+
+// length of request target received so far, e.g. `/index.html` of
+// `GET /index.html HTTP/1.1`
+fn request_target_len(buf: &[u8]) -> usize {
+    let request_line = buf.split(|&b| b == b'\n').next().unwrap_or_default();
+    request_line.splitn(3, |&b| b == b' ').nth(1).map_or(0, <[u8]>::len)
 }
 
 trait StatusCodeExt {
@@ -180,25 +595,676 @@ impl StatusCodeExt for StatusCode {
 #[derive(Debug)]
 struct ParsedMessage {
     req_method: Method,
+    path: String,
+    version: u8,
     headers: HeaderMap,
-    url: Url,
+    url: Option<Url>,
     header_buf: Bytes,
 }
 
+#[derive(Debug)]
+struct RequestInfo {
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
 impl ParsedMessage {
-    fn host_address(&self) -> Option<HostAddress> {
-        match (&self.req_method, self.headers.get(http::header::HOST)) {
-            (&Method::CONNECT, Some(host)) => {
+    fn request_info(
+        &self,
+        original_destination: Option<SocketAddr>,
+        log_privacy: LogPrivacy,
+    ) -> RequestInfo {
+        let header_value = |name| {
+            self.headers
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+
+        let (request_target, referer) = if log_privacy.is_full() {
+            (self.path.clone(), header_value(http::header::REFERER))
+        } else {
+            let request_target = self
+                .host_address(original_destination)
+                .map_or_else(|| "-".to_owned(), |host| log_privacy.anonymize(&host));
+            let referer = header_value(http::header::REFERER).and_then(|referer| {
+                let url = Url::parse(&referer).ok()?;
+                let host = match url.host()? {
+                    url::Host::Domain(domain) => HostAddress::new(domain, 0),
+                    url::Host::Ipv4(ip) => HostAddress::from(SocketAddr::from((ip, 0))),
+                    url::Host::Ipv6(ip) => HostAddress::from(SocketAddr::from((ip, 0))),
+                };
+                Some(log_privacy.anonymize(&host))
+            });
+            (request_target, referer)
+        };
+
+        RequestInfo {
+            request_line: format!("{} {} HTTP/1.{}", self.req_method, request_target, self.version),
+            referer,
+            user_agent: header_value(http::header::USER_AGENT),
+        }
+    }
+
+    fn host_address(&self, original_destination: Option<SocketAddr>) -> Option<HostAddress> {
+        let host = self.headers.get(http::header::HOST);
+        match (&self.req_method, host, &self.url) {
+            (&Method::CONNECT, Some(host), _) => {
                 HostAddress::from_str(host.to_str().unwrap_or_default()).ok()
             }
-            _ => {
-                let domain = &self.url.host_str()?;
-                let port = self.url.port_or_known_default()?;
+            (_, _, Some(url)) => {
+                let domain = url.host_str()?;
+                let port = url.port_or_known_default()?;
                 Some(HostAddress::new(domain, port))
             }
+            // origin-form request of transparent mode, prefer the original destination
+            // as `Host` header is supplied by client
+            (_, host, None) => match original_destination {
+                Some(addr) => Some(HostAddress::from(addr)),
+                None => {
+                    let host = host?.to_str().ok()?;
+                    HostAddress::from_str(host)
+                        .ok()
+                        .or_else(|| Some(HostAddress::new(host, DEFAULT_HTTP_PORT)))
+                }
+            },
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+    };
+    use tracing::Level;
+
+    use crate::{
+        authentication::{AuthenticationManager, NoopGssapi},
+        common::{Policy, ProxyHost, ProxyStrategy},
+        filter::SimpleFilter,
+        service::{
+            http::{AccessLog, BlockPage, Error, Service},
+            ErrorVerbosity,
+        },
+        test_util::{direct_transport, unfiltered_transport, CapturedLogs},
+        transport::{StaticResolver, TimeoutPhase, TokioResolver, Transport},
+    };
+
+    #[tokio::test]
+    async fn reject_denied_hosts() {
+        // an empty allow list denies everything
+        let transport = Arc::new(direct_transport(SimpleFilter::allow_list()));
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let service = Service::new(transport, authentication_manager);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        for request in [
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ] {
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let Err(Error::ConnectRemoteHost { source, .. }) =
+                service.handle(server, client_addr).await
+            else {
+                panic!("connection should be rejected");
+            };
+            assert!(source.is_forbidden());
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, "HTTP/1.1 403 Forbidden\r\n\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_error_detail() {
+        let transport = Arc::new(direct_transport(SimpleFilter::allow_list()));
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        for error_verbosity in [ErrorVerbosity::Terse, ErrorVerbosity::Verbose] {
+            let service = Service::new(transport.clone(), authentication_manager.clone())
+                .with_error_verbosity(error_verbosity);
+
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            client
+                .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+                .await
+                .unwrap();
+            let Err(Error::ConnectRemoteHost { source, .. }) =
+                service.handle(server, client_addr).await
+            else {
+                panic!("connection should be rejected");
+            };
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+            assert_eq!(response.contains(&source.to_string()), error_verbosity.is_verbose());
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_bad_gateway() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        // the port is closed once the listener is dropped, names are not resolved
+        let closed_addr = {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let transport = {
+            let filter = Arc::new(SimpleFilter::deny_list());
+            Arc::new(Transport::direct(Arc::new(StaticResolver::builder().build()), filter))
+        };
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+
+        for (host, error_verbosity) in [
+            (closed_addr.to_string(), ErrorVerbosity::Terse),
+            (closed_addr.to_string(), ErrorVerbosity::Verbose),
+            ("example.invalid:80".to_owned(), ErrorVerbosity::Terse),
+            ("example.invalid:80".to_owned(), ErrorVerbosity::Verbose),
+        ] {
+            let service = Service::new(transport.clone(), authentication_manager.clone())
+                .with_error_verbosity(error_verbosity);
+
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            client
+                .write_all(
+                    format!("GET http://{host}/ HTTP/1.1\r\nHost: {host}\r\n\r\n").as_bytes(),
+                )
+                .await
+                .unwrap();
+            let Err(Error::ConnectRemoteHost { source, .. }) =
+                service.handle(server, client_addr).await
+            else {
+                panic!("connection to {host} should fail");
+            };
+            assert!(!source.is_forbidden());
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{response}");
+            assert_eq!(response.contains(&source.to_string()), error_verbosity.is_verbose());
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_block_page() {
+        let transport = Arc::new(direct_transport(SimpleFilter::allow_list()));
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let block_page = BlockPage::html("<p>Access to {host} is blocked</p>");
+        let service =
+            Service::new(transport, authentication_manager).with_block_page(Arc::new(block_page));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        let Err(Error::ConnectRemoteHost { source, .. }) =
+            service.handle(server, client_addr).await
+        else {
+            panic!("connection should be rejected");
+        };
+        assert!(source.is_forbidden());
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let body = "<p>Access to example.com is blocked</p>";
+        assert_eq!(
+            response,
+            format!(
+                "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html; \
+                 charset=utf-8\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn access_log_forwarded_request() {
+        const RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ntunelo";
+
+        let origin = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(RESPONSE.as_bytes()).await.unwrap();
+        });
+
+        let (log_writer, log_reader) = tokio::io::duplex(1024);
+        let service = {
+            let transport = unfiltered_transport();
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            let access_log = Arc::new(AccessLog::new(log_writer));
+            Service::new(transport, authentication_manager).with_access_log(access_log)
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        let request = format!(
+            "GET http://{origin_addr}/index.html HTTP/1.1\r\nHost: {origin_addr}\r\nReferer: \
+             http://example.com/\r\nUser-Agent: curl/8.0\r\n\r\n"
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        service.handle(server, client_addr).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, RESPONSE);
+
+        let mut logs = String::new();
+        BufReader::new(log_reader).read_line(&mut logs).await.unwrap();
+        let (prefix, line) = logs.split_once("] ").unwrap();
+        assert!(prefix.starts_with("127.0.0.1 - - ["));
+        assert!(prefix.ends_with(" +0000"));
+        assert_eq!(
+            line,
+            format!(
+                "\"GET http://{origin_addr}/index.html HTTP/1.1\" 200 6 \"http://example.com/\" \
+                 \"curl/8.0\"\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn transparent_origin_form_request() {
+        const RESPONSE: &str = "HTTP/1.1 204 No Content\r\n\r\n";
+
+        let origin = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let origin = tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(RESPONSE.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let service = {
+            let transport = unfiltered_transport();
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(transport, authentication_manager).with_transparent(true)
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        // `Host` header is not resolvable, upstream is derived from original
+        // destination
+        let request = "GET /index.html HTTP/1.1\r\nHost: example.invalid\r\n\r\n";
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        service
+            .handle_with_original_destination(server, client_addr, Some(origin_addr))
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, RESPONSE);
+        assert_eq!(origin.await.unwrap(), request);
+    }
+
+    #[tokio::test]
+    async fn count_connect_and_forward_requests() {
+        let service = {
+            let transport = unfiltered_transport();
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(transport, authentication_manager)
+        };
+
+        let origin = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        // authority-form of `CONNECT` is parsed as URL, use a domain name instead of IP
+        let origin_host = format!("localhost:{}", origin.local_addr().unwrap().port());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let mut expected = (0, 0);
+        for (request, is_connect) in [
+            (format!("CONNECT {origin_host} HTTP/1.1\r\nHost: {origin_host}\r\n\r\n"), true),
+            (format!("GET http://{origin_host}/ HTTP/1.1\r\nHost: {origin_host}\r\n\r\n"), false),
+        ] {
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let remote = async {
+                // close the remote side right after accepting, so that relay finishes
+                drop(origin.accept().await.unwrap());
+            };
+            let (result, ()) = tokio::join!(service.handle(server, client_addr), remote);
+            result.unwrap();
+
+            if is_connect {
+                expected.0 += 1;
+            } else {
+                expected.1 += 1;
+            }
+            let metrics = service.metrics();
+            assert_eq!((metrics.connect_requests(), metrics.forward_requests()), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn share_connection_id_in_logs() {
+        let (logs, _guard) = CapturedLogs::capture(Level::INFO);
+
+        let service = {
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(unfiltered_transport(), authentication_manager)
+                .with_log_connection_open(true)
+        };
+
+        let origin = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let origin_host = format!("localhost:{}", origin.local_addr().unwrap().port());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            let request = format!("CONNECT {origin_host} HTTP/1.1\r\nHost: {origin_host}\r\n\r\n");
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let remote = async { drop(origin.accept().await.unwrap()) };
+            let (result, ()) = tokio::join!(service.handle(server, client_addr), remote);
+            result.unwrap();
+        }
+
+        // events of each connection are logged in its own span
+        let opened = logs.connection_ids("Connection opened");
+        assert_eq!(opened.len(), 2, "{}", logs.contents());
+        assert!(opened.iter().all(Option::is_some));
+        assert_ne!(opened[0], opened[1]);
+        assert_eq!(logs.connection_ids(" is disconnected"), opened);
+    }
+
+    #[tokio::test]
+    async fn reject_long_uri() {
+        let service = {
+            let transport = unfiltered_transport();
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(transport, authentication_manager).with_max_uri_length(64)
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        let path = "a".repeat(64);
+        let request =
+            format!("GET http://example.com/{path} HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let Err(Error::UriTooLong { length, limit }) = service.handle(server, client_addr).await
+        else {
+            panic!("request should be rejected");
+        };
+        assert_eq!((length, limit), ("http://example.com/".len() + 64, 64));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 414 URI Too Long\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn reject_unsupported_method() {
+        let service = {
+            let transport = unfiltered_transport();
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(transport, authentication_manager)
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        client
+            .write_all(b"TRACE http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        let Err(Error::UnsupportedMethod { method }) = service.handle(server, client_addr).await
+        else {
+            panic!("TRACE should be rejected");
+        };
+        assert_eq!(method, "TRACE");
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(
+            response.contains("Allow: CONNECT, GET, HEAD, POST, PUT, DELETE, OPTIONS, PATCH\r\n")
+        );
+    }
+
+    fn authenticating_service() -> Service<TcpStream> {
+        let transport = unfiltered_transport();
+        let mut authentication_manager = AuthenticationManager::new();
+        authentication_manager.add_user(b"user".to_vec(), b"pass".to_vec());
+        Service::new(transport, Arc::new(Mutex::new(authentication_manager)))
+    }
+
+    #[tokio::test]
+    async fn require_proxy_authorization() {
+        let service = authenticating_service();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        for (request, is_missing) in [
+            ("GET http://192.0.2.1/ HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n", true),
+            (
+                // `user:wrong`
+                "GET http://192.0.2.1/ HTTP/1.1\r\nHost: 192.0.2.1\r\nProxy-Authorization: Basic \
+                 dXNlcjp3cm9uZw==\r\n\r\n",
+                false,
+            ),
+        ] {
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            match service.handle(server, client_addr).await {
+                Err(Error::ProxyAuthenticationRequired) => assert!(is_missing),
+                Err(Error::AccessDenied { user_name }) => {
+                    assert!(!is_missing);
+                    assert_eq!(user_name, b"user");
+                }
+                _ => panic!("request should be rejected"),
+            }
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert_eq!(
+                response,
+                "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic \
+                 realm=\"tunelo\"\r\nContent-Length: 0\r\n\r\n"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn suppress_identification() {
+        let service = authenticating_service().with_suppress_identification(true);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        for request in [
+            "GET http://192.0.2.1/ HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n",
+            "TRACE http://192.0.2.1/ HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n",
+            "GET / HTTP/1.1\r\n\r\n",
+        ] {
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            let (server, client_addr) = listener.accept().await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            assert!(service.handle(server, client_addr).await.is_err());
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 4"), "{response}");
+            assert!(!response.to_ascii_lowercase().contains("tunelo"), "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_authorized_request() {
+        const RESPONSE: &str = "HTTP/1.1 204 No Content\r\n\r\n";
+
+        let origin = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let origin = tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(RESPONSE.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let service = authenticating_service();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        // `user:pass`
+        let request = format!(
+            "GET http://{origin_addr}/ HTTP/1.1\r\nProxy-Authorization: Basic \
+             dXNlcjpwYXNz\r\nHost: {origin_addr}\r\n\r\n"
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        service.handle(server, client_addr).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, RESPONSE);
+        assert_eq!(
+            origin.await.unwrap(),
+            format!("GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_gssapi_only_authentication() {
+        const RESPONSE: &str = "HTTP/1.1 204 No Content\r\n\r\n";
+
+        let origin = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(RESPONSE.as_bytes()).await.unwrap();
+        });
+
+        let service = {
+            let transport = unfiltered_transport();
+            let mut authentication_manager = AuthenticationManager::new();
+            authentication_manager.set_gssapi(Arc::new(NoopGssapi));
+            Service::new(transport, Arc::new(Mutex::new(authentication_manager)))
+        };
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        let request = format!("GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        service.handle(server, client_addr).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, RESPONSE);
+    }
+
+    #[tokio::test]
+    async fn upstream_proxy_requires_authentication() {
+        let upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _n = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                      Proxy-Authenticate: Basic realm=\"upstream\"\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let service = {
+            let filter = Arc::new(SimpleFilter::deny_list());
+            let strategy = Arc::new(ProxyStrategy::Single(ProxyHost::HttpTunnel {
+                host: upstream_addr.ip().to_string(),
+                port: upstream_addr.port(),
+                user_agent: None,
+                username: None,
+                password: None,
+            }));
+            let transport = Arc::new(
+                Transport::proxy(
+                    Arc::new(TokioResolver::new()),
+                    filter,
+                    strategy,
+                    Policy::default(),
+                )
+                .unwrap(),
+            );
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(transport, authentication_manager)
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        client
+            .write_all(b"GET http://192.0.2.1/ HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let Err(Error::ConnectRemoteHost { source, .. }) =
+            service.handle(server, client_addr).await
+        else {
+            panic!("connection should fail");
+        };
+        assert!(source.is_upstream_authentication_failure());
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.ends_with("\r\n\r\nUpstream proxy server requires authentication\n"));
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let service = {
+            let transport = unfiltered_transport();
+            let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+            Service::new(transport, authentication_manager)
+                .with_handshake_timeout(Duration::from_millis(50))
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        // header is never completed
+        client.write_all(b"GET http://example.com/ HTTP/1.1\r\n").await.unwrap();
+
+        let err = service.handle(server, client_addr).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Handshake));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 408 Request Timeout\r\n\r\n");
+    }
+}
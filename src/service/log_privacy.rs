@@ -0,0 +1,181 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{common::HostAddress, transport};
+
+/// Policy of logging destinations requested by clients.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogPrivacy {
+    /// Log destinations as they are requested.
+    #[default]
+    Full,
+
+    /// Log only the last two labels of domain names, e.g. `example.com` of
+    /// `www.example.com:443`, IP addresses are truncated to `/24` for IPv4 and
+    /// `/48` for IPv6. Ports, paths and queries are dropped.
+    ///
+    /// Public suffixes are not taken into account, `www.example.co.uk` is
+    /// logged as `co.uk`.
+    DomainOnly,
+
+    /// Log 64-bit FNV-1a hashes of hosts in hex, requests to the same host
+    /// can be correlated without revealing the host directly. Ports, paths and
+    /// queries are dropped.
+    Hashed,
+}
+
+impl LogPrivacy {
+    /// Returns `host` in the form to be logged.
+    #[must_use]
+    pub fn anonymize(self, host: &HostAddress) -> String {
+        match (self, host) {
+            (Self::Full, host) => host.to_string(),
+            (Self::DomainOnly, HostAddress::Socket(addr)) => truncate_ip(addr.ip()).to_string(),
+            (Self::Hashed, HostAddress::Socket(addr)) => hash(&addr.ip().to_string()),
+            (_, HostAddress::DomainName(domain, _)) => self.anonymize_domain(domain),
+        }
+    }
+
+    /// Returns `domain` without port in the form to be logged.
+    #[must_use]
+    pub fn anonymize_domain(self, domain: &str) -> String {
+        match self {
+            Self::Full => domain.to_owned(),
+            Self::DomainOnly => {
+                let domain = domain.trim_end_matches('.').to_lowercase();
+                let mut labels = domain.rsplitn(3, '.');
+                match (labels.next(), labels.next()) {
+                    (Some(tld), Some(name)) => format!("{name}.{tld}"),
+                    _ => domain,
+                }
+            }
+            Self::Hashed => hash(&domain.trim_end_matches('.').to_lowercase()),
+        }
+    }
+
+    /// Returns `err` in the form to be logged, only the kind of it unless
+    /// destinations are logged as they are, as errors may mention them.
+    #[must_use]
+    pub fn anonymize_error(self, err: &transport::Error) -> String {
+        if self.is_full() {
+            err.to_string()
+        } else {
+            err.redacted()
+        }
+    }
+
+    /// Returns whether destinations are logged as they are.
+    #[inline]
+    #[must_use]
+    pub const fn is_full(self) -> bool { matches!(self, Self::Full) }
+}
+
+fn truncate_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+fn hash(host: &str) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let hash =
+        host.bytes().fold(OFFSET_BASIS, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME));
+    format!("{hash:016x}")
+}
+
+impl fmt::Display for LogPrivacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "full"),
+            Self::DomainOnly => write!(f, "domain-only"),
+            Self::Hashed => write!(f, "hashed"),
+        }
+    }
+}
+
+impl FromStr for LogPrivacy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "domain-only" => Ok(Self::DomainOnly),
+            "hashed" => Ok(Self::Hashed),
+            _ => Err(format!("invalid log privacy: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::LogPrivacy;
+    use crate::{common::HostAddress, transport};
+
+    #[test]
+    fn anonymize() {
+        let domain = HostAddress::new("WWW.Example.com", 443);
+        let ipv4 = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        let ipv6 = HostAddress::from(SocketAddr::from((
+            Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6),
+            80,
+        )));
+
+        assert_eq!(LogPrivacy::Full.anonymize(&domain), "WWW.Example.com:443");
+        assert_eq!(LogPrivacy::Full.anonymize(&ipv4), "192.0.2.1:80");
+
+        assert_eq!(LogPrivacy::DomainOnly.anonymize(&domain), "example.com");
+        assert_eq!(
+            LogPrivacy::DomainOnly.anonymize(&HostAddress::new("localhost", 80)),
+            "localhost"
+        );
+        assert_eq!(LogPrivacy::DomainOnly.anonymize(&ipv4), "192.0.2.0");
+        assert_eq!(LogPrivacy::DomainOnly.anonymize(&ipv6), "2001:db8:1::");
+
+        // case and port do not affect the hash
+        assert_eq!(LogPrivacy::Hashed.anonymize(&domain), "acc7e7b8b7a0236b");
+        assert_eq!(
+            LogPrivacy::Hashed.anonymize(&HostAddress::new("www.example.com", 80)),
+            "acc7e7b8b7a0236b"
+        );
+        assert_ne!(
+            LogPrivacy::Hashed.anonymize(&HostAddress::new("example.com", 443)),
+            "acc7e7b8b7a0236b"
+        );
+        assert_eq!(LogPrivacy::Hashed.anonymize(&ipv4).len(), 16);
+
+        assert_eq!(LogPrivacy::Full.anonymize_domain("WWW.Example.com"), "WWW.Example.com");
+        assert_eq!(LogPrivacy::DomainOnly.anonymize_domain("WWW.Example.com"), "example.com");
+        assert_eq!(LogPrivacy::Hashed.anonymize_domain("WWW.Example.com"), "acc7e7b8b7a0236b");
+    }
+
+    #[test]
+    fn anonymize_error() {
+        let err = transport::Error::ConnectRemoteServer {
+            host: HostAddress::new("www.example.com", 443),
+            source: std::io::ErrorKind::ConnectionRefused.into(),
+        };
+        assert!(LogPrivacy::Full.anonymize_error(&err).contains("www.example.com"));
+        for log_privacy in [LogPrivacy::DomainOnly, LogPrivacy::Hashed] {
+            let logged = log_privacy.anonymize_error(&err);
+            assert!(!logged.contains("example"), "{logged}");
+            assert!(logged.contains("connection refused"), "{logged}");
+        }
+    }
+}
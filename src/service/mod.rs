@@ -1,2 +1,9 @@
+mod connection_id;
+mod error_verbosity;
 pub mod http;
+mod log_privacy;
 pub mod socks;
+
+pub use self::{
+    connection_id::ConnectionId, error_verbosity::ErrorVerbosity, log_privacy::LogPrivacy,
+};
@@ -0,0 +1,127 @@
+use std::{net::SocketAddr, time::Duration};
+
+use snafu::ResultExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    common::HostAddress,
+    service::{
+        socks::{error, DnsPolicy, Error},
+        LogPrivacy,
+    },
+    transport::{self, TimeoutPhase, Transport},
+};
+
+/// Reason of a failure reply to BIND, mapped to the reply of each SOCKS
+/// version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BindFailure {
+    AddressNotSupported,
+    NotAllowed,
+    Unreachable,
+    TimedOut,
+}
+
+/// Replies to BIND of a SOCKS version.
+pub(crate) trait BindReply {
+    fn success(&self, addr: SocketAddr) -> Vec<u8>;
+
+    fn failure(&self, failure: BindFailure) -> Vec<u8>;
+}
+
+/// Serves BIND, i.e. listens for one inbound connection expected from the
+/// destination of the request, replies with the listening address and then
+/// with the address of the peer connected, and relays between them.
+pub(crate) struct Bind<'a, TransportStream> {
+    pub transport: &'a Transport<TransportStream>,
+    pub dns_policy: DnsPolicy,
+    pub bind_timeout: Option<Duration>,
+    pub log_privacy: LogPrivacy,
+}
+
+impl<TransportStream> Bind<'_, TransportStream>
+where
+    TransportStream: Unpin + AsyncRead + AsyncWrite,
+{
+    pub async fn serve<Stream>(
+        &self,
+        mut stream: Stream,
+        remote_host: &HostAddress,
+        replies: impl BindReply,
+    ) -> Result<(), Error>
+    where
+        Stream: Unpin + AsyncRead + AsyncWrite,
+    {
+        if !self.dns_policy.allows(remote_host) {
+            fail(stream, replies.failure(BindFailure::AddressNotSupported)).await?;
+            return Err(Error::RejectedByDnsPolicy {
+                host: remote_host.clone(),
+                policy: self.dns_policy,
+            });
+        }
+
+        let listener = match self.transport.listen(remote_host).await {
+            Ok(listener) => listener,
+            Err(source) => {
+                fail(stream, replies.failure(failure_of(&source))).await?;
+                return Err(Error::ListenInbound { source, host: remote_host.clone() });
+            }
+        };
+        send(&mut stream, replies.success(listener.local_addr())).await?;
+
+        let timeout = self.bind_timeout.or(self.transport.timeouts().connect);
+        let accept = self.transport.accept(&listener);
+        let (remote_socket, peer_addr) =
+            match transport::with_timeout(timeout, TimeoutPhase::Connect, accept).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(source)) => {
+                    fail(stream, replies.failure(failure_of(&source))).await?;
+                    return Err(Error::AcceptInbound { source });
+                }
+                Err(phase) => {
+                    fail(stream, replies.failure(BindFailure::TimedOut)).await?;
+                    return Err(Error::Timeout { phase });
+                }
+            };
+        drop(listener);
+        send(&mut stream, replies.success(peer_addr)).await?;
+
+        let peer_addr = self.log_privacy.anonymize(&HostAddress::from(peer_addr));
+        tracing::info!("Inbound connection from {} is accepted", peer_addr);
+        self.transport
+            .relay_to(
+                stream,
+                remote_socket,
+                remote_host,
+                Some(Box::new(move || {
+                    tracing::info!("Inbound connection from {} is disconnected", peer_addr);
+                })),
+            )
+            .await
+            .context(error::RelayStreamSnafu)
+    }
+}
+
+fn failure_of(err: &transport::Error) -> BindFailure {
+    if err.is_forbidden() || matches!(err, transport::Error::UnexpectedPeer { .. }) {
+        BindFailure::NotAllowed
+    } else {
+        BindFailure::Unreachable
+    }
+}
+
+async fn send<Stream>(stream: &mut Stream, reply: Vec<u8>) -> Result<(), Error>
+where
+    Stream: Unpin + AsyncWrite,
+{
+    stream.write_all(&reply).await.context(error::WriteStreamSnafu)?;
+    stream.flush().await.context(error::FlushStreamSnafu)
+}
+
+async fn fail<Stream>(mut stream: Stream, reply: Vec<u8>) -> Result<(), Error>
+where
+    Stream: Unpin + AsyncWrite,
+{
+    send(&mut stream, reply).await?;
+    stream.shutdown().await.context(error::ShutdownSnafu)
+}
@@ -0,0 +1,77 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::HostAddress;
+
+/// Policy of destination addresses carried by SOCKS requests.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsPolicy {
+    /// Accept both domain names and IP addresses.
+    #[default]
+    Any,
+
+    /// Reject domain names, clients have to resolve domain names by themselves.
+    RejectDomainRequests,
+
+    /// Reject IP addresses, domain names are always resolved by proxy server.
+    RequireRemoteDns,
+}
+
+impl DnsPolicy {
+    #[must_use]
+    pub const fn allows(self, address: &HostAddress) -> bool {
+        match (self, address) {
+            (Self::Any, _)
+            | (Self::RejectDomainRequests, HostAddress::Socket(_))
+            | (Self::RequireRemoteDns, HostAddress::DomainName(..)) => true,
+            (Self::RejectDomainRequests, HostAddress::DomainName(..))
+            | (Self::RequireRemoteDns, HostAddress::Socket(_)) => false,
+        }
+    }
+}
+
+impl fmt::Display for DnsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::RejectDomainRequests => write!(f, "reject-domain-requests"),
+            Self::RequireRemoteDns => write!(f, "require-remote-dns"),
+        }
+    }
+}
+
+impl FromStr for DnsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Self::Any),
+            "reject-domain-requests" => Ok(Self::RejectDomainRequests),
+            "require-remote-dns" => Ok(Self::RequireRemoteDns),
+            _ => Err(format!("invalid DNS policy: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::DnsPolicy;
+    use crate::common::HostAddress;
+
+    #[test]
+    fn allows() {
+        let domain = HostAddress::new("example.com", 80);
+        let ip = HostAddress::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+
+        assert!(DnsPolicy::Any.allows(&domain));
+        assert!(DnsPolicy::Any.allows(&ip));
+        assert!(!DnsPolicy::RejectDomainRequests.allows(&domain));
+        assert!(DnsPolicy::RejectDomainRequests.allows(&ip));
+        assert!(DnsPolicy::RequireRemoteDns.allows(&domain));
+        assert!(!DnsPolicy::RequireRemoteDns.allows(&ip));
+    }
+}
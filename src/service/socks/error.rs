@@ -1,12 +1,18 @@
 use snafu::Snafu;
 
 use crate::{
+    authentication::{AuthenticationMethod, GssapiError},
     common::HostAddress,
     protocol::{
         self,
-        socks::{v5::Method, SocksCommand, SocksVersion},
+        socks::{
+            v4::Socks4Variant,
+            v5::{GssapiMessageType, Method, ProtectionLevel},
+            SocksCommand, SocksVersion,
+        },
     },
-    transport,
+    service::socks::DnsPolicy,
+    transport::{self, TimeoutPhase},
 };
 
 #[derive(Debug, Snafu)]
@@ -33,15 +39,39 @@ pub enum Error {
     #[snafu(display("Could not establish connection with {}, error: {}", host, source))]
     ConnectRemoteHost { host: HostAddress, source: transport::Error },
 
+    #[snafu(display("Could not listen for inbound connection from {}, error: {}", host, source))]
+    ListenInbound { host: HostAddress, source: transport::Error },
+
+    #[snafu(display("Could not accept inbound connection, error: {}", source))]
+    AcceptInbound { source: transport::Error },
+
     #[snafu(display("Protocol error: {}", source))]
     Protocol { source: protocol::socks::Error },
 
+    #[snafu(display("Destination {host} is rejected by DNS policy {policy}"))]
+    RejectedByDnsPolicy { host: HostAddress, policy: DnsPolicy },
+
+    #[snafu(display("{command} to port {port} is rejected by port policy"))]
+    RejectedByPortPolicy { command: SocksCommand, port: u16 },
+
+    #[snafu(display("Could not resolve target {target} of UDP associate"))]
+    ResolveUdpAssociateTarget { target: HostAddress },
+
+    #[snafu(display("Timed out during {phase}"))]
+    Timeout { phase: TimeoutPhase },
+
     #[snafu(display("Unsupported SOCKS command: {}", command))]
     UnsupportedCommand { command: SocksCommand },
 
     #[snafu(display("Unsupported SOCKS version: {}", version))]
     UnsupportedSocksVersion { version: SocksVersion },
 
+    #[snafu(display("Unsupported SOCKS version 4 variant: {}", variant))]
+    UnsupportedSocks4Variant { variant: Socks4Variant },
+
+    #[snafu(display("SOCKS4 request is rejected as method {method:?} is required"))]
+    AuthenticationRequired { method: AuthenticationMethod },
+
     #[snafu(display("Unsupported method: {}", method))]
     UnsupportedMethod { method: Method },
 
@@ -52,9 +82,24 @@ pub enum Error {
     ))]
     AccessDenied { user_name: Vec<u8>, password: Vec<u8> },
 
+    #[snafu(display("GSS-API failure, error: {}", source))]
+    GssapiFailure { source: GssapiError },
+
+    #[snafu(display("Client aborted GSS-API negotiation"))]
+    GssapiAborted,
+
+    #[snafu(display("Unexpected GSS-API message: {:?}", message_type))]
+    UnexpectedGssapiMessage { message_type: GssapiMessageType },
+
+    #[snafu(display("Unsupported GSS-API protection level: {}", level))]
+    UnsupportedProtectionLevel { level: ProtectionLevel },
+
     #[snafu(display("Invalid SOCKS version: {}", version))]
     InvalidSocksVersion { version: u8 },
 
+    #[snafu(display("Hop count {hops} exceeds limit {max_hops}, proxy servers may form a loop"))]
+    HopLimitExceeded { hops: u8, max_hops: u8 },
+
     #[snafu(display("Invalid address type: {}", ty))]
     InvalidAddressType { ty: u8 },
 
@@ -64,3 +109,18 @@ pub enum Error {
     #[snafu(display("Could not parse handshake request, error: {}", source))]
     ParseHandshakeRequest { source: protocol::socks::Error },
 }
+
+impl Error {
+    /// Returns the phase in which a timeout occurs, `None` if this is not a
+    /// timeout error.
+    #[must_use]
+    pub fn timeout_phase(&self) -> Option<TimeoutPhase> {
+        match self {
+            Self::Timeout { phase } => Some(*phase),
+            Self::RelayStream { source }
+            | Self::ConnectRemoteHost { source, .. }
+            | Self::AcceptInbound { source } => source.timeout_phase(),
+            _ => None,
+        }
+    }
+}
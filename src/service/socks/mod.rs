@@ -1,7 +1,10 @@
+mod bind;
+mod dns_policy;
 mod error;
+mod port_policy;
 mod service;
 
 pub mod v4;
 pub mod v5;
 
-pub use self::{error::Error, service::Service};
+pub use self::{dns_policy::DnsPolicy, error::Error, port_policy::PortPolicy, service::Service};
@@ -0,0 +1,76 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::protocol::socks::SocksCommand;
+
+/// Destination ports allowed for each SOCKS command, e.g. to allow TCP Connect
+/// only to `443` while allowing UDP Associate to any port.
+///
+/// Commands without allowed ports are not restricted. The port of a UDP
+/// Associate request is the source port of the client, so that ports of UDP
+/// Associate are checked for the destination of each datagram instead. TCP
+/// Bind has no destination and is never restricted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PortPolicy {
+    ports: HashMap<SocksCommand, HashSet<u16>>,
+}
+
+impl PortPolicy {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self { Self::default() }
+
+    /// Allows `command` to `ports` in addition to ports allowed before, any
+    /// other port is rejected for `command` afterwards.
+    #[must_use]
+    pub fn allow<I>(mut self, command: SocksCommand, ports: I) -> Self
+    where
+        I: IntoIterator<Item = u16>,
+    {
+        self.ports.entry(command).or_default().extend(ports);
+        self
+    }
+
+    /// Whether `command` to destination `port` is allowed.
+    #[must_use]
+    pub fn allows(&self, command: SocksCommand, port: u16) -> bool {
+        match (command, self.ports.get(&command)) {
+            (SocksCommand::TcpBind, _) | (_, None) => true,
+            (_, Some(ports)) => ports.contains(&port),
+        }
+    }
+
+    /// Whether a request of `command` to `port` is allowed, only requests of
+    /// TCP Connect are to their destinations.
+    #[must_use]
+    pub(crate) fn allows_request(&self, command: SocksCommand, port: u16) -> bool {
+        command != SocksCommand::TcpConnect || self.allows(command, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PortPolicy;
+    use crate::protocol::socks::SocksCommand;
+
+    #[test]
+    fn allows() {
+        let policy = PortPolicy::new().allow(SocksCommand::TcpConnect, [443]);
+        assert!(policy.allows(SocksCommand::TcpConnect, 443));
+        assert!(!policy.allows(SocksCommand::TcpConnect, 80));
+        assert!(policy.allows(SocksCommand::UdpAssociate, 53));
+        assert!(policy.allows(SocksCommand::TcpBind, 80));
+
+        // an empty set of ports rejects every destination
+        let policy = PortPolicy::new().allow(SocksCommand::TcpConnect, []);
+        assert!(!policy.allows(SocksCommand::TcpConnect, 80));
+
+        // ports of UDP Associate are checked per datagram, not per request
+        let policy = PortPolicy::new().allow(SocksCommand::UdpAssociate, [53]);
+        assert!(!policy.allows(SocksCommand::UdpAssociate, 40000));
+        assert!(policy.allows_request(SocksCommand::UdpAssociate, 40000));
+
+        // TCP Bind has no destination
+        let policy = PortPolicy::new().allow(SocksCommand::TcpBind, []);
+        assert!(policy.allows(SocksCommand::TcpBind, 80));
+    }
+}
@@ -1,21 +1,27 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
 
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::{mpsc, Mutex},
 };
+use tracing::Instrument;
 
 use crate::{
     authentication::AuthenticationManager,
-    common::HostAddress,
-    protocol::socks::SocksVersion,
-    service::socks::{v4, v5, Error},
-    transport::Transport,
+    filter::ClientIdentity,
+    protocol::socks::{HopCount, SocksVersion},
+    service::{
+        socks::{v4, v5, v5::UdpAssociateRequest, DnsPolicy, Error, PortPolicy},
+        ConnectionId, ErrorVerbosity, LogPrivacy,
+    },
+    transport::{self, TimeoutPhase, Transport},
 };
 
 pub struct Service<ClientStream, TransportStream> {
     service_v4: Option<v4::Service<ClientStream, TransportStream>>,
     service_v5: Option<v5::Service<ClientStream, TransportStream>>,
+    handshake_timeout: Option<Duration>,
+    max_hops: Option<u8>,
 }
 
 impl<ClientStream, TransportStream> Service<ClientStream, TransportStream>
@@ -29,7 +35,8 @@ where
         authentication_manager: Arc<Mutex<AuthenticationManager>>,
         enable_tcp_connect: bool,
         enable_tcp_bind: bool,
-        udp_associate_stream_tx: Option<Mutex<mpsc::Sender<(ClientStream, HostAddress)>>>,
+        udp_associate_stream_tx: Option<Mutex<mpsc::Sender<UdpAssociateRequest<ClientStream>>>>,
+        log_connection_open: bool,
     ) -> Self {
         let service_v4 = if supported_versions.contains(&SocksVersion::V4) {
             tracing::info!("SOCKS4a is supported");
@@ -38,6 +45,7 @@ where
                 authentication_manager.clone(),
                 enable_tcp_connect,
                 enable_tcp_bind,
+                log_connection_open,
             ))
         } else {
             None
@@ -51,20 +59,70 @@ where
                 enable_tcp_connect,
                 enable_tcp_bind,
                 udp_associate_stream_tx,
+                log_connection_open,
             ))
         } else {
             None
         };
 
-        Self { service_v4, service_v5 }
+        Self { service_v4, service_v5, handshake_timeout: None, max_hops: None }
     }
 
-    pub async fn dispatch(
+    pub async fn dispatch(&self, stream: ClientStream, peer_addr: SocketAddr) -> Result<(), Error> {
+        ClientIdentity::new(peer_addr)
+            .scope(self.dispatch_client(stream, peer_addr))
+            .instrument(ConnectionId::next().span())
+            .await
+    }
+
+    async fn dispatch_client(
+        &self,
+        mut stream: ClientStream,
+        peer_addr: SocketAddr,
+    ) -> Result<(), Error> {
+        let mut version = self.read_version(&mut stream).await?;
+
+        if let Some(max_hops) = self.max_hops {
+            let hops = match version {
+                Ok(byte) if byte == HopCount::MARKER[0] => {
+                    let hops = transport::with_timeout(
+                        self.handshake_timeout,
+                        TimeoutPhase::Handshake,
+                        HopCount::from_reader(&mut stream),
+                    )
+                    .await
+                    .map_err(|phase| Error::Timeout { phase })?
+                    .map_err(|source| Error::Protocol { source })?;
+                    version = self.read_version(&mut stream).await?;
+                    hops
+                }
+                _ => HopCount::default(),
+            };
+
+            if hops.get() > max_hops {
+                let _unused = stream.shutdown().await;
+                return Err(Error::HopLimitExceeded { hops: hops.get(), max_hops });
+            }
+
+            return hops.scope(self.dispatch_version(stream, peer_addr, version)).await;
+        }
+
+        self.dispatch_version(stream, peer_addr, version).await
+    }
+
+    async fn read_version(&self, stream: &mut ClientStream) -> Result<std::io::Result<u8>, Error> {
+        transport::with_timeout(self.handshake_timeout, TimeoutPhase::Handshake, stream.read_u8())
+            .await
+            .map_err(|phase| Error::Timeout { phase })
+    }
+
+    async fn dispatch_version(
         &self,
         mut stream: ClientStream,
         peer_addr: SocketAddr,
+        version: std::io::Result<u8>,
     ) -> Result<(), Error> {
-        match stream.read_u8().await {
+        match version {
             Ok(0x04) => match self.service_v4 {
                 Some(ref service) => service.handle(stream, peer_addr).await,
                 None => Err(Error::UnsupportedSocksVersion { version: SocksVersion::V4 }),
@@ -88,6 +146,103 @@ where
         }
     }
 
+    #[must_use]
+    pub fn with_dns_policy(mut self, dns_policy: DnsPolicy) -> Self {
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_dns_policy(dns_policy);
+        }
+        if let Some(ref mut service) = self.service_v5 {
+            service.set_dns_policy(dns_policy);
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn with_port_policy(mut self, port_policy: PortPolicy) -> Self {
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_port_policy(port_policy.clone());
+        }
+        if let Some(ref mut service) = self.service_v5 {
+            service.set_port_policy(port_policy);
+        }
+        self
+    }
+
+    /// Sets variants of SOCKS version 4 accepted, i.e. plain SOCKS4 with IPv4
+    /// destinations and SOCKS4a with domain names resolved by this server.
+    #[must_use]
+    pub fn with_socks4_variants(mut self, enable_socks4: bool, enable_socks4a: bool) -> Self {
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_supported_variants(enable_socks4, enable_socks4a);
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn with_log_privacy(mut self, log_privacy: LogPrivacy) -> Self {
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_log_privacy(log_privacy);
+        }
+        if let Some(ref mut service) = self.service_v5 {
+            service.set_log_privacy(log_privacy);
+        }
+        self
+    }
+
+    /// Sets the detail of errors of connecting destinations, SOCKS replies
+    /// carry no message so the underlying error is logged in verbose form.
+    #[must_use]
+    pub fn with_error_verbosity(mut self, error_verbosity: ErrorVerbosity) -> Self {
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_error_verbosity(error_verbosity);
+        }
+        if let Some(ref mut service) = self.service_v5 {
+            service.set_error_verbosity(error_verbosity);
+        }
+        self
+    }
+
+    /// Enables loop detection of chained tunelo instances, connections which
+    /// pass through more than `max_hops` tunelo instances are refused. See
+    /// [`HopCount`] for the tunelo-specific marker carrying the hop count.
+    ///
+    /// Upstream proxy servers must be tunelo instances with loop detection
+    /// enabled as well, the marker is sent to them.
+    #[must_use]
+    pub const fn with_max_hops(mut self, max_hops: u8) -> Self {
+        self.max_hops = Some(max_hops);
+        self
+    }
+
+    /// Sets the time limit of receiving request from client, the limit applies
+    /// to SOCKS version detection and the rest of handshake separately.
+    #[must_use]
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = Some(handshake_timeout);
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_handshake_timeout(handshake_timeout);
+        }
+        if let Some(ref mut service) = self.service_v5 {
+            service.set_handshake_timeout(handshake_timeout);
+        }
+        self
+    }
+
+    /// Sets the time limit of waiting for the inbound connection of TCP Bind,
+    /// which is separated from connect timeout as peers like FTP servers may
+    /// take a while to open data connections. Connect timeout of the transport
+    /// is used if it is not set.
+    #[must_use]
+    pub fn with_bind_timeout(mut self, bind_timeout: Duration) -> Self {
+        if let Some(ref mut service) = self.service_v4 {
+            service.set_bind_timeout(bind_timeout);
+        }
+        if let Some(ref mut service) = self.service_v5 {
+            service.set_bind_timeout(bind_timeout);
+        }
+        self
+    }
+
     #[allow(dead_code)]
     pub fn supported_versions(&self) -> Vec<SocksVersion> {
         let mut versions = Vec::new();
@@ -102,4 +257,203 @@ where
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, Mutex},
+    };
+    use tracing::Level;
+
+    use super::Service;
+    use crate::{
+        authentication::AuthenticationManager,
+        common::{Policy, ProxyHost, ProxyStrategy},
+        filter::SimpleFilter,
+        protocol::socks::{HopCount, SocksVersion},
+        service::socks::Error,
+        test_util::{unfiltered_transport, CapturedLogs},
+        transport::{TimeoutPhase, TokioResolver, Transport},
+    };
+
+    fn service<Stream>(transport: Arc<Transport<Stream>>) -> Service<TcpStream, Stream>
+    where
+        Stream: Unpin + tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        Service::new(
+            HashSet::from([SocksVersion::V5]),
+            transport,
+            Arc::new(Mutex::new(AuthenticationManager::new())),
+            true,
+            false,
+            None,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn share_connection_id_in_logs() {
+        let (logs, _guard) = CapturedLogs::capture(Level::INFO);
+
+        let service = {
+            let transport = unfiltered_transport();
+            Service::new(
+                HashSet::from([SocksVersion::V5]),
+                transport,
+                Arc::new(Mutex::new(AuthenticationManager::new())),
+                true,
+                false,
+                None,
+                true,
+            )
+        };
+
+        let remote = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let [port_high, port_low] = remote.local_addr().unwrap().port().to_be_bytes();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (server, peer_addr) = listener.accept().await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            client
+                .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+                .await
+                .unwrap();
+            let accept = async { drop(remote.accept().await.unwrap()) };
+            let (result, ()) = tokio::join!(service.dispatch(server, peer_addr), accept);
+            assert!(result.is_ok());
+        }
+
+        // events of each connection are logged in its own span
+        let opened = logs.connection_ids("Connection opened");
+        assert_eq!(opened.len(), 2, "{}", logs.contents());
+        assert!(opened.iter().all(Option::is_some));
+        assert_ne!(opened[0], opened[1]);
+        assert_eq!(logs.connection_ids(" is connected"), opened);
+        assert_eq!(logs.connection_ids(" is disconnected"), opened);
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let service = {
+            let transport = unfiltered_transport();
+            Service::new(
+                HashSet::from([SocksVersion::V4, SocksVersion::V5]),
+                transport,
+                Arc::new(Mutex::new(AuthenticationManager::new())),
+                true,
+                false,
+                None,
+                false,
+            )
+            .with_handshake_timeout(Duration::from_millis(50))
+        };
+        let peer_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        // nothing is sent
+        let (_client, server) = tokio::io::duplex(64);
+        let err = service.dispatch(server, peer_addr).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Handshake));
+
+        // stalled in the middle of SOCKS5 and SOCKS4 handshakes
+        for request in [&[0x05, 0x01][..], &[0x04, 0x01, 0x00]] {
+            let (mut client, server) = tokio::io::duplex(64);
+            client.write_all(request).await.unwrap();
+            let err = service.dispatch(server, peer_addr).await.unwrap_err();
+            assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Handshake));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_over_limit_hop_count() {
+        let service = {
+            let transport = unfiltered_transport();
+            service(transport).with_max_hops(2)
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, peer_addr) = listener.accept().await.unwrap();
+        client.write_all(&HopCount::new(3).to_bytes()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+        let err = service.dispatch(server, peer_addr).await.unwrap_err();
+        assert!(matches!(err, Error::HopLimitExceeded { hops: 3, max_hops: 2 }));
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+
+        // within the limit
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, peer_addr) = listener.accept().await.unwrap();
+        client.write_all(&HopCount::new(2).to_bytes()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        tokio::spawn(async move { service.dispatch(server, peer_addr).await });
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn detect_proxy_loop() {
+        // two servers use each other as upstream proxy server
+        let listeners = [
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+        ];
+        let addrs = [listeners[0].local_addr().unwrap(), listeners[1].local_addr().unwrap()];
+
+        let (err_tx, mut err_rx) = mpsc::unbounded_channel();
+        for (listener, upstream) in listeners.into_iter().zip([addrs[1], addrs[0]]) {
+            let strategy = Arc::new(ProxyStrategy::Single(ProxyHost::Socks5 {
+                host: upstream.ip().to_string(),
+                port: upstream.port(),
+                username: None,
+                password: None,
+            }));
+            let filter = Arc::new(SimpleFilter::deny_list());
+            let transport = Transport::proxy(
+                Arc::new(TokioResolver::new()),
+                filter,
+                strategy,
+                Policy::default(),
+            )
+            .unwrap();
+            let service = Arc::new(service(Arc::new(transport)).with_max_hops(4));
+            let err_tx = err_tx.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, peer_addr)) = listener.accept().await {
+                    let service = service.clone();
+                    let err_tx = err_tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = service.dispatch(stream, peer_addr).await {
+                            let _unused = err_tx.send(err);
+                        }
+                    });
+                }
+            });
+        }
+
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        // CONNECT 192.0.2.1:80
+        client.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50]).await.unwrap();
+
+        loop {
+            let err = err_rx.recv().await.unwrap();
+            if let Error::HopLimitExceeded { hops, max_hops } = err {
+                assert_eq!((hops, max_hops), (5, 4));
+                break;
+            }
+        }
+    }
+}
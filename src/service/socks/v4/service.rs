@@ -2,6 +2,7 @@ use std::{
     collections::HashSet,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
+    time::Duration,
 };
 
 use snafu::ResultExt;
@@ -11,16 +12,31 @@ use tokio::{
 };
 
 use crate::{
-    authentication::AuthenticationManager,
-    protocol::socks::v4::{Command, Reply, Request},
-    service::socks::{error, Error},
-    transport::Transport,
+    authentication::{AuthenticationManager, AuthenticationMethod},
+    common::HostAddress,
+    protocol::socks::v4::{Command, Reply, Request, Socks4Variant},
+    service::{
+        socks::{
+            bind::{Bind, BindFailure, BindReply},
+            error, DnsPolicy, Error, PortPolicy,
+        },
+        ErrorVerbosity, LogPrivacy,
+    },
+    transport::{self, TimeoutPhase, Transport},
 };
 
 pub struct Service<ClientStream, TransportStream> {
     supported_commands: HashSet<Command>,
+    supported_variants: HashSet<Socks4Variant>,
     transport: Arc<Transport<TransportStream>>,
-    _authentication_manager: Arc<Mutex<AuthenticationManager>>,
+    authentication_manager: Arc<Mutex<AuthenticationManager>>,
+    log_connection_open: bool,
+    dns_policy: DnsPolicy,
+    port_policy: PortPolicy,
+    log_privacy: LogPrivacy,
+    error_verbosity: ErrorVerbosity,
+    handshake_timeout: Option<Duration>,
+    bind_timeout: Option<Duration>,
     _phantom: std::marker::PhantomData<ClientStream>,
 }
 
@@ -34,6 +50,7 @@ where
         authentication_manager: Arc<Mutex<AuthenticationManager>>,
         enable_tcp_connect: bool,
         enable_tcp_bind: bool,
+        log_connection_open: bool,
     ) -> Self {
         let supported_commands = {
             let mut commands = HashSet::new();
@@ -55,12 +72,59 @@ where
 
         Self {
             supported_commands,
+            supported_variants: HashSet::from([Socks4Variant::Socks4, Socks4Variant::Socks4a]),
             transport,
-            _authentication_manager: authentication_manager,
+            authentication_manager,
+            log_connection_open,
+            dns_policy: DnsPolicy::default(),
+            port_policy: PortPolicy::default(),
+            log_privacy: LogPrivacy::default(),
+            error_verbosity: ErrorVerbosity::default(),
+            handshake_timeout: None,
+            bind_timeout: None,
             _phantom: Default::default(),
         }
     }
 
+    /// Sets variants of request accepted, requests of other variants are
+    /// rejected.
+    pub fn set_supported_variants(&mut self, enable_socks4: bool, enable_socks4a: bool) {
+        self.supported_variants.clear();
+        if enable_socks4 {
+            self.supported_variants.insert(Socks4Variant::Socks4);
+        }
+        if enable_socks4a {
+            self.supported_variants.insert(Socks4Variant::Socks4a);
+        }
+        if self.supported_variants.is_empty() {
+            tracing::warn!("No variant of SOCKS4 is supported.");
+        }
+    }
+
+    #[inline]
+    pub fn set_dns_policy(&mut self, dns_policy: DnsPolicy) { self.dns_policy = dns_policy; }
+
+    #[inline]
+    pub fn set_port_policy(&mut self, port_policy: PortPolicy) { self.port_policy = port_policy; }
+
+    #[inline]
+    pub fn set_log_privacy(&mut self, log_privacy: LogPrivacy) { self.log_privacy = log_privacy; }
+
+    #[inline]
+    pub fn set_error_verbosity(&mut self, error_verbosity: ErrorVerbosity) {
+        self.error_verbosity = error_verbosity;
+    }
+
+    #[inline]
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = Some(handshake_timeout);
+    }
+
+    #[inline]
+    pub fn set_bind_timeout(&mut self, bind_timeout: Duration) {
+        self.bind_timeout = Some(bind_timeout);
+    }
+
     pub async fn handle(
         &self,
         mut stream: ClientStream,
@@ -68,7 +132,24 @@ where
     ) -> Result<(), Error> {
         tracing::info!("Receive request from {}", peer_addr);
 
-        let request = Request::from_reader(&mut stream).await.context(error::ParseRequestSnafu)?;
+        let request = transport::with_timeout(
+            self.handshake_timeout,
+            TimeoutPhase::Handshake,
+            Request::from_reader(&mut stream),
+        )
+        .await
+        .map_err(|phase| Error::Timeout { phase })?
+        .context(error::ParseRequestSnafu)?;
+
+        // SOCKS4 carries no credential but USERID, clients must not get around
+        // authentication required by SOCKS5
+        let method = self.authentication_manager.lock().await.supported_method(&peer_addr);
+        if method != AuthenticationMethod::NoAuthentication {
+            let reply = Reply::rejected(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+            let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+            stream.shutdown().await.context(error::ShutdownSnafu)?;
+            return Err(Error::AuthenticationRequired { method });
+        }
 
         if !self.supported_commands.contains(&request.command) {
             let reply = Reply::rejected(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
@@ -77,14 +158,50 @@ where
             return Err(Error::UnsupportedCommand { command: request.command.into() });
         }
 
+        if !self.supported_variants.contains(&request.variant) {
+            let reply = Reply::rejected(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+            let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+            stream.shutdown().await.context(error::ShutdownSnafu)?;
+            return Err(Error::UnsupportedSocks4Variant { variant: request.variant });
+        }
+
+        let (command, port) = (request.command.into(), request.destination_socket.port());
+        if !self.port_policy.allows_request(command, port) {
+            let reply = Reply::rejected(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+            let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+            stream.shutdown().await.context(error::ShutdownSnafu)?;
+            return Err(Error::RejectedByPortPolicy { command, port });
+        }
+
+        if self.log_connection_open {
+            tracing::info!(
+                "Connection opened from {} to {}",
+                peer_addr,
+                self.log_privacy.anonymize(request.destination_socket.as_ref())
+            );
+        }
+
         match request.command {
             Command::TcpConnect => {
                 let remote_host = request.destination_socket.as_ref();
-                use crate::common::HostAddress;
+
+                if !self.dns_policy.allows(remote_host) {
+                    let reply = Reply::rejected(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+                    let _ =
+                        stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+                    stream.shutdown().await.context(error::ShutdownSnafu)?;
+                    return Err(Error::RejectedByDnsPolicy {
+                        host: remote_host.clone(),
+                        policy: self.dns_policy,
+                    });
+                }
 
                 let (remote_socket, remote_addr) = match self.transport.connect(remote_host).await {
                     Ok((socket, addr)) => {
-                        tracing::info!("Remote host {} is connected", remote_host.to_string());
+                        tracing::info!(
+                            "Remote host {} is connected",
+                            self.log_privacy.anonymize(remote_host)
+                        );
                         let remote_addr = match addr {
                             HostAddress::Socket(SocketAddr::V4(addr)) => addr,
                             HostAddress::Socket(_) | HostAddress::DomainName(..) => {
@@ -95,7 +212,19 @@ where
                         (socket, remote_addr)
                     }
                     Err(source) => {
-                        let reply = Reply::unreachable(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+                        if self.error_verbosity.is_verbose() {
+                            tracing::warn!(
+                                "Failed to connect {}, error: {}",
+                                self.log_privacy.anonymize(remote_host),
+                                self.log_privacy.anonymize_error(&source)
+                            );
+                        }
+                        let empty_socket = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+                        let reply = if source.is_forbidden() {
+                            Reply::rejected(empty_socket)
+                        } else {
+                            Reply::unreachable(empty_socket)
+                        };
                         let _ = stream
                             .write(&reply.into_bytes())
                             .await
@@ -109,10 +238,12 @@ where
                 let reply = Reply::granted(remote_addr);
                 let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
 
+                let remote_addr = self.log_privacy.anonymize(&HostAddress::from(remote_addr));
                 self.transport
-                    .relay(
+                    .relay_to(
                         stream,
                         remote_socket,
+                        remote_host,
                         Some(Box::new(move || {
                             tracing::info!("Remote host {} is disconnected", remote_addr);
                         })),
@@ -122,14 +253,168 @@ where
 
                 Ok(())
             }
-            Command::TcpBind => {
-                tracing::debug!("Unsupported SOCKS command, close connection: {:?}", peer_addr);
-                stream.shutdown().await.context(error::ShutdownSnafu)?;
-                Err(Error::UnsupportedCommand { command: Command::TcpBind.into() })
+            Command::TcpBind => self.serve_bind(stream, request).await,
+        }
+    }
+
+    /// Serves BIND, SOCKS4 replies carry IPv4 addresses only, IPv6 addresses
+    /// are replied as `0.0.0.0`.
+    async fn serve_bind(&self, stream: ClientStream, request: Request) -> Result<(), Error> {
+        let bind = Bind {
+            transport: &self.transport,
+            dns_policy: self.dns_policy,
+            bind_timeout: self.bind_timeout,
+            log_privacy: self.log_privacy,
+        };
+        bind.serve(stream, request.destination_socket.as_ref(), BindReplyV4).await
+    }
+}
+
+struct BindReplyV4;
+
+impl BindReply for BindReplyV4 {
+    fn success(&self, addr: SocketAddr) -> Vec<u8> {
+        Reply::granted(to_socket_v4(addr)).into_bytes()
+    }
+
+    fn failure(&self, failure: BindFailure) -> Vec<u8> {
+        let empty_socket = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+        match failure {
+            BindFailure::Unreachable => Reply::unreachable(empty_socket),
+            BindFailure::AddressNotSupported | BindFailure::NotAllowed | BindFailure::TimedOut => {
+                Reply::rejected(empty_socket)
             }
         }
+        .into_bytes()
+    }
+}
+
+fn to_socket_v4(addr: SocketAddr) -> SocketAddrV4 {
+    match addr {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncWriteExt, DuplexStream},
+        net::TcpStream,
+        sync::Mutex,
+    };
+
+    use crate::{
+        authentication::{AuthenticationManager, AuthenticationMethod},
+        filter::SimpleFilter,
+        protocol::socks::{
+            v4::{Command, Reply, ReplyField, Request, Socks4Variant},
+            Address,
+        },
+        service::socks::{v4::Service, Error},
+        test_util::{direct_transport, unfiltered_transport},
+        transport::TimeoutPhase,
+    };
+
+    #[tokio::test]
+    async fn tcp_bind_timeout() {
+        let transport = unfiltered_transport();
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let mut service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            true,
+            false,
+        );
+        service.set_bind_timeout(Duration::from_millis(50));
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let destination = Address::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+        let request = Request::new(Command::TcpBind, destination, Vec::new()).unwrap();
+        // the version is consumed while detecting SOCKS version
+        client.write_all(&request.into_bytes()[1..]).await.unwrap();
+
+        let result = service.handle(server, client_addr).await;
+        assert!(matches!(result, Err(Error::Timeout { phase: TimeoutPhase::Connect })));
+
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Granted);
+        assert_eq!(*reply.destination_socket.ip(), Ipv4Addr::LOCALHOST);
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Rejected);
+    }
+
+    #[tokio::test]
+    async fn reject_when_authentication_required() {
+        let transport = unfiltered_transport();
+        let mut manager = AuthenticationManager::new();
+        manager.add_user(b"user".to_vec(), b"password".to_vec());
+        let service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            Arc::new(Mutex::new(manager)),
+            true,
+            false,
+            false,
+        );
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let destination = Address::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        let request = Request::new(Command::TcpConnect, destination, b"user".to_vec()).unwrap();
+        client.write_all(&request.into_bytes()[1..]).await.unwrap();
+
+        let result = service.handle(server, client_addr).await;
+        assert!(matches!(
+            result,
+            Err(Error::AuthenticationRequired { method: AuthenticationMethod::UsernamePassword })
+        ));
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Rejected);
+    }
+
+    #[tokio::test]
+    async fn reject_disabled_variant() {
+        // an empty allow list denies everything, accepted requests fail later
+        let transport = Arc::new(direct_transport(SimpleFilter::allow_list()));
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let mut service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            false,
+            false,
+        );
+        service.set_supported_variants(true, false);
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        for (destination, variant) in [
+            (Address::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80))), None),
+            (Address::new_domain(b"example.com", 80), Some(Socks4Variant::Socks4a)),
+        ] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let request = Request::new(Command::TcpConnect, destination, Vec::new()).unwrap();
+            client.write_all(&request.into_bytes()[1..]).await.unwrap();
+
+            match (service.handle(server, client_addr).await, variant) {
+                (Err(Error::UnsupportedSocks4Variant { variant }), Some(disabled)) => {
+                    assert_eq!(variant, disabled);
+                }
+                // SOCKS4 passes the variant check and is denied by filter
+                (Err(Error::ConnectRemoteHost { source, .. }), None) => {
+                    assert!(source.is_forbidden());
+                }
+                (result, _) => panic!("unexpected result: {result:?}"),
+            }
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Rejected);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use snafu::ResultExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    authentication::{GssapiAccept, GssapiAuthenticator, GssapiContext},
+    protocol::socks::{
+        self,
+        v5::{GssapiMessage, GssapiMessageType, ProtectionLevel},
+    },
+    service::socks::{error, Error},
+};
+
+/// Largest chunk of user data wrapped into one message, leaves room for the
+/// overhead of `gss_wrap` within the 16-bit token length.
+const MAX_CHUNK_SIZE: usize = 32 * 1024;
+
+const PROTECTION_LEVELS: [ProtectionLevel; 3] = [
+    ProtectionLevel::Integrity,
+    ProtectionLevel::Confidentiality,
+    ProtectionLevel::SelectivePerMessage,
+];
+
+/// Established GSS-API security context and the negotiated protection level.
+pub(crate) struct GssapiSession {
+    authenticator: Arc<dyn GssapiAuthenticator>,
+    context: GssapiContext,
+    protection_level: ProtectionLevel,
+}
+
+/// Establishes a security context with the client and negotiates per-message
+/// protection as described in RFC 1961.
+pub(crate) async fn establish_session<S>(
+    client: &mut S,
+    authenticator: Arc<dyn GssapiAuthenticator>,
+) -> Result<GssapiSession, Error>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    let mut context = GssapiContext::default();
+    loop {
+        let message = read_message(client, GssapiMessageType::Authentication).await?;
+        match authenticator.accept_context(&mut context, &message.token) {
+            Ok(GssapiAccept::Continue { token }) => {
+                write_message(client, GssapiMessageType::Authentication, token).await?;
+            }
+            Ok(GssapiAccept::Established { token }) => {
+                if !token.is_empty() {
+                    write_message(client, GssapiMessageType::Authentication, token).await?;
+                }
+                break;
+            }
+            Err(source) => {
+                abort(client).await?;
+                return Err(Error::GssapiFailure { source });
+            }
+        }
+    }
+    tracing::info!(
+        "GSS-API security context is established with principal: {}",
+        context.principal.as_deref().unwrap_or("<unknown>")
+    );
+
+    let message = read_message(client, GssapiMessageType::ProtectionLevel).await?;
+    let requested = match authenticator.unwrap(&context, &message.token) {
+        Ok(token) => match token[..] {
+            [level] => ProtectionLevel::try_from(level),
+            _ => Err(socks::Error::BadRequest),
+        },
+        Err(source) => {
+            abort(client).await?;
+            return Err(Error::GssapiFailure { source });
+        }
+    };
+    let requested = match requested {
+        Ok(level) => level,
+        Err(source) => {
+            abort(client).await?;
+            return Err(Error::ParseHandshakeRequest { source });
+        }
+    };
+
+    let Some(protection_level) = select_protection_level(authenticator.as_ref(), requested) else {
+        abort(client).await?;
+        return Err(Error::UnsupportedProtectionLevel { level: requested });
+    };
+    if protection_level != requested {
+        tracing::info!(
+            "GSS-API protection level {requested} is not supported, falling back to \
+             {protection_level}"
+        );
+    }
+
+    let token = match authenticator.wrap(&context, false, &[protection_level.into()]) {
+        Ok(token) => token,
+        Err(source) => {
+            abort(client).await?;
+            return Err(Error::GssapiFailure { source });
+        }
+    };
+    write_message(client, GssapiMessageType::ProtectionLevel, token).await?;
+
+    Ok(GssapiSession { authenticator, context, protection_level })
+}
+
+/// Returns `requested` if it is supported, the nearest supported level
+/// otherwise, weaker levels are preferred over stronger ones.
+fn select_protection_level(
+    authenticator: &dyn GssapiAuthenticator,
+    requested: ProtectionLevel,
+) -> Option<ProtectionLevel> {
+    let index = PROTECTION_LEVELS.iter().position(|level| *level == requested)?;
+    PROTECTION_LEVELS[..=index]
+        .iter()
+        .rev()
+        .chain(&PROTECTION_LEVELS[index + 1..])
+        .copied()
+        .find(|level| authenticator.supports_protection_level(*level))
+}
+
+async fn read_message<S>(
+    client: &mut S,
+    expected: GssapiMessageType,
+) -> Result<GssapiMessage, Error>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    let message =
+        GssapiMessage::from_reader(client).await.context(error::ParseHandshakeRequestSnafu)?;
+    match message.message_type {
+        message_type if message_type == expected => Ok(message),
+        GssapiMessageType::Abort => Err(Error::GssapiAborted),
+        message_type => {
+            abort(client).await?;
+            Err(Error::UnexpectedGssapiMessage { message_type })
+        }
+    }
+}
+
+async fn write_message<S>(
+    client: &mut S,
+    message_type: GssapiMessageType,
+    token: Vec<u8>,
+) -> Result<(), Error>
+where
+    S: Unpin + AsyncWrite,
+{
+    let message = GssapiMessage::new(message_type, token);
+    client.write_all(&message.into_bytes()).await.context(error::WriteStreamSnafu)?;
+    client.flush().await.context(error::FlushStreamSnafu)
+}
+
+async fn abort<S>(client: &mut S) -> Result<(), Error>
+where
+    S: Unpin + AsyncWrite,
+{
+    client
+        .write_all(&GssapiMessage::abort().into_bytes())
+        .await
+        .context(error::WriteStreamSnafu)?;
+    client.flush().await.context(error::FlushStreamSnafu)?;
+    client.shutdown().await.context(error::ShutdownSnafu)
+}
+
+/// Stream encapsulating everything after the handshake in GSS-API messages,
+/// including the SOCKS request and reply.
+pub(crate) struct GssapiStream<S> {
+    stream: S,
+    session: GssapiSession,
+    header: [u8; GssapiMessage::HEADER_LEN],
+    header_len: usize,
+    token: Vec<u8>,
+    token_len: usize,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> GssapiStream<S> {
+    pub(crate) fn new(stream: S, session: GssapiSession) -> Self {
+        Self {
+            stream,
+            session,
+            header: [0u8; GssapiMessage::HEADER_LEN],
+            header_len: 0,
+            token: Vec::new(),
+            token_len: 0,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Messages are protected with confidentiality unless only integrity is
+    /// negotiated.
+    fn is_confidential(&self) -> bool {
+        self.session.protection_level != ProtectionLevel::Integrity
+    }
+}
+
+impl<S: AsyncRead + Unpin> GssapiStream<S> {
+    /// Reads the next message into `read_buf`, returns `false` at the end of
+    /// stream.
+    fn poll_read_message(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        while self.header_len < self.header.len() {
+            let mut buf = ReadBuf::new(&mut self.header[self.header_len..]);
+            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 if self.header_len == 0 => return Poll::Ready(Ok(false)),
+                0 => return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into())),
+                n => self.header_len += n,
+            }
+
+            if self.header_len >= 2 {
+                check_header(self.header[0], self.header[1])?;
+            }
+            if self.header_len == self.header.len() {
+                let len = u16::from_be_bytes([self.header[2], self.header[3]]);
+                self.token = vec![0u8; usize::from(len)];
+                self.token_len = 0;
+            }
+        }
+
+        while self.token_len < self.token.len() {
+            let mut buf = ReadBuf::new(&mut self.token[self.token_len..]);
+            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 => return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into())),
+                n => self.token_len += n,
+            }
+        }
+
+        self.header_len = 0;
+        self.read_buf = self
+            .session
+            .authenticator
+            .unwrap(&self.session.context, &self.token)
+            .map_err(std::io::Error::other)?;
+        self.read_pos = 0;
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> GssapiStream<S> {
+    fn poll_write_message(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn check_header(version: u8, message_type: u8) -> std::io::Result<()> {
+    match GssapiMessageType::try_from(message_type) {
+        _ if version != socks::consts::SOCKS5_GSSAPI_VERSION => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            socks::Error::InvalidGssapiVersion { version },
+        )),
+        Ok(GssapiMessageType::Encapsulation) => Ok(()),
+        Ok(GssapiMessageType::Abort) => Err(std::io::ErrorKind::ConnectionAborted.into()),
+        Ok(_) | Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            socks::Error::InvalidGssapiMessageType { ty: message_type },
+        )),
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for GssapiStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        // messages carrying no data are skipped, an empty read means the end of stream
+        while this.read_pos == this.read_buf.len() {
+            if !ready!(this.poll_read_message(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for GssapiStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_CHUNK_SIZE);
+        let token = this
+            .session
+            .authenticator
+            .wrap(&this.session.context, this.is_confidential(), &buf[..n])
+            .map_err(std::io::Error::other)?;
+        if token.len() > usize::from(u16::MAX) {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "wrapped token exceeds 65535 bytes",
+            )));
+        }
+        this.write_buf = GssapiMessage::new(GssapiMessageType::Encapsulation, token).into_bytes();
+
+        // the message is accepted once wrapped, it is written out by later calls if
+        // the stream is not ready
+        if let Poll::Ready(Err(err)) = this.poll_write_message(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
@@ -1,5 +1,8 @@
+mod gssapi;
 mod service;
-// FIXME: uncomment this
-// mod udp;
+mod udp;
 
-pub use self::service::Service;
+pub use self::{
+    service::Service,
+    udp::{UdpAssociateManager, UdpAssociateRequest},
+};
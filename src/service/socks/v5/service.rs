@@ -1,9 +1,10 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
 
 use snafu::ResultExt;
 use tokio::{
     io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     sync::{mpsc, Mutex},
+    time::Instant,
 };
 
 use crate::{
@@ -14,17 +15,35 @@ use crate::{
             Command, HandshakeReply, HandshakeRequest, Method, Reply, Request,
             UserPasswordHandshakeReply, UserPasswordHandshakeRequest,
         },
-        Address,
+        Address, AddressType,
     },
-    service::socks::{error, Error},
-    transport::Transport,
+    service::{
+        socks::{
+            bind::{Bind, BindFailure, BindReply},
+            error,
+            v5::{
+                gssapi::{self, GssapiSession, GssapiStream},
+                UdpAssociateRequest,
+            },
+            DnsPolicy, Error, PortPolicy,
+        },
+        ErrorVerbosity, LogPrivacy,
+    },
+    transport::{self, TimeoutPhase, Transport},
 };
 
 pub struct Service<ClientStream, TransportStream> {
     authentication_manager: Arc<Mutex<AuthenticationManager>>,
     transport: Arc<Transport<TransportStream>>,
-    udp_associate_stream_tx: Option<Mutex<mpsc::Sender<(ClientStream, HostAddress)>>>,
+    udp_associate_stream_tx: Option<Mutex<mpsc::Sender<UdpAssociateRequest<ClientStream>>>>,
     supported_commands: HashSet<Command>,
+    log_connection_open: bool,
+    dns_policy: DnsPolicy,
+    port_policy: PortPolicy,
+    log_privacy: LogPrivacy,
+    error_verbosity: ErrorVerbosity,
+    handshake_timeout: Option<Duration>,
+    bind_timeout: Option<Duration>,
 }
 
 impl<ClientStream, TransportStream> Service<ClientStream, TransportStream>
@@ -37,7 +56,8 @@ where
         authentication_manager: Arc<Mutex<AuthenticationManager>>,
         enable_tcp_connect: bool,
         enable_tcp_bind: bool,
-        udp_associate_stream_tx: Option<Mutex<mpsc::Sender<(ClientStream, HostAddress)>>>,
+        udp_associate_stream_tx: Option<Mutex<mpsc::Sender<UdpAssociateRequest<ClientStream>>>>,
+        log_connection_open: bool,
     ) -> Self {
         let supported_commands = {
             let mut commands = HashSet::new();
@@ -47,10 +67,8 @@ where
             }
 
             if enable_tcp_bind {
-                tracing::info!("SOCKS: TCP Bind is note supported yet.");
-                // FIXME TCP Bind is not supported yet
-                // info!("SOCKS: TCP Bind is supported.");
-                // commands.insert(Command::TcpBind);
+                tracing::info!("SOCKS5: TCP Bind is supported.");
+                commands.insert(Command::TcpBind);
             }
 
             if udp_associate_stream_tx.is_some() {
@@ -64,7 +82,43 @@ where
             commands
         };
 
-        Self { authentication_manager, transport, udp_associate_stream_tx, supported_commands }
+        Self {
+            authentication_manager,
+            transport,
+            udp_associate_stream_tx,
+            supported_commands,
+            log_connection_open,
+            dns_policy: DnsPolicy::default(),
+            port_policy: PortPolicy::default(),
+            log_privacy: LogPrivacy::default(),
+            error_verbosity: ErrorVerbosity::default(),
+            handshake_timeout: None,
+            bind_timeout: None,
+        }
+    }
+
+    #[inline]
+    pub fn set_dns_policy(&mut self, dns_policy: DnsPolicy) { self.dns_policy = dns_policy; }
+
+    #[inline]
+    pub fn set_port_policy(&mut self, port_policy: PortPolicy) { self.port_policy = port_policy; }
+
+    #[inline]
+    pub fn set_log_privacy(&mut self, log_privacy: LogPrivacy) { self.log_privacy = log_privacy; }
+
+    #[inline]
+    pub fn set_error_verbosity(&mut self, error_verbosity: ErrorVerbosity) {
+        self.error_verbosity = error_verbosity;
+    }
+
+    #[inline]
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = Some(handshake_timeout);
+    }
+
+    #[inline]
+    pub fn set_bind_timeout(&mut self, bind_timeout: Duration) {
+        self.bind_timeout = Some(bind_timeout);
     }
 
     #[inline]
@@ -77,10 +131,49 @@ where
         mut stream: ClientStream,
         client_addr: SocketAddr,
     ) -> Result<(), Error> {
-        self.handshake(&mut stream, client_addr).await?;
+        let started = Instant::now();
+        let session = transport::with_timeout(
+            self.handshake_timeout,
+            TimeoutPhase::Handshake,
+            self.handshake(&mut stream, client_addr),
+        )
+        .await
+        .map_err(|phase| Error::Timeout { phase })??;
+        // the request is read within what remains of the handshake timeout
+        let request_timeout =
+            self.handshake_timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+
+        match session {
+            None => self.serve(stream, client_addr, request_timeout, Ok).await,
+            Some(session) => {
+                // UDP Associate needs datagram encapsulation which is not supported
+                let stream = GssapiStream::new(stream, session);
+                self.serve(stream, client_addr, request_timeout, Err).await
+            }
+        }
+    }
 
+    /// Serves the request on `stream`, `into_client_stream`
+    /// returns the stream back if it can not be handed over to UDP Associate.
+    async fn serve<Stream>(
+        &self,
+        mut stream: Stream,
+        client_addr: SocketAddr,
+        request_timeout: Option<Duration>,
+        into_client_stream: impl FnOnce(Stream) -> Result<ClientStream, Stream>,
+    ) -> Result<(), Error>
+    where
+        Stream: Unpin + AsyncRead + AsyncWrite,
+    {
         let request = {
-            let req = Request::from_reader(&mut stream).await.context(error::ParseRequestSnafu)?;
+            let req = transport::with_timeout(
+                request_timeout,
+                TimeoutPhase::Handshake,
+                Request::from_reader(&mut stream),
+            )
+            .await
+            .map_err(|phase| Error::Timeout { phase })?
+            .context(error::ParseRequestSnafu)?;
 
             // check if we support this SOCKS5 command
             if !self.is_supported_command(req.command) {
@@ -98,20 +191,67 @@ where
                 return Err(Error::UnsupportedCommand { command: req.command.into() });
             }
 
+            let (command, port) = (req.command.into(), req.destination_socket.port());
+            if !self.port_policy.allows_request(command, port) {
+                let reply = Reply::not_allowed(req.address_type());
+
+                tracing::debug!(
+                    "{command} to port {port} is not allowed, close connection {client_addr}"
+                );
+                let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+                stream.flush().await.context(error::FlushStreamSnafu)?;
+                stream.shutdown().await.context(error::ShutdownSnafu)?;
+
+                return Err(Error::RejectedByPortPolicy { command, port });
+            }
+
             req
         };
 
+        if self.log_connection_open {
+            tracing::info!(
+                "Connection opened from {} to {}",
+                client_addr,
+                self.log_privacy.anonymize(request.destination_socket.as_ref())
+            );
+        }
+
         match request.command {
             Command::TcpConnect => {
                 let remote_host: &HostAddress = request.destination_socket.as_ref();
+                if !self.dns_policy.allows(remote_host) {
+                    let reply = Reply::address_not_supported(request.address_type());
+                    let _ =
+                        stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+                    stream.flush().await.context(error::FlushStreamSnafu)?;
+                    stream.shutdown().await.context(error::ShutdownSnafu)?;
+                    return Err(Error::RejectedByDnsPolicy {
+                        host: remote_host.clone(),
+                        policy: self.dns_policy,
+                    });
+                }
 
                 let (remote_socket, remote_addr) = match self.transport.connect(remote_host).await {
                     Ok((socket, addr)) => {
-                        tracing::info!("Remote host {} is connected", remote_host.to_string());
+                        tracing::info!(
+                            "Remote host {} is connected",
+                            self.log_privacy.anonymize(remote_host)
+                        );
                         (socket, addr)
                     }
                     Err(source) => {
-                        let reply = Reply::unreachable(request.address_type());
+                        if self.error_verbosity.is_verbose() {
+                            tracing::warn!(
+                                "Failed to connect {}, error: {}",
+                                self.log_privacy.anonymize(remote_host),
+                                self.log_privacy.anonymize_error(&source)
+                            );
+                        }
+                        let reply = if source.is_forbidden() {
+                            Reply::not_allowed(request.address_type())
+                        } else {
+                            Reply::unreachable(request.address_type())
+                        };
                         let _ = stream
                             .write(&reply.into_bytes())
                             .await
@@ -122,18 +262,18 @@ where
                     }
                 };
 
-                let reply = Reply::success(Address::empty_ipv4());
+                let reply = Reply::success_empty(request.address_type());
                 let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
 
+                let destination = remote_addr.clone();
+                let remote_addr = self.log_privacy.anonymize(&remote_addr);
                 self.transport
-                    .relay(
+                    .relay_to(
                         stream,
                         remote_socket,
+                        &destination,
                         Some(Box::new(move || {
-                            tracing::info!(
-                                "Remote host {} is disconnected",
-                                remote_addr.to_string()
-                            );
+                            tracing::info!("Remote host {} is disconnected", remote_addr);
                         })),
                     )
                     .await
@@ -143,31 +283,63 @@ where
             }
             Command::UdpAssociate => match self.udp_associate_stream_tx {
                 Some(ref tx) => {
-                    let target_addr: HostAddress = request.destination_socket.into();
-                    let _unused = tx.lock().await.send((stream, target_addr)).await;
-                    Ok(())
+                    let mut stream = match into_client_stream(stream) {
+                        Ok(stream) => {
+                            let target_addr: HostAddress = request.destination_socket.into();
+                            let _unused =
+                                tx.lock().await.send((stream, client_addr, target_addr)).await;
+                            return Ok(());
+                        }
+                        Err(stream) => stream,
+                    };
+
+                    let reply = Reply::not_supported(request.address_type());
+                    let _ =
+                        stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
+                    stream.flush().await.context(error::FlushStreamSnafu)?;
+                    stream.shutdown().await.context(error::ShutdownSnafu)?;
+                    Err(Error::UnsupportedCommand { command: request.command.into() })
                 }
                 None => unreachable!(),
             },
-            Command::TcpBind => {
-                //
-                todo!()
-            }
+            Command::TcpBind => self.serve_bind(stream, request).await,
         }
     }
 
+    async fn serve_bind<Stream>(&self, stream: Stream, request: Request) -> Result<(), Error>
+    where
+        Stream: Unpin + AsyncRead + AsyncWrite,
+    {
+        let bind = Bind {
+            transport: &self.transport,
+            dns_policy: self.dns_policy,
+            bind_timeout: self.bind_timeout,
+            log_privacy: self.log_privacy,
+        };
+        let replies = BindReplyV5 { address_type: request.address_type() };
+        bind.serve(stream, request.destination_socket.as_ref(), replies).await
+    }
+
+    /// Returns the GSS-API session if method `GSSAPI` is selected.
     async fn handshake(
         &self,
         client: &mut ClientStream,
         client_addr: SocketAddr,
-    ) -> Result<(), Error> {
+    ) -> Result<Option<GssapiSession>, Error> {
         let req = HandshakeRequest::from_reader(client)
             .await
             .context(error::ParseHandshakeRequestSnafu)?;
         tracing::debug!("Received {:?}", req);
 
-        let supported_method: Method =
-            self.authentication_manager.lock().await.supported_method(&client_addr).into();
+        let (supported_method, gssapi): (Method, _) = {
+            let manager = self.authentication_manager.lock().await;
+            match manager.gssapi() {
+                Some(gssapi) if req.contains_method(Method::GSSAPI) => {
+                    (Method::GSSAPI, Some(gssapi))
+                }
+                gssapi => (manager.supported_method(&client_addr).into(), gssapi),
+            }
+        };
 
         if !req.contains_method(supported_method) {
             let reply = HandshakeReply::new(Method::NotAcceptable);
@@ -197,7 +369,9 @@ where
                         user_name: request.user_name.clone(),
                         password: request.password.clone(),
                     };
-                    handler.authenticate(auth).await
+                    // bearer tokens are sent as password
+                    let token = Authentication::Token { token: request.password.clone() };
+                    handler.authenticate(auth).await || handler.authenticate(token).await
                 };
 
                 if !auth_passed {
@@ -222,16 +396,546 @@ where
                 client.flush().await.context(error::FlushStreamSnafu)?;
             }
             Method::GSSAPI => {
-                // TODO
-                client.shutdown().await.context(error::ShutdownSnafu)?;
-                return Err(Error::UnsupportedMethod { method: Method::GSSAPI });
+                let Some(authenticator) = gssapi else { unreachable!() };
+                return gssapi::establish_session(client, authenticator).await.map(Some);
             }
             Method::NotAcceptable => unreachable!(),
         }
 
-        Ok(())
+        Ok(None)
+    }
+}
+
+struct BindReplyV5 {
+    address_type: AddressType,
+}
+
+impl BindReply for BindReplyV5 {
+    fn success(&self, addr: SocketAddr) -> Vec<u8> {
+        Reply::success(Address::from(addr)).into_bytes()
+    }
+
+    fn failure(&self, failure: BindFailure) -> Vec<u8> {
+        match failure {
+            BindFailure::AddressNotSupported => Reply::address_not_supported(self.address_type),
+            BindFailure::NotAllowed => Reply::not_allowed(self.address_type),
+            BindFailure::Unreachable => Reply::unreachable(self.address_type),
+            BindFailure::TimedOut => Reply::ttl_expired(self.address_type),
+        }
+        .into_bytes()
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::{TcpListener, TcpSocket, TcpStream},
+        sync::{mpsc, Mutex},
+    };
+    use tracing::Level;
+
+    use crate::{
+        authentication::{
+            AuthenticationManager, GssapiAccept, GssapiAuthenticator, GssapiContext, GssapiError,
+        },
+        common::HostAddress,
+        filter::SimpleFilter,
+        protocol::socks::{
+            v5::{
+                Command, GssapiMessage, GssapiMessageType, HandshakeReply, Method, ProtectionLevel,
+                Reply, ReplyField, Request,
+            },
+            Address, AddressType, SocksCommand,
+        },
+        service::socks::{v5::Service, DnsPolicy, Error, PortPolicy},
+        test_util::{direct_transport, unfiltered_transport, CapturedLogs},
+        transport::{self, TimeoutPhase},
+    };
+
+    /// Accepts tokens `first` and `second` in turn, wrapped messages are tagged
+    /// with whether confidentiality is requested.
+    struct CannedGssapi;
+
+    impl GssapiAuthenticator for CannedGssapi {
+        fn accept_context(
+            &self,
+            context: &mut GssapiContext,
+            token: &[u8],
+        ) -> Result<GssapiAccept, GssapiError> {
+            match (context.principal.is_some(), token) {
+                (false, b"first") => {
+                    context.principal = Some("alice@EXAMPLE.COM".to_string());
+                    Ok(GssapiAccept::Continue { token: b"challenge".to_vec() })
+                }
+                (true, b"second") => Ok(GssapiAccept::Established { token: b"done".to_vec() }),
+                _ => Err(GssapiError::new("unexpected token")),
+            }
+        }
+
+        fn wrap(
+            &self,
+            _context: &GssapiContext,
+            confidential: bool,
+            message: &[u8],
+        ) -> Result<Vec<u8>, GssapiError> {
+            Ok([&[u8::from(confidential)], message].concat())
+        }
+
+        fn unwrap(&self, _context: &GssapiContext, token: &[u8]) -> Result<Vec<u8>, GssapiError> {
+            token.get(1..).map(<[u8]>::to_vec).ok_or_else(|| GssapiError::new("empty token"))
+        }
+
+        fn supports_protection_level(&self, level: ProtectionLevel) -> bool {
+            level != ProtectionLevel::SelectivePerMessage
+        }
+    }
+
+    fn gssapi_service() -> Service<DuplexStream, TcpStream> {
+        let transport = unfiltered_transport();
+        let mut authentication_manager = AuthenticationManager::new();
+        authentication_manager.set_gssapi(Arc::new(CannedGssapi));
+        Service::new(
+            transport,
+            Arc::new(Mutex::new(authentication_manager)),
+            true,
+            false,
+            None,
+            false,
+        )
+    }
+
+    async fn write_gssapi_message(
+        client: &mut DuplexStream,
+        message_type: GssapiMessageType,
+        token: &[u8],
+    ) {
+        let message = GssapiMessage::new(message_type, token.to_vec());
+        client.write_all(&message.into_bytes()).await.unwrap();
+    }
+
+    async fn connect_with_logs(log_connection_open: bool) -> String {
+        let (logs, _guard) = CapturedLogs::capture(Level::INFO);
+
+        let transport = unfiltered_transport();
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            false,
+            None,
+            log_connection_open,
+        );
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remote_addr = listener.local_addr().unwrap();
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let request = Request {
+            command: Command::TcpConnect,
+            destination_socket: Address::from(remote_addr),
+        };
+        client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+        client.write_all(&request.into_bytes()).await.unwrap();
+
+        let remote = async {
+            // close the remote side right after accepting, so that relay finishes
+            drop(listener.accept().await.unwrap());
+        };
+        let client = async {
+            let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Success);
+            drop(client);
+        };
+        let (result, (), ()) = tokio::join!(service.handle(server, client_addr), remote, client);
+        assert!(result.is_ok());
+
+        logs.contents()
+    }
+
+    #[tokio::test]
+    async fn reply_with_bind_address_of_request_family() {
+        let transport = unfiltered_transport();
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            false,
+            None,
+            false,
+        );
+
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        for (listen_addr, address_type) in [
+            (IpAddr::from(Ipv4Addr::LOCALHOST), AddressType::Ipv4),
+            (IpAddr::from(Ipv6Addr::LOCALHOST), AddressType::Ipv6),
+        ] {
+            let listener = TcpListener::bind((listen_addr, 0)).await.unwrap();
+            let request = Request {
+                command: Command::TcpConnect,
+                destination_socket: Address::from(listener.local_addr().unwrap()),
+            };
+
+            let (mut client, server) = tokio::io::duplex(64);
+            client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+            client.write_all(&request.into_bytes()).await.unwrap();
+
+            let remote = async {
+                drop(listener.accept().await.unwrap());
+            };
+            let client = async {
+                let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+                let reply = Reply::from_reader(&mut client).await.unwrap();
+                assert_eq!(reply.reply, ReplyField::Success);
+                assert_eq!(reply.bind_socket.address_type(), address_type);
+            };
+            let (result, (), ()) =
+                tokio::join!(service.handle(server, client_addr), remote, client);
+            assert!(result.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn log_connection_open() {
+        let opened = "Connection opened from 127.0.0.1:40000 to 127.0.0.1:";
+        assert!(connect_with_logs(true).await.contains(opened));
+        assert!(!connect_with_logs(false).await.contains(opened));
+    }
+
+    #[tokio::test]
+    async fn reject_denied_hosts() {
+        // an empty allow list denies everything
+        let transport = Arc::new(direct_transport(SimpleFilter::allow_list()));
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            false,
+            None,
+            false,
+        );
+
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        for port in [80, 443, 8080] {
+            let (mut client, server) = tokio::io::duplex(64);
+            let request = Request {
+                command: Command::TcpConnect,
+                destination_socket: Address::from(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+            };
+            client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+            client.write_all(&request.into_bytes()).await.unwrap();
+
+            let Err(Error::ConnectRemoteHost { source, .. }) =
+                service.handle(server, client_addr).await
+            else {
+                panic!("connection should be rejected");
+            };
+            assert!(source.is_forbidden());
+
+            let handshake_reply = HandshakeReply::from_reader(&mut client).await.unwrap();
+            assert_eq!(handshake_reply.method, Method::NoAuthentication);
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::NotAllowed);
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_policy() {
+        let transport = unfiltered_transport();
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let mut service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            false,
+            None,
+            false,
+        );
+
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        let domain = Address::new_domain(b"example.com", 80);
+        let ip = Address::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+        for (policy, destination_socket) in
+            [(DnsPolicy::RejectDomainRequests, domain), (DnsPolicy::RequireRemoteDns, ip)]
+        {
+            service.set_dns_policy(policy);
+
+            let (mut client, server) = tokio::io::duplex(64);
+            let request = Request { command: Command::TcpConnect, destination_socket };
+            client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+            client.write_all(&request.into_bytes()).await.unwrap();
+
+            let Err(Error::RejectedByDnsPolicy { policy: rejected_by, .. }) =
+                service.handle(server, client_addr).await
+            else {
+                panic!("request should be rejected by {policy}");
+            };
+            assert_eq!(rejected_by, policy);
+
+            let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::AddressNotSupported);
+        }
+    }
+
+    #[tokio::test]
+    async fn port_policy() {
+        let transport = unfiltered_transport();
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut service = Service::<DuplexStream, TcpStream>::new(
+            transport,
+            authentication_manager,
+            true,
+            false,
+            Some(Mutex::new(tx)),
+            false,
+        );
+        service.set_port_policy(
+            PortPolicy::new()
+                .allow(SocksCommand::TcpConnect, [443])
+                .allow(SocksCommand::UdpAssociate, [53]),
+        );
+
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        let request = |command, port| Request {
+            command,
+            destination_socket: Address::from(SocketAddr::from((
+                Ipv4Addr::new(192, 0, 2, 1),
+                port,
+            ))),
+        };
+
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+        client.write_all(&request(Command::TcpConnect, 80).into_bytes()).await.unwrap();
+        let Err(Error::RejectedByPortPolicy { command, port }) =
+            service.handle(server, client_addr).await
+        else {
+            panic!("TCP Connect to port 80 should be rejected");
+        };
+        assert_eq!((command, port), (SocksCommand::TcpConnect, 80));
+        let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::NotAllowed);
+
+        // the port of UDP Associate is the source of the client, datagrams are
+        // restricted by the UDP relay instead
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+        client.write_all(&request(Command::UdpAssociate, 40001).into_bytes()).await.unwrap();
+        service.handle(server, client_addr).await.unwrap();
+        let (_stream, addr, target_addr) = rx.recv().await.unwrap();
+        assert_eq!(addr, client_addr);
+        assert_eq!(target_addr.port(), 40001);
+    }
+
+    #[tokio::test]
+    async fn gssapi_handshake() {
+        let service = gssapi_service();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remote_addr = listener.local_addr().unwrap();
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let remote = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+        };
+        let client = async {
+            client
+                .write_all(&[0x02, Method::NoAuthentication.into(), Method::GSSAPI.into()])
+                .await
+                .unwrap();
+            let reply = HandshakeReply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.method, Method::GSSAPI);
+
+            for (token, expected) in [(&b"first"[..], &b"challenge"[..]), (b"second", b"done")] {
+                write_gssapi_message(&mut client, GssapiMessageType::Authentication, token).await;
+                let message = GssapiMessage::from_reader(&mut client).await.unwrap();
+                assert_eq!(message.message_type, GssapiMessageType::Authentication);
+                assert_eq!(message.token, expected);
+            }
+
+            // selective per-message protection falls back to confidentiality
+            let level = u8::from(ProtectionLevel::SelectivePerMessage);
+            write_gssapi_message(&mut client, GssapiMessageType::ProtectionLevel, &[0, level])
+                .await;
+            let message = GssapiMessage::from_reader(&mut client).await.unwrap();
+            assert_eq!(message.message_type, GssapiMessageType::ProtectionLevel);
+            assert_eq!(message.token, [0, u8::from(ProtectionLevel::Confidentiality)]);
+
+            let request = Request {
+                command: Command::TcpConnect,
+                destination_socket: Address::from(remote_addr),
+            };
+            let token = [&[1], &request.into_bytes()[..]].concat();
+            write_gssapi_message(&mut client, GssapiMessageType::Encapsulation, &token).await;
+
+            // reply and relayed data are encapsulated with confidentiality
+            let mut received = Vec::new();
+            while let Ok(message) = GssapiMessage::from_reader(&mut client).await {
+                assert_eq!(message.message_type, GssapiMessageType::Encapsulation);
+                assert_eq!(message.token[0], 1);
+                received.extend_from_slice(&message.token[1..]);
+            }
+            let mut received = &received[..];
+            let reply = Reply::from_reader(&mut received).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Success);
+            assert_eq!(received, b"pong");
+        };
+        let (result, (), ()) = tokio::join!(service.handle(server, client_addr), remote, client);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn gssapi_reject_invalid_token() {
+        let service = gssapi_service();
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(&[0x01, Method::GSSAPI.into()]).await.unwrap();
+        write_gssapi_message(&mut client, GssapiMessageType::Authentication, b"second").await;
+
+        let result = service.handle(server, client_addr).await;
+        assert!(matches!(result, Err(Error::GssapiFailure { .. })));
+
+        let reply = HandshakeReply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.method, Method::GSSAPI);
+        let message = GssapiMessage::from_reader(&mut client).await.unwrap();
+        assert_eq!(message.message_type, GssapiMessageType::Abort);
+    }
+
+    fn bind_service() -> Service<DuplexStream, TcpStream> {
+        bind_service_with_filter(SimpleFilter::deny_list())
+    }
+
+    fn bind_service_with_filter(filter: SimpleFilter) -> Service<DuplexStream, TcpStream> {
+        let transport = Arc::new(direct_transport(filter));
+        let authentication_manager = Arc::new(Mutex::new(AuthenticationManager::new()));
+        Service::new(transport, authentication_manager, true, true, None, false)
+    }
+
+    async fn send_bind_request(client: &mut DuplexStream, destination_socket: Address) {
+        let request = Request { command: Command::TcpBind, destination_socket };
+        client.write_all(&[0x01, Method::NoAuthentication.into()]).await.unwrap();
+        client.write_all(&request.into_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_bind() {
+        let service = bind_service();
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let client = async {
+            let destination = Address::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+            send_bind_request(&mut client, destination).await;
+            let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+
+            // the listener is on the interface used to reach the destination
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Success);
+            let HostAddress::Socket(bind_addr) = HostAddress::from(reply.bind_socket) else {
+                panic!("bind address should be a socket address");
+            };
+            assert_eq!(bind_addr.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+
+            let mut peer = TcpStream::connect(bind_addr).await.unwrap();
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Success);
+            let peer_addr = HostAddress::from(peer.local_addr().unwrap());
+            assert_eq!(HostAddress::from(reply.bind_socket), peer_addr);
+
+            peer.write_all(b"hello").await.unwrap();
+            drop(peer);
+            let mut relayed = Vec::new();
+            let _ = client.read_to_end(&mut relayed).await.unwrap();
+            assert_eq!(relayed, b"hello");
+        };
+        let (result, ()) = tokio::join!(service.handle(server, client_addr), client);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tcp_bind_timeout() {
+        let mut service = bind_service();
+        service.set_bind_timeout(Duration::from_millis(50));
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let destination = Address::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+        send_bind_request(&mut client, destination).await;
+
+        let result = service.handle(server, client_addr).await;
+        assert!(matches!(result, Err(Error::Timeout { phase: TimeoutPhase::Connect })));
+
+        let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::TTLExpired);
+    }
+
+    #[tokio::test]
+    async fn tcp_bind_from_unexpected_peer() {
+        let service = bind_service();
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let client = async {
+            // the peer is expected from 127.0.0.2, but connects from 127.0.0.1
+            let destination = Address::from(SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), 0)));
+            send_bind_request(&mut client, destination).await;
+            let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Success);
+            let HostAddress::Socket(bind_addr) = HostAddress::from(reply.bind_socket) else {
+                panic!("bind address should be a socket address");
+            };
+
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+            let _peer = socket.connect(bind_addr).await.unwrap();
+            let reply = Reply::from_reader(&mut client).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::NotAllowed);
+        };
+        let (result, ()) = tokio::join!(service.handle(server, client_addr), client);
+        let Err(Error::AcceptInbound { source }) = result else {
+            panic!("inbound connection should be refused");
+        };
+        assert!(matches!(source, transport::Error::UnexpectedPeer { .. }));
+    }
+
+    #[tokio::test]
+    async fn tcp_bind_to_denied_host() {
+        let mut filter = SimpleFilter::deny_list();
+        filter.add_address(IpAddr::from(Ipv4Addr::LOCALHOST));
+        filter.add_address(IpAddr::from(Ipv6Addr::LOCALHOST));
+        let service = bind_service_with_filter(filter);
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+
+        // the domain name is allowed, but all addresses of it are denied
+        let (mut client, server) = tokio::io::duplex(1024);
+        send_bind_request(&mut client, Address::new_domain(b"localhost", 0)).await;
+        let result = service.handle(server, client_addr).await;
+        let Err(Error::ListenInbound { source, .. }) = result else {
+            panic!("listening should be denied");
+        };
+        assert!(source.is_forbidden());
+        let _ = HandshakeReply::from_reader(&mut client).await.unwrap();
+        let reply = Reply::from_reader(&mut client).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::NotAllowed);
+    }
+}
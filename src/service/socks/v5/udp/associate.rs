@@ -1,37 +1,42 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
 
 use bytes::BytesMut;
 use snafu::ResultExt;
-use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, Mutex},
-};
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
 
 use crate::{
-    protocol::socks::v5::Datagram,
-    service::socks::{error, Error},
-    transport::Resolver,
+    common::HostAddress,
+    filter::FilterAction,
+    protocol::socks::{v5::Datagram, SocksCommand},
+    service::{
+        socks::{error, Error, PortPolicy},
+        LogPrivacy,
+    },
+    transport::{DestinationFilter, Resolver},
 };
 
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
 pub struct UdpAssociate {
-    tx: Mutex<mpsc::Sender<Datagram>>,
-    closed: Arc<AtomicBool>,
+    tx: mpsc::Sender<Datagram>,
+    send_handle: JoinHandle<()>,
+    recv_handles: Vec<JoinHandle<()>>,
 }
 
 impl Drop for UdpAssociate {
-    fn drop(&mut self) { self.closed.store(true, Ordering::Release); }
+    fn drop(&mut self) {
+        self.send_handle.abort();
+        self.recv_handles.iter().for_each(JoinHandle::abort);
+    }
 }
 
 impl UdpAssociate {
     #[inline]
     pub async fn send_to(&self, datagram: Datagram) -> bool {
-        match self.tx.lock().await.send(datagram).await {
+        match self.tx.send(datagram).await {
             Ok(_) => true,
             Err(err) => {
                 tracing::error!("Failed to send packet, error: {:?}", err);
@@ -40,61 +45,121 @@ impl UdpAssociate {
         }
     }
 
+    /// Relays datagrams of the client at `client_addr`, only to and from
+    /// `target` if it is given, datagrams of other remote hosts are dropped. A
+    /// domain name `target` admits every address it resolves to.
+    /// Datagrams to destinations denied by `destination_filter` or to ports not
+    /// allowed by `port_policy` are dropped as well.
     pub async fn new(
         client_addr: SocketAddr,
-        mut response_tx: mpsc::Sender<(SocketAddr, Datagram)>,
+        target: Option<HostAddress>,
+        response_tx: mpsc::Sender<(SocketAddr, Datagram)>,
         resolver: Arc<dyn Resolver>,
-    ) -> Result<UdpAssociate, Error> {
-        let (mut socket_recv, mut socket_send) = {
-            let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-            let remote_socket = UdpSocket::bind(&local_addr)
-                .await
-                .context(error::BindUdpSocketSnafu { addr: local_addr })?;
-            remote_socket.split()
+        destination_filter: DestinationFilter,
+        port_policy: PortPolicy,
+        log_privacy: LogPrivacy,
+    ) -> Result<Self, Error> {
+        let target = match target {
+            Some(target) => Some(Self::resolve_target(target, resolver.as_ref()).await?.into()),
+            None => None,
+        };
+        let ipv4_socket = Arc::new(Self::bind(Ipv4Addr::UNSPECIFIED.into()).await?);
+        // IPv6 may be unavailable on host, only IPv4 destinations are reachable then
+        let ipv6_socket = match Self::bind(Ipv6Addr::UNSPECIFIED.into()).await {
+            Ok(socket) => Some(Arc::new(socket)),
+            Err(err) => {
+                tracing::warn!("Failed to bind IPv6 socket for UDP associate, error: {err}");
+                None
+            }
         };
 
         let (tx, mut rx) = mpsc::channel::<Datagram>(1024);
-        let closed = Arc::new(AtomicBool::new(false));
 
         // local to remote
-        tokio::spawn({
+        let send_handle = tokio::spawn({
+            let ipv4_socket = ipv4_socket.clone();
+            let ipv6_socket = ipv6_socket.clone();
+            let target = target.clone();
             async move {
                 while let Some(datagram) = rx.recv().await {
-                    use crate::common::HostAddress;
-                    let remote_host = match datagram.destination_address() {
+                    let destination = datagram.destination_address();
+                    if !port_policy.allows(SocksCommand::UdpAssociate, destination.port()) {
+                        tracing::debug!("Drop packet to port {} not allowed", destination.port());
+                        continue;
+                    }
+                    if destination_filter.check(destination) == FilterAction::Deny {
+                        tracing::debug!(
+                            "Drop packet to denied remote host {}",
+                            log_privacy.anonymize(destination)
+                        );
+                        continue;
+                    }
+
+                    let remote_host = match destination {
+                        HostAddress::Socket(addr)
+                            if destination_filter.check_resolved(addr) == FilterAction::Deny =>
+                        {
+                            tracing::debug!(
+                                "Drop packet to denied remote host {}",
+                                log_privacy.anonymize(destination)
+                            );
+                            continue;
+                        }
                         HostAddress::Socket(addr) => *addr,
-                        HostAddress::DomainName(host, port) => match resolver.resolve(host).await {
-                            Ok(addrs) => {
-                                if addrs.is_empty() {
-                                    return;
-                                }
-                                SocketAddr::new(addrs[0], *port)
-                            }
-                            Err(_err) => {
+                        HostAddress::DomainName(host, port) => {
+                            let addrs = resolver.resolve(host).await.unwrap_or_default();
+                            let allowed = addrs
+                                .into_iter()
+                                .map(|ip| SocketAddr::new(ip, *port))
+                                .find(|addr| {
+                                    destination_filter.check_resolved(addr) == FilterAction::Allow
+                                });
+                            if let Some(addr) = allowed {
+                                addr
+                            } else {
                                 tracing::warn!(
-                                    "Failed to resolve host address: {}",
-                                    datagram.destination_address()
+                                    "Failed to resolve allowed address of host: {}",
+                                    log_privacy.anonymize(destination)
                                 );
-                                return;
+                                continue;
                             }
-                        },
+                        }
+                    };
+
+                    if !is_target(target.as_deref(), remote_host) {
+                        tracing::debug!(
+                            "Drop packet to remote host {} other than target",
+                            log_privacy.anonymize(&remote_host.into())
+                        );
+                        continue;
+                    }
+
+                    let socket = match (remote_host, &ipv6_socket) {
+                        (SocketAddr::V4(_), _) => &ipv4_socket,
+                        (SocketAddr::V6(_), Some(socket)) => socket,
+                        (SocketAddr::V6(_), None) => {
+                            tracing::warn!(
+                                "Drop packet to IPv6 remote host {}",
+                                log_privacy.anonymize(&remote_host.into())
+                            );
+                            continue;
+                        }
                     };
 
-                    match socket_send.send_to(datagram.data(), &remote_host).await {
+                    match socket.send_to(datagram.data(), &remote_host).await {
                         Ok(n) => {
                             tracing::debug!(
                                 "Send packet to remote host {} with {} bytes",
-                                remote_host.to_string(),
+                                log_privacy.anonymize(&remote_host.into()),
                                 n
                             );
                         }
                         Err(err) => {
                             tracing::warn!(
                                 "Failed to send packet to remote host: {}, error: {:?}",
-                                remote_host,
+                                log_privacy.anonymize(&remote_host.into()),
                                 err
                             );
-                            break;
                         }
                     };
                 }
@@ -102,38 +167,94 @@ impl UdpAssociate {
         });
 
         // remote to local
-        tokio::spawn({
-            let closed = closed.clone();
-            async move {
-                while !closed.load(Ordering::Acquire) {
-                    let mut buf = BytesMut::with_capacity(1024);
-                    match socket_recv.recv_from(&mut buf[..]).await {
-                        Ok((n, remote_addr)) => {
-                            tracing::info!(
-                                "Received packet with {} bytes from remote host {}",
-                                n,
-                                remote_addr
-                            );
+        let recv_handles = std::iter::once(ipv4_socket)
+            .chain(ipv6_socket)
+            .map(|socket| {
+                tokio::spawn(Self::recv(
+                    socket,
+                    client_addr,
+                    target.clone(),
+                    response_tx.clone(),
+                    log_privacy,
+                ))
+            })
+            .collect();
 
-                            let datagram = Datagram::new(0, remote_addr.into(), buf);
-                            if let Err(err) = response_tx.send((client_addr, datagram)).await {
-                                tracing::warn!(
-                                    "Failed to send packet to remote host: {}, error: {:?}",
-                                    remote_addr,
-                                    err
-                                );
-                                break;
-                            }
-                        }
-                        Err(err) => {
-                            tracing::warn!("Failed to receive packet, error: {:?}", err);
-                            break;
-                        }
+        Ok(Self { tx, send_handle, recv_handles })
+    }
+
+    async fn bind(ip: IpAddr) -> Result<UdpSocket, Error> {
+        let local_addr = SocketAddr::new(ip, 0);
+        UdpSocket::bind(&local_addr).await.context(error::BindUdpSocketSnafu { addr: local_addr })
+    }
+
+    async fn resolve_target(
+        target: HostAddress,
+        resolver: &dyn Resolver,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        match target {
+            HostAddress::Socket(addr) => Ok(vec![addr]),
+            HostAddress::DomainName(ref host, port) => match resolver.resolve(host).await {
+                Ok(addrs) if !addrs.is_empty() => {
+                    Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+                }
+                _ => Err(Error::ResolveUdpAssociateTarget { target }),
+            },
+        }
+    }
+
+    async fn recv(
+        socket: Arc<UdpSocket>,
+        client_addr: SocketAddr,
+        target: Option<Arc<[SocketAddr]>>,
+        response_tx: mpsc::Sender<(SocketAddr, Datagram)>,
+        log_privacy: LogPrivacy,
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((n, remote_addr)) => {
+                    tracing::debug!(
+                        "Received packet with {} bytes from remote host {}",
+                        n,
+                        log_privacy.anonymize(&remote_addr.into())
+                    );
+
+                    // IPv4-mapped addresses are received if IPv6 socket is dual-stack
+                    let remote_addr =
+                        SocketAddr::new(remote_addr.ip().to_canonical(), remote_addr.port());
+                    if !is_target(target.as_deref(), remote_addr) {
+                        tracing::debug!(
+                            "Drop packet from remote host {} other than target",
+                            log_privacy.anonymize(&remote_addr.into())
+                        );
+                        continue;
                     }
+                    let datagram = Datagram::new(0, remote_addr.into(), BytesMut::from(&buf[..n]));
+                    if let Err(err) = response_tx.send((client_addr, datagram)).await {
+                        tracing::warn!(
+                            "Failed to send packet to client: {}, error: {:?}",
+                            client_addr,
+                            err
+                        );
+                        break;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to receive packet, error: {:?}", err);
+                    break;
                 }
             }
-        });
-
-        Ok(UdpAssociate { tx: Mutex::new(tx), closed })
+        }
     }
 }
+
+// every remote host is the target of an unrestricted association
+fn is_target(target: Option<&[SocketAddr]>, addr: SocketAddr) -> bool {
+    target.is_none_or(|target| target.iter().any(|target| is_same_addr(*target, addr)))
+}
+
+// IPv4-mapped addresses are the same as their IPv4 addresses
+fn is_same_addr(a: SocketAddr, b: SocketAddr) -> bool {
+    a.ip().to_canonical() == b.ip().to_canonical() && a.port() == b.port()
+}
@@ -1,67 +1,202 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use lru_time_cache::LruCache;
 use tokio::sync::Mutex;
 
-use crate::{common::HostAddress, service::socks::v5::udp::shutdown};
+use crate::common::HostAddress;
 
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-struct CacheKey(String);
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AssociationId(u64);
 
-impl From<HostAddress> for CacheKey {
-    fn from(host_addr: HostAddress) -> CacheKey { Self::from(&host_addr) }
-}
-
-impl From<&HostAddress> for CacheKey {
-    fn from(host_addr: &HostAddress) -> CacheKey {
-        match host_addr {
-            HostAddress::Socket(socket)
-                if socket.ip().is_unspecified() || socket.ip().is_loopback() =>
-            {
-                CacheKey(socket.port().to_string())
-            }
-            _ => CacheKey(host_addr.port().to_string()),
-        }
-    }
+#[derive(Debug)]
+struct Association {
+    id: AssociationId,
+    // port which the client declared in UDP associate request, `0` if unknown
+    declared_port: u16,
+    // source address observed from the first datagram of the client
+    client_source: Option<SocketAddr>,
+    // the only remote host which datagrams are relayed to and from, if any
+    target: Option<HostAddress>,
 }
 
 #[derive(Clone)]
 pub struct UdpAssociateCache {
-    cache: Arc<Mutex<LruCache<CacheKey, shutdown::ShutdownSignal>>>,
+    pin_client_source: bool,
+    next_id: Arc<AtomicU64>,
+    associations: Arc<Mutex<HashMap<IpAddr, Vec<Association>>>>,
 }
 
 impl UdpAssociateCache {
-    pub fn new(cache_expiry_duration: Duration) -> UdpAssociateCache {
-        let cache = Arc::new(Mutex::new(LruCache::with_expiry_duration(cache_expiry_duration)));
-        UdpAssociateCache { cache }
+    pub fn new(pin_client_source: bool) -> Self {
+        Self {
+            pin_client_source,
+            next_id: Arc::new(AtomicU64::new(0)),
+            associations: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
-    pub async fn insert(&self, addr: &HostAddress) -> shutdown::ShutdownSlot {
-        let (shutdown_signal, shutdown_slot) = shutdown::shutdown_handle();
+    /// Inserts an association of the client at `client_ip`, the IP of its
+    /// control connection, the IP declared in UDP associate request is not
+    /// trusted.
+    ///
+    /// `declared_port` of `0` is the wildcard of RFC 1928 sent by clients which
+    /// do not know their source yet, the association is then pinned to the
+    /// first datagram from `client_ip` with any source port. This accepts
+    /// clients behind NAT, at the cost that another host sharing `client_ip`
+    /// may claim the association by sending first.
+    pub async fn insert(&self, client_ip: IpAddr, declared_port: u16) -> AssociationId {
+        self.insert_association(client_ip, declared_port, None).await
+    }
 
+    /// Inserts an association of the client at `client_ip` which only relays
+    /// datagrams to and from `target`.
+    ///
+    /// The declared address is taken as the target rather than the source of
+    /// the client, so the source port is unknown: the association is pinned to
+    /// the first datagram from `client_ip` with any source port, as if the
+    /// wildcard is declared, whether `pin_client_source` is enabled or not.
+    /// Another host sharing `client_ip` may claim the association by sending
+    /// first.
+    pub async fn insert_with_target(
+        &self,
+        client_ip: IpAddr,
+        target: HostAddress,
+    ) -> AssociationId {
+        self.insert_association(client_ip, 0, Some(target)).await
+    }
+
+    async fn insert_association(
+        &self,
+        client_ip: IpAddr,
+        declared_port: u16,
+        target: Option<HostAddress>,
+    ) -> AssociationId {
+        let id = AssociationId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let association = Association { id, declared_port, client_source: None, target };
+
+        self.associations
+            .lock()
+            .await
+            .entry(client_ip.to_canonical())
+            .or_default()
+            .push(association);
+
+        tracing::info!("Client {} is inserted into UDP associate", client_ip);
+        id
+    }
+
+    /// Finds the association which a datagram sent from `source` belongs to.
+    ///
+    /// The first datagram of an association pins it to the observed source
+    /// address, any later datagram has to come from the same address. If
+    /// `pin_client_source` is enabled, the observed source port may differ
+    /// from the declared one, which is what clients behind symmetric NAT
+    /// look like.
+    pub async fn resolve(&self, source: SocketAddr) -> Option<AssociationId> {
+        let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+        let mut associations = self.associations.lock().await;
+        let associations = associations.get_mut(&source.ip())?;
+
+        if let Some(association) =
+            associations.iter().find(|association| association.client_source == Some(source))
         {
-            let key = addr.into();
-            let mut cache = self.cache.lock().await;
-            cache.insert(key, shutdown_signal);
+            return Some(association.id);
         }
 
-        tracing::info!("Client {} is inserted into UDP associate", addr.to_string());
-        shutdown_slot
+        let is_unpinned = |association: &Association| association.client_source.is_none();
+        let position = associations
+            .iter()
+            .position(|association| {
+                is_unpinned(association)
+                    && (association.declared_port == source.port()
+                        || association.declared_port == 0)
+            })
+            .or_else(|| {
+                if self.pin_client_source {
+                    associations.iter().position(is_unpinned)
+                } else {
+                    None
+                }
+            })?;
+
+        let association = &mut associations[position];
+        tracing::info!("Pin UDP association {:?} to client source {}", association.id, source);
+        association.client_source = Some(source);
+        Some(association.id)
     }
 
-    pub async fn contains(&self, addr: &HostAddress) -> bool {
-        self.cache.lock().await.contains_key(&addr.into())
+    /// Returns the target which the association `id` is restricted to.
+    pub async fn target(&self, id: AssociationId) -> Option<HostAddress> {
+        self.associations
+            .lock()
+            .await
+            .values()
+            .flatten()
+            .find(|association| association.id == id)
+            .and_then(|association| association.target.clone())
     }
 
-    pub async fn remove(&self, addr: &HostAddress) {
-        let key = CacheKey::from(addr);
-        let mut cache = self.cache.lock().await;
-        if cache.remove(&key).is_some() {
-            tracing::info!("Drop UDP association {}", addr.to_string());
+    pub async fn contains(&self, id: AssociationId) -> bool {
+        self.associations
+            .lock()
+            .await
+            .values()
+            .any(|associations| associations.iter().any(|association| association.id == id))
+    }
+
+    pub async fn remove(&self, client_ip: IpAddr, id: AssociationId) {
+        let client_ip = client_ip.to_canonical();
+        let mut associations = self.associations.lock().await;
+        if let Some(entries) = associations.get_mut(&client_ip) {
+            entries.retain(|association| association.id != id);
+            if entries.is_empty() {
+                associations.remove(&client_ip);
+            }
+            tracing::info!("Drop UDP association of client {}", client_ip);
         }
     }
 
-    pub async fn clear(&mut self) { self.cache.lock().await.clear(); }
+    pub async fn clear(&self) { self.associations.lock().await.clear(); }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::UdpAssociateCache;
+
+    const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[tokio::test]
+    async fn resolve_declared_source() {
+        let cache = UdpAssociateCache::new(false);
+        let id = cache.insert(CLIENT_IP, 5000).await;
+
+        assert_eq!(cache.resolve(SocketAddr::new(CLIENT_IP, 6000)).await, None);
+        assert_eq!(cache.resolve(SocketAddr::new(CLIENT_IP, 5000)).await, Some(id));
+
+        cache.remove(CLIENT_IP, id).await;
+        assert!(!cache.contains(id).await);
+        assert_eq!(cache.resolve(SocketAddr::new(CLIENT_IP, 5000)).await, None);
+    }
+
+    #[tokio::test]
+    async fn pin_client_source() {
+        let cache = UdpAssociateCache::new(true);
+        let id = cache.insert(CLIENT_IP, 5000).await;
+
+        let source = SocketAddr::new(CLIENT_IP, 6000);
+        assert_eq!(cache.resolve(source).await, Some(id));
+        assert_eq!(cache.resolve(source).await, Some(id));
 
-    pub async fn remove_stalled(&self) { self.cache.lock().await.iter(); }
+        // the association is pinned to the first observed source
+        assert_eq!(cache.resolve(SocketAddr::new(CLIENT_IP, 5000)).await, None);
+        assert_eq!(cache.resolve(SocketAddr::new(CLIENT_IP, 7000)).await, None);
+    }
 }
@@ -1,37 +1,50 @@
 use std::{
     collections::HashSet,
     net::{IpAddr, SocketAddr},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    sync::Arc,
     time::Duration,
 };
 
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use snafu::ResultExt;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::mpsc,
-    time,
+    task::JoinSet,
 };
 
 use crate::{
     common::HostAddress,
-    protocol::socks::{error, v5::Reply, Address, Error},
-    service::socks::v5::udp::{shutdown, UdpAssociateCache, UdpServer},
-    transport::Resolver,
+    protocol::socks::{
+        v5::{DatagramCodec, PlainDatagramCodec, Reply, ReplyField},
+        Address,
+    },
+    service::{
+        socks::{
+            v5::udp::{server::DEFAULT_CACHE_EXPIRY, shutdown, UdpAssociateCache, UdpServer},
+            Error, PortPolicy,
+        },
+        LogPrivacy,
+    },
+    transport::{DestinationFilter, Resolver},
 };
 
+const KEEPALIVE_BUF_SIZE: usize = 64;
+
+pub type UdpAssociateRequest<TransportStream> = (TransportStream, SocketAddr, HostAddress);
+
 pub struct Manager<TransportStream> {
     resolver: Arc<dyn Resolver>,
+    destination_filter: DestinationFilter,
+    port_policy: PortPolicy,
+    codec: Arc<dyn DatagramCodec>,
     cache: UdpAssociateCache,
-    cache_expiry_duration: Duration,
+    log_privacy: LogPrivacy,
+    strict_target: bool,
+    cache_expiry: Duration,
 
-    server_addr: IpAddr,
-    ports: HashSet<u16>,
+    bind_addrs: Vec<SocketAddr>,
 
-    current_server_addr_index: AtomicUsize,
+    current_server_addr_index: usize,
     server_addrs: Vec<SocketAddr>,
 
     _phantom: std::marker::PhantomData<TransportStream>,
@@ -42,28 +55,93 @@ where
     TransportStream: 'static + Send + Sync + Unpin + AsyncRead + AsyncWrite,
 {
     pub fn new(
-        server_addr: IpAddr,
-        ports: HashSet<u16>,
+        bind_addrs: Vec<SocketAddr>,
         resolver: Arc<dyn Resolver>,
-        cache_expiry_duration: Duration,
-    ) -> Manager<TransportStream> {
-        let cache = UdpAssociateCache::new(cache_expiry_duration);
-        let server_addrs = Vec::new();
-        let current_server_addr_index = AtomicUsize::new(0);
-
-        Manager {
+        pin_client_source: bool,
+    ) -> Self {
+        Self {
             resolver,
-            cache,
-            cache_expiry_duration,
-            server_addr,
-            current_server_addr_index,
-            server_addrs,
-            ports,
-            _phantom: Default::default(),
+            destination_filter: DestinationFilter::default(),
+            port_policy: PortPolicy::default(),
+            codec: Arc::new(PlainDatagramCodec),
+            cache: UdpAssociateCache::new(pin_client_source),
+            log_privacy: LogPrivacy::default(),
+            strict_target: false,
+            cache_expiry: DEFAULT_CACHE_EXPIRY,
+            bind_addrs,
+            current_server_addr_index: 0,
+            server_addrs: Vec::new(),
+            _phantom: std::marker::PhantomData,
         }
     }
 
-    pub fn serve(self) -> (mpsc::Sender<(TransportStream, HostAddress)>, shutdown::JoinHandle<()>) {
+    /// Binds UDP sockets on `ports` of the same `server_addr`.
+    pub fn with_ports(
+        server_addr: IpAddr,
+        ports: &HashSet<u16>,
+        resolver: Arc<dyn Resolver>,
+        pin_client_source: bool,
+    ) -> Self {
+        let bind_addrs = ports.iter().map(|port| SocketAddr::new(server_addr, *port)).collect();
+        Self::new(bind_addrs, resolver, pin_client_source)
+    }
+
+    #[must_use]
+    pub fn with_datagram_codec(mut self, codec: Arc<dyn DatagramCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Filters destinations of datagrams like the transport of connections,
+    /// datagrams to denied destinations are dropped. Every destination is
+    /// allowed by default.
+    #[must_use]
+    pub fn with_destination_filter(mut self, destination_filter: DestinationFilter) -> Self {
+        self.destination_filter = destination_filter;
+        self
+    }
+
+    /// Drops datagrams to ports not allowed for UDP Associate by
+    /// `port_policy`.
+    #[must_use]
+    pub fn with_port_policy(mut self, port_policy: PortPolicy) -> Self {
+        self.port_policy = port_policy;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_log_privacy(mut self, log_privacy: LogPrivacy) -> Self {
+        self.log_privacy = log_privacy;
+        self
+    }
+
+    /// Restricts associations to the address declared in UDP associate
+    /// requests as the only remote host, datagrams to and from other remote
+    /// hosts are dropped. Associations declaring the wildcard are not
+    /// restricted.
+    ///
+    /// RFC 1928 defines the declared address as the source of the client, the
+    /// client source is then pinned to the first datagram from the IP of the
+    /// control connection with any source port instead, even if
+    /// `pin_client_source` is disabled. A declared domain name admits every
+    /// address it resolves to.
+    #[must_use]
+    pub const fn with_strict_target(mut self, strict_target: bool) -> Self {
+        self.strict_target = strict_target;
+        self
+    }
+
+    /// Sets how long relay sockets of an association are kept after the
+    /// association is torn down, defaults to 5 seconds.
+    #[must_use]
+    pub const fn with_cache_expiry(mut self, cache_expiry: Duration) -> Self {
+        self.cache_expiry = cache_expiry;
+        self
+    }
+
+    pub fn serve(
+        self,
+    ) -> (mpsc::Sender<UdpAssociateRequest<TransportStream>>, shutdown::JoinHandle<()>) {
         let (stream_sender, stream_acceptor) = mpsc::channel(128);
         let (shutdown_signal, shutdown_slot) = shutdown::shutdown_handle();
         let join_handle = tokio::spawn(async move {
@@ -75,82 +153,71 @@ where
 
     async fn serve_internal(
         mut self,
-        mut stream_acceptor: mpsc::Receiver<(TransportStream, HostAddress)>,
+        mut stream_acceptor: mpsc::Receiver<UdpAssociateRequest<TransportStream>>,
         mut shutdown_slot: shutdown::ShutdownSlot,
     ) -> Result<(), Error> {
         tracing::info!("Start UDP associate manager");
 
-        let server_handles = FuturesUnordered::new();
+        let mut server_handles = FuturesUnordered::new();
         let mut server_shutdown_signals = vec![];
-        for port in &self.ports {
-            let socket_addr = SocketAddr::new(self.server_addr, *port);
-            let (server, shutdown_signal) =
-                UdpServer::new(socket_addr, self.cache.clone(), self.resolver.clone());
-            self.server_addrs.push(socket_addr);
-            server_shutdown_signals.push(shutdown_signal);
-            server_handles.push(tokio::spawn({
-                async move {
-                    let _ = server.serve().await;
+        for socket_addr in self.bind_addrs.iter().copied() {
+            let server = UdpServer::bind(
+                socket_addr,
+                self.cache.clone(),
+                self.resolver.clone(),
+                self.destination_filter.clone(),
+                self.port_policy.clone(),
+                self.codec.clone(),
+                self.log_privacy,
+            )
+            .await;
+            let (server, shutdown_signal) = match server {
+                Ok((server, shutdown_signal)) => {
+                    (server.with_cache_expiry(self.cache_expiry), shutdown_signal)
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to start UDP server, error: {}", err);
+                    continue;
                 }
+            };
+
+            self.server_addrs.push(server.local_addr());
+            server_shutdown_signals.push(shutdown_signal);
+            server_handles.push(tokio::spawn(async move {
+                let _ = server.serve().await;
             }));
         }
 
-        // remove expired UDP associate
-        let mut interval = time::interval(self.cache_expiry_duration);
+        let mut associations = JoinSet::new();
 
         loop {
-            let (mut stream, cache_key) = futures::select! {
+            let (stream, client_addr, declared_addr) = futures::select! {
                 _ = shutdown_slot.wait().fuse() => break,
-                _ = interval.tick().fuse() => {
-                tracing::    debug!("Remove expired UDP associate");
-                    self.cache.remove_stalled().await;
-                    continue;
-                }
-                rx = stream_acceptor.recv().fuse() => {
-                    match rx {
-                        Some((stream, target_addr)) => (stream,  target_addr),
-                        None => break,
-                    }
+                rx = stream_acceptor.recv().fuse() => match rx {
+                    Some(request) => request,
+                    None => break,
                 },
             };
 
-            tokio::spawn({
-                let proxy_addr = match self.pick_server() {
-                    Some(proxy_addr) => proxy_addr,
-                    None => return Ok(()),
-                };
-
-                let cache = self.cache.clone();
-                let mut shutdown_slot = cache.insert(&cache_key).await;
-
-                let reply = Reply::success(Address::from(proxy_addr));
-                let _ = stream.write(&reply.into_bytes()).await.context(error::WriteStreamSnafu)?;
-
-                async move {
-                    let mut buf = [0u8; 1];
-                    loop {
-                        let result = futures::select! {
-                            _ = shutdown_slot.wait().fuse() => break,
-                            res = stream.read(&mut buf).fuse() => res,
-                        };
-
-                        match result {
-                            Ok(0) => break,
-                            Ok(_n) => continue,
-                            Err(_err) => break,
-                        }
-                    }
-
-                    cache.remove(&cache_key).await;
-                    let _ = stream.shutdown().await;
-                }
-            });
+            // reap finished associations
+            while associations.try_join_next().is_some() {}
+
+            let proxy_addr = self.pick_server();
+            associations.spawn(Self::associate(
+                self.cache.clone(),
+                stream,
+                client_addr,
+                declared_addr,
+                proxy_addr,
+                self.strict_target,
+            ));
         }
 
         tracing::info!("Stop receiving UDP associate request");
 
+        associations.shutdown().await;
         server_shutdown_signals.into_iter().for_each(shutdown::ShutdownSignal::shutdown);
-        let _ = server_handles.into_future().await;
+        while server_handles.next().await.is_some() {}
 
         tracing::info!("All UDP servers are stopped");
 
@@ -160,15 +227,537 @@ where
         Ok(())
     }
 
+    async fn associate(
+        cache: UdpAssociateCache,
+        mut stream: TransportStream,
+        client_addr: SocketAddr,
+        declared_addr: HostAddress,
+        proxy_addr: Option<SocketAddr>,
+        strict_target: bool,
+    ) {
+        let Some(proxy_addr) = proxy_addr else {
+            let reply =
+                Reply { reply: ReplyField::ServerFailure, bind_socket: Address::empty_ipv4() };
+            let _ = stream.write(&reply.into_bytes()).await;
+            let _ = stream.shutdown().await;
+            return;
+        };
+
+        let id = if strict_target && !is_wildcard(&declared_addr) {
+            cache.insert_with_target(client_addr.ip(), declared_addr).await
+        } else {
+            cache.insert(client_addr.ip(), declared_addr.port()).await
+        };
+
+        let reply = Reply::success(Address::from(proxy_addr));
+        if stream.write(&reply.into_bytes()).await.is_ok() && stream.flush().await.is_ok() {
+            // the association lives as long as the control connection, clients may
+            // write keep-alives of any size on it, which are discarded, the
+            // association is torn down on EOF or any error
+            let mut buf = [0u8; KEEPALIVE_BUF_SIZE];
+            while let Ok(1..) = stream.read(&mut buf).await {}
+        }
+
+        cache.remove(client_addr.ip(), id).await;
+        let _ = stream.shutdown().await;
+    }
+
     #[inline]
-    fn pick_server(&self) -> Option<SocketAddr> {
+    fn pick_server(&mut self) -> Option<SocketAddr> {
         match self.server_addrs.len() {
             0 => None,
             server_count => {
-                let next = self.current_server_addr_index.fetch_add(1, Ordering::SeqCst);
-                let index = next % server_count;
+                let index = self.current_server_addr_index % server_count;
+                self.current_server_addr_index = self.current_server_addr_index.wrapping_add(1);
                 Some(self.server_addrs[index])
             }
         }
     }
 }
+
+// either the IP or the port is left unspecified by a client which does not know
+// it yet
+fn is_wildcard(addr: &HostAddress) -> bool {
+    match addr {
+        HostAddress::Socket(addr) => addr.ip().is_unspecified() || addr.port() == 0,
+        HostAddress::DomainName(_, port) => *port == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use bytes::BytesMut;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::UdpSocket,
+        time,
+    };
+
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        protocol::socks::{
+            v5::{Datagram, DatagramCodec, PlainDatagramCodec, Reply, ReplyField},
+            Address, AddressType, Error, SocksCommand,
+        },
+        service::socks::{v5::udp::UdpAssociateManager, PortPolicy},
+        transport::{DestinationFilter, Resolver, StaticResolver, TokioResolver},
+    };
+
+    async fn echo_server() -> SocketAddr { echo_server_at(Ipv4Addr::LOCALHOST.into()).await }
+
+    async fn echo_server_at(ip: IpAddr) -> SocketAddr {
+        echo_server_on(SocketAddr::new(ip, 0)).await
+    }
+
+    async fn echo_server_on(addr: SocketAddr) -> SocketAddr {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..n], peer).await;
+            }
+        });
+        addr
+    }
+
+    // simulates a client behind symmetric NAT, the source port of UDP packets
+    // differs from both the control connection and the port declared in UDP
+    // associate request
+    async fn relay_from_mapped_port(
+        pin_client_source: bool,
+        codec: Arc<dyn DatagramCodec>,
+        declared_addr: SocketAddr,
+    ) -> Option<Vec<u8>> {
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv4Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            pin_client_source,
+        )
+        .with_datagram_codec(codec.clone());
+        let (tx, join_handle) = manager.serve();
+
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        tx.send((server_side, control_addr, HostAddress::from(declared_addr))).await.unwrap();
+
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+
+        let echo_addr = echo_server().await;
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let datagram = Datagram::new(0, Address::from(echo_addr), BytesMut::from(&b"tunelo"[..]));
+        client.send_to(&codec.encode(datagram), relay_addr).await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let response =
+            time::timeout(Duration::from_millis(500), client.recv_from(&mut buf)).await.ok();
+
+        drop(control);
+        join_handle.shutdown_and_wait().await;
+
+        let (n, _) = response?.unwrap();
+        let datagram = codec.decode(&buf[..n]).unwrap();
+        assert_eq!(datagram.destination_address(), &HostAddress::from(echo_addr));
+        Some(datagram.data().to_vec())
+    }
+
+    struct XorCodec(u8);
+
+    impl XorCodec {
+        fn xor(&self, buf: &[u8]) -> Vec<u8> { buf.iter().map(|b| b ^ self.0).collect() }
+    }
+
+    impl DatagramCodec for XorCodec {
+        fn decode(&self, buf: &[u8]) -> Result<Datagram, Error> {
+            Datagram::from_bytes(&self.xor(buf))
+        }
+
+        fn encode(&self, datagram: Datagram) -> Vec<u8> { self.xor(&datagram.into_bytes()) }
+    }
+
+    const DECLARED_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40001);
+
+    #[tokio::test]
+    async fn pin_client_source() {
+        let codec = Arc::new(PlainDatagramCodec);
+        let data = relay_from_mapped_port(true, codec, DECLARED_ADDR).await;
+        assert_eq!(data, Some(b"tunelo".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn drop_unexpected_client_source() {
+        let codec = Arc::new(PlainDatagramCodec);
+        assert_eq!(relay_from_mapped_port(false, codec, DECLARED_ADDR).await, None);
+    }
+
+    #[tokio::test]
+    async fn associate_wildcard() {
+        // a client not knowing its source is not restricted to a source port
+        for declared_addr in [
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        ] {
+            let data = relay_from_mapped_port(false, Arc::new(PlainDatagramCodec), declared_addr);
+            assert_eq!(data.await, Some(b"tunelo".to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn close_relay_sockets_after_cache_expiry() {
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv4Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            true,
+        )
+        .with_cache_expiry(Duration::from_millis(50));
+        let (tx, join_handle) = manager.serve();
+
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        tx.send((server_side, control_addr, HostAddress::from(DECLARED_ADDR))).await.unwrap();
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+
+        // the remote host learns the relay socket of the association
+        let remote = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let datagram = Datagram::new(
+            0,
+            Address::from(remote.local_addr().unwrap()),
+            BytesMut::from(&b"tunelo"[..]),
+        );
+        client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let (_, relay_socket_addr) =
+            time::timeout(Duration::from_millis(500), remote.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+
+        drop(control);
+        time::sleep(Duration::from_millis(300)).await;
+
+        // the port of the relay socket is free again once the socket is closed
+        let relay_socket_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, relay_socket_addr.port()));
+        assert!(UdpSocket::bind(relay_socket_addr).await.is_ok());
+
+        join_handle.shutdown_and_wait().await;
+    }
+
+    #[tokio::test]
+    async fn strict_target() {
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv4Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            false,
+        )
+        .with_strict_target(true);
+        let (tx, join_handle) = manager.serve();
+
+        let target_addr = echo_server().await;
+        let other_addr = echo_server().await;
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        tx.send((server_side, control_addr, HostAddress::from(target_addr))).await.unwrap();
+
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+
+        // datagrams to a remote host other than the declared target are dropped
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let datagram = Datagram::new(0, Address::from(other_addr), BytesMut::from(&b"other"[..]));
+        client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+        let response = time::timeout(Duration::from_millis(200), client.recv_from(&mut buf)).await;
+        assert!(response.is_err());
+
+        let datagram = Datagram::new(0, Address::from(target_addr), BytesMut::from(&b"tunelo"[..]));
+        client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+        let (n, _) = time::timeout(Duration::from_millis(500), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let datagram = Datagram::from_bytes(&buf[..n]).unwrap();
+        assert_eq!(datagram.destination_address(), &HostAddress::from(target_addr));
+        assert_eq!(datagram.data(), b"tunelo");
+
+        drop(control);
+        join_handle.shutdown_and_wait().await;
+    }
+
+    #[tokio::test]
+    async fn strict_domain_target() {
+        // every address of the declared domain name is the target
+        let first_addr = echo_server().await;
+        let second_addr =
+            echo_server_on(SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), first_addr.port())))
+                .await;
+        let resolver: Arc<dyn Resolver> = Arc::new(
+            StaticResolver::builder()
+                .insert("udp.example", first_addr.ip())
+                .insert("udp.example", second_addr.ip())
+                .build(),
+        );
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv4Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            false,
+        )
+        .with_strict_target(true);
+        let (tx, join_handle) = manager.serve();
+
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        let target = HostAddress::new("udp.example", first_addr.port());
+        tx.send((server_side, control_addr, target)).await.unwrap();
+
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        for echo_addr in [first_addr, second_addr] {
+            let datagram =
+                Datagram::new(0, Address::from(echo_addr), BytesMut::from(&b"tunelo"[..]));
+            client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+
+            let mut buf = [0u8; 1024];
+            let (n, _) = time::timeout(Duration::from_millis(500), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let datagram = Datagram::from_bytes(&buf[..n]).unwrap();
+            assert_eq!(datagram.destination_address(), &HostAddress::from(echo_addr));
+            assert_eq!(datagram.data(), b"tunelo");
+        }
+
+        drop(control);
+        join_handle.shutdown_and_wait().await;
+    }
+
+    #[tokio::test]
+    async fn filter_destinations() {
+        let allowed_addr = echo_server().await;
+        let denied_addr = echo_server().await;
+        let other_port_addr = echo_server().await;
+        let port_policy = PortPolicy::new()
+            .allow(SocksCommand::UdpAssociate, [allowed_addr.port(), denied_addr.port()]);
+        let destination_filter = {
+            let mut filter = SimpleFilter::deny_list();
+            filter.add_socket(denied_addr);
+            DestinationFilter::new(Arc::new(filter))
+        };
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv4Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            false,
+        )
+        .with_destination_filter(destination_filter)
+        .with_port_policy(port_policy);
+        let (tx, join_handle) = manager.serve();
+
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        let declared_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        tx.send((server_side, control_addr, HostAddress::from(declared_addr))).await.unwrap();
+
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+
+        // datagrams to denied destinations are dropped
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut buf = [0u8; 1024];
+        for addr in [denied_addr, other_port_addr] {
+            let datagram = Datagram::new(0, Address::from(addr), BytesMut::from(&b"denied"[..]));
+            client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+            let response =
+                time::timeout(Duration::from_millis(200), client.recv_from(&mut buf)).await;
+            assert!(response.is_err());
+        }
+
+        let datagram =
+            Datagram::new(0, Address::from(allowed_addr), BytesMut::from(&b"tunelo"[..]));
+        client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+        let (n, _) = time::timeout(Duration::from_millis(500), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let datagram = Datagram::from_bytes(&buf[..n]).unwrap();
+        assert_eq!(datagram.destination_address(), &HostAddress::from(allowed_addr));
+
+        drop(control);
+        join_handle.shutdown_and_wait().await;
+    }
+
+    #[tokio::test]
+    async fn bind_multiple_addresses() {
+        let bind_ips = [Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)];
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::new(
+            bind_ips.iter().map(|ip| SocketAddr::from((*ip, 0))).collect(),
+            resolver,
+            false,
+        );
+        let (tx, join_handle) = manager.serve();
+
+        // associations are distributed to UDP servers in turn
+        let mut relay_ips = HashSet::new();
+        let mut controls = Vec::new();
+        for port in [40000, 40001] {
+            let (mut control, server_side) = tokio::io::duplex(64);
+            let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            tx.send((server_side, client_addr, HostAddress::from(client_addr))).await.unwrap();
+
+            let reply = Reply::from_reader(&mut control).await.unwrap();
+            assert_eq!(reply.reply, ReplyField::Success);
+            match reply.bind_socket.as_ref() {
+                HostAddress::Socket(addr) => relay_ips.insert(addr.ip()),
+                HostAddress::DomainName(..) => unreachable!(),
+            };
+            controls.push(control);
+        }
+
+        drop(controls);
+        join_handle.shutdown_and_wait().await;
+        assert_eq!(relay_ips, bind_ips.iter().copied().map(IpAddr::from).collect());
+    }
+
+    #[tokio::test]
+    async fn custom_datagram_codec() {
+        let codec = Arc::new(XorCodec(0x5a));
+        let data = relay_from_mapped_port(true, codec, DECLARED_ADDR).await;
+        assert_eq!(data, Some(b"tunelo".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn tear_down_after_keepalives() {
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv4Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            true,
+        );
+        let (tx, join_handle) = manager.serve();
+
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+        tx.send((server_side, control_addr, HostAddress::from(control_addr))).await.unwrap();
+
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+
+        // keep-alives of various sizes, some of them exceed the read buffer
+        for size in [1, 7, 64, 300] {
+            control.write_all(&vec![0u8; size]).await.unwrap();
+        }
+        let mut buf = [0u8; 1024];
+        assert!(time::timeout(Duration::from_millis(100), control.read(&mut buf)).await.is_err());
+
+        // the association is still alive
+        let echo_addr = echo_server().await;
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let datagram = Datagram::new(0, Address::from(echo_addr), BytesMut::from(&b"tunelo"[..]));
+        client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+        let (n, _) = time::timeout(Duration::from_millis(500), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Datagram::from_bytes(&buf[..n]).unwrap().data(), b"tunelo");
+
+        // closing the control connection tears down the association
+        control.shutdown().await.unwrap();
+        let n = time::timeout(Duration::from_millis(500), control.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+
+        join_handle.shutdown_and_wait().await;
+    }
+
+    #[tokio::test]
+    async fn associate_over_ipv6() {
+        let resolver: Arc<dyn Resolver> = Arc::new(TokioResolver::new());
+        let manager = UdpAssociateManager::<DuplexStream>::with_ports(
+            Ipv6Addr::LOCALHOST.into(),
+            &HashSet::from_iter([0]),
+            resolver,
+            true,
+        );
+        let (tx, join_handle) = manager.serve();
+
+        let (mut control, server_side) = tokio::io::duplex(64);
+        let control_addr = SocketAddr::from((Ipv6Addr::LOCALHOST, 40000));
+        tx.send((server_side, control_addr, HostAddress::from(control_addr))).await.unwrap();
+
+        let reply = Reply::from_reader(&mut control).await.unwrap();
+        assert_eq!(reply.reply, ReplyField::Success);
+        assert_eq!(reply.bind_socket.address_type(), AddressType::Ipv6);
+        let relay_addr = match reply.bind_socket.as_ref() {
+            HostAddress::Socket(addr) => *addr,
+            HostAddress::DomainName(..) => unreachable!(),
+        };
+        assert_eq!(relay_addr.ip(), IpAddr::from(Ipv6Addr::LOCALHOST));
+
+        let client = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        for echo_ip in [IpAddr::from(Ipv4Addr::LOCALHOST), IpAddr::from(Ipv6Addr::LOCALHOST)] {
+            let echo_addr = echo_server_at(echo_ip).await;
+            let datagram =
+                Datagram::new(0, Address::from(echo_addr), BytesMut::from(&b"tunelo"[..]));
+            client.send_to(&datagram.into_bytes(), relay_addr).await.unwrap();
+
+            let mut buf = [0u8; 1024];
+            let (n, _) = time::timeout(Duration::from_millis(500), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let datagram = Datagram::from_bytes(&buf[..n]).unwrap();
+            assert_eq!(datagram.destination_address(), &HostAddress::from(echo_addr));
+            assert_eq!(datagram.data(), b"tunelo");
+        }
+
+        drop(control);
+        join_handle.shutdown_and_wait().await;
+    }
+}
@@ -4,5 +4,5 @@ mod manager;
 mod server;
 mod shutdown;
 
-pub use self::manager::Manager as UdpAssociateManager;
+pub use self::manager::{Manager as UdpAssociateManager, UdpAssociateRequest};
 use self::{associate::UdpAssociate, cache::UdpAssociateCache, server::UdpServer};
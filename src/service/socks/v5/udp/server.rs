@@ -1,90 +1,156 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::FutureExt;
-use lru_time_cache::LruCache;
 use snafu::ResultExt;
 use tokio::{net::UdpSocket, sync::mpsc, time};
 
 use crate::{
-    protocol::socks::{error, v5::Datagram, Error},
-    service::socks::v5::udp::{shutdown, UdpAssociate, UdpAssociateCache},
-    transport::Resolver,
+    protocol::socks::v5::{Datagram, DatagramCodec},
+    service::{
+        socks::{
+            error,
+            v5::udp::{cache::AssociationId, shutdown, UdpAssociate, UdpAssociateCache},
+            Error, PortPolicy,
+        },
+        LogPrivacy,
+    },
+    transport::{DestinationFilter, Resolver},
 };
 
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+pub const DEFAULT_CACHE_EXPIRY: Duration = Duration::from_secs(5);
+
 pub struct UdpServer {
+    socket: UdpSocket,
     local_addr: SocketAddr,
     cache: UdpAssociateCache,
     resolver: Arc<dyn Resolver>,
+    destination_filter: DestinationFilter,
+    port_policy: PortPolicy,
+    codec: Arc<dyn DatagramCodec>,
+    log_privacy: LogPrivacy,
+    cache_expiry: Duration,
     shutdown_slot: shutdown::ShutdownSlot,
 }
 
 impl UdpServer {
-    pub fn new(
+    pub async fn bind(
         local_addr: SocketAddr,
         udp_associate_cache: UdpAssociateCache,
         resolver: Arc<dyn Resolver>,
-    ) -> (UdpServer, shutdown::ShutdownSignal) {
+        destination_filter: DestinationFilter,
+        port_policy: PortPolicy,
+        codec: Arc<dyn DatagramCodec>,
+        log_privacy: LogPrivacy,
+    ) -> Result<(Self, shutdown::ShutdownSignal), Error> {
+        let socket = UdpSocket::bind(&local_addr)
+            .await
+            .context(error::BindUdpSocketSnafu { addr: local_addr })?;
+        let local_addr =
+            socket.local_addr().context(error::BindUdpSocketSnafu { addr: local_addr })?;
+
         let (shutdown_signal, shutdown_slot) = shutdown::shutdown_handle();
-        (
-            UdpServer { local_addr, cache: udp_associate_cache, resolver, shutdown_slot },
+        Ok((
+            Self {
+                socket,
+                local_addr,
+                cache: udp_associate_cache,
+                resolver,
+                destination_filter,
+                port_policy,
+                codec,
+                log_privacy,
+                cache_expiry: DEFAULT_CACHE_EXPIRY,
+                shutdown_slot,
+            },
             shutdown_signal,
-        )
+        ))
     }
 
+    /// Sets the interval of closing relay sockets of associations torn down.
+    #[must_use]
+    pub const fn with_cache_expiry(mut self, cache_expiry: Duration) -> Self {
+        self.cache_expiry = cache_expiry;
+        self
+    }
+
+    #[inline]
+    pub const fn local_addr(&self) -> SocketAddr { self.local_addr }
+
     pub async fn serve(self) -> Result<(), Error> {
         tracing::info!("Starting UDP server for UDP associate at {}", self.local_addr);
-        let udp_socket =
-            UdpSocket::bind(&self.local_addr).await.context(error::BindUdpSocketSnafu)?;
-        let mut shutdown_slot = self.shutdown_slot;
-        let (mut udp_recv, mut udp_send) = udp_socket.split();
+        let Self {
+            socket,
+            local_addr,
+            cache,
+            resolver,
+            destination_filter,
+            port_policy,
+            codec,
+            log_privacy,
+            cache_expiry,
+            mut shutdown_slot,
+        } = self;
+        let socket = Arc::new(socket);
 
         // FIXME buffer size
         let (pkt_tx, mut pkt_rx) = mpsc::channel::<(SocketAddr, Datagram)>(1024);
 
-        tokio::spawn(async move {
-            while let Some((client_addr, datagram)) = pkt_rx.recv().await {
-                if let Err(err) = udp_send.send_to(&datagram.into_bytes(), &client_addr).await {
-                    tracing::error!("UDP packet send failed, error: {:?}", err);
-                    break;
+        let send_handle = tokio::spawn({
+            let socket = socket.clone();
+            let codec = codec.clone();
+            async move {
+                while let Some((client_addr, datagram)) = pkt_rx.recv().await {
+                    if let Err(err) = socket.send_to(&codec.encode(datagram), &client_addr).await {
+                        tracing::warn!("UDP packet send failed, error: {:?}", err);
+                    }
                 }
             }
         });
 
-        let mut udp_associates: LruCache<String, UdpAssociate> =
-            LruCache::with_expiry_duration(Duration::from_secs(10));
-        let timeout = Duration::from_secs(5);
-        let mut buf = [0u8; 2048];
+        let mut udp_associates: HashMap<AssociationId, UdpAssociate> = HashMap::new();
+        // relay sockets of associations torn down are closed within `cache_expiry`
+        let mut interval = time::interval(cache_expiry.max(Duration::from_millis(1)));
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
 
         loop {
             let (buf_len, client_addr) = futures::select! {
-                    _ = shutdown_slot.wait().fuse() => break,
-                    res = time::timeout(timeout, udp_recv.recv_from(&mut buf)).fuse() => {
-                        match res {
-                            Ok(Ok((n, client_addr))) => {
-            tracing::                    info!("Received {} byte(s) from {}", n, client_addr);
-                                (n, client_addr)
-                            },
-                            Ok(Err(err)) => {
-                    tracing::            warn!(
-                                    "Failed to receive data from local UDP listener {}, error: {:?}",
-                                    self.local_addr, err
-                                );
-                                break;
-                            }
-                            Err(_err) => {
-                            tracing::    debug!("Remove expired UDP associate objects");
-                                let _ = udp_associates.iter();
-                                continue;
-                            }
+                _ = shutdown_slot.wait().fuse() => break,
+                _ = interval.tick().fuse() => {
+                    tracing::debug!("Remove stale UDP associate objects");
+                    let mut stale = vec![];
+                    for id in udp_associates.keys() {
+                        if !cache.contains(*id).await {
+                            stale.push(*id);
                         }
                     }
-                };
+                    for id in stale {
+                        udp_associates.remove(&id);
+                    }
+                    continue;
+                }
+                res = socket.recv_from(&mut buf).fuse() => match res {
+                    Ok((n, client_addr)) => {
+                        tracing::debug!("Received {} byte(s) from {}", n, client_addr);
+                        (n, client_addr)
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Failed to receive data from local UDP listener {}, error: {:?}",
+                            local_addr,
+                            err
+                        );
+                        break;
+                    }
+                },
+            };
 
             if buf_len == 0 {
                 continue;
             }
 
-            let datagram = match Datagram::from_bytes(&buf[0..buf_len]) {
+            let datagram = match codec.decode(&buf[0..buf_len]) {
                 Ok(datagram) => datagram,
                 Err(err) => {
                     tracing::info!(
@@ -96,20 +162,34 @@ impl UdpServer {
                 }
             };
 
-            match (
-                self.cache.contains(&client_addr.into()).await,
-                udp_associates.get(&client_addr.to_string()),
-            ) {
-                (true, Some(associate)) => {
+            if datagram.frag() != 0 {
+                tracing::debug!("Drop fragmented packet from client: {}", client_addr);
+                continue;
+            }
+
+            let Some(id) = cache.resolve(client_addr).await else {
+                tracing::debug!("Drop packet from unassociated client: {}", client_addr);
+                continue;
+            };
+
+            match udp_associates.get(&id) {
+                Some(associate) => {
                     associate.send_to(datagram).await;
                 }
-                (true, None) => {
-                    match UdpAssociate::new(client_addr, pkt_tx.clone(), self.resolver.clone())
-                        .await
-                    {
+                None => {
+                    let associate = UdpAssociate::new(
+                        client_addr,
+                        cache.target(id).await,
+                        pkt_tx.clone(),
+                        resolver.clone(),
+                        destination_filter.clone(),
+                        port_policy.clone(),
+                        log_privacy,
+                    );
+                    match associate.await {
                         Ok(associate) => {
                             associate.send_to(datagram).await;
-                            udp_associates.insert(client_addr.to_string(), associate);
+                            udp_associates.insert(id, associate);
                         }
                         Err(err) => {
                             tracing::warn!(
@@ -117,16 +197,16 @@ impl UdpServer {
                                 client_addr,
                                 err
                             );
-
-                            self.cache.remove(&client_addr.into()).await;
                         }
                     };
                 }
-                (false, _) => {}
             }
         }
 
-        tracing::info!("UDP server {} is stopped", self.local_addr);
+        drop(udp_associates);
+        send_handle.abort();
+
+        tracing::info!("UDP server {} is stopped", local_addr);
         Ok(())
     }
 }
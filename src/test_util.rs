@@ -0,0 +1,123 @@
+//! Fixtures shared by tests of the crate.
+
+use std::{
+    collections::HashSet,
+    io::Write,
+    net::Ipv4Addr,
+    sync::{self, Arc},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tracing::{subscriber::DefaultGuard, Level};
+
+use crate::{
+    authentication::AuthenticationManager,
+    common::ProxyHost,
+    filter::SimpleFilter,
+    protocol::socks::SocksVersion,
+    service::socks::Service,
+    transport::{TokioResolver, Transport},
+};
+
+/// Returns a transport connecting directly to destinations allowed by
+/// `filter`.
+pub(crate) fn direct_transport(filter: SimpleFilter) -> Transport<TcpStream> {
+    Transport::direct(Arc::new(TokioResolver::new()), Arc::new(filter))
+}
+
+/// Returns a transport connecting directly to every destination.
+pub(crate) fn unfiltered_transport() -> Arc<Transport<TcpStream>> {
+    Arc::new(direct_transport(SimpleFilter::deny_list()))
+}
+
+/// Returns a SOCKS5 service supporting `CONNECT` only.
+pub(crate) fn socks5_service(
+    transport: Arc<Transport<TcpStream>>,
+    authentication_manager: AuthenticationManager,
+) -> Service<TcpStream, TcpStream> {
+    Service::new(
+        HashSet::from_iter([SocksVersion::V5]),
+        transport,
+        Arc::new(Mutex::new(authentication_manager)),
+        true,
+        false,
+        None,
+        false,
+    )
+}
+
+/// Serves every connection to a local port with `service`, returns the proxy
+/// server.
+pub(crate) async fn serve_socks5_with(service: Service<TcpStream, TcpStream>) -> ProxyHost {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = Arc::new(service);
+    tokio::spawn(async move {
+        while let Ok((stream, peer_addr)) = listener.accept().await {
+            let service = service.clone();
+            tokio::spawn(async move { service.dispatch(stream, peer_addr).await });
+        }
+    });
+    ProxyHost::Socks5 {
+        host: addr.ip().to_string(),
+        port: addr.port(),
+        username: None,
+        password: None,
+    }
+}
+
+/// Serves SOCKS5 without authentication on a local port, returns the proxy
+/// server.
+pub(crate) async fn serve_socks5() -> ProxyHost {
+    serve_socks5_with(socks5_service(unfiltered_transport(), AuthenticationManager::new())).await
+}
+
+/// Log lines written by the subscriber set by [`CapturedLogs::capture`].
+#[derive(Clone, Default)]
+pub(crate) struct CapturedLogs(Arc<sync::Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    /// Captures events up to `max_level` of the current thread until the
+    /// guard is dropped.
+    pub(crate) fn capture(max_level: Level) -> (Self, DefaultGuard) {
+        let logs = Self::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_max_level(max_level)
+            .with_ansi(false)
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).to_string()
+    }
+
+    /// Returns the `id` of the `connection` span of each line containing
+    /// `message`, `None` for lines outside of the span.
+    pub(crate) fn connection_ids(&self, message: &str) -> Vec<Option<String>> {
+        self.contents()
+            .lines()
+            .filter(|line| line.contains(message))
+            .map(|line| {
+                let rest = line.split("connection{id=").nth(1)?;
+                rest.split('}').next().map(str::to_owned)
+            })
+            .collect()
+    }
+}
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
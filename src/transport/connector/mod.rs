@@ -5,9 +5,13 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::common::HostAddress;
 
+mod nodelay;
 mod proxy;
+mod retry;
+mod ttl;
 
-pub use self::proxy::ProxyConnector;
+pub(crate) use self::retry::retry_connect;
+pub use self::{nodelay::NoDelayConnector, proxy::ProxyConnector, ttl::TtlConnector};
 
 pub type Connect<Stream, Error> = Pin<Box<dyn Future<Output = Result<Stream, Error>> + Send>>;
 
@@ -54,3 +58,61 @@ pub fn connect_fn<Stream, Error>(
 ) -> Arc<FnConnector<Stream, Error>> {
     Arc::new(FnConnector { connect_fn, connect_addr_fn })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::{Connect, Connector};
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        transport::{Error, TokioResolver, Transport},
+    };
+
+    // records connected hosts and hands out in-memory streams
+    #[derive(Default)]
+    struct RecordingConnector {
+        hosts: Mutex<Vec<HostAddress>>,
+        peers: Mutex<Vec<DuplexStream>>,
+    }
+
+    impl Connector for RecordingConnector {
+        type Error = Error;
+        type Stream = DuplexStream;
+
+        fn connect(&self, host: &HostAddress) -> Connect<Self::Stream, Self::Error> {
+            self.hosts.lock().unwrap().push(host.clone());
+            let (stream, peer) = tokio::io::duplex(64);
+            self.peers.lock().unwrap().push(peer);
+            Box::pin(futures::future::ready(Ok(stream)))
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_connector() {
+        let connector = Arc::new(RecordingConnector::default());
+        let transport = Transport::with_connector(
+            Arc::new(TokioResolver::new()),
+            Arc::new(SimpleFilter::deny_list()),
+            connector.clone(),
+        );
+
+        let addr = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80));
+        let (mut stream, connected_addr) =
+            transport.connect(&HostAddress::from(addr)).await.unwrap();
+        assert_eq!(connected_addr, addr);
+        assert_eq!(*connector.hosts.lock().unwrap(), vec![HostAddress::from(addr)]);
+
+        stream.write_all(b"tunelo").await.unwrap();
+        let mut peer = connector.peers.lock().unwrap().pop().unwrap();
+        let mut buf = [0u8; 6];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tunelo");
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use futures::FutureExt;
+use snafu::ResultExt;
+use tokio::net::TcpStream;
+
+use crate::{
+    common::HostAddress,
+    transport::{
+        connector::{Connect, Connector},
+        error, Error,
+    },
+};
+
+/// Sets `TCP_NODELAY` on streams established by the inner connector according
+/// to destination port, streams to ports not in the map keep the system
+/// default.
+pub struct NoDelayConnector {
+    connector: Arc<dyn Connector<Stream = TcpStream, Error = Error>>,
+    ports: Arc<HashMap<u16, bool>>,
+}
+
+impl NoDelayConnector {
+    #[inline]
+    pub fn new(
+        connector: Arc<dyn Connector<Stream = TcpStream, Error = Error>>,
+        ports: HashMap<u16, bool>,
+    ) -> Self {
+        Self { connector, ports: Arc::new(ports) }
+    }
+
+    fn set_nodelay(
+        stream: TcpStream,
+        port: u16,
+        ports: &HashMap<u16, bool>,
+    ) -> Result<TcpStream, Error> {
+        if let Some(&nodelay) = ports.get(&port) {
+            stream.set_nodelay(nodelay).context(error::SetNoDelaySnafu { nodelay })?;
+        }
+        Ok(stream)
+    }
+}
+
+impl Connector for NoDelayConnector {
+    type Error = Error;
+    type Stream = TcpStream;
+
+    fn connect(&self, host: &HostAddress) -> Connect<Self::Stream, Self::Error> {
+        let (port, ports) = (host.port(), self.ports.clone());
+        self.connector
+            .connect(host)
+            .map(move |stream| Self::set_nodelay(stream?, port, &ports))
+            .boxed()
+    }
+
+    fn connect_addr(&self, addr: &SocketAddr) -> Connect<Self::Stream, Self::Error> {
+        let (port, ports) = (addr.port(), self.ports.clone());
+        self.connector
+            .connect_addr(addr)
+            .map(move |stream| Self::set_nodelay(stream?, port, &ports))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use tokio::net::TcpListener;
+
+    use crate::{common::HostAddress, filter::SimpleFilter, test_util::direct_transport};
+
+    #[tokio::test]
+    async fn nodelay_by_destination_port() {
+        let interactive = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let interactive_addr = interactive.local_addr().unwrap();
+        let bulk = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let bulk_addr = bulk.local_addr().unwrap();
+
+        let transport = {
+            direct_transport(SimpleFilter::deny_list()).with_nodelay_ports(HashMap::from([
+                (interactive_addr.port(), true),
+                (bulk_addr.port(), false),
+            ]))
+        };
+
+        let (stream, _) = transport.connect(&HostAddress::from(interactive_addr)).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        let (stream, _) = transport.connect(&HostAddress::from(bulk_addr)).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+}
@@ -2,13 +2,12 @@ use std::{net::SocketAddr, sync::Arc};
 
 use futures::FutureExt;
 use snafu::ResultExt;
-use tokio::net::TcpStream;
 
 use crate::{
-    client,
-    common::{HostAddress, ProxyStrategy},
+    client::{self, ProxySocket},
+    common::{HostAddress, IncompatibleChain, Policy, ProxyStrategy},
     transport::{
-        connector::{Connect, Connector},
+        connector::{retry_connect, Connect, Connector},
         error, Error,
     },
 };
@@ -16,27 +15,48 @@ use crate::{
 #[derive(Clone)]
 pub struct ProxyConnector {
     connector: client::ProxyConnector,
+    policy: Policy,
 }
 
 impl ProxyConnector {
+    /// Proxy chains failing [`ProxyStrategy::validate`] are rejected.
+    ///
+    /// Each hop is connected and handshaked within the timeouts of `policy`,
+    /// establishing the whole chain is retried as `policy` if it fails with a
+    /// connection error on any hop.
     #[inline]
-    pub fn new(proxy_strategy: Arc<ProxyStrategy>) -> Result<Self, Error> {
-        let connector = client::ProxyConnector::new(proxy_strategy)
-            .context(error::CreateProxyConnectorSnafu)?;
-        Ok(Self { connector })
+    pub fn new(
+        proxy_strategy: Arc<ProxyStrategy>,
+        max_chain_length: usize,
+        policy: Policy,
+    ) -> Result<Self, Error> {
+        proxy_strategy.validate().map_err(|IncompatibleChain { hop, reason }| {
+            Error::IncompatibleChain { hop, reason }
+        })?;
+        // retries are made over the whole chain by `retry_connect`
+        let hop_policy = Policy { retries: 0, ..policy };
+        let connector =
+            client::ProxyConnector::with_max_chain_length(proxy_strategy, max_chain_length)
+                .context(error::CreateProxyConnectorSnafu)?
+                .with_policy(hop_policy);
+        Ok(Self { connector, policy })
     }
 }
 
 impl Connector for ProxyConnector {
     type Error = Error;
-    type Stream = TcpStream;
+    type Stream = ProxySocket;
 
     fn connect(&self, host: &HostAddress) -> Connect<Self::Stream, Self::Error> {
         let host = host.clone();
         let connector = self.connector.clone();
+        let policy = self.policy;
 
         async move {
-            let stream = connector.connect(&host).await.context(error::ConnectProxyServerSnafu)?;
+            let stream = retry_connect(&policy, || async {
+                connector.connect(&host).await.context(error::ConnectProxyServerSnafu)
+            })
+            .await?;
             Ok(stream.into_inner())
         }
         .boxed()
@@ -47,3 +67,104 @@ impl Connector for ProxyConnector {
         self.connect(&host)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        client,
+        common::{HostAddress, Policy, ProxyHost, ProxyStrategy},
+        filter::SimpleFilter,
+        transport::{Error, TokioResolver, Transport},
+    };
+
+    #[tokio::test]
+    async fn connect_via_https_tunnel() {
+        // closes connections without completing the TLS handshake
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = proxy.accept().await {
+                drop(stream);
+            }
+        });
+
+        let strategy = Arc::new(ProxyStrategy::Single(ProxyHost::HttpsTunnel {
+            host: "localhost".to_owned(),
+            port: proxy_addr.port(),
+            user_agent: None,
+            username: None,
+            password: None,
+            sni: None,
+        }));
+        let transport = Transport::proxy(
+            Arc::new(TokioResolver::new()),
+            Arc::new(SimpleFilter::deny_list()),
+            strategy,
+            Policy::default(),
+        )
+        .unwrap();
+
+        let destination = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        let Err(Error::ConnectProxyServer { source }) = transport.connect(&destination).await
+        else {
+            panic!("connection should fail");
+        };
+        assert!(matches!(source, client::Error::InitializeTlsStream { .. }), "{source}");
+    }
+
+    #[tokio::test]
+    async fn time_out_handshake_with_proxy_server() {
+        // accepts connections without ever replying
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok(Ok((stream, _))) =
+                tokio::time::timeout(Duration::from_millis(500), proxy.accept()).await
+            {
+                streams.push(stream);
+            }
+            streams.len()
+        });
+
+        let strategy = Arc::new(ProxyStrategy::Single(ProxyHost::Socks5 {
+            host: proxy_addr.ip().to_string(),
+            port: proxy_addr.port(),
+            username: None,
+            password: None,
+        }));
+        let policy = Policy {
+            handshake_timeout: Some(Duration::from_millis(50)),
+            retries: 1,
+            backoff: Duration::from_millis(10),
+            ..Policy::default()
+        };
+        let transport = Transport::proxy(
+            Arc::new(TokioResolver::new()),
+            Arc::new(SimpleFilter::deny_list()),
+            strategy,
+            policy,
+        )
+        .unwrap();
+
+        let destination = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+        let Err(Error::RetriesExhausted { attempts: 2, source }) =
+            transport.connect(&destination).await
+        else {
+            panic!("handshake should time out");
+        };
+        assert!(
+            matches!(*source, Error::ConnectProxyServer { source: client::Error::Timeout }),
+            "{source}"
+        );
+        assert_eq!(accepted.await.unwrap(), 2);
+    }
+}
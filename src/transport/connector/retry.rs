@@ -0,0 +1,127 @@
+use futures::Future;
+
+use crate::{common::Policy, transport::Error};
+
+/// Runs `connect` until it succeeds or retries of `policy` are exhausted,
+/// only connection errors are retried.
+///
+/// The error of the last attempt is wrapped in [`Error::RetriesExhausted`] if
+/// more than one attempt is made.
+pub(crate) async fn retry_connect<F, Fut, T>(policy: &Policy, mut connect: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match connect().await {
+            Ok(value) => return Ok(value),
+            Err(err) if !err.is_connection_error() => return Err(err),
+            Err(err) if attempts > policy.retries => {
+                return Err(if attempts > 1 {
+                    Error::RetriesExhausted { attempts, source: Box::new(err) }
+                } else {
+                    err
+                });
+            }
+            Err(err) => {
+                tracing::debug!("Retry connecting after attempt {attempts} failed, error: {err}");
+                tokio::time::sleep(policy.delay(attempts)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{Ipv4Addr, SocketAddr},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::FutureExt;
+    use tokio::io::DuplexStream;
+
+    use super::retry_connect;
+    use crate::{
+        common::{HostAddress, Policy},
+        transport::{Connect, Connector, Error},
+    };
+
+    // fails the first `failures` attempts with `error`
+    struct FlakyConnector {
+        attempts: AtomicUsize,
+        failures: usize,
+        error: fn(&HostAddress) -> Error,
+    }
+
+    impl FlakyConnector {
+        fn new(failures: usize, error: fn(&HostAddress) -> Error) -> Self {
+            Self { attempts: AtomicUsize::new(0), failures, error }
+        }
+
+        fn attempts(&self) -> usize { self.attempts.load(Ordering::SeqCst) }
+    }
+
+    impl Connector for FlakyConnector {
+        type Error = Error;
+        type Stream = DuplexStream;
+
+        fn connect(&self, host: &HostAddress) -> Connect<Self::Stream, Self::Error> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            let result = if attempt > self.failures {
+                Ok(tokio::io::duplex(64).0)
+            } else {
+                Err((self.error)(host))
+            };
+            futures::future::ready(result).boxed()
+        }
+    }
+
+    fn refused(host: &HostAddress) -> Error {
+        let source = io::Error::from(io::ErrorKind::ConnectionRefused);
+        Error::ConnectRemoteServer { host: host.clone(), source }
+    }
+
+    fn forbidden(host: &HostAddress) -> Error {
+        Error::ConnectForbiddenHosts { hosts: vec![host.clone()] }
+    }
+
+    fn policy(retries: usize) -> Policy {
+        Policy {
+            retries,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            jitter: Duration::from_millis(1),
+            ..Policy::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_flaky_connector() {
+        let host = HostAddress::from(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 80)));
+
+        // the third attempt wins
+        let connector = FlakyConnector::new(2, refused);
+        assert!(retry_connect(&policy(2), || connector.connect(&host)).await.is_ok());
+        assert_eq!(connector.attempts(), 3);
+
+        let connector = FlakyConnector::new(2, refused);
+        match retry_connect(&policy(1), || connector.connect(&host)).await {
+            Err(Error::RetriesExhausted { attempts: 2, source }) => {
+                assert!(matches!(*source, Error::ConnectRemoteServer { .. }));
+            }
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+        assert_eq!(connector.attempts(), 2);
+
+        // denials of filter are never retried
+        let connector = FlakyConnector::new(2, forbidden);
+        let err = retry_connect(&policy(2), || connector.connect(&host)).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectForbiddenHosts { .. }));
+        assert_eq!(connector.attempts(), 1);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use futures::FutureExt;
+use snafu::ResultExt;
+use socket2::SockRef;
+use tokio::net::TcpStream;
+
+use crate::{
+    common::HostAddress,
+    transport::{
+        connector::{Connect, Connector},
+        error, Error,
+    },
+};
+
+/// Sets `IP_TTL` or `IPV6_UNICAST_HOPS` on streams established by the inner
+/// connector.
+pub struct TtlConnector {
+    connector: Arc<dyn Connector<Stream = TcpStream, Error = Error>>,
+    ttl: u32,
+}
+
+impl TtlConnector {
+    #[inline]
+    pub fn new(connector: Arc<dyn Connector<Stream = TcpStream, Error = Error>>, ttl: u32) -> Self {
+        Self { connector, ttl }
+    }
+
+    fn set_ttl(stream: TcpStream, ttl: u32) -> Result<TcpStream, Error> {
+        let socket = SockRef::from(&stream);
+        match stream.local_addr().context(error::SetTtlSnafu { ttl })? {
+            SocketAddr::V4(_) => socket.set_ttl(ttl),
+            SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl),
+        }
+        .context(error::SetTtlSnafu { ttl })?;
+        Ok(stream)
+    }
+}
+
+impl Connector for TtlConnector {
+    type Error = Error;
+    type Stream = TcpStream;
+
+    fn connect(&self, host: &HostAddress) -> Connect<Self::Stream, Self::Error> {
+        let ttl = self.ttl;
+        self.connector.connect(host).map(move |stream| Self::set_ttl(stream?, ttl)).boxed()
+    }
+
+    fn connect_addr(&self, addr: &SocketAddr) -> Connect<Self::Stream, Self::Error> {
+        let ttl = self.ttl;
+        self.connector.connect_addr(addr).map(move |stream| Self::set_ttl(stream?, ttl)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use socket2::SockRef;
+    use tokio::net::TcpListener;
+
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        test_util::direct_transport,
+        transport::{Error, Transport},
+    };
+
+    fn transport() -> Transport<tokio::net::TcpStream> {
+        direct_transport(SimpleFilter::deny_list())
+    }
+
+    #[tokio::test]
+    async fn set_ttl() {
+        let transport = transport().with_ttl(42).unwrap();
+
+        for ip in [IpAddr::from(Ipv4Addr::LOCALHOST), IpAddr::from(Ipv6Addr::LOCALHOST)] {
+            let listener = TcpListener::bind((ip, 0)).await.unwrap();
+            let host = HostAddress::from(listener.local_addr().unwrap());
+            let (stream, _) = transport.connect(&host).await.unwrap();
+
+            let socket = SockRef::from(&stream);
+            let ttl = if ip.is_ipv4() { socket.ttl() } else { socket.unicast_hops_v6() };
+            assert_eq!(ttl.unwrap(), 42);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_ttl() {
+        for ttl in [0, 256] {
+            assert!(matches!(transport().with_ttl(ttl), Err(Error::InvalidTtl { .. })));
+        }
+        assert!(transport().with_ttl(255).is_ok());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    common::HostAddress,
+    filter::{FilterAction, FilterEvents, HostFilter, SimpleFilter},
+    service::LogPrivacy,
+};
+
+/// Filters of destinations of a [`Transport`](super::Transport), shared with
+/// the paths relaying without it, e.g. datagrams of UDP associate.
+#[derive(Clone)]
+pub struct DestinationFilter {
+    filter: Arc<dyn HostFilter>,
+    resolved_filter: Option<Arc<dyn HostFilter>>,
+    pub(super) events: FilterEvents,
+    pub(super) log_privacy: LogPrivacy,
+}
+
+impl Default for DestinationFilter {
+    /// Allows every destination.
+    fn default() -> Self { Self::new(Arc::new(SimpleFilter::deny_list())) }
+}
+
+impl DestinationFilter {
+    #[inline]
+    #[must_use]
+    pub fn new(filter: Arc<dyn HostFilter>) -> Self {
+        Self {
+            filter,
+            resolved_filter: None,
+            events: FilterEvents::default(),
+            log_privacy: LogPrivacy::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_resolved_filter(mut self, resolved_filter: Arc<dyn HostFilter>) -> Self {
+        self.resolved_filter = Some(resolved_filter);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_events(mut self, events: FilterEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn filter(&self) -> Arc<dyn HostFilter> { self.filter.clone() }
+
+    /// Returns the action of the filter for `host`, denied if either its
+    /// address or its port is denied, and emits it as an event.
+    ///
+    /// IPv4-mapped IPv6 addresses are filtered as the IPv4 addresses they
+    /// reach.
+    pub(crate) fn check(&self, host: &HostAddress) -> FilterAction {
+        let canonical;
+        let host = match host {
+            HostAddress::Socket(addr) => {
+                canonical = HostAddress::from(canonical_addr(*addr));
+                &canonical
+            }
+            HostAddress::DomainName(..) => host,
+        };
+        let action = match self.filter.filter_host_address(host) {
+            FilterAction::Allow => self.filter.filter_port(host.port()),
+            FilterAction::Deny => FilterAction::Deny,
+        };
+        self.events.emit(self.filter.as_ref(), host, action, self.log_privacy);
+        action
+    }
+
+    // returns the action of the filter of resolved addresses for `addr` and emits
+    // it as an event, addresses are allowed without the filter
+    pub(crate) fn check_resolved(&self, addr: &SocketAddr) -> FilterAction {
+        let Some(ref filter) = self.resolved_filter else {
+            return FilterAction::Allow;
+        };
+        let addr = HostAddress::from(canonical_addr(*addr));
+        let action = filter.filter_host_address(&addr);
+        self.events.emit(filter.as_ref(), &addr, action, self.log_privacy);
+        action
+    }
+
+    // whether `addr` is denied by filter or by filter of resolved addresses
+    pub(crate) fn is_denied_addr(&self, addr: &SocketAddr) -> bool {
+        self.check(&HostAddress::from(*addr)) == FilterAction::Deny
+            || self.check_resolved(addr) == FilterAction::Deny
+    }
+}
+
+// `addr` with IPv4-mapped IPv6 address converted to IPv4
+#[inline]
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
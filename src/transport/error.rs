@@ -1,8 +1,13 @@
 use std::path::PathBuf;
 
 use snafu::Snafu;
+use trust_dns_resolver::error::ResolveErrorKind;
 
-use crate::{client, common::HostAddress};
+use crate::{
+    client,
+    common::{HostAddress, IncompatibleReason},
+    transport::TimeoutPhase,
+};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
@@ -19,9 +24,39 @@ pub enum Error {
     #[snafu(display("Could not connect proxy server, error: {}", source))]
     ConnectProxyServer { source: client::Error },
 
+    #[snafu(display("Gave up after {attempts} attempts, error: {source}"))]
+    RetriesExhausted { attempts: usize, source: Box<Error> },
+
+    #[snafu(display("Proxy server #{hop} is incompatible with proxy chain: {reason}"))]
+    IncompatibleChain { hop: usize, reason: IncompatibleReason },
+
+    #[snafu(display("Invalid TTL {ttl}, TTL must be in range of 1..=255"))]
+    InvalidTtl { ttl: u32 },
+
+    #[snafu(display("Could not set TTL {ttl} on socket, error: {source}"))]
+    SetTtl { ttl: u32, source: std::io::Error },
+
+    #[snafu(display("Could not set TCP_NODELAY to {nodelay} on socket, error: {source}"))]
+    SetNoDelay { nodelay: bool, source: std::io::Error },
+
+    #[snafu(display("Could not listen on {addr}, error: {source}"))]
+    Listen { addr: std::net::SocketAddr, source: std::io::Error },
+
+    #[snafu(display("Could not accept inbound connection, error: {source}"))]
+    Accept { source: std::io::Error },
+
+    #[snafu(display("Inbound connection from {peer} is not from the expected host"))]
+    UnexpectedPeer { peer: std::net::SocketAddr },
+
     #[snafu(display("Could not resolve domain name: {}", domain_name))]
     ResolveDomainName { domain_name: String },
 
+    #[snafu(display("Timed out during {phase}"))]
+    Timeout { phase: TimeoutPhase },
+
+    #[snafu(display("Relay exceeds quota of {max_bytes} bytes"))]
+    QuotaExceeded { max_bytes: u64 },
+
     #[snafu(display("Connect to forbidden hosts: {:?}", hosts))]
     ConnectForbiddenHosts { hosts: Vec<HostAddress> },
 
@@ -30,4 +65,113 @@ pub enum Error {
 
     #[snafu(display("Could not resolve domain name via trust_dns_resolver, error: {}", source))]
     LookupTrustDnsResolver { source: trust_dns_resolver::error::ResolveError },
+
+    #[cfg(feature = "doh")]
+    #[snafu(display("Invalid DNS over HTTPS endpoint: {endpoint}"))]
+    InvalidDohEndpoint { endpoint: url::Url },
+
+    #[cfg(feature = "doh")]
+    #[snafu(display("Could not build HTTP client of DNS over HTTPS, error: {source}"))]
+    BuildDohClient { source: reqwest::Error },
+
+    #[cfg(feature = "doh")]
+    #[snafu(display("Could not exchange DNS message with {endpoint}, error: {source}"))]
+    ExchangeDohMessage { endpoint: url::Url, source: reqwest::Error },
+
+    #[cfg(feature = "doh")]
+    #[snafu(display("Invalid DNS over HTTPS response from {endpoint}: {reason}"))]
+    InvalidDohResponse { endpoint: url::Url, reason: &'static str },
+}
+
+impl Error {
+    #[inline]
+    #[must_use]
+    pub fn is_forbidden(&self) -> bool {
+        match self {
+            Self::ConnectForbiddenHosts { .. } => true,
+            Self::RetriesExhausted { source, .. } => source.is_forbidden(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether connecting fails, times out or the connection breaks,
+    /// which may succeed if attempted again, hosts denied by filter are never
+    /// retried.
+    #[inline]
+    #[must_use]
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Self::ConnectRemoteServer { .. } | Self::Timeout { .. } => true,
+            Self::ConnectProxyServer { source } => source.is_connection_error(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether a resolver fails to answer, e.g. it times out or is
+    /// unreachable, rather than the domain name does not exist.
+    #[must_use]
+    pub fn is_resolver_failure(&self) -> bool {
+        match self {
+            Self::Timeout { phase } => *phase == TimeoutPhase::Dns,
+            Self::InitializeTrustDnsResolver { .. } => true,
+            Self::LookupTrustDnsResolver { source } => {
+                !matches!(source.kind(), ResolveErrorKind::NoRecordsFound { .. })
+            }
+            #[cfg(feature = "doh")]
+            Self::BuildDohClient { .. }
+            | Self::ExchangeDohMessage { .. }
+            | Self::InvalidDohResponse { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns whether an upstream proxy server requires authentication, or
+    /// rejects the credentials provided.
+    #[inline]
+    #[must_use]
+    pub fn is_upstream_authentication_failure(&self) -> bool {
+        match self {
+            Self::ConnectProxyServer { source } => source.is_authentication_failure(),
+            Self::RetriesExhausted { source, .. } => source.is_upstream_authentication_failure(),
+            _ => false,
+        }
+    }
+
+    /// Returns the kind of the error without hosts or addresses, for logs which
+    /// must not reveal destinations.
+    #[must_use]
+    pub fn redacted(&self) -> String {
+        if self.is_forbidden() {
+            return "destination is forbidden".to_owned();
+        }
+        if let Some(phase) = self.timeout_phase() {
+            return format!("timed out during {phase}");
+        }
+        match self {
+            Self::ConnectRemoteServer { source, .. } => {
+                format!("could not connect remote server, error: {}", source.kind())
+            }
+            Self::ConnectProxyServer { .. } => "could not connect proxy server".to_owned(),
+            Self::RetriesExhausted { attempts, source } => {
+                format!("gave up after {attempts} attempts, error: {}", source.redacted())
+            }
+            Self::ResolveDomainName { .. } | Self::LookupTrustDnsResolver { .. } => {
+                "could not resolve domain name".to_owned()
+            }
+            Self::QuotaExceeded { .. } => self.to_string(),
+            _ => "could not connect destination".to_owned(),
+        }
+    }
+
+    /// Returns the phase in which a timeout occurs, `None` if this is not a
+    /// timeout error.
+    #[inline]
+    #[must_use]
+    pub fn timeout_phase(&self) -> Option<TimeoutPhase> {
+        match self {
+            Self::Timeout { phase } => Some(*phase),
+            Self::RetriesExhausted { source, .. } => source.timeout_phase(),
+            _ => None,
+        }
+    }
 }
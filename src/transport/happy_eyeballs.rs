@@ -0,0 +1,149 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{stream::FuturesUnordered, Future, StreamExt};
+
+/// Delay before starting the next connection attempt while the previous one
+/// is still pending, as recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Reorders `addrs` to alternate between address families, starting with the
+/// family of the first address, the order within each family is kept.
+pub(crate) fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_ipv6 = first.is_ipv6();
+    let (preferred, others): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + others.len());
+    let (mut preferred, mut others) = (preferred.into_iter(), others.into_iter());
+    loop {
+        match (preferred.next(), others.next()) {
+            (None, None) => return interleaved,
+            (preferred, other) => interleaved.extend(preferred.into_iter().chain(other)),
+        }
+    }
+}
+
+/// Connects `addrs` in order and returns the first connection established,
+/// pending attempts are cancelled.
+///
+/// The next attempt is started once the previous one fails, or after `delay`
+/// while it is still pending, `None` means attempts never overlap. The error of
+/// the last failed attempt is returned if none succeeds, `None` if `addrs` is
+/// empty.
+pub(crate) async fn connect_first<F, Fut, T, E>(
+    addrs: Vec<SocketAddr>,
+    delay: Option<Duration>,
+    mut connect: F,
+) -> Result<T, Option<E>>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut addrs = addrs.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        // each iteration follows a failed attempt or the delay
+        if let Some(addr) = addrs.next() {
+            attempts.push(connect(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_error);
+        }
+
+        let stagger = async {
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            },
+            () = stagger, if addrs.peek().is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use futures::FutureExt;
+    use snafu::ResultExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::interleave_families;
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        transport::{connector, error, StaticResolver, Transport},
+    };
+
+    #[test]
+    fn interleave_address_families() {
+        let v4 = |n| SocketAddr::from((Ipv4Addr::new(192, 0, 2, n), 80));
+        let v6 = |n| SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n), 80));
+
+        let addrs = vec![v6(1), v6(2), v6(3), v4(1), v4(2)];
+        assert_eq!(interleave_families(addrs), [v6(1), v4(1), v6(2), v4(2), v6(3)]);
+
+        let addrs = vec![v4(1), v4(2), v6(1)];
+        assert_eq!(interleave_families(addrs), [v4(1), v6(1), v4(2)]);
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn skip_black_holed_address() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let black_hole = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+        // connecting the black-holed address never completes
+        let connector = connector::connect_fn(
+            Box::new(|_host: &HostAddress| futures::future::pending().boxed()),
+            Box::new(move |addr: &SocketAddr| {
+                let addr = *addr;
+                if addr.ip() == black_hole {
+                    return futures::future::pending().boxed();
+                }
+                async move {
+                    TcpStream::connect(addr)
+                        .await
+                        .context(error::ConnectRemoteServerSnafu { host: HostAddress::from(addr) })
+                }
+                .boxed()
+            }),
+        );
+        let resolver = StaticResolver::builder()
+            .insert("dual-stack.example", black_hole)
+            .insert("dual-stack.example", Ipv4Addr::LOCALHOST)
+            .build();
+        let transport = Transport::with_connector(
+            Arc::new(resolver),
+            Arc::new(SimpleFilter::deny_list()),
+            connector,
+        );
+        let host = HostAddress::new("dual-stack.example", port);
+
+        let (stream, _) = tokio::time::timeout(Duration::from_secs(1), transport.connect(&host))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+
+        // the black-holed address stalls connecting without happy eyeballs
+        let transport = transport.with_happy_eyeballs(false);
+        let connect = tokio::time::timeout(Duration::from_secs(1), transport.connect(&host)).await;
+        assert!(connect.is_err());
+    }
+}
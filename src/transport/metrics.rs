@@ -1,6 +1,6 @@
 use std::{
     collections::HashSet,
-    fmt,
+    fmt::{self, Write},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -9,12 +9,14 @@ use std::{
 
 use tokio::sync::Mutex;
 
-use crate::common::HostAddress;
+use crate::{common::HostAddress, transport::StatMonitor};
 
 #[derive(Clone, Debug)]
 pub struct TransportMetrics {
     received_bytes: Arc<AtomicUsize>,
     transmitted_bytes: Arc<AtomicUsize>,
+    interval_received_bytes: Arc<AtomicUsize>,
+    interval_transmitted_bytes: Arc<AtomicUsize>,
     relay_counter: Counter,
     client_counter: Counter,
     remote_counter: Counter,
@@ -27,6 +29,7 @@ pub struct TransportMetrics {
 pub struct Counter {
     current: Arc<AtomicUsize>,
     accumulated: Arc<AtomicUsize>,
+    interval: Arc<AtomicUsize>,
 }
 
 impl Counter {
@@ -34,7 +37,8 @@ impl Counter {
     pub fn new(n: usize) -> Self {
         let current = Arc::new(AtomicUsize::new(n));
         let accumulated = Arc::new(AtomicUsize::new(n));
-        Self { current, accumulated }
+        let interval = Arc::new(AtomicUsize::new(n));
+        Self { current, accumulated, interval }
     }
 
     #[inline]
@@ -43,6 +47,7 @@ impl Counter {
     #[inline]
     pub fn increase(&self) -> usize {
         self.accumulated.fetch_add(1, Ordering::SeqCst);
+        self.interval.fetch_add(1, Ordering::SeqCst);
         self.current.fetch_add(1, Ordering::SeqCst)
     }
 
@@ -54,6 +59,27 @@ impl Counter {
 
     #[inline]
     pub fn accumulated(&self) -> usize { self.accumulated.load(Ordering::Acquire) }
+
+    /// Returns the count accumulated since the previous call and starts a new
+    /// interval, the accumulated total is left untouched.
+    #[inline]
+    pub fn take_interval(&self) -> usize { self.interval.swap(0, Ordering::SeqCst) }
+}
+
+/// Counters of [`TransportMetrics`] read at a time.
+///
+/// Byte and accumulated counts cover the interval since the previous snapshot,
+/// current counts are read as they are.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct MetricsSnapshot {
+    pub received_bytes: usize,
+    pub transmitted_bytes: usize,
+    pub current_client: usize,
+    pub accumulated_client: usize,
+    pub current_relay: usize,
+    pub accumulated_relay: usize,
+    pub current_remote: usize,
+    pub accumulated_remote: usize,
 }
 
 pub struct CounterHelper(Counter);
@@ -70,18 +96,18 @@ impl Drop for CounterHelper {
     fn drop(&mut self) { self.0.decrease(); }
 }
 
-// FIXME: re-implement this
-// impl StatMonitor for TransportMetrics {
-//     fn increase_tx(&mut self, n: usize) { self.transmitted_bytes.fetch_add(n,
-// Ordering::SeqCst); }
-//
-//     fn increase_rx(&mut self, n: usize) { self.received_bytes.fetch_add(n,
-// Ordering::SeqCst); } }
+impl StatMonitor for TransportMetrics {
+    fn increase_tx(&mut self, n: usize) { Self::increase_tx(self, n); }
+
+    fn increase_rx(&mut self, n: usize) { Self::increase_rx(self, n); }
+}
 
 impl Default for TransportMetrics {
     fn default() -> Self {
         let received_bytes = Arc::new(AtomicUsize::new(0));
         let transmitted_bytes = Arc::new(AtomicUsize::new(0));
+        let interval_received_bytes = Arc::new(AtomicUsize::new(0));
+        let interval_transmitted_bytes = Arc::new(AtomicUsize::new(0));
         let relay_counter = Counter::zero();
         let client_counter = Counter::zero();
         let remote_counter = Counter::zero();
@@ -91,6 +117,8 @@ impl Default for TransportMetrics {
         Self {
             received_bytes,
             transmitted_bytes,
+            interval_received_bytes,
+            interval_transmitted_bytes,
             relay_counter,
             client_counter,
             remote_counter,
@@ -107,6 +135,24 @@ impl TransportMetrics {
     #[inline]
     pub fn reset(&mut self) { *self = Self::new(); }
 
+    #[inline]
+    pub fn received_bytes(&self) -> usize { self.received_bytes.load(Ordering::Acquire) }
+
+    #[inline]
+    pub fn transmitted_bytes(&self) -> usize { self.transmitted_bytes.load(Ordering::Acquire) }
+
+    #[inline]
+    pub fn increase_rx(&self, n: usize) {
+        self.received_bytes.fetch_add(n, Ordering::SeqCst);
+        self.interval_received_bytes.fetch_add(n, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn increase_tx(&self, n: usize) {
+        self.transmitted_bytes.fetch_add(n, Ordering::SeqCst);
+        self.interval_transmitted_bytes.fetch_add(n, Ordering::SeqCst);
+    }
+
     #[inline]
     pub fn current_relay(&self) -> usize { self.relay_counter.current() }
 
@@ -125,6 +171,28 @@ impl TransportMetrics {
     #[inline]
     pub fn accumulated_remote(&self) -> usize { self.remote_counter.accumulated() }
 
+    /// Returns the counts since the previous snapshot and starts a new
+    /// interval, so that the next snapshot contains counts of the following
+    /// interval only.
+    ///
+    /// Each interval counter is read and zeroed atomically, counts increased
+    /// concurrently go to either this snapshot or the next one and are never
+    /// lost. Totals returned by [`Self::received_bytes`],
+    /// [`Self::accumulated_client`] and alike keep growing monotonically, and
+    /// current counts are not reset.
+    pub fn snapshot_and_reset(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            received_bytes: self.interval_received_bytes.swap(0, Ordering::SeqCst),
+            transmitted_bytes: self.interval_transmitted_bytes.swap(0, Ordering::SeqCst),
+            current_client: self.current_client(),
+            accumulated_client: self.client_counter.take_interval(),
+            current_relay: self.current_relay(),
+            accumulated_relay: self.relay_counter.take_interval(),
+            current_remote: self.current_remote(),
+            accumulated_remote: self.remote_counter.take_interval(),
+        }
+    }
+
     #[inline]
     pub fn count_relay(&self) -> (CounterHelper, usize) {
         CounterHelper::count(self.relay_counter.clone())
@@ -157,3 +225,158 @@ impl fmt::Display for TransportMetrics {
         )
     }
 }
+
+/// Renders `metrics` in the Prometheus text exposition format.
+///
+/// Gauges of active connections follow the counters held while connections
+/// are alive, totals are accumulated since the metrics were created and are
+/// not affected by [`TransportMetrics::snapshot_and_reset`].
+#[must_use]
+pub fn render_prometheus(metrics: &TransportMetrics) -> String {
+    let families = [
+        (
+            "tunelo_bytes_tx_total",
+            "counter",
+            "Bytes transmitted to clients.",
+            metrics.transmitted_bytes(),
+        ),
+        (
+            "tunelo_bytes_rx_total",
+            "counter",
+            "Bytes received from clients.",
+            metrics.received_bytes(),
+        ),
+        ("tunelo_active_clients", "gauge", "Clients being relayed.", metrics.current_client()),
+        ("tunelo_clients_total", "counter", "Clients relayed.", metrics.accumulated_client()),
+        ("tunelo_active_relays", "gauge", "Relays in progress.", metrics.current_relay()),
+        ("tunelo_relays_total", "counter", "Relays started.", metrics.accumulated_relay()),
+        ("tunelo_active_remotes", "gauge", "Remote hosts being relayed.", metrics.current_remote()),
+        ("tunelo_remotes_total", "counter", "Remote hosts relayed.", metrics.accumulated_remote()),
+    ];
+
+    let mut output = String::new();
+    for (name, kind, help, value) in families {
+        // writing to `String` never fails
+        let _unused = writeln!(output, "# HELP {name} {help}");
+        let _unused = writeln!(output, "# TYPE {name} {kind}");
+        let _unused = writeln!(output, "{name} {value}");
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use super::{render_prometheus, TransportMetrics};
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = TransportMetrics::new();
+        metrics.increase_tx(1024);
+        metrics.increase_rx(2048);
+        let (_client, _prev) = metrics.count_client();
+        let (_relay, _prev) = metrics.count_relay();
+        drop(metrics.count_remote());
+
+        let mut types = HashMap::new();
+        let mut samples = HashMap::new();
+        let output = render_prometheus(&metrics);
+        for line in output.lines() {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            match fields[..] {
+                ["#", "HELP", name, ..] => assert!(name.starts_with("tunelo_")),
+                ["#", "TYPE", name, kind] => {
+                    assert!(matches!(kind, "counter" | "gauge"));
+                    assert!(types.insert(name, kind).is_none());
+                }
+                [name, value] => {
+                    assert!(types.contains_key(name), "{name} is sampled before its type");
+                    assert!(samples.insert(name, value.parse::<f64>().unwrap()).is_none());
+                }
+                _ => panic!("malformed line: {line}"),
+            }
+        }
+
+        assert_eq!(samples.len(), types.len());
+        assert_eq!(samples["tunelo_bytes_tx_total"], 1024.0);
+        assert_eq!(samples["tunelo_bytes_rx_total"], 2048.0);
+        assert_eq!(samples["tunelo_active_clients"], 1.0);
+        assert_eq!(samples["tunelo_active_relays"], 1.0);
+        assert_eq!(samples["tunelo_active_remotes"], 0.0);
+        assert_eq!(samples["tunelo_remotes_total"], 1.0);
+        assert_eq!(types["tunelo_active_clients"], "gauge");
+        assert_eq!(types["tunelo_bytes_tx_total"], "counter");
+    }
+
+    #[test]
+    fn snapshot_and_reset_under_concurrent_updates() {
+        const THREADS: usize = 4;
+        const COUNTS_PER_THREAD: usize = 10_000;
+
+        let metrics = TransportMetrics::new();
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let reporter = {
+            let metrics = metrics.clone();
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                let mut total = 0;
+                while !finished.load(Ordering::SeqCst) {
+                    total += metrics.snapshot_and_reset().accumulated_client;
+                }
+                total
+            })
+        };
+
+        let workers = (0..THREADS)
+            .map(|_| {
+                let metrics = metrics.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..COUNTS_PER_THREAD {
+                        let (_counter, _prev) = metrics.count_client();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers.into_iter().for_each(|worker| worker.join().unwrap());
+        finished.store(true, Ordering::SeqCst);
+
+        let total = reporter.join().unwrap() + metrics.snapshot_and_reset().accumulated_client;
+        assert_eq!(total, THREADS * COUNTS_PER_THREAD);
+
+        let snapshot = metrics.snapshot_and_reset();
+        assert_eq!((snapshot.current_client, snapshot.accumulated_client), (0, 0));
+        assert_eq!(metrics.accumulated_client(), THREADS * COUNTS_PER_THREAD);
+    }
+
+    #[test]
+    fn keep_totals_monotonic_across_snapshots() {
+        let metrics = TransportMetrics::new();
+        metrics.increase_tx(1024);
+        metrics.increase_rx(2048);
+        drop(metrics.count_relay());
+
+        let snapshot = metrics.snapshot_and_reset();
+        assert_eq!((snapshot.transmitted_bytes, snapshot.received_bytes), (1024, 2048));
+        assert_eq!(snapshot.accumulated_relay, 1);
+
+        metrics.increase_tx(1);
+        drop(metrics.count_relay());
+
+        let snapshot = metrics.snapshot_and_reset();
+        assert_eq!((snapshot.transmitted_bytes, snapshot.received_bytes), (1, 0));
+        assert_eq!(snapshot.accumulated_relay, 1);
+
+        let output = render_prometheus(&metrics);
+        assert!(output.contains("tunelo_bytes_tx_total 1025\n"));
+        assert!(output.contains("tunelo_bytes_rx_total 2048\n"));
+        assert!(output.contains("tunelo_relays_total 2\n"));
+    }
+}
@@ -1,46 +1,98 @@
-mod acceptor;
 mod connector;
+mod destination_filter;
 pub mod error;
+mod happy_eyeballs;
 mod metrics;
+mod quota;
+mod relay;
+mod resolution;
 mod resolver;
-// FIXME: uncomment this
-// mod stream_ext;
+mod stream_ext;
+mod timeout;
 
 use std::{
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
     path::Path,
     sync::Arc,
+    time::Instant,
 };
 
 use futures::FutureExt;
 use snafu::ResultExt;
 use tokio::{
     fs::File,
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
 
-use self::{
-    connector::{Connector, ProxyConnector},
-    metrics::TransportMetrics,
-    resolver::DummyResolver,
-};
+#[cfg(feature = "doh")]
+pub use self::resolver::{DohMethod, DohResolver};
+pub(crate) use self::timeout::with_timeout;
 pub use self::{
+    connector::{Connect, Connector},
+    destination_filter::DestinationFilter,
     error::Error,
-    resolver::{Resolver, TokioResolver, TrustDnsResolver},
-    // FIXME: uncomment this
-    // stream_ext::StatMonitor,
+    happy_eyeballs::CONNECTION_ATTEMPT_DELAY,
+    metrics::{render_prometheus, MetricsSnapshot, TransportMetrics},
+    relay::{ClosedBy, FlushPolicy, RelayStats},
+    resolution::ResolutionOrder,
+    resolver::{
+        CachingResolver, InitResolver, ReloadingResolver, Resolver, StaticResolver,
+        StaticResolverBuilder, TokioResolver, TrustDnsResolver,
+    },
+    stream_ext::{MonitoredStream, StatMonitor, TimedStream},
+    timeout::{TimeoutPhase, Timeouts},
 };
+use self::{
+    connector::{NoDelayConnector, ProxyConnector, TtlConnector},
+    destination_filter::canonical_addr,
+    quota::Quota,
+    resolution::RttTable,
+    resolver::DummyResolver,
+    timeout::Activity,
+};
+#[cfg(feature = "debug")]
+pub use self::{relay::DebugLatency, stream_ext::DelayedReader};
 use crate::{
-    common::{HostAddress, ProxyStrategy},
-    filter::{FilterAction, HostFilter},
+    client::{ProxySocket, DEFAULT_MAX_CHAIN_LENGTH},
+    common::{HostAddress, Policy, ProxyStrategy},
+    filter::{FilterAction, FilterEvents, HostFilter},
+    service::LogPrivacy,
 };
 
+/// Listener of [`Transport::listen`] for an inbound connection.
+#[derive(Debug)]
+pub struct BindListener {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    // canonical IP addresses of the host expected to connect
+    peers: Vec<IpAddr>,
+}
+
+impl BindListener {
+    #[inline]
+    #[must_use]
+    pub const fn local_addr(&self) -> SocketAddr { self.local_addr }
+}
+
 pub struct Transport<Stream> {
     metrics: TransportMetrics,
     resolver: Arc<dyn Resolver>,
     connector: Arc<dyn Connector<Stream = Stream, Error = Error>>,
-    filter: Arc<dyn HostFilter>,
+    destination_filter: DestinationFilter,
+    resolution_order: ResolutionOrder,
+    rtt_table: RttTable,
+    timeouts: Timeouts,
+    policy: Policy,
+    max_bytes_per_connection: Option<u64>,
+    strict_address_family: bool,
+    happy_eyeballs: bool,
+    flush_policy: FlushPolicy,
+    interactive_ports: HashSet<u16>,
+    log_privacy: LogPrivacy,
+    #[cfg(feature = "debug")]
+    debug_latency: Option<DebugLatency>,
 }
 
 impl Transport<File> {
@@ -48,7 +100,6 @@ impl Transport<File> {
     where
         P: AsRef<Path>,
     {
-        let metrics = TransportMetrics::new();
         let connector = connector::connect_fn(
             {
                 let file_path = path.as_ref().to_path_buf();
@@ -79,7 +130,7 @@ impl Transport<File> {
         );
 
         let resolver = Arc::new(DummyResolver::new());
-        Self { metrics, resolver, connector, filter }
+        Self::with_connector(resolver, filter, connector)
     }
 
     #[inline]
@@ -95,8 +146,6 @@ impl Transport<File> {
 
 impl Transport<TcpStream> {
     pub fn direct(resolver: Arc<dyn Resolver>, filter: Arc<dyn HostFilter>) -> Self {
-        let metrics = TransportMetrics::new();
-
         let connector = connector::connect_fn(
             Box::new(|host: &HostAddress| {
                 let host = host.clone();
@@ -118,40 +167,268 @@ impl Transport<TcpStream> {
             }),
         );
 
-        Self { metrics, resolver, connector, filter }
+        Self::with_connector(resolver, filter, connector)
     }
 
+    /// Sets TTL (or hop limit for IPv6) of upstream sockets, `ttl` must be in
+    /// range of `1..=255`.
+    ///
+    /// The TTL is applied once a socket is connected, it does not affect
+    /// packets of the TCP handshake.
+    pub fn with_ttl(mut self, ttl: u32) -> Result<Self, Error> {
+        if !(1..=255).contains(&ttl) {
+            return Err(Error::InvalidTtl { ttl });
+        }
+
+        self.connector = Arc::new(TtlConnector::new(self.connector, ttl));
+        Ok(self)
+    }
+
+    /// Sets `TCP_NODELAY` of upstream sockets by destination port, e.g. enable
+    /// it for interactive protocols like SSH while keeping Nagle's algorithm
+    /// for bulk transfers. Sockets to ports not in `ports` are left untouched.
+    ///
+    /// Relays to ports with `TCP_NODELAY` enabled are flushed with
+    /// [`FlushPolicy::Immediate`].
+    #[must_use]
+    pub fn with_nodelay_ports(mut self, ports: HashMap<u16, bool>) -> Self {
+        self.interactive_ports =
+            ports.iter().filter(|(_, nodelay)| **nodelay).map(|(port, _)| *port).collect();
+        self.connector = Arc::new(NoDelayConnector::new(self.connector, ports));
+        self
+    }
+}
+
+impl Transport<ProxySocket> {
+    /// Connects via proxy servers of `strategy` within the connect and
+    /// handshake timeouts of `policy`, establishing the whole chain is
+    /// retried as `policy` on connection errors.
+    #[inline]
     pub fn proxy(
         resolver: Arc<dyn Resolver>,
         filter: Arc<dyn HostFilter>,
         strategy: Arc<ProxyStrategy>,
+        policy: Policy,
     ) -> Result<Self, Error> {
-        let metrics = TransportMetrics::new();
+        Self::proxy_with_max_chain_length(
+            resolver,
+            filter,
+            strategy,
+            DEFAULT_MAX_CHAIN_LENGTH,
+            policy,
+        )
+    }
 
+    pub fn proxy_with_max_chain_length(
+        resolver: Arc<dyn Resolver>,
+        filter: Arc<dyn HostFilter>,
+        strategy: Arc<ProxyStrategy>,
+        max_chain_length: usize,
+        policy: Policy,
+    ) -> Result<Self, Error> {
         let (pass, denied_hosts) = filter.check_proxy_strategy(strategy.as_ref());
         if !pass {
             return Err(Error::ConnectForbiddenHosts { hosts: denied_hosts });
         }
 
-        let connector = Arc::new(ProxyConnector::new(strategy)?);
-        Ok(Self { metrics, resolver, connector, filter })
+        let connector = Arc::new(ProxyConnector::new(strategy, max_chain_length, policy)?);
+        Ok(Self::with_connector(resolver, filter, connector))
     }
 }
 
-// FIXME: re-implement this
-// impl<Stream> StatMonitor for Transport<Stream>
-// where
-//     Stream: Unpin + AsyncRead + AsyncWrite,
-// {
-//     fn increase_tx(&mut self, n: usize) { self.metrics.increase_tx(n); }
-//
-//     fn increase_rx(&mut self, n: usize) { self.metrics.increase_rx(n); }
-// }
+impl<Stream> StatMonitor for Transport<Stream>
+where
+    Stream: Unpin + AsyncRead + AsyncWrite,
+{
+    fn increase_tx(&mut self, n: usize) { self.metrics.increase_tx(n); }
+
+    fn increase_rx(&mut self, n: usize) { self.metrics.increase_rx(n); }
+}
 
 impl<Stream> Transport<Stream>
 where
     Stream: Unpin + AsyncRead + AsyncWrite,
 {
+    /// Creates a transport which connects remote hosts with `connector`, e.g.
+    /// one adding instrumentation or speaking a custom protocol.
+    pub fn with_connector(
+        resolver: Arc<dyn Resolver>,
+        filter: Arc<dyn HostFilter>,
+        connector: Arc<dyn Connector<Stream = Stream, Error = Error>>,
+    ) -> Self {
+        Self {
+            metrics: TransportMetrics::new(),
+            resolver,
+            connector,
+            destination_filter: DestinationFilter::new(filter),
+            resolution_order: ResolutionOrder::default(),
+            rtt_table: RttTable::default(),
+            timeouts: Timeouts::default(),
+            policy: Policy::default(),
+            max_bytes_per_connection: None,
+            strict_address_family: false,
+            happy_eyeballs: true,
+            flush_policy: FlushPolicy::default(),
+            interactive_ports: HashSet::new(),
+            log_privacy: LogPrivacy::default(),
+            #[cfg(feature = "debug")]
+            debug_latency: None,
+        }
+    }
+
+    /// Delays data relayed in both directions by `debug_latency`, for testing
+    /// the retries and timeouts of clients.
+    #[cfg(feature = "debug")]
+    #[must_use]
+    pub const fn with_debug_latency(mut self, debug_latency: DebugLatency) -> Self {
+        self.debug_latency = Some(debug_latency);
+        self
+    }
+
+    /// Logs destinations and resolved addresses in the form of `log_privacy`,
+    /// including those of filter decision events.
+    #[must_use]
+    pub const fn with_log_privacy(mut self, log_privacy: LogPrivacy) -> Self {
+        self.log_privacy = log_privacy;
+        self.destination_filter.log_privacy = log_privacy;
+        self
+    }
+
+    /// Sets how decisions of the filter are emitted as `tracing` events.
+    #[must_use]
+    pub const fn with_filter_events(mut self, filter_events: FilterEvents) -> Self {
+        self.destination_filter.events = filter_events;
+        self
+    }
+
+    /// Filters addresses resolved from destinations right before connecting,
+    /// e.g. by their country with `GeoIpFilter` of the `geoip` feature,
+    /// addresses denied are skipped.
+    #[must_use]
+    pub fn with_resolved_filter(mut self, resolved_filter: Arc<dyn HostFilter>) -> Self {
+        self.destination_filter = self.destination_filter.with_resolved_filter(resolved_filter);
+        self
+    }
+
+    /// Returns the filters of destinations, for relaying without the
+    /// transport, e.g. datagrams of UDP associate.
+    #[inline]
+    #[must_use]
+    pub fn destination_filter(&self) -> DestinationFilter { self.destination_filter.clone() }
+
+    #[inline]
+    pub(crate) fn check_filter(&self, host: &HostAddress) -> FilterAction {
+        self.destination_filter.check(host)
+    }
+
+    #[inline]
+    fn check_resolved_filter(&self, addr: &SocketAddr) -> FilterAction {
+        self.destination_filter.check_resolved(addr)
+    }
+
+    #[must_use]
+    pub fn with_resolution_order(mut self, resolution_order: ResolutionOrder) -> Self {
+        self.resolution_order = resolution_order;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn resolution_order(&self) -> ResolutionOrder { self.resolution_order }
+
+    #[must_use]
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self.policy.connect_timeout = timeouts.connect;
+        self.policy.idle_timeout = timeouts.idle;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn timeouts(&self) -> Timeouts { self.timeouts }
+
+    /// Applies the connect and idle timeouts of `policy` and retries failed
+    /// connections accordingly, the other timeouts are kept. Handshakes with
+    /// proxy servers are timed out by the policy given to [`Transport::proxy`].
+    #[must_use]
+    pub const fn with_policy(mut self, policy: Policy) -> Self {
+        self.timeouts.connect = policy.connect_timeout;
+        self.timeouts.idle = policy.idle_timeout;
+        self.policy = policy;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn policy(&self) -> Policy { self.policy }
+
+    /// Terminates relays once `max_bytes` are transferred in both directions
+    /// in total, `relay_bidirectional` fails with `Error::QuotaExceeded`.
+    #[must_use]
+    pub const fn with_max_bytes_per_connection(mut self, max_bytes: u64) -> Self {
+        self.max_bytes_per_connection = Some(max_bytes);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn max_bytes_per_connection(&self) -> Option<u64> { self.max_bytes_per_connection }
+
+    /// Connects IPv4-mapped IPv6 destinations like `::ffff:192.0.2.1` over IPv4
+    /// instead of IPv6, so that IPv4 destinations always use IPv4 sockets and
+    /// IPv6 destinations use IPv6 sockets.
+    #[must_use]
+    pub const fn with_strict_address_family(mut self, strict: bool) -> Self {
+        self.strict_address_family = strict;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn strict_address_family(&self) -> bool { self.strict_address_family }
+
+    /// Connects resolved addresses of a destination concurrently with
+    /// [`CONNECTION_ATTEMPT_DELAY`] between attempts and alternating address
+    /// families as described by RFC 8305, so that an unreachable address does
+    /// not stall the connection. Addresses are connected one after another if
+    /// it is disabled. It is enabled by default.
+    #[must_use]
+    pub const fn with_happy_eyeballs(mut self, happy_eyeballs: bool) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn happy_eyeballs(&self) -> bool { self.happy_eyeballs }
+
+    /// Sets how relayed data is flushed, relays to interactive ports set by
+    /// `with_nodelay_ports` are always flushed immediately.
+    #[must_use]
+    pub const fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Returns the flush policy of relays to destination `port`.
+    #[must_use]
+    pub fn flush_policy(&self, port: u16) -> FlushPolicy {
+        if self.interactive_ports.contains(&port) {
+            FlushPolicy::Immediate
+        } else {
+            self.flush_policy
+        }
+    }
+
+    fn outbound_addr(&self, addr: SocketAddr) -> SocketAddr {
+        if self.strict_address_family {
+            canonical_addr(addr)
+        } else {
+            addr
+        }
+    }
+
     #[inline]
     #[must_use]
     pub fn resolver(&self) -> Arc<dyn Resolver> { self.resolver.clone() }
@@ -164,7 +441,7 @@ where
 
     #[inline]
     #[must_use]
-    pub fn filter(&self) -> Arc<dyn HostFilter> { self.filter.clone() }
+    pub fn filter(&self) -> Arc<dyn HostFilter> { self.destination_filter.filter() }
 
     #[inline]
     #[must_use]
@@ -173,14 +450,22 @@ where
     #[must_use]
     pub fn stat_monitor(&self) -> TransportMetrics { self.metrics.clone() }
 
+    async fn resolve_with_timeout(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        with_timeout(self.timeouts.dns, TimeoutPhase::Dns, self.resolver.resolve(host))
+            .await
+            .map_err(|phase| Error::Timeout { phase })?
+    }
+
     pub async fn resolve_host(&self, host: &str) -> Result<IpAddr, Error> {
-        let addrs = self.resolver.resolve(host).await?;
+        let addrs = self.resolve_with_timeout(host).await?;
+        let logged_host = self.log_privacy.anonymize_domain(host);
         if addrs.is_empty() {
-            tracing::warn!("Failed to resolve domain name {host}");
+            tracing::warn!("Failed to resolve domain name {logged_host}");
             return Err(Error::ResolveDomainName { domain_name: host.to_owned() });
         }
         let addr = addrs[0];
-        tracing::debug!("Resolved {host} => {addr}");
+        let logged_addr = self.log_privacy.anonymize(&SocketAddr::new(addr, 0).into());
+        tracing::debug!("Resolved {logged_host} => {logged_addr}");
         Ok(addr)
     }
 
@@ -194,77 +479,355 @@ where
         }
     }
 
-    #[inline]
+    /// Resolves all addresses of `host`, ordered by resolution order of this
+    /// transport.
+    pub async fn resolve_all(&self, host: &HostAddress) -> Result<Vec<SocketAddr>, Error> {
+        match host {
+            HostAddress::Socket(addr) => Ok(vec![*addr]),
+            HostAddress::DomainName(host, port) => {
+                let mut addrs: Vec<_> = self
+                    .resolve_with_timeout(host)
+                    .await?
+                    .into_iter()
+                    .map(|addr| SocketAddr::new(addr, *port))
+                    .collect();
+                let logged_host = self.log_privacy.anonymize_domain(host);
+                if addrs.is_empty() {
+                    tracing::warn!("Failed to resolve domain name {logged_host}");
+                    return Err(Error::ResolveDomainName { domain_name: host.clone() });
+                }
+                self.rtt_table.sort(self.resolution_order, &mut addrs);
+                let logged_addrs: Vec<_> =
+                    addrs.iter().map(|addr| self.log_privacy.anonymize(&(*addr).into())).collect();
+                tracing::debug!("Resolved {logged_host} => {logged_addrs:?}");
+                Ok(addrs)
+            }
+        }
+    }
+
     pub async fn connect(&self, host: &HostAddress) -> Result<(Stream, HostAddress), Error> {
-        if self.filter.filter_host_address(host) == FilterAction::Deny {
+        if self.check_filter(host) == FilterAction::Deny {
             let hosts = Vec::from([host.clone()]);
             return Err(Error::ConnectForbiddenHosts { hosts });
         }
 
-        tracing::debug!("Try to connect remote host {host}");
-        let host_addr = self.resolve(host).await?;
-        let stream = match self.connector.connect_addr(&host_addr).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                tracing::error!("Failed to connect host: {host}, error: {err}");
-                return Err(err);
+        let logged_host = self.log_privacy.anonymize(host);
+        tracing::debug!("Try to connect remote host {logged_host}");
+        let mut host_addrs = Vec::new();
+        for host_addr in self.resolve_all(host).await? {
+            if self.check_resolved_filter(&host_addr) == FilterAction::Deny {
+                let logged_addr = self.log_privacy.anonymize(&host_addr.into());
+                tracing::debug!("Skip denied address {logged_addr} of host {logged_host}");
+                continue;
             }
+            host_addrs.push(self.outbound_addr(host_addr));
+        }
+
+        let delay = if self.happy_eyeballs {
+            host_addrs = happy_eyeballs::interleave_families(host_addrs);
+            Some(CONNECTION_ATTEMPT_DELAY)
+        } else {
+            None
         };
-        Ok((stream, host.clone()))
+        let logged_host = &logged_host;
+        let connected = happy_eyeballs::connect_first(host_addrs, delay, |host_addr| async move {
+            let started = Instant::now();
+            match self.connect_with_timeout(&host_addr).await {
+                Ok(stream) => {
+                    if self.resolution_order == ResolutionOrder::Rtt {
+                        self.rtt_table.record(host_addr.ip(), started.elapsed());
+                    }
+                    Ok(stream)
+                }
+                Err(err) => {
+                    tracing::debug!(
+                        "Failed to connect {} of host {logged_host}, error: {}",
+                        self.log_privacy.anonymize(&host_addr.into()),
+                        self.log_privacy.anonymize_error(&err)
+                    );
+                    Err(err)
+                }
+            }
+        })
+        .await;
+
+        match connected {
+            Ok(stream) => Ok((stream, host.clone())),
+            Err(last_error) => {
+                // every address is denied if none is tried
+                let err = last_error
+                    .unwrap_or_else(|| Error::ConnectForbiddenHosts { hosts: vec![host.clone()] });
+                tracing::error!(
+                    "Failed to connect host: {logged_host}, error: {}",
+                    self.log_privacy.anonymize_error(&err)
+                );
+                Err(err)
+            }
+        }
     }
 
     #[inline]
     pub async fn connect_addr(&self, addr: &SocketAddr) -> Result<(Stream, SocketAddr), Error> {
-        if self.filter.filter_socket(addr) == FilterAction::Deny {
+        if self.check_filter(&HostAddress::from(*addr)) == FilterAction::Deny
+            || self.check_resolved_filter(addr) == FilterAction::Deny
+        {
             return Err(Error::ConnectForbiddenHosts { hosts: vec![(*addr).into()] });
         }
 
-        tracing::debug!("Try to connect remote host {}", addr);
-        let stream = match self.connector.connect_addr(addr).await {
+        let logged_addr = self.log_privacy.anonymize(&(*addr).into());
+        tracing::debug!("Try to connect remote host {logged_addr}");
+        let stream = match self.connect_with_timeout(&self.outbound_addr(*addr)).await {
             Ok(stream) => stream,
             Err(err) => {
-                tracing::error!("Failed to connect host: {}, error: {:?}", addr, err);
+                tracing::error!(
+                    "Failed to connect host: {logged_addr}, error: {}",
+                    self.log_privacy.anonymize_error(&err)
+                );
                 return Err(err);
             }
         };
         Ok((stream, *addr))
     }
 
-    pub async fn relay<Client>(
+    async fn connect_with_timeout(&self, addr: &SocketAddr) -> Result<Stream, Error> {
+        self.policy
+            .retry(|| async {
+                let connect = self.connector.connect_addr(addr);
+                with_timeout(self.timeouts.connect, TimeoutPhase::Connect, connect)
+                    .await
+                    .map_err(|phase| Error::Timeout { phase })?
+            })
+            .await
+    }
+
+    /// Listens for an inbound connection from `host`, e.g. for SOCKS BIND.
+    ///
+    /// The listener is bound on the local address used to reach `host`, so that
+    /// it is on the same interface as outbound connections. Only addresses of
+    /// `host` allowed by filter are expected to connect.
+    pub async fn listen(&self, host: &HostAddress) -> Result<BindListener, Error> {
+        if self.check_filter(host) == FilterAction::Deny {
+            let hosts = Vec::from([host.clone()]);
+            return Err(Error::ConnectForbiddenHosts { hosts });
+        }
+
+        let mut peers = Vec::new();
+        for addr in self.resolve_all(host).await? {
+            if !self.destination_filter.is_denied_addr(&addr) {
+                peers.push(self.outbound_addr(addr));
+            }
+        }
+        let Some(&remote_addr) = peers.first() else {
+            return Err(Error::ConnectForbiddenHosts { hosts: vec![host.clone()] });
+        };
+
+        let local_ip = outbound_ip(remote_addr).await;
+        let addr = SocketAddr::new(local_ip, 0);
+        let listener = TcpListener::bind(addr).await.context(error::ListenSnafu { addr })?;
+        let local_addr = listener.local_addr().context(error::ListenSnafu { addr })?;
+        tracing::debug!(
+            "Listening on {local_addr} for inbound connection from {}",
+            self.log_privacy.anonymize(host)
+        );
+        let peers = peers.into_iter().map(|addr| addr.ip().to_canonical()).collect();
+        Ok(BindListener { listener, local_addr, peers })
+    }
+
+    /// Accepts an inbound connection on `listener`, connections from peers
+    /// other than the host expected fail with `Error::UnexpectedPeer`, and
+    /// connections from peers denied by filter fail with
+    /// `Error::ConnectForbiddenHosts`.
+    pub async fn accept(&self, listener: &BindListener) -> Result<(TcpStream, SocketAddr), Error> {
+        let (stream, peer_addr) = listener.listener.accept().await.context(error::AcceptSnafu)?;
+        if !listener.peers.contains(&peer_addr.ip().to_canonical()) {
+            drop(stream);
+            return Err(Error::UnexpectedPeer { peer: peer_addr });
+        }
+        if self.destination_filter.is_denied_addr(&peer_addr) {
+            drop(stream);
+            return Err(Error::ConnectForbiddenHosts { hosts: vec![peer_addr.into()] });
+        }
+
+        tracing::debug!(
+            "Accepted inbound connection from {}",
+            self.log_privacy.anonymize(&peer_addr.into())
+        );
+        Ok((stream, peer_addr))
+    }
+
+    #[inline]
+    pub async fn relay<Client, Remote>(
         &self,
         client: Client,
-        remote: Stream,
+        remote: Remote,
         on_finished: Option<Box<dyn FnOnce() + Send>>,
     ) -> Result<(), Error>
     where
         Client: Unpin + AsyncRead + AsyncWrite,
+        Remote: Unpin + AsyncRead + AsyncWrite,
+    {
+        self.relay_bidirectional(client, remote, on_finished).await.map(|_| ())
+    }
+
+    /// Same as [`Transport::relay`], but flushes with the policy of
+    /// `destination`.
+    #[inline]
+    pub async fn relay_to<Client, Remote>(
+        &self,
+        client: Client,
+        remote: Remote,
+        destination: &HostAddress,
+        on_finished: Option<Box<dyn FnOnce() + Send>>,
+    ) -> Result<(), Error>
+    where
+        Client: Unpin + AsyncRead + AsyncWrite,
+        Remote: Unpin + AsyncRead + AsyncWrite,
+    {
+        self.relay_bidirectional_to(client, remote, destination, on_finished).await.map(|_| ())
+    }
+
+    /// Same as [`Transport::relay_bidirectional`], but flushes with the policy
+    /// of `destination`, i.e. immediately if its port is an interactive port
+    /// set by `with_nodelay_ports`.
+    #[inline]
+    pub async fn relay_bidirectional_to<Client, Remote>(
+        &self,
+        client: Client,
+        remote: Remote,
+        destination: &HostAddress,
+        on_finished: Option<Box<dyn FnOnce() + Send>>,
+    ) -> Result<(ClosedBy, RelayStats), Error>
+    where
+        Client: Unpin + AsyncRead + AsyncWrite,
+        Remote: Unpin + AsyncRead + AsyncWrite,
+    {
+        let flush_policy = self.flush_policy(destination.port());
+        self.relay_with_flush_policy(client, remote, flush_policy, on_finished).await
+    }
+
+    /// Relays between `client` and `remote` until either side closes, returns
+    /// the side closed first and bytes relayed in each direction.
+    ///
+    /// The destination is unknown here, the relay is flushed with the policy
+    /// set by `with_flush_policy` even for interactive ports, see
+    /// [`Transport::relay_bidirectional_to`].
+    ///
+    /// Fails with [`Error::Timeout`] if relay or idle timeout of this
+    /// transport expires.
+    #[inline]
+    pub async fn relay_bidirectional<Client, Remote>(
+        &self,
+        client: Client,
+        remote: Remote,
+        on_finished: Option<Box<dyn FnOnce() + Send>>,
+    ) -> Result<(ClosedBy, RelayStats), Error>
+    where
+        Client: Unpin + AsyncRead + AsyncWrite,
+        Remote: Unpin + AsyncRead + AsyncWrite,
+    {
+        self.relay_with_flush_policy(client, remote, self.flush_policy, on_finished).await
+    }
+
+    async fn relay_with_flush_policy<Client, Remote>(
+        &self,
+        client: Client,
+        remote: Remote,
+        flush_policy: FlushPolicy,
+        on_finished: Option<Box<dyn FnOnce() + Send>>,
+    ) -> Result<(ClosedBy, RelayStats), Error>
+    where
+        Client: Unpin + AsyncRead + AsyncWrite,
+        Remote: Unpin + AsyncRead + AsyncWrite,
     {
         let (client_counter, _prev_count) = self.metrics.count_client();
         let (remote_counter, _prev_count) = self.metrics.count_remote();
         let (relay_counter, _prev_count) = self.metrics.count_relay();
 
-        let (mut client_reader, mut client_writer) = tokio::io::split(client);
-        let (mut remote_reader, mut remote_writer) = tokio::io::split(remote);
+        // bytes are counted on the client side, both halves report to the metrics
+        let client = MonitoredStream::new(client, self.metrics.clone());
+        let (client_reader, mut client_writer) = client.split();
+        let (remote_reader, mut remote_writer) = tokio::io::split(remote);
+
+        let mut stats = RelayStats::default();
+        let activity = Activity::new();
+        let quota = Quota::new(self.max_bytes_per_connection);
+        #[cfg(feature = "debug")]
+        let (client_reader, remote_reader) = (
+            DelayedReader::new(client_reader, self.debug_latency),
+            DelayedReader::new(remote_reader, self.debug_latency),
+        );
+        let (mut client_reader, mut remote_reader) = (client_reader, remote_reader);
+
+        let closed_by = {
+            let half1 = copy(
+                &mut client_reader,
+                &mut remote_writer,
+                &mut stats.client_to_remote,
+                &activity,
+                &quota,
+                flush_policy,
+            );
+            let half2 = copy(
+                &mut remote_reader,
+                &mut client_writer,
+                &mut stats.remote_to_client,
+                &activity,
+                &quota,
+                flush_policy,
+            );
+            let relay = async {
+                match futures::future::select(Box::pin(half1), Box::pin(half2)).await {
+                    futures::future::Either::Left((Ok(()), _)) => ClosedBy::Client,
+                    futures::future::Either::Right((Ok(()), _)) => ClosedBy::Remote,
+                    futures::future::Either::Left((Err(err), _))
+                    | futures::future::Either::Right((Err(err), _)) => {
+                        tracing::debug!("Error occurred while relaying stream, error: {err}");
+                        ClosedBy::Error
+                    }
+                }
+            };
+
+            let expired = async {
+                let lifetime = async {
+                    match self.timeouts.relay {
+                        Some(relay) => tokio::time::sleep(relay).await,
+                        None => futures::future::pending().await,
+                    }
+                };
+                let idle = async {
+                    match self.timeouts.idle {
+                        Some(idle) => activity.idle(idle).await,
+                        None => futures::future::pending().await,
+                    }
+                };
+                match futures::future::select(Box::pin(lifetime), Box::pin(idle)).await {
+                    futures::future::Either::Left(_) => TimeoutPhase::Relay,
+                    futures::future::Either::Right(_) => TimeoutPhase::Idle,
+                }
+            };
 
-        {
-            let half1 = tokio::io::copy(&mut client_reader, &mut remote_writer);
-            let half2 = tokio::io::copy(&mut remote_reader, &mut client_writer);
-
-            futures::future::select(
-                Box::pin(async move {
-                    drop(half1.await);
-                }),
-                Box::pin(async move {
-                    drop(half2.await);
-                }),
-            )
-            .await;
-        }
+            match futures::future::select(Box::pin(relay), Box::pin(expired)).await {
+                futures::future::Either::Left((..)) if quota.is_exceeded() => {
+                    let max_bytes = self.max_bytes_per_connection.unwrap_or_default();
+                    tracing::debug!("Relay exceeds quota of {max_bytes} bytes");
+                    Err(Error::QuotaExceeded { max_bytes })
+                }
+                futures::future::Either::Left((closed_by, _)) => Ok(closed_by),
+                futures::future::Either::Right((phase, _)) => {
+                    tracing::debug!("Relay is timed out during {phase}");
+                    Err(Error::Timeout { phase })
+                }
+            }
+        };
 
         if let Some(on_finished) = on_finished {
             on_finished();
         }
 
+        #[cfg(feature = "debug")]
+        let (client_reader, remote_reader) =
+            (client_reader.into_inner(), remote_reader.into_inner());
+
         let mut client = client_reader.unsplit(client_writer);
         let mut remote = remote_reader.unsplit(remote_writer);
 
@@ -276,6 +839,70 @@ where
 
         drop(relay_counter);
 
-        Ok(())
+        closed_by.map(|closed_by| (closed_by, stats))
+    }
+}
+
+// local address of the route to `remote_addr`, connecting a UDP socket sends
+// nothing but selects the route; the unspecified address is used if there is no
+// route
+async fn outbound_ip(remote_addr: SocketAddr) -> IpAddr {
+    // the route depends on the address only, port 0 can not be connected
+    const DISCARD_PORT: u16 = 9;
+
+    let unspecified = match remote_addr {
+        SocketAddr::V4(_) => IpAddr::from(std::net::Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED),
+    };
+    if remote_addr.ip().is_unspecified() {
+        return unspecified;
+    }
+
+    let local_addr = async {
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+        socket.connect((remote_addr.ip(), DISCARD_PORT)).await?;
+        socket.local_addr()
+    };
+    local_addr.await.map_or(unspecified, |addr| addr.ip())
+}
+
+// same as `tokio::io::copy`, but bytes copied are kept in `copied` even if the
+// future is dropped before completion
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    copied: &mut u64,
+    activity: &Activity,
+    quota: &Quota,
+    flush_policy: FlushPolicy,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 8 * 1024];
+    loop {
+        // reading is cancel safe, a pending read is dropped and awaited again after
+        // flushing
+        let n = match reader.read(&mut buf).now_or_never() {
+            Some(n) => n?,
+            None => {
+                writer.flush().await?;
+                reader.read(&mut buf).await?
+            }
+        };
+        if n == 0 {
+            return writer.flush().await;
+        }
+        let allowed = quota.take(n);
+        writer.write_all(&buf[..allowed]).await?;
+        if flush_policy == FlushPolicy::Immediate {
+            writer.flush().await?;
+        }
+        *copied += allowed as u64;
+        activity.touch();
+        if allowed < n {
+            return Err(std::io::Error::other("quota of relay is exceeded"));
+        }
     }
 }
@@ -0,0 +1,35 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Bytes allowed to be relayed in both directions of a connection.
+#[derive(Debug)]
+pub(crate) struct Quota {
+    remaining: Option<AtomicU64>,
+    exceeded: AtomicBool,
+}
+
+impl Quota {
+    pub(crate) fn new(max_bytes: Option<u64>) -> Self {
+        Self { remaining: max_bytes.map(AtomicU64::new), exceeded: AtomicBool::new(false) }
+    }
+
+    /// Takes up to `n` bytes from the quota, returns number of bytes allowed to
+    /// be relayed. The quota is marked as exceeded if less than `n` bytes are
+    /// allowed.
+    pub(crate) fn take(&self, n: usize) -> usize {
+        let Some(ref remaining) = self.remaining else {
+            return n;
+        };
+
+        let wanted = n as u64;
+        let prev = remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| Some(r.saturating_sub(wanted)))
+            .unwrap_or_default();
+        let allowed = prev.min(wanted);
+        if allowed < wanted {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+        usize::try_from(allowed).unwrap_or(n)
+    }
+
+    pub(crate) fn is_exceeded(&self) -> bool { self.exceeded.load(Ordering::Relaxed) }
+}
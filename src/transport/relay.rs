@@ -0,0 +1,306 @@
+#[cfg(feature = "debug")]
+use std::time::Duration;
+
+#[cfg(feature = "debug")]
+use rand::Rng;
+
+/// Side which ends a relay first.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ClosedBy {
+    /// Client closes its write half.
+    Client,
+
+    /// Remote host closes its write half.
+    Remote,
+
+    /// Reading or writing either side fails.
+    Error,
+}
+
+/// When data relayed is flushed to the other side.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum FlushPolicy {
+    /// Flushes once no more data is ready to read, so that bursts are written
+    /// out together.
+    #[default]
+    Coalesce,
+
+    /// Flushes after each chunk read, for latency-sensitive connections like
+    /// interactive shells.
+    Immediate,
+}
+
+/// Artificial latency injected into relays for testing the retries and
+/// timeouts of clients, each chunk relayed is delayed by `latency` plus a
+/// random jitter of up to `jitter`.
+///
+/// Only available with the `debug` feature, which does not compile in release
+/// builds, so that it can not be enabled there by accident.
+#[cfg(feature = "debug")]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct DebugLatency {
+    pub latency: Duration,
+    pub jitter: Duration,
+}
+
+#[cfg(feature = "debug")]
+impl DebugLatency {
+    pub(crate) fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        self.latency.saturating_add(rand::thread_rng().gen_range(Duration::ZERO..=self.jitter))
+    }
+}
+
+/// Bytes relayed in each direction.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct RelayStats {
+    pub client_to_remote: u64,
+    pub remote_to_client: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io,
+        net::Ipv4Addr,
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        test_util::direct_transport,
+        transport::{ClosedBy, Error, FlushPolicy, RelayStats},
+    };
+
+    // remote host which never sends, keeps what is written by each flush
+    #[derive(Default)]
+    struct FlushRecorder {
+        written: Vec<u8>,
+        flushed: Vec<Vec<u8>>,
+    }
+
+    impl AsyncRead for FlushRecorder {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for FlushRecorder {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let written = self.written.clone();
+            self.flushed.push(written);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // client writing chunks which are all ready to read at once
+    fn chunks() -> impl AsyncRead + AsyncWrite + Unpin {
+        tokio::io::join((&b"a"[..]).chain(&b"b"[..]).chain(&b"c"[..]), tokio::io::sink())
+    }
+
+    #[tokio::test]
+    async fn client_closes_first() {
+        let transport = direct_transport(SimpleFilter::deny_list());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let client = async move {
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+            // remote keeps the connection open
+        };
+        let peer = async move {
+            let mut buf = [0u8; 4];
+            peer.read_exact(&mut buf).await.unwrap();
+            peer.write_all(b"pong").await.unwrap();
+            peer
+        };
+
+        let (result, (), _peer) =
+            tokio::join!(transport.relay_bidirectional(server, remote, None), client, peer);
+        let (closed_by, stats) = result.unwrap();
+        assert_eq!(closed_by, ClosedBy::Client);
+        assert_eq!(stats, RelayStats { client_to_remote: 4, remote_to_client: 4 });
+    }
+
+    #[tokio::test]
+    async fn quota_exceeded() {
+        let transport =
+            direct_transport(SimpleFilter::deny_list()).with_max_bytes_per_connection(8);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let client = async move {
+            client.write_all(b"0123456789abcdef").await.unwrap();
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.is_empty());
+        };
+        let peer = async move {
+            let mut buf = Vec::new();
+            peer.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"01234567");
+        };
+
+        let (result, (), ()) =
+            tokio::join!(transport.relay_bidirectional(server, remote, None), client, peer);
+        assert!(matches!(result, Err(Error::QuotaExceeded { max_bytes: 8 })));
+    }
+
+    #[tokio::test]
+    async fn flush_small_writes() {
+        for flush_policy in [FlushPolicy::Immediate, FlushPolicy::Coalesce] {
+            let transport =
+                direct_transport(SimpleFilter::deny_list()).with_flush_policy(flush_policy);
+
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let remote = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (mut peer, _) = listener.accept().await.unwrap();
+            // writes to remote are held in the buffer until flushed
+            let remote = BufWriter::new(remote);
+
+            let (mut client, server) = tokio::io::duplex(64);
+            let relay = tokio::spawn(async move {
+                transport.relay_bidirectional(server, remote, None).await.unwrap()
+            });
+
+            // the keystroke arrives while the client keeps the connection open
+            client.write_all(b"k").await.unwrap();
+            let mut buf = [0u8; 1];
+            tokio::time::timeout(Duration::from_secs(1), peer.read_exact(&mut buf))
+                .await
+                .expect("small write should be flushed")
+                .unwrap();
+            assert_eq!(&buf, b"k");
+
+            drop(client);
+            let (closed_by, _stats) = relay.await.unwrap();
+            assert_eq!(closed_by, ClosedBy::Client);
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_each_chunk_immediately() {
+        let immediate = [b"a".to_vec(), b"ab".to_vec(), b"abc".to_vec(), b"abc".to_vec()];
+        for (flush_policy, flushed) in
+            [(FlushPolicy::Immediate, &immediate[..]), (FlushPolicy::Coalesce, &[b"abc".to_vec()])]
+        {
+            let transport =
+                direct_transport(SimpleFilter::deny_list()).with_flush_policy(flush_policy);
+            let mut remote = FlushRecorder::default();
+            let (closed_by, _stats) =
+                transport.relay_bidirectional(chunks(), &mut remote, None).await.unwrap();
+            assert_eq!(closed_by, ClosedBy::Client);
+            assert_eq!(remote.flushed, flushed, "{flush_policy:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_relays_to_interactive_ports_immediately() {
+        let transport = direct_transport(SimpleFilter::deny_list())
+            .with_nodelay_ports(HashMap::from([(22, true)]));
+
+        let mut remote = FlushRecorder::default();
+        let destination = HostAddress::new("192.0.2.1", 22);
+        let _unused = transport
+            .relay_bidirectional_to(chunks(), &mut remote, &destination, None)
+            .await
+            .unwrap();
+        assert_eq!(remote.flushed.len(), 4);
+
+        let mut remote = FlushRecorder::default();
+        let destination = HostAddress::new("192.0.2.1", 80);
+        let _unused = transport
+            .relay_bidirectional_to(chunks(), &mut remote, &destination, None)
+            .await
+            .unwrap();
+        assert_eq!(remote.flushed, [b"abc".to_vec()]);
+    }
+
+    #[cfg(feature = "debug")]
+    #[tokio::test]
+    async fn inject_latency() {
+        use std::time::Instant;
+
+        use crate::transport::{DebugLatency, Transport};
+
+        async fn round_trip(transport: Transport<TcpStream>) -> Duration {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let remote = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (mut peer, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4];
+                peer.read_exact(&mut buf).await.unwrap();
+                peer.write_all(&buf).await.unwrap();
+            });
+
+            let (mut client, server) = tokio::io::duplex(64);
+            let _relay =
+                tokio::spawn(
+                    async move { transport.relay_bidirectional(server, remote, None).await },
+                );
+
+            let started = Instant::now();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            started.elapsed()
+        }
+
+        let transport = || direct_transport(SimpleFilter::deny_list());
+        let latency =
+            DebugLatency { latency: Duration::from_millis(100), jitter: Duration::from_millis(20) };
+
+        // both directions are delayed
+        let direct = round_trip(transport()).await;
+        let delayed = round_trip(transport().with_debug_latency(latency)).await;
+        assert!(delayed >= Duration::from_millis(200), "{delayed:?}");
+        assert!(delayed > direct, "{delayed:?} <= {direct:?}");
+    }
+
+    #[test]
+    fn flush_interactive_ports_immediately() {
+        let transport = direct_transport(SimpleFilter::deny_list())
+            .with_nodelay_ports(HashMap::from([(22, true), (80, false)]));
+        assert_eq!(transport.flush_policy(22), FlushPolicy::Immediate);
+        assert_eq!(transport.flush_policy(80), FlushPolicy::Coalesce);
+        assert_eq!(transport.flush_policy(443), FlushPolicy::Coalesce);
+    }
+}
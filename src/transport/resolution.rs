@@ -0,0 +1,192 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use lru::LruCache;
+use rand::seq::SliceRandom;
+
+/// Order in which resolved addresses of a domain name are attempted.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ResolutionOrder {
+    /// Keep the order returned by resolver.
+    #[default]
+    Sequential,
+
+    /// Shuffle addresses to spread load across records.
+    Random,
+
+    /// Prefer addresses with lower observed connect time, addresses never
+    /// connected before are attempted last in resolver order.
+    Rtt,
+}
+
+/// Maximum number of addresses of which RTT is kept by default.
+const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+/// Connect time observed of each address, the least recently recorded one is
+/// evicted once `max_entries` addresses are kept.
+#[derive(Clone, Debug)]
+pub(crate) struct RttTable(Arc<Mutex<LruCache<IpAddr, Duration>>>);
+
+impl Default for RttTable {
+    fn default() -> Self { Self::with_max_entries(DEFAULT_MAX_ENTRIES) }
+}
+
+impl RttTable {
+    fn with_max_entries(max_entries: usize) -> Self {
+        let max_entries = NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self(Arc::new(Mutex::new(LruCache::new(max_entries))))
+    }
+
+    pub(crate) fn record(&self, addr: IpAddr, rtt: Duration) {
+        let _unused = self.0.lock().expect("RTT table is poisoned").put(addr, rtt);
+    }
+
+    pub(crate) fn sort(&self, order: ResolutionOrder, addrs: &mut [SocketAddr]) {
+        match order {
+            ResolutionOrder::Sequential => {}
+            ResolutionOrder::Random => addrs.shuffle(&mut rand::thread_rng()),
+            ResolutionOrder::Rtt => {
+                let table = self.0.lock().expect("RTT table is poisoned");
+                // `sort_by_key` is stable, addresses without RTT keep resolver order
+                addrs.sort_by_key(|addr| table.peek(&addr.ip()).copied().unwrap_or(Duration::MAX));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::{ResolutionOrder, RttTable};
+    use crate::{
+        common::HostAddress, filter::SimpleFilter, test_util::direct_transport, transport,
+    };
+
+    fn addrs() -> Vec<SocketAddr> {
+        (1..=8).map(|n| SocketAddr::from((Ipv4Addr::new(10, 0, 0, n), 80))).collect()
+    }
+
+    #[test]
+    fn sequential_preserves_order() {
+        let mut sorted = addrs();
+        RttTable::default().sort(ResolutionOrder::Sequential, &mut sorted);
+        assert_eq!(sorted, addrs());
+    }
+
+    #[test]
+    fn random_shuffles() {
+        let table = RttTable::default();
+        let orders: HashSet<_> = (0..100)
+            .map(|_| {
+                let mut sorted = addrs();
+                table.sort(ResolutionOrder::Random, &mut sorted);
+                sorted
+            })
+            .collect();
+
+        // the chance of getting less than 3 distinct orders of 8 addresses in 100
+        // shuffles is negligible
+        assert!(orders.len() > 2);
+        assert!(orders.iter().all(|sorted| sorted.iter().collect::<HashSet<_>>().len() == 8));
+    }
+
+    #[test]
+    fn rtt_prefers_faster_addresses() {
+        let table = RttTable::default();
+        let addrs = addrs();
+        table.record(addrs[5].ip(), Duration::from_millis(20));
+        table.record(addrs[3].ip(), Duration::from_millis(10));
+
+        let mut sorted = addrs.clone();
+        table.sort(ResolutionOrder::Rtt, &mut sorted);
+        assert_eq!(&sorted[..2], &[addrs[3], addrs[5]]);
+        assert_eq!(&sorted[2..], &[addrs[0], addrs[1], addrs[2], addrs[4], addrs[6], addrs[7]]);
+    }
+
+    #[test]
+    fn evict_least_recently_recorded() {
+        let table = RttTable::with_max_entries(2);
+        let addrs = addrs();
+        table.record(addrs[0].ip(), Duration::from_millis(30));
+        table.record(addrs[1].ip(), Duration::from_millis(20));
+        table.record(addrs[2].ip(), Duration::from_millis(10));
+
+        let mut sorted = addrs[..3].to_vec();
+        table.sort(ResolutionOrder::Rtt, &mut sorted);
+        assert_eq!(sorted, [addrs[2], addrs[1], addrs[0]]);
+        assert_eq!(table.0.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn record_rtt_only_if_ordered_by_rtt() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let host = HostAddress::from(listener.local_addr().unwrap());
+
+        for (order, recorded) in [(ResolutionOrder::Sequential, 0), (ResolutionOrder::Rtt, 1)] {
+            let transport =
+                direct_transport(SimpleFilter::deny_list()).with_resolution_order(order);
+            let _stream = transport.connect(&host).await.unwrap();
+            assert_eq!(transport.rtt_table.0.lock().unwrap().len(), recorded);
+        }
+    }
+
+    #[tokio::test]
+    async fn address_family_follows_destination() {
+        let transport = direct_transport(SimpleFilter::deny_list());
+        let strict = direct_transport(SimpleFilter::deny_list()).with_strict_address_family(true);
+
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        let host = HostAddress::from(listener.local_addr().unwrap());
+        let (stream, _) = transport.connect(&host).await.unwrap();
+        assert!(stream.local_addr().unwrap().is_ipv6());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (stream, _) = transport.connect(&HostAddress::new("127.0.0.1", port)).await.unwrap();
+        assert!(stream.local_addr().unwrap().is_ipv4());
+
+        // IPv4-mapped IPv6 destination is connected over IPv4 in strict mode
+        let mapped = SocketAddr::from((Ipv4Addr::LOCALHOST.to_ipv6_mapped(), port));
+        let (stream, _) = strict.connect(&HostAddress::from(mapped)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        let (stream, _) = strict.connect_addr(&mapped).await.unwrap();
+        assert!(stream.local_addr().unwrap().is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn mapped_addresses_are_filtered_as_ipv4() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mapped = SocketAddr::from((Ipv4Addr::LOCALHOST.to_ipv6_mapped(), port));
+
+        for strict in [false, true] {
+            let mut filter = SimpleFilter::deny_list();
+            filter.add_address(IpAddr::from(Ipv4Addr::LOCALHOST));
+            let transport = direct_transport(filter).with_strict_address_family(strict);
+            assert!(matches!(
+                transport.connect_addr(&mapped).await,
+                Err(transport::Error::ConnectForbiddenHosts { .. })
+            ));
+            assert!(matches!(
+                transport.connect(&HostAddress::from(mapped)).await,
+                Err(transport::Error::ConnectForbiddenHosts { .. })
+            ));
+
+            let mut filter = SimpleFilter::allow_list();
+            filter.add_address(IpAddr::from(Ipv4Addr::LOCALHOST));
+            let transport = direct_transport(filter).with_strict_address_family(strict);
+            assert!(transport.connect_addr(&mapped).await.is_ok());
+        }
+    }
+}
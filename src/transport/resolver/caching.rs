@@ -0,0 +1,250 @@
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use lru::LruCache;
+use tokio::sync::Mutex;
+
+use crate::transport::resolver::{Resolve, Resolver};
+
+// addresses of each normalized host and when they are cached
+type Cache = LruCache<String, (Vec<IpAddr>, Instant)>;
+
+/// Caches results of another [`Resolver`], so that repeated connections to the
+/// same host do not query the inner resolver each time.
+///
+/// Host names are cached case-insensitively. Empty results are cached with a
+/// shorter TTL, failures are not cached. Once the cache is full, the least
+/// recently used entry is evicted.
+#[derive(Clone)]
+pub struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    // `None` if caching is disabled
+    cache: Option<Arc<Mutex<Cache>>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl CachingResolver {
+    /// Time to live of negative results by default, shorter than positive
+    /// ones as names may be registered or fixed anytime.
+    pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+    #[must_use]
+    pub fn new(inner: Arc<dyn Resolver>, ttl: Duration, max_entries: usize) -> Self {
+        let cache = NonZeroUsize::new(max_entries)
+            .map(|max_entries| Arc::new(Mutex::new(LruCache::new(max_entries))));
+        Self { inner, cache, ttl, negative_ttl: Self::DEFAULT_NEGATIVE_TTL.min(ttl) }
+    }
+
+    /// Sets the time to live of empty results.
+    #[must_use]
+    pub const fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Returns the number of cached entries, including expired ones not
+    /// evicted yet.
+    pub async fn len(&self) -> usize {
+        match self.cache {
+            Some(ref cache) => cache.lock().await.len(),
+            None => 0,
+        }
+    }
+
+    pub async fn is_empty(&self) -> bool { self.len().await == 0 }
+
+    /// Removes all cached entries.
+    pub async fn clear(&self) {
+        if let Some(ref cache) = self.cache {
+            cache.lock().await.clear();
+        }
+    }
+
+    // DNS names are case-insensitive and may be written fully qualified
+    fn normalize(host: &str) -> String {
+        host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+    }
+
+    const fn ttl_of(&self, addrs: &[IpAddr]) -> Duration {
+        if addrs.is_empty() {
+            self.negative_ttl
+        } else {
+            self.ttl
+        }
+    }
+
+    async fn get(&self, key: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.as_ref()?.lock().await;
+        let (addrs, cached_at) = cache.get(key)?;
+        if cached_at.elapsed() < self.ttl_of(addrs) {
+            return Some(addrs.clone());
+        }
+        let _unused = cache.pop(key);
+        None
+    }
+
+    async fn insert(&self, key: String, addrs: Vec<IpAddr>) {
+        let Some(ref cache) = self.cache else { return };
+        if self.ttl_of(&addrs).is_zero() {
+            return;
+        }
+        let _unused = cache.lock().await.put(key, (addrs, Instant::now()));
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve(&self, host: &str) -> Resolve {
+        let host = host.to_owned();
+        let resolver = self.clone();
+
+        async move {
+            let key = Self::normalize(&host);
+            if let Some(addrs) = resolver.get(&key).await {
+                return Ok(addrs);
+            }
+
+            let addrs = resolver.inner.resolve(&host).await?;
+            resolver.insert(key, addrs.clone()).await;
+            Ok(addrs)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::FutureExt;
+
+    use super::CachingResolver;
+    use crate::transport::{
+        resolver::{Resolve, Resolver},
+        Error,
+    };
+
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: AtomicUsize,
+    }
+
+    impl CountingResolver {
+        fn lookups(&self) -> usize { self.lookups.load(Ordering::SeqCst) }
+    }
+
+    impl Resolver for CountingResolver {
+        fn resolve(&self, host: &str) -> Resolve {
+            let _unused = self.lookups.fetch_add(1, Ordering::SeqCst);
+            let result = match host {
+                "nxdomain.example" => Ok(Vec::new()),
+                "servfail.example" => {
+                    Err(Error::ResolveDomainName { domain_name: host.to_owned() })
+                }
+                _ => Ok(vec![IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))]),
+            };
+            futures::future::ready(result).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_cached_results() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 16)
+            .with_negative_ttl(Duration::from_millis(50));
+
+        let expected = vec![IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))];
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), expected);
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), expected);
+        assert_eq!(inner.lookups(), 1);
+
+        // negative results are cached with the shorter TTL
+        assert!(resolver.resolve("nxdomain.example").await.unwrap().is_empty());
+        assert!(resolver.resolve("nxdomain.example").await.unwrap().is_empty());
+        assert_eq!(inner.lookups(), 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(resolver.resolve("nxdomain.example").await.unwrap().is_empty());
+        assert_eq!(inner.lookups(), 3);
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), expected);
+        assert_eq!(inner.lookups(), 3);
+
+        // failures are not cached
+        assert!(resolver.resolve("servfail.example").await.is_err());
+        assert!(resolver.resolve("servfail.example").await.is_err());
+        assert_eq!(inner.lookups(), 5);
+    }
+
+    #[tokio::test]
+    async fn cache_host_names_case_insensitively() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 16);
+
+        for host in ["example.com", "EXAMPLE.com", "Example.Com.", "example.com."] {
+            let _unused = resolver.resolve(host).await.unwrap();
+        }
+        assert_eq!(inner.lookups(), 1);
+        assert_eq!(resolver.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used_entries() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 2);
+
+        let _unused = resolver.resolve("a.example").await.unwrap();
+        let _unused = resolver.resolve("b.example").await.unwrap();
+        // `a.example` is used again, so `b.example` is evicted instead
+        let _unused = resolver.resolve("a.example").await.unwrap();
+        let _unused = resolver.resolve("c.example").await.unwrap();
+        assert_eq!(resolver.len().await, 2);
+        assert_eq!(inner.lookups(), 3);
+
+        let _unused = resolver.resolve("a.example").await.unwrap();
+        let _unused = resolver.resolve("c.example").await.unwrap();
+        assert_eq!(inner.lookups(), 3);
+        let _unused = resolver.resolve("b.example").await.unwrap();
+        assert_eq!(inner.lookups(), 4);
+    }
+
+    #[tokio::test]
+    async fn disable_caching_without_entries() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 0);
+
+        let _unused = resolver.resolve("example.com").await.unwrap();
+        let _unused = resolver.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 2);
+        assert!(resolver.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn evict_oldest_entries() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_secs(60), 2);
+
+        for host in ["a.example", "b.example", "c.example"] {
+            let _unused = resolver.resolve(host).await.unwrap();
+        }
+        assert_eq!(resolver.len().await, 2);
+        assert_eq!(inner.lookups(), 3);
+
+        // `a.example` is evicted, the others are still cached
+        let _unused = resolver.resolve("c.example").await.unwrap();
+        let _unused = resolver.resolve("b.example").await.unwrap();
+        assert_eq!(inner.lookups(), 3);
+        let _unused = resolver.resolve("a.example").await.unwrap();
+        assert_eq!(inner.lookups(), 4);
+    }
+}
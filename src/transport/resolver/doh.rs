@@ -0,0 +1,537 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::FutureExt;
+use lru::LruCache;
+use reqwest::{header, StatusCode};
+use snafu::ResultExt;
+use tokio_rustls::rustls;
+use url::Url;
+
+use crate::transport::{
+    error,
+    resolver::{Resolve, Resolver},
+    Error, TimeoutPhase,
+};
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+const RECORD_TYPE_A: u16 = 1;
+const RECORD_TYPE_AAAA: u16 = 28;
+const RECORD_CLASS_IN: u16 = 1;
+
+const RCODE_NO_ERROR: u8 = 0;
+const RCODE_NAME_ERROR: u8 = 3;
+
+// DNS messages over HTTPS are limited to 65535 bytes
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// HTTP method of DNS queries, see RFC 8484 section 4.1.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum DohMethod {
+    /// Query is sent as base64url-encoded parameter `dns`, which may be
+    /// cached by HTTP caches.
+    Get,
+
+    /// Query is sent as body of the request.
+    #[default]
+    Post,
+}
+
+/// Resolves domain names with DNS queries over HTTPS (RFC 8484), for
+/// networks where plain DNS is blocked.
+///
+/// Connections to the endpoint are kept alive and reused by later queries.
+/// Addresses are cached until the smallest TTL of the answer expires, once the
+/// cache is full the least recently used entry is evicted. The host of the
+/// endpoint is resolved by the system resolver, use an IP address to avoid
+/// plain DNS entirely.
+#[derive(Clone)]
+pub struct DohResolver {
+    endpoint: Url,
+    method: DohMethod,
+    client: reqwest::Client,
+    timeout: Duration,
+    cache: Option<Arc<Mutex<LruCache<String, CacheEntry>>>>,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+impl DohResolver {
+    /// Number of cached host names by default.
+    pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+    /// Limit of the time of a lookup by default, including connecting the
+    /// endpoint.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Creates a resolver sending queries to `endpoint`, e.g.
+    /// `https://cloudflare-dns.com/dns-query`, endpoints of scheme `http` are
+    /// accepted for testing.
+    pub fn new(endpoint: Url) -> Result<Self, Error> {
+        if !matches!(endpoint.scheme(), "http" | "https") || endpoint.host().is_none() {
+            return Err(Error::InvalidDohEndpoint { endpoint });
+        }
+
+        let client = {
+            let mut root_store = rustls::RootCertStore::empty();
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            build_client(root_store)?
+        };
+        Ok(Self {
+            endpoint,
+            method: DohMethod::default(),
+            client,
+            timeout: Self::DEFAULT_TIMEOUT,
+            cache: None,
+        }
+        .with_max_entries(Self::DEFAULT_MAX_ENTRIES))
+    }
+
+    #[must_use]
+    pub const fn with_method(mut self, method: DohMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Sets the limit of the time of a lookup, queries of `A` and `AAAA`
+    /// records are sent concurrently.
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of cached host names, `0` disables the cache.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.cache = NonZeroUsize::new(max_entries)
+            .map(|max_entries| Arc::new(Mutex::new(LruCache::new(max_entries))));
+        self
+    }
+
+    /// Verifies certificates of the endpoint with `root_store` instead of the
+    /// bundled Mozilla root certificates.
+    pub fn with_root_store(mut self, root_store: rustls::RootCertStore) -> Result<Self, Error> {
+        self.client = build_client(root_store)?;
+        Ok(self)
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn endpoint(&self) -> &Url { &self.endpoint }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.as_ref()?.lock().expect("cache of DoH resolver is poisoned");
+        match cache.get(host) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.addrs.clone()),
+            Some(_) => {
+                let _unused = cache.pop(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let (v4, v6) =
+            futures::join!(self.query(host, RECORD_TYPE_A), self.query(host, RECORD_TYPE_AAAA));
+        let ((mut addrs, ttl), other) = match (v4, v6) {
+            (Ok(v4), Ok(v6)) => (v4, Some(v6)),
+            (Ok(answer), Err(err)) | (Err(err), Ok(answer)) => {
+                tracing::debug!("Failed to query {host} via DNS over HTTPS, error: {err}");
+                (answer, None)
+            }
+            (Err(err), Err(_)) => return Err(err),
+        };
+        let ttl = match other {
+            Some((other, other_ttl)) => {
+                addrs.extend(other);
+                ttl.into_iter().chain(other_ttl).min()
+            }
+            None => ttl,
+        };
+
+        if let (Some(cache), Some(ttl)) = (&self.cache, ttl.filter(|ttl| *ttl > 0)) {
+            let entry = CacheEntry {
+                addrs: addrs.clone(),
+                expires_at: Instant::now() + Duration::from_secs(u64::from(ttl)),
+            };
+            let _unused = cache
+                .lock()
+                .expect("cache of DoH resolver is poisoned")
+                .put(host.to_owned(), entry);
+        }
+        Ok(addrs)
+    }
+
+    async fn query(
+        &self,
+        host: &str,
+        record_type: u16,
+    ) -> Result<(Vec<IpAddr>, Option<u32>), Error> {
+        let query = build_query(host, record_type)
+            .ok_or_else(|| Error::ResolveDomainName { domain_name: host.to_owned() })?;
+        let request = match self.method {
+            DohMethod::Get => {
+                let mut url = self.endpoint.clone();
+                let _ = url.query_pairs_mut().append_pair("dns", &URL_SAFE_NO_PAD.encode(&query));
+                self.client.get(url)
+            }
+            DohMethod::Post => self
+                .client
+                .post(self.endpoint.clone())
+                .header(header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+                .body(query),
+        };
+
+        let invalid_response =
+            |reason| Error::InvalidDohResponse { endpoint: self.endpoint.clone(), reason };
+        let mut response =
+            request.header(header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE).send().await.with_context(
+                |_| error::ExchangeDohMessageSnafu { endpoint: self.endpoint.clone() },
+            )?;
+        if response.status() != StatusCode::OK {
+            return Err(invalid_response("unexpected status code"));
+        }
+        let content_type = response.headers().get(header::CONTENT_TYPE);
+        if content_type.is_some_and(|value| value != DNS_MESSAGE_CONTENT_TYPE) {
+            return Err(invalid_response("unexpected content type"));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|_| error::ExchangeDohMessageSnafu { endpoint: self.endpoint.clone() })?
+        {
+            if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+                return Err(invalid_response("response is too large"));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        parse_answer(&body, record_type).map_err(invalid_response)
+    }
+}
+
+impl Resolver for DohResolver {
+    fn resolve(&self, host: &str) -> Resolve {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let resolver = self.clone();
+
+        async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![ip]);
+            }
+            if let Some(addrs) = resolver.cached(&host) {
+                return Ok(addrs);
+            }
+            tokio::time::timeout(resolver.timeout, resolver.lookup(&host))
+                .await
+                .map_err(|_| Error::Timeout { phase: TimeoutPhase::Dns })?
+        }
+        .boxed()
+    }
+}
+
+fn build_client(root_store: rustls::RootCertStore) -> Result<reqwest::Client, Error> {
+    let tls_config =
+        rustls::ClientConfig::builder().with_root_certificates(root_store).with_no_client_auth();
+    reqwest::Client::builder()
+        .use_preconfigured_tls(tls_config)
+        .build()
+        .context(error::BuildDohClientSnafu)
+}
+
+/// Builds a recursive query of `record_type` for `host`, returns `None` if
+/// `host` is not a valid domain name.
+fn build_query(host: &str, record_type: u16) -> Option<Vec<u8>> {
+    if host.is_empty() || host.len() > 253 {
+        return None;
+    }
+
+    // ID is 0 as recommended by RFC 8484, flags with RD set
+    let mut query = vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    for label in host.split('.') {
+        let len = u8::try_from(label.len()).ok().filter(|len| (1..=63).contains(len))?;
+        query.push(len);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(record_type.to_be_bytes());
+    query.extend(RECORD_CLASS_IN.to_be_bytes());
+    Some(query)
+}
+
+/// Returns addresses of `record_type` in the answer section and the smallest
+/// TTL of them, other records like `CNAME` are skipped.
+fn parse_answer(
+    message: &[u8],
+    record_type: u16,
+) -> Result<(Vec<IpAddr>, Option<u32>), &'static str> {
+    const HEADER_LEN: usize = 12;
+
+    let read_u16 = |pos: usize| {
+        message.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or("truncated")
+    };
+
+    if message.len() < HEADER_LEN {
+        return Err("truncated DNS message");
+    }
+    if message[2] & 0x80 == 0 {
+        return Err("DNS message is not a response");
+    }
+    match message[3] & 0x0f {
+        RCODE_NO_ERROR => {}
+        RCODE_NAME_ERROR => return Ok((Vec::new(), None)),
+        _ => return Err("DNS server failed to answer"),
+    }
+
+    let question_count = read_u16(4)?;
+    let answer_count = read_u16(6)?;
+    let mut pos = HEADER_LEN;
+    for _ in 0..question_count {
+        pos = skip_name(message, pos).ok_or("malformed question")? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut min_ttl = None;
+    for _ in 0..answer_count {
+        pos = skip_name(message, pos).ok_or("malformed answer")?;
+        let ty = read_u16(pos)?;
+        let class = read_u16(pos + 2)?;
+        let ttl = message
+            .get(pos + 4..pos + 8)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or("truncated")?;
+        let data_len = usize::from(read_u16(pos + 8)?);
+        let data = message.get(pos + 10..pos + 10 + data_len).ok_or("truncated")?;
+        pos += 10 + data_len;
+
+        if ty != record_type || class != RECORD_CLASS_IN {
+            continue;
+        }
+        let addr = match (record_type, data) {
+            (RECORD_TYPE_A, &[a, b, c, d]) => IpAddr::from(Ipv4Addr::new(a, b, c, d)),
+            (RECORD_TYPE_AAAA, data) if data.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                IpAddr::from(Ipv6Addr::from(octets))
+            }
+            _ => return Err("malformed address record"),
+        };
+        addrs.push(addr);
+        min_ttl = Some(min_ttl.map_or(ttl, |min: u32| min.min(ttl)));
+    }
+    Ok((addrs, min_ttl))
+}
+
+// returns the position after the name at `pos`, names may end with a
+// compression pointer
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return message.get(pos + 1).map(|_| pos + 2),
+            len if len & 0xc0 == 0 => pos += 1 + usize::from(len),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use url::Url;
+
+    use super::{DohMethod, DohResolver, RECORD_TYPE_A, RECORD_TYPE_AAAA};
+    use crate::transport::{Error, Resolver, TimeoutPhase};
+
+    // answers the query with one record of TTL 300 for `A`, TTL 60 for `AAAA`,
+    // the name is a pointer to the question
+    fn canned_answer(query: &[u8]) -> Vec<u8> {
+        let record_type = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+        let mut message = query.to_vec();
+        message[2] |= 0x80;
+        message[7] = 1;
+        message.extend([0xc0, 0x0c]);
+        message.extend(record_type.to_be_bytes());
+        message.extend([0x00, 0x01]);
+        match record_type {
+            RECORD_TYPE_A => {
+                message.extend(300u32.to_be_bytes());
+                message.extend([0x00, 0x04, 192, 0, 2, 1]);
+            }
+            RECORD_TYPE_AAAA => {
+                message.extend(60u32.to_be_bytes());
+                message.extend([0x00, 0x10]);
+                message.extend(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+            }
+            _ => unreachable!(),
+        }
+        message
+    }
+
+    // reads the query of the next request on a kept-alive connection, `None` if
+    // the client closes it
+    async fn read_query(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut request = Vec::new();
+        loop {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.ok().filter(|n| *n > 0)?;
+            request.extend_from_slice(&buf[..n]);
+
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut parsed = httparse::Request::new(&mut headers);
+            let Ok(httparse::Status::Complete(len)) = parsed.parse(&request) else { continue };
+            let path = Url::parse("http://localhost").unwrap().join(parsed.path?).unwrap();
+            assert_eq!(path.path(), "/dns-query");
+            if parsed.method == Some("GET") {
+                let (_, query) = path.query_pairs().find(|(name, _)| name == "dns")?;
+                return URL_SAFE_NO_PAD.decode(query.as_bytes()).ok();
+            }
+
+            let content_length = parsed
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                .map(|header| {
+                    std::str::from_utf8(header.value).unwrap().parse::<usize>().unwrap()
+                })?;
+            if request.len() >= len + content_length {
+                return Some(request[len..len + content_length].to_vec());
+            }
+        }
+    }
+
+    // answers queries on kept-alive connections, counts accepted connections and
+    // answered queries
+    async fn serve_endpoint() -> (Url, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let endpoint =
+            Url::parse(&format!("http://{}/dns-query", listener.local_addr().unwrap())).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let queries = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let (connections, queries) = (connections.clone(), queries.clone());
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let _ = connections.fetch_add(1, Ordering::SeqCst);
+                    let queries = queries.clone();
+                    tokio::spawn(async move {
+                        while let Some(query) = read_query(&mut stream).await {
+                            let _ = queries.fetch_add(1, Ordering::SeqCst);
+                            let body = canned_answer(&query);
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: \
+                                 application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                                body.len()
+                            )
+                            .into_bytes();
+                            response.extend(body);
+                            stream.write_all(&response).await.unwrap();
+                        }
+                    });
+                }
+            }
+        });
+        (endpoint, connections, queries)
+    }
+
+    #[tokio::test]
+    async fn resolve_with_mocked_endpoint() {
+        let expected = vec![
+            IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ];
+        for method in [DohMethod::Get, DohMethod::Post] {
+            let (endpoint, connections, queries) = serve_endpoint().await;
+            let resolver = DohResolver::new(endpoint).unwrap().with_method(method);
+
+            for host in ["example.com", "example.org"] {
+                let mut addrs = resolver.resolve(host).await.unwrap();
+                addrs.sort();
+                assert_eq!(addrs, expected);
+            }
+            assert_eq!(queries.load(Ordering::SeqCst), 4);
+            // connections of the first lookup are reused by the second one
+            assert!(connections.load(Ordering::SeqCst) <= 2);
+
+            // served from cache
+            let mut addrs = resolver.resolve("Example.COM.").await.unwrap();
+            addrs.sort();
+            assert_eq!(addrs, expected);
+            assert_eq!(queries.load(Ordering::SeqCst), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let (endpoint, _connections, queries) = serve_endpoint().await;
+        let resolver = DohResolver::new(endpoint).unwrap().with_max_entries(1);
+
+        for host in ["example.com", "example.org", "example.com"] {
+            let _addrs = resolver.resolve(host).await.unwrap();
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 6);
+        let _addrs = resolver.resolve("example.com").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn lookup_timeout() {
+        // accepts connections and never answers
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let endpoint =
+            Url::parse(&format!("http://{}/dns-query", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let resolver = DohResolver::new(endpoint).unwrap().with_timeout(Duration::from_millis(100));
+        let err = resolver.resolve("example.com").await.unwrap_err();
+        assert!(matches!(err, Error::Timeout { phase: TimeoutPhase::Dns }), "{err}");
+    }
+
+    #[test]
+    fn parse_answer_records() {
+        let query = super::build_query("example.com", RECORD_TYPE_A).unwrap();
+        assert_eq!(query.len(), 29);
+        assert_eq!(
+            super::parse_answer(&canned_answer(&query), RECORD_TYPE_A).unwrap(),
+            (vec![IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))], Some(300))
+        );
+        // answers of another type are skipped
+        assert_eq!(
+            super::parse_answer(&canned_answer(&query), RECORD_TYPE_AAAA).unwrap(),
+            (Vec::new(), None)
+        );
+        assert!(super::parse_answer(&query, RECORD_TYPE_A).is_err());
+        assert!(super::build_query("example..com", RECORD_TYPE_A).is_none());
+    }
+}
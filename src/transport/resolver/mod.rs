@@ -4,10 +4,23 @@ use futures::Future;
 
 use crate::transport::Error;
 
+mod caching;
+#[cfg(feature = "doh")]
+mod doh;
+mod reloading;
+mod static_hosts;
 mod tokio_dns;
 mod trust_dns;
 
-pub use self::{tokio_dns::TokioResolver, trust_dns::TrustDnsResolver};
+#[cfg(feature = "doh")]
+pub use self::doh::{DohMethod, DohResolver};
+pub use self::{
+    caching::CachingResolver,
+    reloading::{InitResolver, ReloadingResolver},
+    static_hosts::{StaticResolver, StaticResolverBuilder},
+    tokio_dns::TokioResolver,
+    trust_dns::TrustDnsResolver,
+};
 
 pub type Resolve = Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, Error>> + Send>>;
 
@@ -0,0 +1,303 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use tokio::sync::Mutex;
+
+use crate::transport::{
+    resolver::{Resolve, Resolver},
+    Error,
+};
+
+type Init = Pin<Box<dyn Future<Output = Result<Arc<dyn Resolver>, Error>> + Send>>;
+
+/// Function initializing the inner resolver of [`ReloadingResolver`].
+pub type InitResolver = Arc<dyn Fn() -> Init + Send + Sync>;
+
+struct Current {
+    resolver: Arc<dyn Resolver>,
+    initialized_at: Instant,
+}
+
+/// Re-initializes another [`Resolver`] after consecutive failures or
+/// periodically, e.g. to pick up changes of `/etc/resolv.conf` in a
+/// long-running server.
+///
+/// Only failures of the resolver itself are counted, see
+/// [`Error::is_resolver_failure`], names which do not exist are not. The
+/// inner resolver is re-initialized in the background, lookups meanwhile are
+/// served by the current one, which is kept if re-initializing fails.
+#[derive(Clone)]
+pub struct ReloadingResolver {
+    init: InitResolver,
+    current: Arc<RwLock<Current>>,
+    failures: Arc<AtomicUsize>,
+    max_failures: usize,
+    reload_interval: Option<Duration>,
+    reloading: Arc<Mutex<()>>,
+}
+
+impl ReloadingResolver {
+    /// Number of consecutive failures re-initializing the inner resolver by
+    /// default.
+    pub const DEFAULT_MAX_FAILURES: usize = 5;
+
+    /// Initializes the inner resolver with `init`.
+    pub async fn new(init: InitResolver) -> Result<Self, Error> {
+        let resolver = init().await?;
+        Ok(Self {
+            init,
+            current: Arc::new(RwLock::new(Current { resolver, initialized_at: Instant::now() })),
+            failures: Arc::default(),
+            max_failures: Self::DEFAULT_MAX_FAILURES,
+            reload_interval: None,
+            reloading: Arc::default(),
+        })
+    }
+
+    /// Sets the number of consecutive failures re-initializing the inner
+    /// resolver, `0` disables it.
+    #[must_use]
+    pub const fn with_max_failures(mut self, max_failures: usize) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+
+    /// Re-initializes the inner resolver once it is older than
+    /// `reload_interval`, checked when resolving. Attempts failing to
+    /// re-initialize it are retried after another `reload_interval`.
+    #[must_use]
+    pub const fn with_reload_interval(mut self, reload_interval: Duration) -> Self {
+        self.reload_interval = Some(reload_interval);
+        self
+    }
+
+    fn resolver(&self) -> Arc<dyn Resolver> {
+        self.current.read().expect("lock is not poisoned").resolver.clone()
+    }
+
+    fn is_expired(&self) -> bool {
+        self.reload_interval.is_some_and(|interval| {
+            self.current.read().expect("lock is not poisoned").initialized_at.elapsed() >= interval
+        })
+    }
+
+    /// Re-initializes the inner resolver, unless it is being re-initialized.
+    pub async fn reload(&self) {
+        let Ok(_reloading) = self.reloading.try_lock() else {
+            return;
+        };
+
+        match (self.init)().await {
+            Ok(resolver) => {
+                tracing::info!("Domain name resolver is re-initialized");
+                *self.current.write().expect("lock is not poisoned") =
+                    Current { resolver, initialized_at: Instant::now() };
+                self.failures.store(0, Ordering::SeqCst);
+            }
+            Err(err) => {
+                tracing::warn!("Failed to re-initialize domain name resolver, error: {err}");
+                self.current.write().expect("lock is not poisoned").initialized_at = Instant::now();
+            }
+        }
+    }
+
+    fn reload_in_background(&self) {
+        let resolver = self.clone();
+        drop(tokio::spawn(async move { resolver.reload().await }));
+    }
+}
+
+impl Resolver for ReloadingResolver {
+    fn resolve(&self, host: &str) -> Resolve {
+        let host = host.to_owned();
+        let resolver = self.clone();
+
+        async move {
+            if resolver.is_expired() {
+                resolver.reload_in_background();
+            }
+
+            match resolver.resolver().resolve(&host).await {
+                Ok(addrs) => {
+                    resolver.failures.store(0, Ordering::SeqCst);
+                    Ok(addrs)
+                }
+                Err(err) if err.is_resolver_failure() => {
+                    let failures = resolver.failures.fetch_add(1, Ordering::SeqCst) + 1;
+                    if resolver.max_failures != 0 && failures >= resolver.max_failures {
+                        tracing::warn!(
+                            "Domain name resolver failed {failures} times in a row, \
+                             re-initializing it"
+                        );
+                        resolver.reload_in_background();
+                    }
+                    Err(err)
+                }
+                Err(err) => Err(err),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::FutureExt;
+
+    use super::{InitResolver, ReloadingResolver};
+    use crate::transport::{
+        resolver::{DummyResolver, Resolve, Resolver},
+        Error, TimeoutPhase,
+    };
+
+    // fails like a resolver with a stale configuration
+    struct BrokenResolver;
+
+    impl Resolver for BrokenResolver {
+        fn resolve(&self, _host: &str) -> Resolve {
+            futures::future::ready(Err(Error::Timeout { phase: TimeoutPhase::Dns })).boxed()
+        }
+    }
+
+    // answers that no name exists
+    struct NotFoundResolver;
+
+    impl Resolver for NotFoundResolver {
+        fn resolve(&self, host: &str) -> Resolve {
+            let domain_name = host.to_owned();
+            futures::future::ready(Err(Error::ResolveDomainName { domain_name })).boxed()
+        }
+    }
+
+    // waits for the resolver re-initialized in the background
+    async fn wait_for_inits(inits: &AtomicUsize, expected: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while inits.load(Ordering::SeqCst) < expected {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::task::yield_now().await;
+    }
+
+    // initializes a broken resolver first and working ones afterwards
+    fn init_resolver(inits: Arc<AtomicUsize>) -> InitResolver {
+        Arc::new(move || {
+            let resolver: Arc<dyn Resolver> = match inits.fetch_add(1, Ordering::SeqCst) {
+                0 => Arc::new(BrokenResolver),
+                _ => Arc::new(DummyResolver::new()),
+            };
+            futures::future::ok(resolver).boxed()
+        })
+    }
+
+    #[tokio::test]
+    async fn reload_after_failures() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let resolver = ReloadingResolver::new(init_resolver(inits.clone()))
+            .await
+            .unwrap()
+            .with_max_failures(2);
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+
+        assert!(resolver.resolve("example.com").await.is_err());
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+        assert!(resolver.resolve("example.com").await.is_err());
+        wait_for_inits(&inits, 2).await;
+
+        // recovered with the re-initialized resolver
+        assert_eq!(
+            resolver.resolve("example.com").await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::UNSPECIFIED)]
+        );
+        assert_eq!(inits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn reload_periodically() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let resolver = ReloadingResolver::new(init_resolver(inits.clone()))
+            .await
+            .unwrap()
+            .with_max_failures(0)
+            .with_reload_interval(Duration::from_millis(50));
+
+        assert!(resolver.resolve("example.com").await.is_err());
+        assert!(resolver.resolve("example.com").await.is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // served by the current resolver while reloading in the background
+        assert!(resolver.resolve("example.com").await.is_err());
+        wait_for_inits(&inits, 2).await;
+        assert!(resolver.resolve("example.com").await.is_ok());
+        assert_eq!(inits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn ignore_names_not_found() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let init: InitResolver = {
+            let inits = inits.clone();
+            Arc::new(move || {
+                let _ = inits.fetch_add(1, Ordering::SeqCst);
+                let resolver: Arc<dyn Resolver> = Arc::new(NotFoundResolver);
+                futures::future::ok(resolver).boxed()
+            })
+        };
+        let resolver = ReloadingResolver::new(init).await.unwrap().with_max_failures(2);
+
+        for _ in 0..4 {
+            assert!(resolver.resolve("example.invalid").await.is_err());
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_failed_reload_after_interval() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let init: InitResolver = {
+            let inits = inits.clone();
+            Arc::new(move || {
+                let result = match inits.fetch_add(1, Ordering::SeqCst) {
+                    0 => Ok(Arc::new(BrokenResolver) as Arc<dyn Resolver>),
+                    _ => Err(Error::Timeout { phase: TimeoutPhase::Dns }),
+                };
+                futures::future::ready(result).boxed()
+            })
+        };
+        let resolver = ReloadingResolver::new(init)
+            .await
+            .unwrap()
+            .with_max_failures(0)
+            .with_reload_interval(Duration::from_millis(50));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(resolver.resolve("example.com").await.is_err());
+        wait_for_inits(&inits, 2).await;
+
+        // the failed attempt is not retried by every lookup
+        for _ in 0..4 {
+            assert!(resolver.resolve("example.com").await.is_err());
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(inits.load(Ordering::SeqCst), 2);
+    }
+}
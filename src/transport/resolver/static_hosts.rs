@@ -0,0 +1,170 @@
+use std::{collections::HashMap, net::IpAddr, path::Path, sync::Arc};
+
+use futures::FutureExt;
+use snafu::ResultExt;
+
+use crate::transport::{
+    error,
+    resolver::{Resolve, Resolver},
+    Error,
+};
+
+/// Resolves host names from a static map, e.g. to map `test.local` to
+/// `127.0.0.1` without touching `/etc/hosts`. Names not in the map are
+/// resolved by the fallback resolver if there is one.
+///
+/// Names are matched exactly and case-insensitively, wildcards are not
+/// supported.
+#[derive(Clone)]
+pub struct StaticResolver {
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl StaticResolver {
+    #[inline]
+    #[must_use]
+    pub fn builder() -> StaticResolverBuilder { StaticResolverBuilder::default() }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize { self.hosts.len() }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool { self.hosts.is_empty() }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, host: &str) -> Resolve {
+        if let Some(addrs) = self.hosts.get(&normalize(host)) {
+            let addrs = addrs.clone();
+            return futures::future::ready(Ok(addrs)).boxed();
+        }
+
+        match self.fallback {
+            Some(ref fallback) => fallback.resolve(host),
+            None => {
+                let domain_name = host.to_owned();
+                futures::future::ready(Err(Error::ResolveDomainName { domain_name })).boxed()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StaticResolverBuilder {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl StaticResolverBuilder {
+    /// Maps `host` to `addr` in addition to addresses mapped before.
+    #[must_use]
+    pub fn insert(mut self, host: impl AsRef<str>, addr: impl Into<IpAddr>) -> Self {
+        let addrs = self.hosts.entry(normalize(host.as_ref())).or_default();
+        let addr = addr.into();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        self
+    }
+
+    /// Sets the resolver of names not in the map.
+    #[must_use]
+    pub fn fallback(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.fallback = Some(resolver);
+        self
+    }
+
+    /// Adds mappings in the format of `/etc/hosts`, each line consists of an
+    /// IP address followed by host names, text after `#` is ignored. Lines
+    /// with invalid addresses are skipped.
+    #[must_use]
+    pub fn hosts_text(mut self, text: &str) -> Self {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(addr) = fields.next() else {
+                continue;
+            };
+            let Ok(addr) = addr.parse::<IpAddr>() else {
+                tracing::warn!("Skip invalid line of hosts file: {line}");
+                continue;
+            };
+            for host in fields {
+                self = self.insert(host, addr);
+            }
+        }
+        self
+    }
+
+    /// Adds mappings of a file in the format of `/etc/hosts`.
+    pub fn hosts_file<P: AsRef<Path>>(self, file_path: P) -> Result<Self, Error> {
+        let file_path = file_path.as_ref();
+        let text = std::fs::read_to_string(file_path)
+            .context(error::OpenFileSnafu { file_path: file_path.to_owned() })?;
+        Ok(self.hosts_text(&text))
+    }
+
+    #[must_use]
+    pub fn build(self) -> StaticResolver {
+        StaticResolver { hosts: Arc::new(self.hosts), fallback: self.fallback }
+    }
+}
+
+fn normalize(host: &str) -> String { host.trim_end_matches('.').to_ascii_lowercase() }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+    };
+
+    use super::StaticResolver;
+    use crate::transport::{resolver::DummyResolver, Error, Resolver};
+
+    #[tokio::test]
+    async fn resolve_static_hosts() {
+        let resolver = StaticResolver::builder()
+            .insert("test.local", Ipv4Addr::LOCALHOST)
+            .hosts_text(
+                "# comment\n127.0.0.2 alias.local Other.Local # trailing\n::1 test.local\nbogus \
+                 host.local\n",
+            )
+            .build();
+        assert_eq!(resolver.len(), 3);
+
+        assert_eq!(
+            resolver.resolve("TEST.local.").await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::LOCALHOST), IpAddr::from(Ipv6Addr::LOCALHOST)]
+        );
+        assert_eq!(
+            resolver.resolve("other.local").await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::new(127, 0, 0, 2))]
+        );
+
+        // misses without fallback
+        assert!(matches!(
+            resolver.resolve("host.local").await,
+            Err(Error::ResolveDomainName { domain_name }) if domain_name == "host.local"
+        ));
+    }
+
+    #[tokio::test]
+    async fn fall_back_on_miss() {
+        let resolver = StaticResolver::builder()
+            .insert("test.local", Ipv4Addr::LOCALHOST)
+            .fallback(Arc::new(DummyResolver::new()))
+            .build();
+        assert_eq!(
+            resolver.resolve("test.local").await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::LOCALHOST)]
+        );
+        assert_eq!(
+            resolver.resolve("example.com").await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::UNSPECIFIED)]
+        );
+    }
+}
@@ -15,6 +15,10 @@ impl TokioResolver {
     pub const fn new() -> Self { Self }
 }
 
+impl Default for TokioResolver {
+    fn default() -> Self { Self::new() }
+}
+
 impl Resolver for TokioResolver {
     fn resolve(&self, host: &str) -> Resolve {
         let host = host.to_owned();
@@ -0,0 +1,80 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Future;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::{self, Sleep},
+};
+
+use crate::transport::DebugLatency;
+
+const BUF_SIZE: usize = 8 * 1024;
+
+/// Reader holding back each chunk read for the delay of [`DebugLatency`]
+/// before returning it, `None` means no delay.
+pub struct DelayedReader<Reader> {
+    reader: Reader,
+    latency: Option<DebugLatency>,
+    buf: Vec<u8>,
+    pos: usize,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<Reader> DelayedReader<Reader>
+where
+    Reader: Unpin + AsyncRead,
+{
+    #[inline]
+    pub fn new(reader: Reader, latency: Option<DebugLatency>) -> Self {
+        Self { reader, latency, buf: Vec::new(), pos: 0, timer: None }
+    }
+
+    /// Returns the inner reader, data held back is discarded.
+    #[inline]
+    pub fn into_inner(self) -> Reader { self.reader }
+}
+
+impl<Reader> AsyncRead for DelayedReader<Reader>
+where
+    Reader: Unpin + AsyncRead,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let Some(latency) = self.latency else {
+            return Pin::new(&mut self.reader).poll_read(cx, buf);
+        };
+
+        if self.pos == self.buf.len() {
+            let mut chunk = [0u8; BUF_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut self.reader).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(())) if chunk.filled().is_empty() => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(())) => {
+                    self.buf = chunk.filled().to_vec();
+                    self.pos = 0;
+                    self.timer = Some(Box::pin(time::sleep(latency.delay())));
+                }
+                poll => return poll,
+            }
+        }
+
+        if let Some(timer) = self.timer.as_mut() {
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.timer = None;
+        }
+
+        let n = buf.remaining().min(self.buf.len() - self.pos);
+        buf.put_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -6,6 +6,8 @@ use std::{
 
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+/// Counts bytes read from and written to a [`MonitoredStream`], both halves of
+/// a split stream report to the same monitor.
 pub trait StatMonitor: Send + Sync {
     fn increase_rx(&mut self, n: usize);
     fn increase_tx(&mut self, n: usize);
@@ -54,16 +56,17 @@ where
 
 impl<Stream, Monitor> AsyncRead for MonitoredStream<Stream, Monitor>
 where
-    Stream: AsyncRead,
-    Monitor: StatMonitor,
+    Stream: Unpin + AsyncRead,
+    Monitor: Unpin + StatMonitor,
 {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
         let n = match Pin::new(&mut self.stream).poll_read(cx, buf)? {
-            Poll::Ready(_) => buf.filled().len(),
+            Poll::Ready(()) => buf.filled().len() - filled,
             Poll::Pending => return Poll::Pending,
         };
         self.monitor.increase_rx(n);
@@ -100,3 +103,56 @@ where
         Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        filter::SimpleFilter,
+        transport::{TokioResolver, Transport},
+    };
+
+    #[tokio::test]
+    async fn count_relayed_bytes() {
+        const UPLOAD: usize = 100_000;
+        const DOWNLOAD: usize = 30_000;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (client_peer, _) = listener.accept().await.unwrap();
+        let mut remote = TcpStream::connect(addr).await.unwrap();
+        let (remote_peer, _) = listener.accept().await.unwrap();
+
+        let transport = Arc::new(Transport::direct(
+            Arc::new(TokioResolver::new()),
+            Arc::new(SimpleFilter::deny_list()),
+        ));
+        let relay = tokio::spawn({
+            let transport = transport.clone();
+            async move { transport.relay(client_peer, remote_peer, None).await }
+        });
+
+        let uploading = tokio::spawn(async move {
+            let mut buf = vec![0u8; UPLOAD];
+            remote.read_exact(&mut buf).await.unwrap();
+            remote.write_all(&[0u8; DOWNLOAD]).await.unwrap();
+            remote
+        });
+        client.write_all(&[0u8; UPLOAD]).await.unwrap();
+        let mut buf = vec![0u8; DOWNLOAD];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(uploading.await.unwrap());
+        drop(client);
+        let _unused = relay.await.unwrap();
+
+        assert_eq!(transport.metrics().received_bytes(), UPLOAD);
+        assert_eq!(transport.metrics().transmitted_bytes(), DOWNLOAD);
+    }
+}
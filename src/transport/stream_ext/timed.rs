@@ -8,13 +8,20 @@ use std::{
 use futures::Future;
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf},
-    time::{self, Sleep},
+    time::{self, Instant, Sleep},
 };
 
+/// Stream failing with `io::ErrorKind::TimedOut` once nothing is read or
+/// written for `timeout`, `None` means no timeout.
+///
+/// Reading and writing share the deadline, so that a peer which only
+/// receives data is not considered idle.
 pub struct TimedStream<Stream> {
     stream: Stream,
-    timer: Option<Sleep>,
     timeout: Option<Duration>,
+    deadline: Instant,
+    read_timer: Option<Pin<Box<Sleep>>>,
+    write_timer: Option<Pin<Box<Sleep>>>,
 }
 
 impl<Stream> TimedStream<Stream>
@@ -23,7 +30,8 @@ where
 {
     #[inline]
     pub fn new(stream: Stream, timeout: Option<Duration>) -> TimedStream<Stream> {
-        TimedStream { stream, timeout, timer: None }
+        let deadline = Instant::now() + timeout.unwrap_or_default();
+        TimedStream { stream, timeout, deadline, read_timer: None, write_timer: None }
     }
 
     #[inline]
@@ -53,23 +61,37 @@ impl<Stream> TimedStream<Stream> {
     #[inline]
     fn make_timeout_error() -> io::Error { io::ErrorKind::TimedOut.into() }
 
-    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        loop {
-            if let Some(ref mut timer) = self.timer {
-                futures::ready!(Pin::new(timer).poll(cx));
-                // FIXME: Clear self.timer or not?
-                return Poll::Ready(Err(Self::make_timeout_error()));
-            } else {
-                match self.timeout {
-                    Some(timeout) => self.timer = Some(time::sleep(timeout)),
-                    None => break,
-                }
-            }
+    fn touch(&mut self) {
+        if let Some(timeout) = self.timeout {
+            self.deadline = Instant::now() + timeout;
+        }
+    }
+
+    // polls `timer` which is moved to the latest deadline first, the deadline
+    // may be extended by activities in the other direction
+    fn poll_timer(
+        timer: &mut Option<Pin<Box<Sleep>>>,
+        timeout: Option<Duration>,
+        deadline: Instant,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Error> {
+        if timeout.is_none() {
+            return Poll::Pending;
+        }
+        let timer = timer.get_or_insert_with(|| Box::pin(time::sleep_until(deadline)));
+        if timer.deadline() != deadline {
+            timer.as_mut().reset(deadline);
         }
-        Poll::Ready(Ok(()))
+        timer.as_mut().poll(cx).map(|()| Self::make_timeout_error())
+    }
+
+    fn poll_read_timeout(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        Self::poll_timer(&mut self.read_timer, self.timeout, self.deadline, cx)
     }
 
-    fn cancel_timeout(&mut self) { let _ = self.timer.take(); }
+    fn poll_write_timeout(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        Self::poll_timer(&mut self.write_timer, self.timeout, self.deadline, cx)
+    }
 }
 
 impl<Stream> AsyncRead for TimedStream<Stream>
@@ -83,13 +105,10 @@ where
     ) -> Poll<io::Result<()>> {
         match Pin::new(&mut self.stream).poll_read(cx, buf) {
             Poll::Ready(r) => {
-                self.cancel_timeout();
+                self.touch();
                 Poll::Ready(r)
             }
-            Poll::Pending => {
-                futures::ready!(self.poll_timeout(cx))?;
-                Poll::Pending
-            }
+            Poll::Pending => self.poll_read_timeout(cx).map(Err),
         }
     }
 }
@@ -105,26 +124,17 @@ where
     ) -> Poll<Result<usize, io::Error>> {
         match Pin::new(&mut self.stream).poll_write(cx, buf) {
             Poll::Ready(r) => {
-                self.cancel_timeout();
+                self.touch();
                 Poll::Ready(r)
             }
-            Poll::Pending => {
-                futures::ready!(self.poll_timeout(cx))?;
-                Poll::Pending
-            }
+            Poll::Pending => self.poll_write_timeout(cx).map(Err),
         }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
         match Pin::new(&mut self.stream).poll_flush(cx) {
-            Poll::Ready(r) => {
-                self.cancel_timeout();
-                Poll::Ready(r)
-            }
-            Poll::Pending => {
-                futures::ready!(self.poll_timeout(cx))?;
-                Poll::Pending
-            }
+            Poll::Ready(r) => Poll::Ready(r),
+            Poll::Pending => self.poll_write_timeout(cx).map(Err),
         }
     }
 
@@ -135,3 +145,60 @@ where
         Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::TimedStream;
+
+    #[tokio::test]
+    async fn drop_stalled_peer() {
+        let (stream, _peer) = tokio::io::duplex(64);
+        let mut stream = TimedStream::new(stream, Some(Duration::from_millis(50)));
+
+        let mut buf = [0u8; 8];
+        let err = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        // a full buffer which is never drained by the peer stalls writing
+        let (stream, _peer) = tokio::io::duplex(8);
+        let mut stream = TimedStream::new(stream, Some(Duration::from_millis(50)));
+        let err = tokio::time::timeout(Duration::from_secs(1), stream.write_all(&[0u8; 64]))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn keep_active_peer() {
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let mut stream = TimedStream::new(stream, Some(Duration::from_millis(100)));
+
+        let writer = tokio::spawn(async move {
+            for _ in 0..5 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                peer.write_all(b"ping").await.unwrap();
+            }
+            peer
+        });
+
+        // reads for longer than the timeout in total, but never idles for it
+        let mut buf = [0u8; 20];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pingpingpingpingping");
+        drop(writer.await.unwrap());
+
+        // never times out without timeout
+        let (stream, _peer) = tokio::io::duplex(64);
+        let mut stream = TimedStream::new(stream, None);
+        let read = tokio::time::timeout(Duration::from_millis(200), stream.read_u8()).await;
+        assert!(read.is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use futures::Future;
+use tokio::time::Instant;
+
+/// Phase of a connection in which a timeout occurs.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TimeoutPhase {
+    /// Resolving domain name of remote host.
+    Dns,
+
+    /// Connecting remote host, including handshakes with upstream proxy
+    /// servers.
+    Connect,
+
+    /// Receiving request from client.
+    Handshake,
+
+    /// Relaying longer than the allowed lifetime of a connection.
+    Relay,
+
+    /// No data is relayed in either direction.
+    Idle,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dns => write!(f, "DNS resolution"),
+            Self::Connect => write!(f, "connect"),
+            Self::Handshake => write!(f, "handshake"),
+            Self::Relay => write!(f, "relay"),
+            Self::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// Timeouts of [`Transport`](super::Transport), `None` means no timeout.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timeouts {
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub relay: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+/// Runs `fut` within `duration`, fails with `phase` if it is not completed in
+/// time.
+pub(crate) async fn with_timeout<F>(
+    duration: Option<Duration>,
+    phase: TimeoutPhase,
+    fut: F,
+) -> Result<F::Output, TimeoutPhase>
+where
+    F: Future,
+{
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, fut).await.map_err(|_| phase),
+        None => Ok(fut.await),
+    }
+}
+
+/// Tracks the last time data is relayed.
+#[derive(Debug)]
+pub(crate) struct Activity {
+    started: Instant,
+    // milliseconds since `started`
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self { Self { started: Instant::now(), last: AtomicU64::new(0) } }
+
+    pub(crate) fn touch(&self) {
+        let elapsed = u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Completes once nothing is relayed for `duration`.
+    pub(crate) async fn idle(&self, duration: Duration) {
+        loop {
+            let last = self.started + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + duration;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use futures::FutureExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{TimeoutPhase, Timeouts};
+    use crate::{
+        common::HostAddress,
+        filter::SimpleFilter,
+        transport::{
+            connector,
+            resolver::{Resolve, Resolver},
+            TokioResolver, Transport,
+        },
+    };
+
+    struct StalledResolver;
+
+    impl Resolver for StalledResolver {
+        fn resolve(&self, _host: &str) -> Resolve { Box::pin(futures::future::pending()) }
+    }
+
+    fn transport(resolver: Arc<dyn Resolver>, timeouts: Timeouts) -> Transport<TcpStream> {
+        let filter = Arc::new(SimpleFilter::deny_list());
+        Transport::direct(resolver, filter).with_timeouts(timeouts)
+    }
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+        (stream, peer)
+    }
+
+    #[tokio::test]
+    async fn dns_timeout() {
+        let timeouts = Timeouts { dns: Some(Duration::from_millis(50)), ..Timeouts::default() };
+        let transport = transport(Arc::new(StalledResolver), timeouts);
+
+        let host = HostAddress::DomainName("example.com".to_string(), 80);
+        let err = transport.connect(&host).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Dns));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let timeouts = Timeouts { connect: Some(Duration::from_millis(50)), ..Timeouts::default() };
+        let mut transport = transport(Arc::new(TokioResolver::new()), timeouts);
+        transport.connector = connector::connect_fn(
+            Box::new(|_| futures::future::pending().boxed()),
+            Box::new(|_| futures::future::pending().boxed()),
+        );
+
+        let host = HostAddress::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+        let err = transport.connect(&host).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Connect));
+    }
+
+    #[tokio::test]
+    async fn relay_timeout() {
+        let timeouts = Timeouts { relay: Some(Duration::from_millis(50)), ..Timeouts::default() };
+        let transport = transport(Arc::new(TokioResolver::new()), timeouts);
+
+        let (client, _client_peer) = connected_pair().await;
+        let (remote, _remote_peer) = connected_pair().await;
+        let err = transport.relay_bidirectional(client, remote, None).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Relay));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let timeouts = Timeouts { idle: Some(Duration::from_millis(50)), ..Timeouts::default() };
+        let transport = transport(Arc::new(TokioResolver::new()), timeouts);
+
+        let (client, _client_peer) = connected_pair().await;
+        let (remote, _remote_peer) = connected_pair().await;
+        let err = transport.relay_bidirectional(client, remote, None).await.unwrap_err();
+        assert_eq!(err.timeout_phase(), Some(TimeoutPhase::Idle));
+    }
+}